@@ -0,0 +1,28 @@
+//! Deterministic fixed-point arithmetic. Ratios stay as integer numerators
+//! and denominators in u128 and are divided once, with the rounding stated
+//! at every call, so every validator and client computes the same result.
+
+use crate::Rounding;
+
+/// `a * b / denominator` rounded as asked. When `a * b` overflows u128 the
+/// quotient is split as `(a / d) * b + (a % d) * b / d`. `None` for a zero
+/// denominator or a result that still does not fit.
+pub fn mul_div(a: u128, b: u128, denominator: u128, rounding: Rounding) -> Option<u128> {
+    if denominator == 0 {
+        return None;
+    }
+    let (quotient, remainder) = match a.checked_mul(b) {
+        Some(product) => (product / denominator, product % denominator),
+        None => {
+            let whole = (a / denominator).checked_mul(b)?;
+            let rest = (a % denominator).checked_mul(b)?;
+            (whole.checked_add(rest / denominator)?, rest % denominator)
+        }
+    };
+    let round_up = match rounding {
+        Rounding::Down => false,
+        Rounding::Up => remainder > 0,
+        Rounding::Nearest => remainder >= denominator - remainder,
+    };
+    quotient.checked_add(round_up as u128)
+}
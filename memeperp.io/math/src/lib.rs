@@ -0,0 +1,333 @@
+//! Pure margin, PnL, funding and liquidation math used by the memeperp
+//! program. The crate is `no_std` with no dependencies so risk dashboards
+//! and bots (native or wasm) can link the exact code that runs on-chain.
+//! It is versioned together with the program.
+#![no_std]
+
+pub mod fixed;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Basis point denominator
+pub const BPS: u128 = 10000;
+
+/// Margin posted for `size` at `price` and `leverage`.
+pub fn required_margin(size: u64, price: u64, leverage: u8) -> u64 {
+    (size * price) / leverage as u64
+}
+
+/// Price at which a position opened at `entry_price` is liquidated, with
+/// `liquidation_threshold` in bps of margin.
+pub fn liquidation_price(is_long: bool, entry_price: u64, leverage: u8, liquidation_threshold: u16) -> u64 {
+    liquidation_price_at_leverage(is_long, entry_price, leverage as u128, 1, liquidation_threshold)
+}
+
+/// `liquidation_price` for a position holding `margin` against `notional`.
+/// Margin added on top of what `leverage` required lowers the effective
+/// leverage; a shortfall does not raise it above `leverage`.
+pub fn liquidation_price_for_margin(
+    is_long: bool,
+    entry_price: u64,
+    notional: u64,
+    margin: u64,
+    leverage: u8,
+    liquidation_threshold: u16,
+) -> u64 {
+    if margin == 0 || notional as u128 >= margin as u128 * leverage as u128 {
+        return liquidation_price(is_long, entry_price, leverage, liquidation_threshold);
+    }
+    liquidation_price_at_leverage(is_long, entry_price, notional as u128, margin as u128, liquidation_threshold)
+}
+
+/// Liquidation price at a leverage of `leverage_numerator / leverage_denominator`:
+/// the entry price moved by `(1 - threshold) * leverage` of itself. The
+/// move is rounded down, which liquidates a little early rather than late.
+fn liquidation_price_at_leverage(
+    is_long: bool,
+    entry_price: u64,
+    leverage_numerator: u128,
+    leverage_denominator: u128,
+    liquidation_threshold: u16,
+) -> u64 {
+    let loss_bps = BPS.saturating_sub(liquidation_threshold as u128);
+    let price_move = fixed::mul_div(
+        entry_price as u128 * loss_bps,
+        leverage_numerator,
+        leverage_denominator * BPS,
+        Rounding::Down,
+    )
+    .unwrap_or(u128::MAX);
+
+    let liquidation_price = if is_long {
+        (entry_price as u128).saturating_sub(price_move)
+    } else {
+        (entry_price as u128).saturating_add(price_move)
+    };
+    liquidation_price.min(u64::MAX as u128) as u64
+}
+
+/// PnL in quote units: the base size times the price change in the
+/// position's favor. `None` on overflow.
+pub fn pnl(is_long: bool, base_size: u64, entry_price: u64, current_price: u64) -> Option<i64> {
+    let price_change = if is_long {
+        current_price as i128 - entry_price as i128
+    } else {
+        entry_price as i128 - current_price as i128
+    };
+    let pnl = price_change.checked_mul(base_size as i128)?;
+    i64::try_from(pnl).ok()
+}
+
+/// Signed premium of `mark_price` over `index_price` in bps, truncated
+/// toward zero: positive while the mark trades above the index. Zero
+/// without an index price.
+pub fn premium_bps(mark_price: u64, index_price: u64) -> i64 {
+    if index_price == 0 {
+        return 0;
+    }
+    let premium = (mark_price as i128 - index_price as i128) * BPS as i128 / index_price as i128;
+    premium.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// Funding rate in bps per interval from the time-weighted premium:
+/// `premium + clamp(interest - premium, -clamp_bps, clamp_bps)`, positive
+/// when longs pay shorts, clamped to ±`max_rate_bps`.
+pub fn funding_rate(premium_bps: i64, interest_rate_bps: i64, clamp_bps: i64, max_rate_bps: i64) -> i64 {
+    let clamp_bps = clamp_bps.max(0);
+    let max_rate_bps = max_rate_bps.max(0);
+    let pull = interest_rate_bps.saturating_sub(premium_bps).clamp(-clamp_bps, clamp_bps);
+    premium_bps.saturating_add(pull).clamp(-max_rate_bps, max_rate_bps)
+}
+
+/// Signed funding a position receives (positive) or pays (negative) for one
+/// interval at `funding_rate` bps.
+pub fn funding_payment(notional: u64, funding_rate: i64, is_long: bool) -> i64 {
+    let amount = ((notional as i128 * funding_rate as i128) / BPS as i128) as i64;
+    if is_long {
+        -amount
+    } else {
+        amount
+    }
+}
+
+/// Margin after applying a funding payment. `None` if it would go negative
+/// or overflow.
+pub fn apply_funding(margin: u64, funding_amount: i64) -> Option<u64> {
+    if funding_amount > 0 {
+        margin.checked_add(funding_amount as u64)
+    } else {
+        margin.checked_sub(funding_amount.unsigned_abs())
+    }
+}
+
+/// Price at which a position's loss equals its margin, given the price move
+/// that loses the whole margin. Past it the position has negative equity.
+pub fn bankruptcy_price(is_long: bool, entry_price: u64, margin_move: u64) -> u64 {
+    if is_long {
+        entry_price.saturating_sub(margin_move)
+    } else {
+        entry_price.saturating_add(margin_move)
+    }
+}
+
+pub fn is_liquidatable(is_long: bool, liquidation_price: u64, current_price: u64) -> bool {
+    if is_long {
+        current_price <= liquidation_price
+    } else {
+        current_price >= liquidation_price
+    }
+}
+
+/// Margin as a share of position value, in bps.
+pub fn health_ratio(margin: u64, base_size: u64, current_price: u64) -> Option<u16> {
+    let position_value = (base_size as u128).checked_mul(current_price as u128)?;
+    let margin_ratio = (margin as u128).checked_mul(BPS)?.checked_div(position_value)?;
+    Some(margin_ratio as u16)
+}
+
+/// Distance between mark and index in bps of index; `u64::MAX` without an index.
+pub fn mark_index_deviation_bps(mark_price: u64, index_price: u64) -> u64 {
+    if index_price == 0 {
+        return u64::MAX;
+    }
+    let difference = mark_price.abs_diff(index_price) as u128;
+    (difference * BPS / index_price as u128).min(u64::MAX as u128) as u64
+}
+
+/// Average price per unit for moving a constant-product pool with `depth`
+/// virtual base reserve from `from` to `to` net base bought by traders
+/// (negative when net sold). The pool quotes `peg_price` at zero net; the
+/// mark is the price with `from == to`. `None` once the base reserve would
+/// be used up.
+pub fn vamm_price(depth: u64, peg_price: u64, from: i64, to: i64, rounding: Rounding) -> Option<u64> {
+    let reserve = |net: i64| u128::try_from(depth as i128 - net as i128).ok().filter(|reserve| *reserve > 0);
+    let (before, after) = (reserve(from)?, reserve(to)?);
+    // Quote reserve is k / base with k = depth^2 * peg, so the average is
+    // peg * depth^2 / (before * after)
+    let price = fixed::mul_div(peg_price as u128, depth as u128, before, rounding)?;
+    u64::try_from(fixed::mul_div(price, depth as u128, after, rounding)?).ok()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rounding {
+    Down,
+    Up,
+    Nearest,
+}
+
+/// Converts an oracle price `price * 10^expo` into fixed point with
+/// `decimals` decimals. `None` for negative prices or if the result does
+/// not fit in a u64.
+pub fn normalize_price(price: i64, expo: i32, decimals: u8, rounding: Rounding) -> Option<u64> {
+    let price = u64::try_from(price).ok()? as u128;
+    let shift = expo as i64 + decimals as i64;
+
+    if shift >= 0 {
+        if price == 0 {
+            return Some(0);
+        }
+        let scale = 10u128.checked_pow(u32::try_from(shift).ok()?)?;
+        return u64::try_from(price.checked_mul(scale)?).ok();
+    }
+
+    // u128 holds at most 10^38; any larger divisor leaves a quotient of zero
+    let (quotient, remainder, divisor) = match 10u128.checked_pow(shift.unsigned_abs() as u32) {
+        Some(divisor) => (price / divisor, price % divisor, Some(divisor)),
+        None => (0, price, None),
+    };
+    let round_up = match rounding {
+        Rounding::Down => false,
+        Rounding::Up => remainder > 0,
+        Rounding::Nearest => divisor.is_some_and(|divisor| remainder * 2 >= divisor),
+    };
+    u64::try_from(quotient + round_up as u128).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_down_with_rounding() {
+        // 1.2345678 at 6 decimals
+        assert_eq!(normalize_price(12_345_678, -7, 6, Rounding::Down), Some(1_234_567));
+        assert_eq!(normalize_price(12_345_678, -7, 6, Rounding::Up), Some(1_234_568));
+        assert_eq!(normalize_price(12_345_678, -7, 6, Rounding::Nearest), Some(1_234_568));
+        assert_eq!(normalize_price(12_345_674, -7, 6, Rounding::Nearest), Some(1_234_567));
+        assert_eq!(normalize_price(12_345_670, -7, 6, Rounding::Up), Some(1_234_567));
+    }
+
+    #[test]
+    fn scales_up_and_matches_exact_exponents() {
+        assert_eq!(normalize_price(42, 0, 6, Rounding::Down), Some(42_000_000));
+        assert_eq!(normalize_price(42, 3, 0, Rounding::Down), Some(42_000));
+        assert_eq!(normalize_price(1_500_000, -6, 6, Rounding::Up), Some(1_500_000));
+    }
+
+    #[test]
+    fn handles_extreme_negative_exponents() {
+        // Memecoin prices far below one unit
+        assert_eq!(normalize_price(123, -12, 6, Rounding::Down), Some(0));
+        assert_eq!(normalize_price(123, -12, 6, Rounding::Up), Some(1));
+        assert_eq!(normalize_price(600_000, -12, 6, Rounding::Nearest), Some(1));
+        assert_eq!(normalize_price(i64::MAX, -60, 0, Rounding::Down), Some(0));
+        assert_eq!(normalize_price(i64::MAX, -60, 0, Rounding::Up), Some(1));
+        assert_eq!(normalize_price(i64::MAX, -60, 0, Rounding::Nearest), Some(0));
+        assert_eq!(normalize_price(i64::MAX, i32::MIN, 18, Rounding::Up), Some(1));
+    }
+
+    #[test]
+    fn extra_margin_moves_the_liquidation_price_away() {
+        // 10x at 1000 with a 95% threshold; doubling the margin makes it 5x
+        let at_10x = liquidation_price(true, 1000, 10, 9500);
+        assert_eq!(liquidation_price_for_margin(true, 1000, 10_000, 1000, 10, 9500), at_10x);
+        assert_eq!(liquidation_price_for_margin(true, 1000, 10_000, 2000, 10, 9500), liquidation_price(true, 1000, 5, 9500));
+        assert_eq!(liquidation_price_for_margin(false, 1000, 10_000, 2000, 10, 9500), liquidation_price(false, 1000, 5, 9500));
+        assert!(liquidation_price(true, 1000, 5, 9500) > at_10x);
+        // Less margin than the leverage required keeps the leverage's price
+        assert_eq!(liquidation_price_for_margin(true, 1000, 10_000, 500, 10, 9500), at_10x);
+        assert_eq!(liquidation_price_for_margin(true, 1000, 10_000, 0, 10, 9500), at_10x);
+    }
+
+    #[test]
+    fn vamm_price_moves_with_net_flow() {
+        assert_eq!(vamm_price(1000, 100, 0, 0, Rounding::Down), Some(100));
+        // Buying half the base reserve doubles the average and quadruples the mark
+        assert_eq!(vamm_price(1000, 100, 0, 500, Rounding::Down), Some(200));
+        assert_eq!(vamm_price(1000, 100, 500, 500, Rounding::Down), Some(400));
+        // Selling as much halves the average and quarters the mark
+        assert_eq!(vamm_price(1000, 100, 0, -1000, Rounding::Down), Some(50));
+        assert_eq!(vamm_price(1000, 100, -1000, -1000, Rounding::Down), Some(25));
+        assert_eq!(vamm_price(1000, 100, 0, 3, Rounding::Down), Some(100));
+        assert_eq!(vamm_price(1000, 100, 0, 3, Rounding::Up), Some(101));
+        assert_eq!(vamm_price(1000, 100, 0, 1000, Rounding::Down), None);
+        assert_eq!(vamm_price(0, 100, 0, 0, Rounding::Down), None);
+    }
+
+    #[test]
+    fn mul_div_rounds_as_asked() {
+        assert_eq!(fixed::mul_div(10, 10, 3, Rounding::Down), Some(33));
+        assert_eq!(fixed::mul_div(10, 10, 3, Rounding::Up), Some(34));
+        assert_eq!(fixed::mul_div(10, 10, 3, Rounding::Nearest), Some(33));
+        assert_eq!(fixed::mul_div(5, 1, 2, Rounding::Nearest), Some(3));
+        assert_eq!(fixed::mul_div(9, 2, 6, Rounding::Up), Some(3));
+        assert_eq!(fixed::mul_div(1, 1, 0, Rounding::Down), None);
+    }
+
+    #[test]
+    fn mul_div_survives_an_overflowing_product() {
+        let a = u128::MAX / 3;
+        assert_eq!(fixed::mul_div(a, 6, 6, Rounding::Down), Some(a));
+        assert_eq!(fixed::mul_div(u128::MAX, 2, 4, Rounding::Up), Some(u128::MAX / 2 + 1));
+        assert_eq!(fixed::mul_div(u128::MAX, 3, 2, Rounding::Down), None);
+    }
+
+    #[test]
+    fn liquidation_price_is_exact_and_rounds_early() {
+        // 10x with a 95% threshold moves the price 50%
+        assert_eq!(liquidation_price(true, 1000, 10, 9500), 500);
+        assert_eq!(liquidation_price(false, 1000, 10, 9500), 1500);
+        // A fractional move is rounded toward liquidating earlier
+        assert_eq!(liquidation_price(true, 999, 3, 9000), 700);
+        assert_eq!(liquidation_price(false, 999, 3, 9000), 1298);
+        // A long cannot be liquidated below zero
+        assert_eq!(liquidation_price(true, 1000, 100, 5000), 0);
+        assert_eq!(liquidation_price(false, u64::MAX, 100, 5000), u64::MAX);
+        assert_eq!(liquidation_price_for_margin(true, 1000, 9999, 3333, 10, 9000), 700);
+    }
+
+    #[test]
+    fn premium_truncates_toward_zero() {
+        assert_eq!(premium_bps(10_000, 10_000), 0);
+        assert_eq!(premium_bps(10_005, 10_000), 5);
+        assert_eq!(premium_bps(9_995, 10_000), -5);
+        assert_eq!(premium_bps(100_009, 100_000), 0);
+        assert_eq!(premium_bps(99_991, 100_000), 0);
+        assert_eq!(premium_bps(1, 0), 0);
+    }
+
+    #[test]
+    fn funding_rate_pulls_toward_interest_and_clamps() {
+        // The interest rate moves funding by at most the clamp
+        assert_eq!(funding_rate(0, 1, 5, 10), 1);
+        assert_eq!(funding_rate(4, 1, 5, 10), 1);
+        assert_eq!(funding_rate(8, 1, 5, 10), 3);
+        assert_eq!(funding_rate(-8, 1, 5, 10), -3);
+        // Without a clamp funding is the premium alone
+        assert_eq!(funding_rate(-4, 1, 0, 10), -4);
+        // The market's maximum rate bounds the result either way
+        assert_eq!(funding_rate(40, 0, 5, 25), 25);
+        assert_eq!(funding_rate(i64::MAX, 0, 5, 10), 10);
+        assert_eq!(funding_rate(i64::MIN, 0, 5, 10), -10);
+    }
+
+    #[test]
+    fn rejects_overflow_and_negative_prices() {
+        assert_eq!(normalize_price(1, 20, 0, Rounding::Down), None);
+        assert_eq!(normalize_price(i64::MAX, 1, 6, Rounding::Down), None);
+        assert_eq!(normalize_price(1, i32::MAX, 0, Rounding::Down), None);
+        assert_eq!(normalize_price(0, 40, 6, Rounding::Down), Some(0));
+        assert_eq!(normalize_price(-1, -6, 6, Rounding::Down), None);
+        assert_eq!(normalize_price(u64::MAX as i64, 0, 0, Rounding::Down), None);
+    }
+}
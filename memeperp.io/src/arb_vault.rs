@@ -0,0 +1,50 @@
+use anchor_lang::prelude::*;
+use crate::{ErrorCode, Market, Position, Side};
+
+/// Protocol-operated vault that collects funding by holding the receiving
+/// side of each market. It trades through its own margin account (authority
+/// is this PDA, sub-account 0) and depositors share its results pro rata
+/// through `share_mint`.
+#[account]
+pub struct FundingArbVault {
+    pub operator: Pubkey,  // keeper allowed to open and close positions
+    pub token_account: Pubkey,  // idle quote, owned by this PDA
+    pub share_mint: Pubkey,  // mint authority is this PDA
+    pub deployed_margin: u64,  // margin posted in open positions, as of the last revaluation
+    pub max_market_exposure_bps: u16,  // per-market notional cap, in bps of equity
+    pub bump: u8,
+}
+
+impl FundingArbVault {
+    pub const LEN: usize = 8 + 32 + 32 + 32 + 8 + 2 + 1;
+
+    /// Equity of the vault: `idle` plus each of its positions at the oracle
+    /// price with funding, as loaded by `load_valued_positions`. Refreshes
+    /// `deployed_margin` from the same positions, so margin lost to a
+    /// liquidation drops out here.
+    pub fn revalue(&mut self, idle: u64, positions: &[(Position, u64)]) -> u64 {
+        self.deployed_margin = positions.iter()
+            .fold(0u64, |total, (position, _)| total.saturating_add(position.margin));
+        positions.iter().fold(idle, |total, (_, value)| total.saturating_add(*value))
+    }
+
+    /// Side of `market` that currently receives funding, if any.
+    pub fn receiving_side(market: &Market) -> Option<Side> {
+        match market.funding_rate.signum() {
+            1 => Some(Side::Short),  // longs pay shorts
+            -1 => Some(Side::Long),
+            _ => None,
+        }
+    }
+
+    /// `positions` are all of the vault's positions, across markets.
+    pub fn check_exposure(&self, market: &Pubkey, positions: &[Position], equity: u64) -> Result<()> {
+        let exposure: u128 = positions.iter()
+            .filter(|position| position.market == *market)
+            .map(|position| position.notional as u128)
+            .sum();
+        let cap = equity as u128 * self.max_market_exposure_bps as u128 / 10000;
+        require!(exposure <= cap, ErrorCode::ExposureLimitExceeded);
+        Ok(())
+    }
+}
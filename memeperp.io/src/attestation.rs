@@ -0,0 +1,74 @@
+use anchor_lang::prelude::*;
+use crate::{ErrorCode, Market, Position, Side};
+
+/// Compact summary of one margin account's exposure in one market, returned
+/// as instruction return data. The runtime tags return data with the program
+/// id, so a caller that CPIs into `attest_positions` knows it came from this
+/// program and not from the trader.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PositionAttestation {
+    pub market: Pubkey,
+    pub margin_account: Pubkey,
+    pub authority: Pubkey,
+    pub timestamp: i64,
+    pub slot: u64,
+    pub oracle_price: u64,
+    pub position_count: u16,
+    pub long_size: u64,
+    pub short_size: u64,
+    pub notional: u64,  // at the oracle price
+    pub margin: u64,
+    pub unrealized_pnl: i64,
+    pub health_bps: u64,  // equity over notional; u64::MAX with no exposure
+    pub liquidatable: bool,
+}
+
+impl PositionAttestation {
+    pub fn build(
+        market: &Market,
+        market_key: Pubkey,
+        margin_account: Pubkey,
+        authority: Pubkey,
+        positions: &[Position],
+        oracle_price: u64,
+        clock: &Clock,
+    ) -> Result<Self> {
+        let mut attestation = Self {
+            market: market_key,
+            margin_account,
+            authority,
+            timestamp: clock.unix_timestamp,
+            slot: clock.slot,
+            oracle_price,
+            position_count: 0,
+            long_size: 0,
+            short_size: 0,
+            notional: 0,
+            margin: 0,
+            unrealized_pnl: 0,
+            health_bps: u64::MAX,
+            liquidatable: false,
+        };
+
+        for position in positions.iter().filter(|position| position.market == market_key) {
+            attestation.position_count = attestation.position_count.saturating_add(1);
+            match position.side {
+                Side::Long => attestation.long_size = attestation.long_size.saturating_add(position.base_size),
+                Side::Short => attestation.short_size = attestation.short_size.saturating_add(position.base_size),
+            }
+            attestation.notional = attestation.notional
+                .saturating_add(position.base_size.saturating_mul(oracle_price));
+            attestation.margin = attestation.margin.saturating_add(position.margin);
+            attestation.unrealized_pnl = attestation.unrealized_pnl
+                .checked_add(market.position_pnl(position, oracle_price)?)
+                .ok_or(ErrorCode::MathOverflow)?;
+            attestation.liquidatable |= position.is_liquidatable(oracle_price);
+        }
+
+        if attestation.notional > 0 {
+            let equity = (attestation.margin as i128 + attestation.unrealized_pnl as i128).max(0) as u128;
+            attestation.health_bps = (equity * 10000 / attestation.notional as u128).min(u64::MAX as u128) as u64;
+        }
+        Ok(attestation)
+    }
+}
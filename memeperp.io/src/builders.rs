@@ -0,0 +1,214 @@
+//! Instruction builders for the trading instructions, for programs that
+//! invoke memeperp directly and for off-chain clients. PDAs are derived
+//! here, so callers only supply the accounts they own.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::{InstructionData, ToAccountMetas};
+use anchor_spl::token::ID as TOKEN_PROGRAM_ID;
+
+use crate::referral::REFERRAL_CODE_LEN;
+use crate::{accounts, instruction, pda, Side};
+
+/// Market-level accounts shared by the order instructions.
+pub struct MarketAccounts {
+    pub market: Pubkey,
+    pub market_vault: Pubkey,
+    pub price_feed: Pubkey,
+}
+
+pub fn initialize_margin_account(authority: Pubkey, sub_account_id: u16) -> Instruction {
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts::InitializeMarginAccount {
+            margin_account: pda::margin_account(&authority, sub_account_id).0,
+            authority,
+            system_program: anchor_lang::system_program::ID,
+        }
+        .to_account_metas(None),
+        data: instruction::InitializeMarginAccount { sub_account_id }.data(),
+    }
+}
+
+/// Market order. `trade_history` must be the current page when the
+/// sub-account has history enabled; the order book is passed when the
+/// market has a mark/index deviation cap. `integrator` is the integrator key
+/// and its fee token account for routed orders. Pass `min_fill_size = size`
+/// for an all-or-nothing order. The fill fails if it is more than
+/// `max_slippage_bps` worse than `price`. `tag` is echoed in the position's
+/// events. `position_id` is the sub-account's `next_position_id`.
+/// `launch_registered` passes the sub-account's launch registration, needed
+/// while the market has a launch trader cap. `with_insurance_coverage` passes
+/// the market's insurance coverage account, needed once it has one.
+/// `hook_program` is the market's hook program, needed while it has one.
+/// `with_trader_stats` passes the wallet's volume stats for a fee tier
+/// discount. `referral_code` is the code the order was referred by, and
+/// `referral_vault` the protocol's referral vault. The order fails if it
+/// lands after `expiry_ts`.
+pub fn place_order(
+    user: Pubkey,
+    user_token_account: Pubkey,
+    market: &MarketAccounts,
+    side: Side,
+    size: u64,
+    min_fill_size: u64,
+    price: u64,
+    max_slippage_bps: u16,
+    leverage: u8,
+    sub_account_id: u16,
+    tag: [u8; 32],
+    position_id: u64,
+    with_order_book: bool,
+    trade_history: Option<Pubkey>,
+    integrator: Option<(Pubkey, Pubkey)>,
+    launch_registered: bool,
+    with_insurance_coverage: bool,
+    hook_program: Option<Pubkey>,
+    with_trader_stats: bool,
+    referral: Option<([u8; REFERRAL_CODE_LEN], Pubkey)>,
+    expiry_ts: Option<i64>,
+) -> Instruction {
+    let margin_account = pda::margin_account(&user, sub_account_id).0;
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts::PlaceOrder {
+            market: market.market,
+            protocol_config: pda::protocol_config().0,
+            user,
+            margin_account,
+            position: pda::position(&market.market, &margin_account, position_id).0,
+            user_token_account,
+            market_vault: market.market_vault,
+            price_feed: market.price_feed,
+            token_program: TOKEN_PROGRAM_ID,
+            system_program: anchor_lang::system_program::ID,
+            order_book: with_order_book.then(|| pda::order_book(&market.market).0),
+            trade_history,
+            integrator: integrator.map(|(key, _)| pda::integrator(&key).0),
+            integrator_fee_account: integrator.map(|(_, fee_account)| fee_account),
+            launch_registration: launch_registered.then(|| pda::launch_registration(&market.market, &margin_account).0),
+            insurance_coverage: with_insurance_coverage.then(|| pda::insurance_coverage(&market.market).0),
+            hook_program,
+            trader_stats: with_trader_stats.then(|| pda::trader_stats(&user).0),
+            referral_code: referral.map(|(code, _)| pda::referral_code(&code).0),
+            referral_vault: referral.map(|(_, vault)| vault),
+        }
+        .to_account_metas(None),
+        data: instruction::PlaceOrder { side, size, min_fill_size, price, max_slippage_bps, leverage, _sub_account_id: sub_account_id, tag, expiry_ts }.data(),
+    }
+}
+
+/// Market order that adds to the open position at `position_id`, on its
+/// side and at its leverage. Optional accounts are as for `place_order`.
+pub fn increase_position(
+    user: Pubkey,
+    user_token_account: Pubkey,
+    market: &MarketAccounts,
+    size: u64,
+    min_fill_size: u64,
+    price: u64,
+    sub_account_id: u16,
+    tag: [u8; 32],
+    position_id: u64,
+    with_order_book: bool,
+    trade_history: Option<Pubkey>,
+    integrator: Option<(Pubkey, Pubkey)>,
+    with_insurance_coverage: bool,
+    hook_program: Option<Pubkey>,
+    with_trader_stats: bool,
+    referral: Option<([u8; REFERRAL_CODE_LEN], Pubkey)>,
+) -> Instruction {
+    let margin_account = pda::margin_account(&user, sub_account_id).0;
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts::IncreasePosition {
+            market: market.market,
+            protocol_config: pda::protocol_config().0,
+            user,
+            margin_account,
+            position: pda::position(&market.market, &margin_account, position_id).0,
+            user_token_account,
+            market_vault: market.market_vault,
+            price_feed: market.price_feed,
+            token_program: TOKEN_PROGRAM_ID,
+            order_book: with_order_book.then(|| pda::order_book(&market.market).0),
+            trade_history,
+            integrator: integrator.map(|(key, _)| pda::integrator(&key).0),
+            integrator_fee_account: integrator.map(|(_, fee_account)| fee_account),
+            insurance_coverage: with_insurance_coverage.then(|| pda::insurance_coverage(&market.market).0),
+            hook_program,
+            trader_stats: with_trader_stats.then(|| pda::trader_stats(&user).0),
+            referral_code: referral.map(|(code, _)| pda::referral_code(&code).0),
+            referral_vault: referral.map(|(_, vault)| vault),
+        }
+        .to_account_metas(None),
+        data: instruction::IncreasePosition { size, min_fill_size, price, _sub_account_id: sub_account_id, tag }.data(),
+    }
+}
+
+/// Resting limit order. Like `place_order`, it opens the position at
+/// `position_id`, which fills merge into, and takes the launch registration
+/// when `launch_registered`.
+pub fn place_limit_order(
+    user: Pubkey,
+    user_token_account: Pubkey,
+    market: &MarketAccounts,
+    side: Side,
+    price: u64,
+    size: u64,
+    leverage: u8,
+    sub_account_id: u16,
+    tag: [u8; 32],
+    position_id: u64,
+    launch_registered: bool,
+) -> Instruction {
+    let margin_account = pda::margin_account(&user, sub_account_id).0;
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts::PlaceLimitOrder {
+            market: market.market,
+            protocol_config: pda::protocol_config().0,
+            order_book: pda::order_book(&market.market).0,
+            user,
+            margin_account,
+            position: pda::position(&market.market, &margin_account, position_id).0,
+            user_token_account,
+            market_vault: market.market_vault,
+            vault_authority: pda::vault_authority(&market.market).0,
+            price_feed: market.price_feed,
+            token_program: TOKEN_PROGRAM_ID,
+            system_program: anchor_lang::system_program::ID,
+            launch_registration: launch_registered.then(|| pda::launch_registration(&market.market, &margin_account).0),
+        }
+        .to_account_metas(None),
+        data: instruction::PlaceLimitOrder { side, price, size, leverage, _sub_account_id: sub_account_id, tag }.data(),
+    }
+}
+
+pub fn cancel_order(
+    owner: Pubkey,
+    owner_token_account: Pubkey,
+    market: &MarketAccounts,
+    side: Side,
+    order_id: u64,
+    sub_account_id: u16,
+    position_id: u64,
+) -> Instruction {
+    let margin_account = pda::margin_account(&owner, sub_account_id).0;
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts::CancelOrder {
+            market: market.market,
+            order_book: pda::order_book(&market.market).0,
+            owner,
+            margin_account,
+            position: pda::position(&market.market, &margin_account, position_id).0,
+            owner_token_account,
+            market_vault: market.market_vault,
+            vault_authority: pda::vault_authority(&market.market).0,
+            token_program: TOKEN_PROGRAM_ID,
+        }
+        .to_account_metas(None),
+        data: instruction::CancelOrder { side, order_id, _sub_account_id: sub_account_id }.data(),
+    }
+}
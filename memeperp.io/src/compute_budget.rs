@@ -0,0 +1,4 @@
+// Per-instruction bounds on work that grows with market state. Anything that
+// may need more continues in a later instruction.
+pub const MAX_MATCH_LEVELS_PER_IX: u8 = 8;
+pub const MAX_CRANK_LIQUIDATIONS_PER_IX: usize = 8;
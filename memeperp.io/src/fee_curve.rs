@@ -0,0 +1,135 @@
+use anchor_lang::prelude::*;
+use crate::Side;
+
+pub const VOLUME_WINDOW: i64 = 24 * 60 * 60;  // 1 day
+pub const MAX_FEE_HOLIDAYS: usize = 4;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Default)]
+pub enum FeeCurveBasis {
+    #[default]
+    RecentVolume,
+    VaultDepth,
+}
+
+/// Surcharge on top of `Market::fee_bps` for taker orders that are large
+/// relative to the market. An order whose notional is `ratio_bps` of the
+/// reference pays `(ratio_bps - threshold_bps) * slope_bps / 10000` extra,
+/// capped at `max_surcharge_bps`. A zero cap disables the curve.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct FeeCurve {
+    pub basis: FeeCurveBasis,
+    pub threshold_bps: u16,
+    pub slope_bps: u16,
+    pub max_surcharge_bps: u16,
+}
+
+impl FeeCurve {
+    pub const LEN: usize = 1 + 2 + 2 + 2;
+
+    pub fn surcharge_bps(&self, notional: u64, reference: u64) -> u16 {
+        if self.max_surcharge_bps == 0 {
+            return 0;
+        }
+        // With nothing to measure against, every order is treated as outsized
+        if reference == 0 {
+            return self.max_surcharge_bps;
+        }
+        let ratio_bps = notional as u128 * 10000 / reference as u128;
+        let excess_bps = ratio_bps.saturating_sub(self.threshold_bps as u128);
+        let surcharge = excess_bps * self.slope_bps as u128 / 10000;
+        surcharge.min(self.max_surcharge_bps as u128) as u16
+    }
+}
+
+/// Traded notional in the current and the last complete `VOLUME_WINDOW`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct VolumeWindow {
+    pub window_start: i64,
+    pub current: u64,
+    pub previous: u64,
+}
+
+impl VolumeWindow {
+    pub const LEN: usize = 8 + 8 + 8;
+
+    pub fn record(&mut self, now: i64, notional: u64) {
+        let elapsed = now - self.window_start;
+        if elapsed >= VOLUME_WINDOW {
+            // A gap of more than one window means the last one saw no volume
+            self.previous = if elapsed < 2 * VOLUME_WINDOW { self.current } else { 0 };
+            self.current = 0;
+            self.window_start = now - elapsed % VOLUME_WINDOW;
+        }
+        self.current = self.current.saturating_add(notional);
+    }
+}
+
+/// Whether a fee was paid on a market order or close filled at the oracle
+/// price, or on a resting limit order filled from the book.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum FeeRole {
+    Taker,
+    Maker,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum FeeKind {
+    Open,
+    Close,
+}
+
+/// Trading fees a market has charged since it was listed, by whether the
+/// order opened or closed a position and by the position's side, and again
+/// by taker and maker. These are the gross fees traders paid, before any
+/// integrator or insurance share, and unlike `Market::total_fee_accrued`
+/// they are never paid out. Maker rebates are tracked on their own.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct FeeAccrual {
+    pub open_long: u64,
+    pub open_short: u64,
+    pub close_long: u64,
+    pub close_short: u64,
+    pub taker: u64,
+    pub maker: u64,
+    pub maker_rebates: u64,
+}
+
+impl FeeAccrual {
+    pub const LEN: usize = 8 + 8 + 8 + 8 + 8 + 8 + 8;
+
+    pub fn record(&mut self, kind: FeeKind, side: Side, role: FeeRole, fee: u64) {
+        let total = match (kind, side) {
+            (FeeKind::Open, Side::Long) => &mut self.open_long,
+            (FeeKind::Open, Side::Short) => &mut self.open_short,
+            (FeeKind::Close, Side::Long) => &mut self.close_long,
+            (FeeKind::Close, Side::Short) => &mut self.close_short,
+        };
+        *total = total.saturating_add(fee);
+        let total = match role {
+            FeeRole::Taker => &mut self.taker,
+            FeeRole::Maker => &mut self.maker,
+        };
+        *total = total.saturating_add(fee);
+    }
+
+    pub fn record_rebate(&mut self, rebate: u64) {
+        self.maker_rebates = self.maker_rebates.saturating_add(rebate);
+    }
+}
+
+/// A promotion that cuts a market's trading fees by `discount_bps` (10000
+/// waives them) from `start` until `end`. An unused slot has `end == 0`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeHoliday {
+    pub start: i64,
+    pub end: i64,
+    pub discount_bps: u16,
+}
+
+impl FeeHoliday {
+    pub const LEN: usize = 8 + 8 + 2;
+
+    pub fn is_active(&self, now: i64) -> bool {
+        now >= self.start && now < self.end
+    }
+}
@@ -0,0 +1,50 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+pub const MAX_FUNDING_CHECKPOINTS: usize = 256;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct FundingCheckpoint {
+    pub timestamp: i64,
+    pub funding_rate: i64,  // rate applied at this update, in bps
+    pub cumulative_index: i64,  // `Market::cumulative_funding_long` after it
+}
+
+impl FundingCheckpoint {
+    pub const LEN: usize = 8 + 8 + 8;
+}
+
+/// Ring buffer of the market's cumulative funding index, one checkpoint per
+/// funding update. Funding owed by a position of notional `n` between two
+/// times is `n * (index(t1) - index(t0)) / 10000`, longs paying when positive.
+#[account]
+pub struct FundingHistory {
+    pub market: Pubkey,
+    pub head: u16,  // slot the next checkpoint is written to once full
+    pub checkpoints: Vec<FundingCheckpoint>,
+    pub bump: u8,
+}
+
+impl FundingHistory {
+    pub const LEN: usize = 8 + 32 + 2 + 4 + FundingCheckpoint::LEN * MAX_FUNDING_CHECKPOINTS + 1;
+
+    pub fn push(&mut self, checkpoint: FundingCheckpoint) {
+        if self.checkpoints.len() < MAX_FUNDING_CHECKPOINTS {
+            self.checkpoints.push(checkpoint);
+        } else {
+            self.checkpoints[self.head as usize] = checkpoint;
+            self.head = ((self.head as usize + 1) % MAX_FUNDING_CHECKPOINTS) as u16;
+        }
+    }
+
+    /// Cumulative index as of `timestamp`, i.e. at the latest checkpoint at or
+    /// before it. Fails if `timestamp` predates the oldest retained checkpoint.
+    pub fn index_at(&self, timestamp: i64) -> Result<i64> {
+        self.checkpoints
+            .iter()
+            .filter(|checkpoint| checkpoint.timestamp <= timestamp)
+            .max_by_key(|checkpoint| checkpoint.timestamp)
+            .map(|checkpoint| checkpoint.cumulative_index)
+            .ok_or(error!(ErrorCode::FundingHistoryUnavailable))
+    }
+}
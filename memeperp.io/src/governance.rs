@@ -0,0 +1,61 @@
+use anchor_lang::prelude::*;
+use crate::{ErrorCode, Market};
+
+pub const MAX_FEE_BPS: u16 = 100;  // 1%
+pub const MAX_FUNDING_RATE_BPS: u16 = 100;  // 1% per funding interval
+pub const MAX_EMISSION_RATE: u64 = 1_000_000_000;
+pub const MAX_QUEUED_PARAM_CHANGES: usize = 8;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum ParameterChange {
+    FeeBps(u16),
+    EmissionRate(u64),
+}
+
+impl ParameterChange {
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            ParameterChange::FeeBps(bps) => require!(bps <= MAX_FEE_BPS, ErrorCode::ParameterOutOfBounds),
+            ParameterChange::EmissionRate(rate) => require!(rate <= MAX_EMISSION_RATE, ErrorCode::ParameterOutOfBounds),
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, market: &mut Market) {
+        match *self {
+            ParameterChange::FeeBps(bps) => market.fee_bps = bps,
+            ParameterChange::EmissionRate(rate) => market.mining.emission_rate = rate,
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct QueuedParamChange {
+    pub change: ParameterChange,
+    pub eta: i64,  // earliest time the change can be applied
+}
+
+impl QueuedParamChange {
+    pub const LEN: usize = 1 + 8 + 8;
+}
+
+#[account]
+pub struct ParamProposal {
+    pub id: u64,
+    pub market: Pubkey,
+    pub proposer: Pubkey,
+    pub change: ParameterChange,
+    pub voting_ends_at: i64,
+    pub votes_for: u64,
+    pub votes_against: u64,
+    pub queued: bool,
+    pub bump: u8,
+}
+
+impl ParamProposal {
+    pub const LEN: usize = 8 + 8 + 32 + 32 + (1 + 8) + 8 + 8 + 8 + 1 + 1;
+
+    pub fn vote_passed(&self, quorum: u64) -> bool {
+        self.votes_for >= quorum && self.votes_for > self.votes_against
+    }
+}
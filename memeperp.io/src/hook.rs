@@ -0,0 +1,43 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke;
+use crate::position::PositionRecord;
+
+/// Instruction discriminator of a hook's `on_position_event(event)`, as an
+/// Anchor program would derive it: sha256("global:on_position_event")[..8].
+pub const HOOK_DISCRIMINATOR: [u8; 8] = [120, 221, 195, 93, 12, 4, 120, 156];
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum HookEventKind {
+    Open,  // a new position or added size
+    Close,
+    Liquidation,
+}
+
+/// What a market's hook program is told about a position. `size` and
+/// `price` are those of this event alone; `record` has the totals.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct HookEvent {
+    pub kind: HookEventKind,
+    pub record: PositionRecord,
+    pub size: u64,
+    pub price: u64,
+}
+
+/// Calls `on_position_event` on `hook_program` with the market as its only
+/// account, read-only and not signing.
+pub fn invoke_hook<'info>(
+    hook_program: &AccountInfo<'info>,
+    market: &AccountInfo<'info>,
+    event: &HookEvent,
+) -> Result<()> {
+    let mut data = HOOK_DISCRIMINATOR.to_vec();
+    event.serialize(&mut data)?;
+    let instruction = Instruction {
+        program_id: *hook_program.key,
+        accounts: vec![AccountMeta::new_readonly(*market.key, false)],
+        data,
+    };
+    invoke(&instruction, &[market.clone(), hook_program.clone()])?;
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+pub const MAX_ORACLE_INCIDENTS: usize = 32;
+
+/// Window in which the market's oracle diverged badly from the real price.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct OracleIncident {
+    pub start: i64,
+    pub end: i64,
+    pub declared_at: i64,
+}
+
+impl OracleIncident {
+    pub const LEN: usize = 8 + 8 + 8;
+
+    pub fn contains(&self, timestamp: i64) -> bool {
+        timestamp >= self.start && timestamp < self.end
+    }
+}
+
+/// Oracle incidents the guardian declared for a market. Positions opened
+/// inside an incident close fee-free, with the funding of the window
+/// reversed; close instructions look them up here.
+#[account]
+pub struct IncidentRegistry {
+    pub market: Pubkey,
+    pub incidents: Vec<OracleIncident>,
+    pub bump: u8,
+}
+
+impl IncidentRegistry {
+    pub const LEN: usize = 8 + 32 + 4 + OracleIncident::LEN * MAX_ORACLE_INCIDENTS + 1;
+
+    /// Records a past incident; once full, the oldest is dropped.
+    pub fn declare(&mut self, start: i64, end: i64, now: i64) -> Result<()> {
+        require!(start < end && end <= now, ErrorCode::ParameterOutOfBounds);
+        if self.incidents.len() == MAX_ORACLE_INCIDENTS {
+            self.incidents.remove(0);
+        }
+        self.incidents.push(OracleIncident { start, end, declared_at: now });
+        Ok(())
+    }
+
+    /// The incident a position opened at `opened_at` falls in, if any.
+    pub fn incident_at(&self, opened_at: i64) -> Option<OracleIncident> {
+        self.incidents.iter().copied().find(|incident| incident.contains(opened_at))
+    }
+}
@@ -0,0 +1,91 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+/// A market's own insurance balance. Liquidation shortfalls draw on it
+/// first; whatever it cannot cover falls through to the protocol backstop.
+/// Every shortfall is attributed here, whichever pool ends up paying for it.
+#[account]
+pub struct InsuranceFund {
+    pub market: Pubkey,
+    pub vault: Pubkey,  // token account owned by this PDA
+    pub total_deposits: u64,
+    pub total_bad_debt: u64,  // all liquidation shortfalls from this market
+    pub covered_by_fund: u64,
+    pub covered_by_backstop: u64,
+    pub uncovered_bad_debt: u64,  // left unpaid once both pools ran dry
+    pub total_withdrawn: u64,
+    pub bump: u8,
+}
+
+impl InsuranceFund {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 8 + 8 + 8 + 8 + 8 + 1;
+
+    /// Tokens the fund's vault should hold: deposits less shortfalls paid
+    /// and withdrawals.
+    pub fn balance(&self) -> u64 {
+        self.total_deposits
+            .saturating_sub(self.covered_by_fund)
+            .saturating_sub(self.total_withdrawn)
+    }
+
+    pub fn record_shortfall(&mut self, shortfall: u64, from_fund: u64, from_backstop: u64) -> Result<()> {
+        self.total_bad_debt = self.total_bad_debt.checked_add(shortfall).ok_or(ErrorCode::MathOverflow)?;
+        self.covered_by_fund = self.covered_by_fund.checked_add(from_fund).ok_or(ErrorCode::MathOverflow)?;
+        self.covered_by_backstop = self.covered_by_backstop
+            .checked_add(from_backstop)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.uncovered_bad_debt = self.uncovered_bad_debt
+            .checked_add(shortfall - from_fund - from_backstop)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+/// Live insurance coverage of a market: its fund's balance against its open
+/// interest, kept in one small account for UIs and risk engines to read.
+/// Once a market has one, every instruction that moves either side updates it.
+#[account]
+pub struct InsuranceCoverage {
+    pub market: Pubkey,
+    pub insurance_balance: u64,
+    pub open_interest_notional: u64,  // both sides, at entry
+    pub coverage_bps: u64,  // insurance per open interest; u64::MAX with no open interest
+    pub updated_at: i64,
+    pub bump: u8,
+}
+
+impl InsuranceCoverage {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 8 + 8 + 1;
+
+    pub fn record_insurance(&mut self, balance: u64, now: i64) {
+        self.insurance_balance = balance;
+        self.refresh(now);
+    }
+
+    pub fn record_open_interest(&mut self, notional: u64, now: i64) {
+        self.open_interest_notional = notional;
+        self.refresh(now);
+    }
+
+    fn refresh(&mut self, now: i64) {
+        self.coverage_bps = if self.open_interest_notional == 0 {
+            u64::MAX
+        } else {
+            (self.insurance_balance as u128 * 10000 / self.open_interest_notional as u128).min(u64::MAX as u128) as u64
+        };
+        self.updated_at = now;
+    }
+}
+
+/// Protocol-level pool that covers shortfalls beyond any single market's fund.
+#[account]
+pub struct InsuranceBackstop {
+    pub vault: Pubkey,  // token account owned by this PDA
+    pub total_deposits: u64,
+    pub total_covered: u64,
+    pub bump: u8,
+}
+
+impl InsuranceBackstop {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 1;
+}
@@ -0,0 +1,44 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+pub const INTEGRATOR_RATE_WINDOW: i64 = 60;  // seconds
+
+/// A frontend or program allowed to route orders. Orders that name it pay
+/// `fee_share_bps` of their fee straight to the integrator.
+#[account]
+pub struct Integrator {
+    pub key: Pubkey,  // identifies the integrator in orders
+    pub fee_destination: Pubkey,  // token account receiving the fee share
+    pub fee_share_bps: u16,
+    pub max_orders_per_window: u32,  // 0 means unlimited
+    pub window_start: i64,
+    pub orders_in_window: u32,
+    pub total_volume: u64,
+    pub total_fees_earned: u64,
+    pub active: bool,
+    pub bump: u8,
+}
+
+impl Integrator {
+    pub const LEN: usize = 8 + 32 + 32 + 2 + 4 + 8 + 4 + 8 + 8 + 1 + 1;
+
+    /// Counts an order against the rate limit and books its volume and fee
+    /// share. Returns the part of `fee` owed to the integrator.
+    pub fn record_order(&mut self, now: i64, notional: u64, fee: u64) -> Result<u64> {
+        require!(self.active, ErrorCode::IntegratorInactive);
+        if now - self.window_start >= INTEGRATOR_RATE_WINDOW {
+            self.window_start = now;
+            self.orders_in_window = 0;
+        }
+        require!(
+            self.max_orders_per_window == 0 || self.orders_in_window < self.max_orders_per_window,
+            ErrorCode::IntegratorRateLimited
+        );
+        self.orders_in_window += 1;
+
+        let share = (fee as u128 * self.fee_share_bps as u128 / 10000) as u64;
+        self.total_volume = self.total_volume.saturating_add(notional);
+        self.total_fees_earned = self.total_fees_earned.checked_add(share).ok_or(ErrorCode::MathOverflow)?;
+        Ok(share)
+    }
+}
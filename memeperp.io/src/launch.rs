@@ -0,0 +1,17 @@
+use anchor_lang::prelude::*;
+
+/// A sub-account's seat in a market's launch phase. Created first come,
+/// first served by `register_launch_trader` at seeds `"launch_registration"`,
+/// market, margin account; while the market has a trader cap, only
+/// registered sub-accounts can open positions in it.
+#[account]
+pub struct LaunchRegistration {
+    pub market: Pubkey,
+    pub margin_account: Pubkey,
+    pub registered_at: i64,
+    pub bump: u8,
+}
+
+impl LaunchRegistration {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 1;
+}
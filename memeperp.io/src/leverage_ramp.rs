@@ -0,0 +1,44 @@
+use anchor_lang::prelude::*;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Default)]
+pub enum RampBasis {
+    #[default]
+    Time,
+    Volume,
+}
+
+/// Leverage schedule for newly listed markets. The cap starts at
+/// `start_leverage` and rises by `step_leverage` every `step_interval`
+/// seconds (Time) or units of traded notional (Volume) until it reaches the
+/// market's `max_leverage`. A zero `step_interval` disables the ramp.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct LeverageRamp {
+    pub basis: RampBasis,
+    pub start_leverage: u8,
+    pub step_leverage: u8,
+    pub step_interval: u64,
+}
+
+impl LeverageRamp {
+    pub const LEN: usize = 1 + 1 + 1 + 8;
+
+    pub fn is_enabled(&self) -> bool {
+        self.step_interval > 0
+    }
+
+    /// Leverage allowed after `elapsed` seconds since listing and `volume`
+    /// of cumulative notional, never above `max_leverage`.
+    pub fn max_leverage(&self, max_leverage: u8, elapsed: i64, volume: u64) -> u8 {
+        if !self.is_enabled() {
+            return max_leverage;
+        }
+        let progress = match self.basis {
+            RampBasis::Time => elapsed.max(0) as u64,
+            RampBasis::Volume => volume,
+        };
+        let steps = progress / self.step_interval;
+        let leverage = (self.start_leverage as u64)
+            .saturating_add(steps.saturating_mul(self.step_leverage as u64));
+        leverage.min(max_leverage as u64) as u8
+    }
+}
@@ -1,10 +1,127 @@
+#![allow(clippy::result_large_err, clippy::too_many_arguments)]
+
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount};
+use anchor_spl::token::{self, Mint, Token, TokenAccount};
 use std::collections::VecDeque;
-mod price_feed;
-use price_feed::PriceFeed;
+pub mod arb_vault;
+pub mod attestation;
+pub mod compute_budget;
+pub mod fee_curve;
+pub mod funding_history;
+pub mod governance;
+pub mod hook;
+pub mod incident;
+pub mod insurance;
+pub mod integrator;
+pub mod launch;
+pub mod leverage_ramp;
+pub mod liquidation_lanes;
+pub mod lp_vault;
+pub mod margin_account;
+pub mod migration;
+pub mod mining;
+pub mod notional_cap;
+pub mod oracle_rotation;
+pub mod order_book;
+pub mod pda;
+pub mod portfolio;
+pub mod position;
+pub mod premium;
+pub mod price_feed;
+pub mod price_history;
+pub mod protocol_config;
+pub mod recovery;
+pub mod referral;
+pub mod revenue;
+pub mod staking;
+pub mod swap;
+pub mod trade_history;
+pub mod trader_stats;
+pub mod treasury;
+pub mod trigger_order;
+pub mod vamm;
+pub mod withdrawal;
+
+#[cfg(feature = "cpi")]
+pub mod builders;
+
+pub use memeperp_math as math;
+
+use arb_vault::FundingArbVault;
+use attestation::PositionAttestation;
+use compute_budget::{MAX_CRANK_LIQUIDATIONS_PER_IX, MAX_MATCH_LEVELS_PER_IX};
+use fee_curve::{FeeAccrual, FeeCurve, FeeCurveBasis, FeeHoliday, FeeKind, FeeRole, VolumeWindow, MAX_FEE_HOLIDAYS};
+use funding_history::{FundingCheckpoint, FundingHistory};
+use hook::{HookEvent, HookEventKind};
+use incident::IncidentRegistry;
+use governance::{
+    ParamProposal, ParameterChange, QueuedParamChange, MAX_FEE_BPS, MAX_FUNDING_RATE_BPS, MAX_QUEUED_PARAM_CHANGES,
+};
+use insurance::{InsuranceBackstop, InsuranceCoverage, InsuranceFund};
+use integrator::Integrator;
+use launch::LaunchRegistration;
+use leverage_ramp::{LeverageRamp, RampBasis};
+use liquidation_lanes::PriorityLanes;
+use lp_vault::{LpSharePrice, LpVault};
+use margin_account::{MarginAccount, UserStats, MAX_SUB_ACCOUNTS};
+use migration::PositionExport;
+use mining::{EmissionMode, MiningState};
+use notional_cap::NotionalCap;
+use oracle_rotation::OracleRotation;
+use order_book::{BookDepth, Order, OrderBook, MAX_DEPTH_LEVELS};
+use premium::PremiumTwap;
+use price_history::{PriceHistoryPage, PriceSample, PRICE_SAMPLE_INTERVAL};
+use portfolio::{load_valued_positions, portfolio_health, PortfolioHealth, PostTradeHealth};
+use position::{load_all_positions, CloseReason, MarginMode, Position, PositionRecord};
+use price_feed::{
+    CustomOracle, OracleSource, PriceFeed, PriceRounding, MAX_CUSTOM_ORACLE_CONF_BPS, MAX_ORACLE_CONF_MULTIPLIER_BPS,
+};
+use protocol_config::{FeeSplit, FeeTier, MarketPreset, MarketTemplate, OrderRateLimits, ProtocolConfig, MAX_FEE_TIERS, MAX_HOOK_PROGRAMS, MAX_MARKET_MAKERS};
+use recovery::AuthorityRecovery;
+use referral::{ReferralCode, REFERRAL_CODE_LEN};
+use revenue::{revenue_epoch, RevenueLedger, RevenueRecord, RevenueSource};
+use swap::JUPITER_PROGRAM_ID;
+use staking::{EpochDistribution, StakePool, StakerAccount};
+use trade_history::{TradeHistoryPage, TradeKind, TradeRecord};
+use trader_stats::{TraderStats, VOLUME_DAYS};
+use treasury::{SpendProposal, Treasury, VoteLock, VoteRecord, MAX_COUNCIL_SIZE};
+use trigger_order::{TriggerKind, TriggerOrder, TriggerRegistry};
+use vamm::Vamm;
+use withdrawal::{WithdrawalApproval, WithdrawalPolicy, WithdrawalRequest, WithdrawalSource};
+
+declare_id!("MeMePrP111111111111111111111111111111111111");
+
+pub const DEFAULT_FEE_BPS: u16 = 10;  // 0.1%
+pub const DEFAULT_GUARDIAN_PAUSE_DURATION: i64 = 6 * 60 * 60;  // 6 hours
+pub const DEFAULT_MAX_ORACLE_CONF_BPS: u16 = 200;  // 2% of the price
+pub const DEFAULT_ORACLE_CONF_MULTIPLIER_BPS: u16 = 10000;  // one conf
+pub const DEFAULT_LIMIT_PRICE_BAND_BPS: u16 = 1000;  // 10% of the index
+
+// Reasons a market is in ReduceOnly mode, stored in `Market::reduce_only_flags`
+pub const REDUCE_ONLY_ORACLE_FAILOVER: u8 = 1 << 0;
+pub const REDUCE_ONLY_LOW_COVERAGE: u8 = 1 << 1;
+pub const REDUCE_ONLY_MARK_DEVIATION: u8 = 1 << 2;
+pub const REDUCE_ONLY_AUTHORITY: u8 = 1 << 3;
+
+// Coverage must recover this far above the minimum before ReduceOnly lifts
+pub const COVERAGE_HYSTERESIS_BPS: u64 = 500;
 
-declare_id!("MeMePrP1111111111111111111111111111111111");
+pub const MAX_PRICE_DECIMALS: u8 = 12;
+
+// Keeper rewards can be scaled up to 5x while transaction fees spike
+pub const MAX_KEEPER_REWARD_MULTIPLIER_BPS: u16 = 50_000;
+
+/// Upper bound on the liquidation fee, in bps of the liquidated position's margin.
+pub const MAX_LIQUIDATION_FEE_BPS: u16 = 5000;
+
+// Most virtual quote a sub-account can hold for paper trading
+pub const MAX_PAPER_BALANCE: u64 = 1_000_000_000_000;
+
+pub const MAX_MULTI_ORDER_LEGS: usize = 4;
+// market, market vault, price feed, order book, new position
+pub const MULTI_ORDER_ACCOUNTS: usize = 5;
+// position, its margin account, owner's token account
+pub const LIQUIDATION_CRANK_ACCOUNTS: usize = 3;
 
 #[program]
 pub mod memeperp {
@@ -20,356 +137,8573 @@ pub mod memeperp {
         maintenance_margin_fraction: u16,  // in basis points
         max_position_size: u64,
         funding_interval: i64,  // in seconds
+        max_funding_rate_bps: u16,  // per funding interval, either way
+        max_oracle_staleness_secs: i64,
+    ) -> Result<()> {
+        let template = MarketTemplate {
+            configured: true,
+            min_base_order_size,
+            tick_size,
+            max_leverage: initial_leverage_max,
+            liquidation_threshold,
+            maintenance_margin_fraction,
+            max_position_size,
+            funding_interval,
+            fee_bps: DEFAULT_FEE_BPS,
+            max_funding_rate_bps,
+            max_oracle_staleness_secs,
+        };
+        template.validate()?;
+        init_market(
+            &mut ctx.accounts.market,
+            ctx.accounts.authority.key(),
+            ctx.accounts.market_vault.key(),
+            market_name,
+            &template,
+        )
+    }
+
+    /// Lists a market with one of the protocol's preset parameter sets.
+    pub fn initialize_market_from_template(
+        ctx: Context<InitializeMarketFromTemplate>,
+        market_name: String,
+        preset: MarketPreset,
+    ) -> Result<()> {
+        let config = &ctx.accounts.protocol_config;
+        let template = config.market_template(preset)?.clone();
+        init_market(
+            &mut ctx.accounts.market,
+            ctx.accounts.authority.key(),
+            ctx.accounts.market_vault.key(),
+            market_name,
+            &template,
+        )?;
+        ctx.accounts.market.apply_fee_split(&config.fee_split);
+        Ok(())
+    }
+
+    pub fn configure_mining(
+        ctx: Context<ConfigureMining>,
+        mode: EmissionMode,
+        emission_rate: u64,
+        long_weight_bps: u16,
+        short_weight_bps: u16,
     ) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        market.name = market_name;
-        market.authority = ctx.accounts.authority.key();
-        market.min_base_order_size = min_base_order_size;
-        market.tick_size = tick_size;
-        market.max_leverage = initial_leverage_max;
-        market.liquidation_threshold = liquidation_threshold;
-        market.maintenance_margin_fraction = maintenance_margin_fraction;
-        market.long_positions = VecDeque::new();
-        market.short_positions = VecDeque::new();
-        market.is_initialized = true;
-        market.total_fee_accrued = 0;
-        market.max_position_size = max_position_size;
-        market.funding_rate = 0;
-        market.last_funding_time = Clock::get()?.unix_timestamp;
-        market.funding_interval = funding_interval;
+        require!(
+            long_weight_bps as u32 + short_weight_bps as u32 == 10000,
+            ErrorCode::InvalidMiningConfig
+        );
+
+        // Close out emissions under the old parameters before switching
+        let now = Clock::get()?.unix_timestamp;
+        let long_open_interest = market.open_interest(Side::Long);
+        let short_open_interest = market.open_interest(Side::Short);
+        market.mining.accrue(now, long_open_interest, short_open_interest)?;
+
+        market.mining.mode = mode;
+        market.mining.reward_vault = ctx.accounts.reward_vault.key();
+        market.mining.emission_rate = emission_rate;
+        market.mining.long_weight_bps = long_weight_bps;
+        market.mining.short_weight_bps = short_weight_bps;
+        Ok(())
+    }
+
+    pub fn claim_mining_rewards(ctx: Context<ClaimMiningRewards>, _sub_account_id: u16) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        market.accrue_mining(now)?;
+
+        // Settle the position and collect what it earned
+        let position = &mut ctx.accounts.position;
+        let total_rewards = market.mining.settle_position(position)?;
+        position.pending_rewards = 0;
+        require!(total_rewards > 0, ErrorCode::NoRewardsToClaim);
+
+        let market_key = market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.reward_vault.to_account_info(),
+                    to: ctx.accounts.owner_reward_account.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            total_rewards,
+        )?;
+
+        ctx.accounts.margin_account.unlock();
         Ok(())
     }
 
+    /// Starts a new funding round once the interval has elapsed, at a rate
+    /// set by the round's time-weighted mark/index premium. Positions are
+    /// charged lazily: each one settles the change in the cumulative index
+    /// the next time it is touched.
     pub fn update_funding_rate(ctx: Context<UpdateFunding>) -> Result<()> {
         let market = &mut ctx.accounts.market;
         let clock = Clock::get()?;
         let current_time = clock.unix_timestamp;
-        
+
+        require!(!market.is_paused(current_time), ErrorCode::MarketPaused);
+
         // Check if it's time to update funding
         if current_time - market.last_funding_time < market.funding_interval {
             return Ok(());
         }
 
-        // Calculate imbalance between longs and shorts
-        let total_long_size: u64 = market.long_positions.iter()
-            .map(|pos| pos.size)
-            .sum();
-        let total_short_size: u64 = market.short_positions.iter()
-            .map(|pos| pos.size)
-            .sum();
+        // Longs pay shorts while the mark trades above the index, and the
+        // other way round below it, at most `max_funding_rate_bps` per interval
+        let premium = mark_premium_bps(market, ctx.accounts.order_book.as_deref(), &ctx.accounts.price_feed)?;
+        market.premium.sample(current_time, premium);
+        let twap = market.premium.close_round(current_time);
+        market.funding_rate = math::funding_rate(
+            twap,
+            market.premium.interest_rate_bps as i64,
+            market.premium.premium_clamp_bps as i64,
+            market.max_funding_rate_bps as i64,
+        );
+        market.last_funding_time = current_time;
+        let funding_rate = market.funding_rate;
+        market.apply_funding_rate(funding_rate)?;
+        ctx.accounts.funding_history.push(FundingCheckpoint {
+            timestamp: current_time,
+            funding_rate,
+            cumulative_index: market.cumulative_funding_long,
+        });
 
-        // Calculate funding rate based on imbalance
-        // Rate is in basis points (1/10000)
-        let imbalance_ratio = if total_short_size == 0 {
-            1.0
-        } else {
-            total_long_size as f64 / total_short_size as f64
-        };
+        // The caller is paid out of the fees the market has accrued, so an
+        // update is only rewarded once and never eats into trader margin.
+        // Paper-trading fees are virtual and pay no one.
+        if market.paper_trading {
+            return Ok(());
+        }
+        let reward = market.keeper_reward(market.funding_update_reward).min(market.total_fee_accrued);
+        if reward == 0 {
+            return Ok(());
+        }
+        market.total_fee_accrued -= reward;
+        let market_key = market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.market_vault.to_account_info(),
+                    to: ctx.accounts.keeper_token_account.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            reward,
+        )?;
+        check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())
+    }
 
-        // Funding rate calculation:
-        // - If longs > shorts, longs pay shorts
-        // - If shorts > longs, shorts pay longs
-        // - Max rate is 0.1% per funding interval
-        let new_funding_rate = ((imbalance_ratio - 1.0) * 10.0) as i64;
-        market.funding_rate = new_funding_rate.max(-10).min(10); // Clamp to ±0.1%
-        market.last_funding_time = current_time;
+    pub fn initialize_stake_pool(ctx: Context<InitializeStakePool>, epoch_duration: i64) -> Result<()> {
+        require!(epoch_duration > 0, ErrorCode::InvalidMarketState);
+        let pool = &mut ctx.accounts.stake_pool;
+        pool.market = ctx.accounts.market.key();
+        pool.stake_vault = ctx.accounts.stake_vault.key();
+        pool.distribution_vault = ctx.accounts.distribution_vault.key();
+        pool.epoch_duration = epoch_duration;
+        pool.current_epoch = 0;
+        pool.epoch_start_time = Clock::get()?.unix_timestamp;
+        pool.checkpointed_stake = 0;
+        pool.pending_stake = 0;
+        pool.bump = ctx.bumps["stake_pool"];
+        Ok(())
+    }
+
+    pub fn stake(ctx: Context<Stake>, amount: u64) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let staker = &mut ctx.accounts.staker;
+
+        if staker.owner == Pubkey::default() {
+            staker.owner = ctx.accounts.owner.key();
+            staker.pool = pool.key();
+            staker.next_claim_epoch = pool.current_epoch;
+            staker.bump = ctx.bumps["staker"];
+        }
+        require!(staker.next_claim_epoch == pool.current_epoch, ErrorCode::UnclaimedEpochs);
+
+        // New stake only counts from the next checkpoint, so staking right
+        // before an epoch roll earns nothing from that epoch's fees
+        staker.promote_pending(pool.current_epoch)?;
+        staker.pending_stake = staker.pending_stake.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        staker.pending_epoch = pool.current_epoch;
+        pool.pending_stake = pool.pending_stake.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.owner_token_account.to_account_info(),
+                    to: ctx.accounts.stake_vault.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn unstake(ctx: Context<Unstake>, amount: u64) -> Result<()> {
+        let pool = &mut ctx.accounts.stake_pool;
+        let staker = &mut ctx.accounts.staker;
+
+        require!(staker.next_claim_epoch == pool.current_epoch, ErrorCode::UnclaimedEpochs);
+        staker.promote_pending(pool.current_epoch)?;
+        require!(amount <= staker.total_stake(), ErrorCode::InsufficientStake);
+
+        // Withdraw not-yet-eligible stake first, then stake that is counted
+        // in the current epoch's checkpoint
+        let from_pending = amount.min(staker.pending_stake);
+        let from_active = amount - from_pending;
+        staker.pending_stake -= from_pending;
+        staker.active_stake -= from_active;
+        pool.pending_stake = pool.pending_stake.checked_sub(from_pending).ok_or(ErrorCode::MathOverflow)?;
+        pool.checkpointed_stake = pool.checkpointed_stake.checked_sub(from_active).ok_or(ErrorCode::MathOverflow)?;
+
+        let market_key = pool.market;
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.stake_vault.to_account_info(),
+                    to: ctx.accounts.owner_token_account.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn roll_epoch(ctx: Context<RollEpoch>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let pool = &mut ctx.accounts.stake_pool;
+        let now = Clock::get()?.unix_timestamp;
+        require!(pool.can_roll(now), ErrorCode::EpochNotFinished);
+
+        // Fees accrued so far go to this epoch's stakers. With nobody staked
+        // they stay accrued on the market and roll into the next epoch.
+        let fees = if pool.checkpointed_stake > 0 { market.total_fee_accrued } else { 0 };
 
-        // Apply funding to all positions
-        for position in market.long_positions.iter_mut() {
-            apply_funding_to_position(position, market.funding_rate, true)?;
+        let distribution = &mut ctx.accounts.epoch_distribution;
+        distribution.pool = pool.key();
+        distribution.epoch = pool.current_epoch;
+        distribution.total_fees = fees;
+        distribution.total_stake = pool.checkpointed_stake;
+        distribution.claimed = 0;
+        distribution.bump = ctx.bumps["epoch_distribution"];
+
+        if fees > 0 {
+            market.total_fee_accrued = 0;
+            let market_key = market.key();
+            let seeds = &[
+                b"vault_authority".as_ref(),
+                market_key.as_ref(),
+                &[ctx.bumps["vault_authority"]],
+            ];
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.distribution_vault.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                fees,
+            )?;
         }
-        for position in market.short_positions.iter_mut() {
-            apply_funding_to_position(position, market.funding_rate, false)?;
+
+        pool.roll(now)?;
+        Ok(())
+    }
+
+    pub fn claim_epoch_fees(ctx: Context<ClaimEpochFees>) -> Result<()> {
+        let staker = &mut ctx.accounts.staker;
+        let distribution = &mut ctx.accounts.epoch_distribution;
+
+        // Claims are made epoch by epoch so each one is paid against the
+        // stake snapshot that was checkpointed for it
+        staker.promote_pending(distribution.epoch)?;
+        let share = distribution.share_of(staker.active_stake)?;
+        distribution.claimed = distribution.claimed.checked_add(share).ok_or(ErrorCode::MathOverflow)?;
+        staker.next_claim_epoch = distribution.epoch.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+
+        if share > 0 {
+            let market_key = ctx.accounts.stake_pool.market;
+            let seeds = &[
+                b"vault_authority".as_ref(),
+                market_key.as_ref(),
+                &[ctx.bumps["vault_authority"]],
+            ];
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.distribution_vault.to_account_info(),
+                        to: ctx.accounts.owner_token_account.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                share,
+            )?;
         }
 
         Ok(())
     }
 
-    pub fn place_order(
-        ctx: Context<PlaceOrder>,
-        side: Side,
-        size: u64,
-        price: u64,
-        leverage: u8,
+    pub fn initialize_treasury(
+        ctx: Context<InitializeTreasury>,
+        council: Vec<Pubkey>,
+        council_threshold: u8,
+        quorum: u64,
+        voting_period: i64,
+        timelock: i64,
     ) -> Result<()> {
-        let market = &mut ctx.accounts.market;
-        let user = &mut ctx.accounts.user;
+        require!(council.len() <= MAX_COUNCIL_SIZE, ErrorCode::InvalidTreasuryConfig);
+        require!(council_threshold as usize <= council.len(), ErrorCode::InvalidTreasuryConfig);
+        require!(council.is_empty() || council_threshold > 0, ErrorCode::InvalidTreasuryConfig);
+        require!(voting_period > 0 && timelock >= 0, ErrorCode::InvalidTreasuryConfig);
 
-        // Get current price from pump.fun oracle
-        let price_feed = PriceFeed::new_from_pyth(&ctx.accounts.price_feed)?;
-        let current_price = price_feed.get_adjusted_price()?;
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.admin = ctx.accounts.admin.key();
+        treasury.governance_mint = ctx.accounts.lock_vault.mint;
+        treasury.lock_vault = ctx.accounts.lock_vault.key();
+        treasury.council = [Pubkey::default(); MAX_COUNCIL_SIZE];
+        treasury.council[..council.len()].copy_from_slice(&council);
+        treasury.council_size = council.len() as u8;
+        treasury.council_threshold = council_threshold;
+        treasury.quorum = quorum;
+        treasury.voting_period = voting_period;
+        treasury.timelock = timelock;
+        treasury.proposal_count = 0;
+        treasury.bump = ctx.bumps["treasury"];
+        Ok(())
+    }
 
-        // Validate order parameters
-        require!(leverage <= market.max_leverage, ErrorCode::LeverageTooHigh);
-        require!(size >= market.min_base_order_size, ErrorCode::OrderTooSmall);
-        require!(size <= market.max_position_size, ErrorCode::OrderTooLarge);
-        require!(price % market.tick_size == 0, ErrorCode::InvalidPrice);
+    pub fn lock_tokens(ctx: Context<LockTokens>, amount: u64, unlock_time: i64) -> Result<()> {
+        let vote_lock = &mut ctx.accounts.vote_lock;
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            unlock_time > now && unlock_time >= vote_lock.unlock_time,
+            ErrorCode::InvalidLockDuration
+        );
 
-        // Calculate total position size after this order
-        let total_size = match side {
-            Side::Long => market.long_positions.iter().map(|p| p.size).sum::<u64>(),
-            Side::Short => market.short_positions.iter().map(|p| p.size).sum::<u64>(),
-        };
-        
+        vote_lock.owner = ctx.accounts.owner.key();
+        vote_lock.amount = vote_lock.amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        vote_lock.unlock_time = unlock_time;
+        vote_lock.bump = ctx.bumps["vote_lock"];
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.owner_token_account.to_account_info(),
+                    to: ctx.accounts.lock_vault.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn unlock_tokens(ctx: Context<UnlockTokens>) -> Result<()> {
+        let vote_lock = &mut ctx.accounts.vote_lock;
         require!(
-            total_size.checked_add(size).ok_or(ErrorCode::MathOverflow)? <= market.max_position_size,
-            ErrorCode::ExceedsMaxPosition
+            Clock::get()?.unix_timestamp >= vote_lock.unlock_time,
+            ErrorCode::TokensLocked
         );
+        let amount = vote_lock.amount;
+        vote_lock.amount = 0;
+
+        let seeds = &[b"treasury".as_ref(), &[ctx.accounts.treasury.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.lock_vault.to_account_info(),
+                    to: ctx.accounts.owner_token_account.to_account_info(),
+                    authority: ctx.accounts.treasury.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
 
-        // Calculate required margin
-        let required_margin = calculate_required_margin(size, current_price, leverage);
-        
-        // Calculate and collect fees (0.1% fee)
-        let fee = (size * current_price) / 1000;
-        market.total_fee_accrued = market.total_fee_accrued.checked_add(fee)
+        Ok(())
+    }
+
+    pub fn propose_spend(ctx: Context<ProposeSpend>, amount: u64) -> Result<()> {
+        let treasury = &mut ctx.accounts.treasury;
+        let proposal = &mut ctx.accounts.proposal;
+
+        proposal.id = treasury.proposal_count;
+        proposal.proposer = ctx.accounts.proposer.key();
+        proposal.source_vault = ctx.accounts.source_vault.key();
+        proposal.destination = ctx.accounts.destination.key();
+        proposal.amount = amount;
+        proposal.voting_ends_at = Clock::get()?.unix_timestamp
+            .checked_add(treasury.voting_period)
             .ok_or(ErrorCode::MathOverflow)?;
+        proposal.votes_for = 0;
+        proposal.votes_against = 0;
+        proposal.council_approvals = 0;
+        proposal.approved_at = 0;
+        proposal.executed = false;
+        proposal.bump = ctx.bumps["proposal"];
+
+        treasury.proposal_count = treasury.proposal_count.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn vote_on_spend(ctx: Context<VoteOnSpend>, support: bool) -> Result<()> {
+        let proposal = &mut ctx.accounts.proposal;
+        require!(
+            Clock::get()?.unix_timestamp < proposal.voting_ends_at,
+            ErrorCode::VotingClosed
+        );
+
+        // Only tokens locked past the end of voting count, so the same
+        // tokens cannot be moved to another wallet and voted twice
+        let weight = ctx.accounts.vote_lock.voting_weight(proposal.voting_ends_at);
+        require!(weight > 0, ErrorCode::NoVotingPower);
+
+        if support {
+            proposal.votes_for = proposal.votes_for.checked_add(weight).ok_or(ErrorCode::MathOverflow)?;
+        } else {
+            proposal.votes_against = proposal.votes_against.checked_add(weight).ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        let record = &mut ctx.accounts.vote_record;
+        record.proposal = proposal.key();
+        record.voter = ctx.accounts.voter.key();
+        record.weight = weight;
+        record.support = support;
+        Ok(())
+    }
+
+    pub fn approve_spend_as_council(ctx: Context<ApproveSpendAsCouncil>) -> Result<()> {
+        let treasury = &ctx.accounts.treasury;
+        let proposal = &mut ctx.accounts.proposal;
+        let index = treasury
+            .council_index(&ctx.accounts.council_member.key())
+            .ok_or(ErrorCode::Unauthorized)?;
+
+        proposal.council_approvals |= 1 << index;
+        if proposal.approved_at == 0 && proposal.council_approval_count() >= treasury.council_threshold {
+            proposal.approved_at = Clock::get()?.unix_timestamp;
+        }
+        Ok(())
+    }
+
+    pub fn finalize_spend_vote(ctx: Context<FinalizeSpendVote>) -> Result<()> {
+        let proposal = &mut ctx.accounts.proposal;
+        let now = Clock::get()?.unix_timestamp;
+        require!(now >= proposal.voting_ends_at, ErrorCode::VotingNotFinished);
+        require!(proposal.approved_at == 0, ErrorCode::InvalidMarketState);
+        require!(
+            proposal.vote_passed(ctx.accounts.treasury.quorum),
+            ErrorCode::ProposalNotPassed
+        );
+
+        // The timelock starts once the vote has passed
+        proposal.approved_at = now;
+        Ok(())
+    }
 
-        // Verify user has enough collateral (including fees)
+    pub fn execute_spend(ctx: Context<ExecuteSpend>) -> Result<()> {
+        let treasury = &ctx.accounts.treasury;
+        let proposal = &mut ctx.accounts.proposal;
+        require!(!proposal.executed, ErrorCode::ProposalAlreadyExecuted);
+        require!(proposal.approved_at != 0, ErrorCode::ProposalNotPassed);
         require!(
-            ctx.accounts.user_token_account.amount >= required_margin.checked_add(fee)
+            Clock::get()?.unix_timestamp >= proposal.approved_at
+                .checked_add(treasury.timelock)
                 .ok_or(ErrorCode::MathOverflow)?,
-            ErrorCode::InsufficientCollateral
+            ErrorCode::TimelockNotElapsed
         );
+        proposal.executed = true;
 
-        // Transfer margin and fees
+        let seeds = &[b"treasury".as_ref(), &[treasury.bump]];
         token::transfer(
-            CpiContext::new(
+            CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
                 token::Transfer {
-                    from: ctx.accounts.user_token_account.to_account_info(),
-                    to: ctx.accounts.market_vault.to_account_info(),
-                    authority: user.to_account_info(),
+                    from: ctx.accounts.source_vault.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    authority: treasury.to_account_info(),
                 },
+                &[&seeds[..]],
             ),
-            required_margin.checked_add(fee).unwrap(),
+            proposal.amount,
         )?;
 
-        // Create new position
-        let position = Position {
-            owner: user.key(),
+        Ok(())
+    }
+
+    /// Market order for up to `size`. It fills as much as the open-interest
+    /// cap allows and cancels the rest, failing if that is below `min_fill_size`.
+    /// The fill fails with `SlippageExceeded` if its price is more than
+    /// `max_slippage_bps` worse than the expected `price`, and with
+    /// `OrderExpired` if it lands after `expiry_ts`.
+    /// `tag` is an opaque caller reference, kept on the position and echoed
+    /// in its fill, close and liquidation events.
+    pub fn place_order(
+        ctx: Context<PlaceOrder>,
+        side: Side,
+        size: u64,
+        min_fill_size: u64,
+        price: u64,
+        max_slippage_bps: u16,
+        leverage: u8,
+        _sub_account_id: u16,
+        tag: [u8; 32],
+        expiry_ts: Option<i64>,
+    ) -> Result<()> {
+        check_order_expiry(expiry_ts, Clock::get()?.unix_timestamp)?;
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        ctx.accounts.protocol_config.check_order_rate(&mut ctx.accounts.margin_account, 1, &Clock::get()?)?;
+        require_launch_registration(&ctx.accounts.market, ctx.accounts.launch_registration.as_ref())?;
+        let market_key = ctx.accounts.market.key();
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let position_key = ctx.accounts.position.key();
+        let position_id = ctx.accounts.margin_account.open_position()?;
+        ctx.accounts.position.set_inner(Position::new(
+            market_key,
+            margin_account_key,
+            position_id,
+            side,
+            leverage,
+            Clock::get()?.unix_timestamp,
+            ctx.bumps["position"],
+        ));
+        ctx.accounts.position.tag = tag;
+        ctx.accounts.position.funding_exempt = ctx.accounts.margin_account.hedger;
+        let now = Clock::get()?.unix_timestamp;
+        let vault_balance = ctx.accounts.market.vault_balance(ctx.accounts.market_vault.amount);
+        let fee_discount_bps =
+            volume_fee_discount_bps(&ctx.accounts.protocol_config, ctx.accounts.trader_stats.as_deref(), now);
+        let (required_margin, fee, notional) = open_market_order(
+            &mut ctx.accounts.market,
+            market_key,
+            margin_account_key,
+            &mut ctx.accounts.margin_account.stats,
+            ctx.accounts.trade_history.as_deref_mut(),
+            ctx.accounts.order_book.as_deref(),
+            &ctx.accounts.price_feed,
+            vault_balance,
+            fee_discount_bps,
+            &mut ctx.accounts.position,
+            position_key,
             side,
             size,
-            entry_price: current_price,
+            min_fill_size,
+            price,
+            Some(max_slippage_bps),
             leverage,
-            margin: required_margin,
-            last_funding_timestamp: Clock::get()?.unix_timestamp,
-            liquidation_price: calculate_liquidation_price(
-                side,
-                current_price,
-                leverage,
-                market.liquidation_threshold,
-            )?,
-        };
+            tag,
+        )?;
 
-        // Add position to the appropriate queue
-        match side {
-            Side::Long => market.long_positions.push_back(position),
-            Side::Short => market.short_positions.push_back(position),
-        }
+        let accounts = ctx.accounts;
+        collect_market_order(
+            &mut accounts.market,
+            &mut accounts.margin_account,
+            &accounts.user,
+            &accounts.user_token_account,
+            &accounts.market_vault,
+            &accounts.token_program,
+            accounts.integrator.as_mut(),
+            accounts.integrator_fee_account.as_ref(),
+            &accounts.protocol_config,
+            accounts.referral_code.as_mut(),
+            accounts.referral_vault.as_ref(),
+            required_margin,
+            fee,
+            notional,
+        )?;
+        record_trader_volume(&accounts.market, accounts.trader_stats.as_mut(), now, notional);
 
+        accounts.margin_account.unlock();
+        sync_coverage_open_interest(&accounts.market, accounts.insurance_coverage.as_mut())?;
+        let filled_size = accounts.position.base_size;
+        notify_hook(
+            &accounts.protocol_config,
+            &accounts.market,
+            accounts.hook_program.as_ref(),
+            HookEventKind::Open,
+            accounts.position.record(position_key, now),
+            filled_size,
+            notional / filled_size,
+        )?;
         Ok(())
     }
 
-    pub fn liquidate_position(
-        ctx: Context<LiquidatePosition>,
-        position_index: u64,
-        side: Side,
+    /// Market order that adds to an open position instead of opening a new
+    /// one, so repeated orders on the same side net into a single position
+    /// with a size-weighted entry price, one margin and one liquidation
+    /// price. The order uses the position's side and leverage. Its fill event
+    /// echoes `tag`; the position keeps the tag it was opened with.
+    pub fn increase_position(
+        ctx: Context<IncreasePosition>,
+        size: u64,
+        min_fill_size: u64,
+        price: u64,
+        _sub_account_id: u16,
+        tag: [u8; 32],
     ) -> Result<()> {
-        let market = &mut ctx.accounts.market;
-        let price_feed = PriceFeed::new_from_pyth(&ctx.accounts.price_feed)?;
-        let current_price = price_feed.get_adjusted_price()?;
-
-        // Find and remove the position
-        let position = match side {
-            Side::Long => {
-                require!(position_index < market.long_positions.len() as u64, ErrorCode::InvalidPositionIndex);
-                market.long_positions.remove(position_index as usize)
-            }
-            Side::Short => {
-                require!(position_index < market.short_positions.len() as u64, ErrorCode::InvalidPositionIndex);
-                market.short_positions.remove(position_index as usize)
-            }
-        }.ok_or(ErrorCode::PositionNotFound)?;
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        ctx.accounts.protocol_config.check_order_rate(&mut ctx.accounts.margin_account, 1, &Clock::get()?)?;
+        let position = &ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+        let (side, leverage, size_before) = (position.side, position.leverage, position.base_size);
+        let now = Clock::get()?.unix_timestamp;
+        let market_key = ctx.accounts.market.key();
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let position_key = position.key();
+        let vault_balance = ctx.accounts.market.vault_balance(ctx.accounts.market_vault.amount);
+        let fee_discount_bps =
+            volume_fee_discount_bps(&ctx.accounts.protocol_config, ctx.accounts.trader_stats.as_deref(), now);
+        let (required_margin, fee, notional) = open_market_order(
+            &mut ctx.accounts.market,
+            market_key,
+            margin_account_key,
+            &mut ctx.accounts.margin_account.stats,
+            ctx.accounts.trade_history.as_deref_mut(),
+            ctx.accounts.order_book.as_deref(),
+            &ctx.accounts.price_feed,
+            vault_balance,
+            fee_discount_bps,
+            &mut ctx.accounts.position,
+            position_key,
+            side,
+            size,
+            min_fill_size,
+            price,
+            None,
+            leverage,
+            tag,
+        )?;
 
-        // Check if position can be liquidated
-        let can_liquidate = match side {
-            Side::Long => current_price <= position.liquidation_price,
-            Side::Short => current_price >= position.liquidation_price,
-        };
+        let accounts = ctx.accounts;
+        collect_market_order(
+            &mut accounts.market,
+            &mut accounts.margin_account,
+            &accounts.user,
+            &accounts.user_token_account,
+            &accounts.market_vault,
+            &accounts.token_program,
+            accounts.integrator.as_mut(),
+            accounts.integrator_fee_account.as_ref(),
+            &accounts.protocol_config,
+            accounts.referral_code.as_mut(),
+            accounts.referral_vault.as_ref(),
+            required_margin,
+            fee,
+            notional,
+        )?;
+        record_trader_volume(&accounts.market, accounts.trader_stats.as_mut(), now, notional);
 
-        require!(can_liquidate, ErrorCode::CannotLiquidate);
+        accounts.margin_account.unlock();
+        sync_coverage_open_interest(&accounts.market, accounts.insurance_coverage.as_mut())?;
+        let filled_size = accounts.position.base_size - size_before;
+        notify_hook(
+            &accounts.protocol_config,
+            &accounts.market,
+            accounts.hook_program.as_ref(),
+            HookEventKind::Open,
+            accounts.position.record(position_key, now),
+            filled_size,
+            notional / filled_size,
+        )?;
+        Ok(())
+    }
 
-        // Calculate PnL and remaining margin
-        let pnl = calculate_pnl(
-            side,
-            position.size,
-            position.entry_price,
-            current_price,
-            position.leverage,
+    /// Opens market orders in several markets at once, all funded from one
+    /// sub-account. Each leg passes `MULTI_ORDER_ACCOUNTS` remaining accounts:
+    /// market, market vault, price feed, order book (the program id stands
+    /// in for a market without a book) and the uninitialized position PDA for
+    /// the sub-account's next position id. Any failing leg fails them all.
+    pub fn place_orders_multi<'info>(
+        ctx: Context<'_, '_, '_, 'info, PlaceOrdersMulti<'info>>,
+        orders: Vec<MultiOrderLeg>,
+        _sub_account_id: u16,
+    ) -> Result<()> {
+        require!(
+            !orders.is_empty() && orders.len() <= MAX_MULTI_ORDER_LEGS,
+            ErrorCode::InvalidOrderLegs
+        );
+        require!(
+            ctx.remaining_accounts.len() == orders.len() * MULTI_ORDER_ACCOUNTS,
+            ErrorCode::InvalidOrderLegs
+        );
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        ctx.accounts.protocol_config.check_order_rate(
+            &mut ctx.accounts.margin_account,
+            orders.len() as u16,
+            &Clock::get()?,
         )?;
+        let margin_account_key = ctx.accounts.margin_account.key();
 
-        // Transfer remaining margin (if any) back to user
-        let remaining_margin = if pnl > 0 {
-            position.margin.checked_add(pnl).ok_or(ErrorCode::MathOverflow)?
-        } else {
-            position.margin.checked_sub(pnl.abs() as u64).ok_or(ErrorCode::MathOverflow)?
-        };
+        let mut total_amount: u64 = 0;
+        let mut transfers = Vec::with_capacity(orders.len());
+        for (leg, accounts) in orders.iter().zip(ctx.remaining_accounts.chunks(MULTI_ORDER_ACCOUNTS)) {
+            let [market_info, vault_info, price_feed, book_info, position_info] = accounts else {
+                return err!(ErrorCode::InvalidOrderLegs);
+            };
+            let mut market: Account<'info, Market> = Account::try_from(market_info)?;
+            require!(market_info.is_writable, ErrorCode::InvalidOrderLegs);
 
-        if remaining_margin > 0 {
+            require_keys_eq!(vault_info.key(), market.vault, ErrorCode::InvalidOrderLegs);
+            market.check_configured_oracle(price_feed.key)?;
+            let market_vault: Account<'info, TokenAccount> = Account::try_from(vault_info)?;
+
+            let order_book = if book_info.key() == crate::ID {
+                None
+            } else {
+                let order_book: Account<'info, OrderBook> = Account::try_from(book_info)?;
+                require!(order_book.market == market_info.key(), ErrorCode::InvalidOrderLegs);
+                Some(order_book)
+            };
+
+            let position_id = ctx.accounts.margin_account.open_position()?;
+            require_launch_registration(&market, None)?;
+            sync_coverage_open_interest(&market, None)?;
+            require!(
+                !ctx.accounts.protocol_config.is_approved_hook(&market.hook_program),
+                ErrorCode::HookProgramRequired
+            );
+            let mut position = create_position_account(
+                ctx.accounts.user.to_account_info(),
+                position_info,
+                ctx.accounts.system_program.to_account_info(),
+                market_info.key(),
+                margin_account_key,
+                position_id,
+                leg.side,
+                leg.leverage,
+            )?;
+            position.tag = leg.tag;
+            position.funding_exempt = ctx.accounts.margin_account.hedger;
+            let vault_balance = market.vault_balance(market_vault.amount);
+            let (required_margin, fee, _) = open_market_order(
+                &mut market,
+                market_info.key(),
+                margin_account_key,
+                &mut ctx.accounts.margin_account.stats,
+                ctx.accounts.trade_history.as_deref_mut(),
+                order_book.as_deref(),
+                price_feed,
+                vault_balance,
+                0,
+                &mut position,
+                position_info.key(),
+                leg.side,
+                leg.size,
+                leg.size,
+                leg.price,
+                None,
+                leg.leverage,
+                leg.tag,
+            )?;
+            // Paper-trading legs are paid from the virtual balance right away
+            let amount = required_margin.checked_add(fee).ok_or(ErrorCode::MathOverflow)?;
+            if market.paper_trading {
+                market.collect_paper(&mut ctx.accounts.margin_account, amount)?;
+            } else {
+                total_amount = total_amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+                transfers.push((vault_info, amount));
+            }
+            market.exit(&crate::ID)?;
+            position.exit(&crate::ID)?;
+        }
+
+        require!(
+            ctx.accounts.user_token_account.amount >= total_amount,
+            ErrorCode::InsufficientCollateral
+        );
+        for (market_vault, amount) in transfers {
             token::transfer(
                 CpiContext::new(
                     ctx.accounts.token_program.to_account_info(),
                     token::Transfer {
-                        from: ctx.accounts.market_vault.to_account_info(),
-                        to: ctx.accounts.user_token_account.to_account_info(),
-                        authority: market.to_account_info(),
+                        from: ctx.accounts.user_token_account.to_account_info(),
+                        to: market_vault.clone(),
+                        authority: ctx.accounts.user.to_account_info(),
                     },
                 ),
-                remaining_margin,
+                amount,
             )?;
         }
 
+        ctx.accounts.margin_account.unlock();
         Ok(())
     }
-}
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
-pub enum Side {
-    Long,
-    Short,
-}
+    pub fn liquidate_position(mut ctx: Context<LiquidatePosition>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        let price_feed = market.load_price_feed(&ctx.accounts.price_feed)?;
+        let oracle_price = price_feed.get_price_for(ctx.accounts.position.side)?;
 
-#[account]
-pub struct Market {
-    pub name: String,
-    pub authority: Pubkey,
-    pub min_base_order_size: u64,
-    pub tick_size: u64,
-    pub max_leverage: u8,
-    pub liquidation_threshold: u16,
-    pub maintenance_margin_fraction: u16,
-    pub long_positions: VecDeque<Position>,
-    pub short_positions: VecDeque<Position>,
-    pub is_initialized: bool,
-    pub total_fee_accrued: u64,
-    pub max_position_size: u64,
-    pub funding_rate: i64,
-    pub last_funding_time: i64,
-    pub funding_interval: i64,  // in seconds
-}
+        // Check if position can be liquidated at the price it would close at
+        let position = &mut ctx.accounts.position;
+        let current_price = market.exit_price(position.side, position.base_size, oracle_price)?;
+        require!(position.is_liquidatable(current_price), ErrorCode::CannotLiquidate);
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct Position {
-    pub owner: Pubkey,
-    pub side: Side,
-    pub size: u64,
-    pub entry_price: u64,
-    pub leverage: u8,
-    pub margin: u64,
-    pub last_funding_timestamp: i64,
-    pub liquidation_price: u64,
-    pub realized_pnl: i64,
-    pub unrealized_pnl: i64,
-    pub last_update_price: u64,
-    pub creation_time: i64,
-    pub total_funding_paid: i64,
-}
-
-impl Position {
-    pub fn new(
-        owner: Pubkey,
+        check_priority_lane(market, ctx.accounts.keeper_stake.as_ref(), ctx.accounts.liquidator.key)?;
+
+        // Bring mining rewards and funding up to date before open interest
+        // changes. Unclaimed rewards of a liquidated position are forfeited.
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+        sync_coverage_open_interest(market, ctx.accounts.insurance_coverage.as_mut())?;
+        let position_key = position.key();
+        let mut position = position.clone().into_inner();
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.liquidator.to_account_info(),
+        )?;
+
+        // If the oracle gapped past the bankruptcy price between updates, the
+        // position closes at the bankruptcy price with nothing left for the
+        // owner, and the gap is a shortfall for the bad-debt waterfall
+        let market = &mut ctx.accounts.market;
+        let pnl = market.position_pnl(&position, current_price)?;
+        if pnl < 0 && pnl.unsigned_abs() > position.margin {
+            let shortfall = pnl.unsigned_abs() - position.margin;
+            let bankruptcy_price = market.bankruptcy_price(&position);
+            market.last_settled_price = bankruptcy_price;
+            market.last_oracle_price = oracle_price;
+            position.record_exit(closed_size, bankruptcy_price, -(position.margin as i64));
+            market.realize_pnl(-(position.margin as i64));
+            emit!(PositionClosed {
+                record: position.record(position_key, now),
+                reason: CloseReason::Liquidation,
+                closed_size,
+                remaining_size: 0,
+                exit_price: bankruptcy_price,
+            });
+            notify_hook(
+                &ctx.accounts.protocol_config,
+                market,
+                ctx.accounts.hook_program.as_ref(),
+                HookEventKind::Liquidation,
+                position.record(position_key, now),
+                closed_size,
+                bankruptcy_price,
+            )?;
+            emit!(BankruptcyLiquidation {
+                market: market.key(),
+                owner: position.owner,
+                tag: position.tag,
+                side: position.side,
+                bankruptcy_price,
+                oracle_price,
+                shortfall,
+            });
+            // Paper-trading losses are virtual and need no cover
+            if !market.paper_trading {
+                cover_shortfall(&mut ctx, shortfall)?;
+            }
+            return Ok(());
+        }
+
+        // The liquidation fee comes out of what is left of the margin; the
+        // insurance fund's part is held in the vault until swept
+        let remaining_margin = if pnl > 0 {
+            position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?
+        } else {
+            position.margin - pnl.unsigned_abs()
+        };
+        let (liquidator_fee, insurance_fee) = market.liquidation_fee(position.margin, remaining_margin);
+        market.accrue_liquidation_fee(insurance_fee, now)?;
+        market.last_settled_price = current_price;
+        market.last_oracle_price = oracle_price;
+        position.record_exit(closed_size, current_price, pnl - (liquidator_fee + insurance_fee) as i64);
+        market.realize_pnl(pnl);
+        emit!(PositionClosed {
+            record: position.record(position_key, now),
+            reason: CloseReason::Liquidation,
+            closed_size,
+            remaining_size: 0,
+            exit_price: current_price,
+        });
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Liquidation,
+            position.record(position_key, now),
+            closed_size,
+            current_price,
+        )?;
+
+        // Pay the liquidator's fee, then the remaining margin (if any) back to user
+        let remaining_margin = remaining_margin - liquidator_fee - insurance_fee;
+        let market_key = ctx.accounts.market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        let signer = &[&seeds[..]];
+        if liquidator_fee > 0 {
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.liquidator_token_account.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    signer,
+                ),
+                liquidator_fee,
+            )?;
+        }
+
+        if remaining_margin > 0 {
+            let transfer = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.market_vault.to_account_info(),
+                    to: ctx.accounts.user_token_account.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                signer,
+            );
+            pay_trader(
+                &mut ctx.accounts.market,
+                &mut ctx.accounts.margin_account,
+                transfer,
+                remaining_margin,
+            )?;
+        } else if liquidator_fee > 0 {
+            check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn propose_param_change(ctx: Context<ProposeParamChange>, change: ParameterChange) -> Result<()> {
+        change.validate()?;
+        let treasury = &mut ctx.accounts.treasury;
+        let proposal = &mut ctx.accounts.proposal;
+
+        let voting_ends_at = Clock::get()?.unix_timestamp
+            .checked_add(treasury.voting_period)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(
+            ctx.accounts.vote_lock.voting_weight(voting_ends_at) > 0,
+            ErrorCode::NoVotingPower
+        );
+
+        proposal.id = treasury.proposal_count;
+        proposal.market = ctx.accounts.market.key();
+        proposal.proposer = ctx.accounts.proposer.key();
+        proposal.change = change;
+        proposal.voting_ends_at = voting_ends_at;
+        proposal.votes_for = 0;
+        proposal.votes_against = 0;
+        proposal.queued = false;
+        proposal.bump = ctx.bumps["proposal"];
+
+        treasury.proposal_count = treasury.proposal_count.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn vote_on_param_change(ctx: Context<VoteOnParamChange>, support: bool) -> Result<()> {
+        let proposal = &mut ctx.accounts.proposal;
+        require!(
+            Clock::get()?.unix_timestamp < proposal.voting_ends_at,
+            ErrorCode::VotingClosed
+        );
+
+        let weight = ctx.accounts.vote_lock.voting_weight(proposal.voting_ends_at);
+        require!(weight > 0, ErrorCode::NoVotingPower);
+
+        if support {
+            proposal.votes_for = proposal.votes_for.checked_add(weight).ok_or(ErrorCode::MathOverflow)?;
+        } else {
+            proposal.votes_against = proposal.votes_against.checked_add(weight).ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        let record = &mut ctx.accounts.vote_record;
+        record.proposal = proposal.key();
+        record.voter = ctx.accounts.voter.key();
+        record.weight = weight;
+        record.support = support;
+        Ok(())
+    }
+
+    pub fn queue_param_change(ctx: Context<QueueParamChange>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let proposal = &mut ctx.accounts.proposal;
+        let treasury = &ctx.accounts.treasury;
+        let now = Clock::get()?.unix_timestamp;
+
+        require!(now >= proposal.voting_ends_at, ErrorCode::VotingNotFinished);
+        require!(!proposal.queued, ErrorCode::ProposalAlreadyExecuted);
+        require!(proposal.vote_passed(treasury.quorum), ErrorCode::ProposalNotPassed);
+        require!(
+            market.param_queue.len() < MAX_QUEUED_PARAM_CHANGES,
+            ErrorCode::ParamQueueFull
+        );
+
+        market.param_queue.push_back(QueuedParamChange {
+            change: proposal.change,
+            eta: now.checked_add(treasury.timelock).ok_or(ErrorCode::MathOverflow)?,
+        });
+        proposal.queued = true;
+        Ok(())
+    }
+
+    pub fn apply_param_changes(ctx: Context<ApplyParamChanges>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+
+        // Emission changes must not retroactively reprice accrued rewards
+        let long_open_interest = market.open_interest(Side::Long);
+        let short_open_interest = market.open_interest(Side::Short);
+        market.mining.accrue(now, long_open_interest, short_open_interest)?;
+
+        let mut applied = 0;
+        while market.param_queue.front().is_some_and(|queued| queued.eta <= now) {
+            let queued = market.param_queue.pop_front().unwrap();
+            queued.change.apply(market);
+            applied += 1;
+        }
+        require!(applied > 0, ErrorCode::TimelockNotElapsed);
+        Ok(())
+    }
+
+    pub fn set_guardian(ctx: Context<MarketAdmin>, guardian: Pubkey, pause_duration: i64) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(pause_duration > 0, ErrorCode::InvalidMarketState);
+        let market = &mut ctx.accounts.market;
+        market.guardian = guardian;
+        market.guardian_pause_duration = pause_duration;
+        Ok(())
+    }
+
+    /// Pauses the market for `guardian_pause_duration`. The guardian cannot
+    /// extend a pause in force, nor pause again until a cooldown of one
+    /// more pause duration has passed, unless the authority lifts it first.
+    pub fn pause_market(ctx: Context<PauseMarket>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::InvalidMarketState);
+        require!(market.guardian_pause_available(now), ErrorCode::GuardianPauseCooldown);
+        market.paused_until = now
+            .checked_add(market.guardian_pause_duration)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn ratify_pause(ctx: Context<MarketAdmin>) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        require!(market.is_paused(Clock::get()?.unix_timestamp), ErrorCode::InvalidMarketState);
+        market.pause_ratified = true;
+        Ok(())
+    }
+
+    pub fn unpause_market(ctx: Context<MarketAdmin>) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        market.paused_until = 0;
+        market.pause_ratified = false;
+        Ok(())
+    }
+
+    /// Sets the trading status the authority wants for the market. `Paused`
+    /// is a pause that holds until lifted, `ReduceOnly` only lets positions
+    /// shrink, and `Active` lifts both. Reduce-only set by the market's own
+    /// safeguards stays until they clear it.
+    pub fn set_market_status(ctx: Context<MarketAdmin>, status: TradingStatus) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        market.reduce_only_flags &= !REDUCE_ONLY_AUTHORITY;
+        market.paused_until = 0;
+        market.pause_ratified = false;
+        match status {
+            TradingStatus::Active => {}
+            TradingStatus::ReduceOnly => market.reduce_only_flags |= REDUCE_ONLY_AUTHORITY,
+            TradingStatus::Paused => market.pause_ratified = true,
+        }
+        Ok(())
+    }
+
+    /// Updates the market's sizing and risk parameters. Fields left `None`
+    /// keep their current value, and the result has to pass the same bounds
+    /// as a market template. Open positions are held to the new leverage and
+    /// margin requirements from their next check.
+    pub fn update_market_params(ctx: Context<MarketAdmin>, params: MarketParamsUpdate) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        let updated = MarketTemplate {
+            configured: true,
+            min_base_order_size: params.min_base_order_size.unwrap_or(market.min_base_order_size),
+            tick_size: params.tick_size.unwrap_or(market.tick_size),
+            max_leverage: params.max_leverage.unwrap_or(market.max_leverage),
+            liquidation_threshold: params.liquidation_threshold.unwrap_or(market.liquidation_threshold),
+            maintenance_margin_fraction: params
+                .maintenance_margin_fraction
+                .unwrap_or(market.maintenance_margin_fraction),
+            max_position_size: params.max_position_size.unwrap_or(market.max_position_size),
+            funding_interval: market.funding_interval,
+            fee_bps: market.fee_bps,
+            max_funding_rate_bps: market.max_funding_rate_bps,
+            max_oracle_staleness_secs: params
+                .max_oracle_staleness_secs
+                .unwrap_or(market.max_oracle_staleness_secs),
+        };
+        updated.validate()?;
+
+        market.min_base_order_size = updated.min_base_order_size;
+        market.tick_size = updated.tick_size;
+        market.max_leverage = updated.max_leverage;
+        market.liquidation_threshold = updated.liquidation_threshold;
+        market.maintenance_margin_fraction = updated.maintenance_margin_fraction;
+        market.max_position_size = updated.max_position_size;
+        market.max_oracle_staleness_secs = updated.max_oracle_staleness_secs;
+        Ok(())
+    }
+
+    pub fn initialize_order_book(ctx: Context<InitializeOrderBook>) -> Result<()> {
+        let order_book = &mut ctx.accounts.order_book;
+        order_book.market = ctx.accounts.market.key();
+        order_book.next_order_id = 0;
+        order_book.bids = Vec::new();
+        order_book.asks = Vec::new();
+        order_book.bump = ctx.bumps["order_book"];
+        ctx.accounts.market.has_order_book = true;
+        Ok(())
+    }
+
+    pub fn place_limit_order(
+        ctx: Context<PlaceLimitOrder>,
         side: Side,
+        price: u64,
         size: u64,
-        entry_price: u64,
         leverage: u8,
-        margin: u64,
-        liquidation_price: u64,
-    ) -> Self {
-        let current_time = Clock::get().unwrap().unix_timestamp;
-        Self {
-            owner,
+        _sub_account_id: u16,
+        tag: [u8; 32],
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        ctx.accounts.protocol_config.check_order_rate(&mut ctx.accounts.margin_account, 1, &Clock::get()?)?;
+        let market = &ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        require!(!market.is_reduce_only(), ErrorCode::MarketReduceOnly);
+        require_launch_registration(market, ctx.accounts.launch_registration.as_ref())?;
+
+        let price_feed = market.load_price_feed(&ctx.accounts.price_feed)?;
+        let index_price = price_feed.get_index_price()?;
+        check_mark_index_deviation(market, Some(&ctx.accounts.order_book), index_price)?;
+
+        require!(price > 0, ErrorCode::InvalidPrice);
+        check_limit_price_band(market, price, index_price)?;
+        require!(leverage > 0 && leverage <= market.current_max_leverage(now), ErrorCode::LeverageTooHigh);
+        require!(
+            size >= ctx.accounts.order_book.min_order_size(side, market.min_base_order_size),
+            ErrorCode::OrderTooSmall
+        );
+        require!(size <= market.max_position_size, ErrorCode::OrderTooLarge);
+        require!(price.is_multiple_of(market.tick_size), ErrorCode::InvalidPrice);
+
+        // Escrow margin and fee at the limit price until the order fills or is
+        // cancelled. The margin also covers the gap between the limit price and
+        // the index, which the position would mark at as soon as it fills
+        let required_margin = banded_required_margin(size, price, leverage, index_price, price)?;
+        let notional = size.checked_mul(price).ok_or(ErrorCode::MathOverflow)?;
+        let fee = market.maker_fee(notional, now);
+        let locked_amount = required_margin.checked_add(fee).ok_or(ErrorCode::MathOverflow)?;
+
+        // Fills open into a position account created with the order
+        let market_key = market.key();
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let position_id = ctx.accounts.margin_account.open_position()?;
+        let mut position = Position::new(
+            market_key,
+            margin_account_key,
+            position_id,
             side,
-            size,
-            entry_price,
             leverage,
-            margin,
-            last_funding_timestamp: current_time,
-            liquidation_price,
-            realized_pnl: 0,
-            unrealized_pnl: 0,
-            last_update_price: entry_price,
-            creation_time: current_time,
-            total_funding_paid: 0,
-        }
-    }
-
-    pub fn update_unrealized_pnl(&mut self, current_price: u64) -> Result<()> {
-        self.unrealized_pnl = calculate_pnl(
-            self.side,
-            self.size,
-            self.entry_price,
-            current_price,
-            self.leverage,
+            now,
+            ctx.bumps["position"],
+        );
+        position.resting_order = true;
+        position.tag = tag;
+        position.funding_exempt = ctx.accounts.margin_account.hedger;
+        ctx.accounts.position.set_inner(position);
+
+        ctx.accounts.order_book.insert(side, Order {
+            order_id: 0,
+            owner: margin_account_key,
+            position: ctx.accounts.position.key(),
+            price,
+            filled_size: 0,
+            remaining_size: size,
+            leverage,
+            locked_margin: required_margin,
+            locked_fee: fee,
+            placed_at: now,
+        })?;
+
+        let transfer = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.user_token_account.to_account_info(),
+                to: ctx.accounts.market_vault.to_account_info(),
+                authority: ctx.accounts.user.to_account_info(),
+            },
+        );
+        collect_from_trader(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.margin_account,
+            transfer,
+            locked_amount,
         )?;
-        self.last_update_price = current_price;
+
+        ctx.accounts.margin_account.unlock();
         Ok(())
     }
 
-    pub fn get_health_ratio(&self, current_price: u64) -> Result<u16> {
-        let position_value = (self.size as u128)
-            .checked_mul(current_price as u128)
+    pub fn cancel_order(
+        ctx: Context<CancelOrder>,
+        side: Side,
+        order_id: u64,
+        _sub_account_id: u16,
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let order_book = &mut ctx.accounts.order_book;
+        let is_owner = order_book.orders(side)
+            .iter()
+            .any(|order| order.order_id == order_id && order.owner == ctx.accounts.margin_account.key());
+        require!(is_owner, ErrorCode::OrderNotFound);
+        let order = order_book.remove(side, order_id)?;
+        require_keys_eq!(order.position, ctx.accounts.position.key(), ErrorCode::PositionAccountMissing);
+
+        // A position that never filled goes away with its order
+        ctx.accounts.position.resting_order = false;
+        if ctx.accounts.position.base_size == 0 {
+            close_position_account(
+                &mut ctx.accounts.position,
+                &mut ctx.accounts.margin_account,
+                ctx.accounts.owner.to_account_info(),
+            )?;
+        }
+
+        let market_key = ctx.accounts.market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        let signer = &[&seeds[..]];
+        let transfer = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.market_vault.to_account_info(),
+                to: ctx.accounts.owner_token_account.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            },
+            signer,
+        );
+        pay_trader(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.margin_account,
+            transfer,
+            order.locked_amount(),
+        )?;
+
+        ctx.accounts.margin_account.unlock();
+        Ok(())
+    }
+
+    pub fn view_book_depth(ctx: Context<ViewBookDepth>, levels: u8) -> Result<BookDepth> {
+        require!(levels > 0 && levels <= MAX_DEPTH_LEVELS, ErrorCode::InvalidDepthLevels);
+        Ok(ctx.accounts.order_book.depth(levels))
+    }
+
+    pub fn set_matching_policy(ctx: Context<MarketAdmin>, policy: MatchingPolicy) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        ctx.accounts.market.matching_policy = policy;
+        Ok(())
+    }
+
+    /// Matches crossing levels. Every order filled passes its position
+    /// account in the remaining accounts.
+    pub fn match_orders<'info>(ctx: Context<'_, '_, '_, 'info, MatchOrders<'info>>, max_levels: u8) -> Result<()> {
+        require!(max_levels <= MAX_MATCH_LEVELS_PER_IX, ErrorCode::ParameterOutOfBounds);
+        let market = &mut ctx.accounts.market;
+        let order_book = &mut ctx.accounts.order_book;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        // Every fill opens or adds to a position, so resting orders wait out
+        // reduce-only mode or are cancelled
+        require!(!market.is_reduce_only(), ErrorCode::MarketReduceOnly);
+        let index_price = market.load_price_feed(&ctx.accounts.price_feed)?.get_index_price()?;
+
+        for _ in 0..max_levels {
+            let long_open_interest = market.open_interest(Side::Long);
+            let short_open_interest = market.open_interest(Side::Short);
+            let Some(fills) = order_book.match_best_levels(market.matching_policy) else {
+                break;
+            };
+            // Orders were placed within the band; the index may have moved since
+            if let Some(fill) = fills.first() {
+                check_limit_price_band(market, fill.price, index_price)?;
+            }
+
+            // Matched volume opens the same size on both sides
+            let matched: u64 = fills.iter()
+                .filter(|fill| fill.side == Side::Long)
+                .map(|fill| fill.base_size)
+                .sum();
+            require!(
+                long_open_interest.max(short_open_interest)
+                    .checked_add(matched)
+                    .ok_or(ErrorCode::MathOverflow)? <= market.max_position_size,
+                ErrorCode::ExceedsMaxPosition
+            );
+
+            for fill in fills {
+                let notional = fill.base_size.checked_mul(fill.price).ok_or(ErrorCode::MathOverflow)?;
+                // Each match fills both sides; count its volume once
+                if fill.side == Side::Long {
+                    market.record_volume(now, notional)?;
+                }
+                market.last_settled_price = fill.price;
+                let position_info = ctx.remaining_accounts
+                    .iter()
+                    .find(|info| *info.key == fill.position)
+                    .ok_or(ErrorCode::PositionAccountMissing)?;
+                let mut position: Account<'info, Position> = Account::try_from(position_info)?;
+                // A maker rebate is paid out of accrued fees into the new margin
+                let rebate = market.maker_rebate(notional);
+                let margin = fill.margin.checked_add(rebate).ok_or(ErrorCode::MathOverflow)?;
+                market.open_position(&mut position, fill.base_size, fill.price, margin, now)?;
+                market.pay_maker_rebate(rebate, now);
+                if fill.remaining_size == 0 {
+                    position.resting_order = false;
+                }
+                position.exit(&crate::ID)?;
+                market.accrue_fee(FeeKind::Open, fill.side, FeeRole::Maker, fill.fee, now)?;
+
+                emit!(OrderFilled {
+                    market: market.key(),
+                    owner: fill.owner,
+                    position: fill.position,
+                    tag: position.tag,
+                    order_id: Some(fill.order_id),
+                    side: fill.side,
+                    price: fill.price,
+                    fill_size: fill.base_size,
+                    filled_size: fill.filled_size,
+                    remaining_size: fill.remaining_size,
+                    timestamp: now,
+                });
+                notify_hook(
+                    &ctx.accounts.protocol_config,
+                    market,
+                    ctx.accounts.hook_program.as_ref(),
+                    HookEventKind::Open,
+                    position.record(fill.position, now),
+                    fill.base_size,
+                    fill.price,
+                )?;
+            }
+        }
+
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+        Ok(())
+    }
+
+    /// Sets how far from the oracle index, in bps, limit orders may be placed
+    /// and matched.
+    pub fn set_limit_price_band(ctx: Context<MarketAdmin>, band_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(band_bps > 0 && band_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.limit_price_band_bps = band_bps;
+        Ok(())
+    }
+
+    pub fn set_mark_deviation_cap(ctx: Context<MarketAdmin>, max_deviation_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(max_deviation_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.max_mark_index_deviation_bps = max_deviation_bps;
+        Ok(())
+    }
+
+    /// Caps each market order at `cap_bps` of the order book's two-sided
+    /// depth within `band_bps` of the oracle price. A cap of 0 disables it.
+    pub fn set_depth_order_cap(ctx: Context<MarketAdmin>, cap_bps: u16, band_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(cap_bps <= 10000 && band_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        require!(cap_bps == 0 || band_bps > 0, ErrorCode::ParameterOutOfBounds);
+        let market = &mut ctx.accounts.market;
+        market.depth_cap_bps = cap_bps;
+        market.depth_band_bps = band_bps;
+        Ok(())
+    }
+
+    pub fn update_mark_deviation(ctx: Context<UpdateMarkDeviation>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let price_feed = market.load_price_feed(&ctx.accounts.price_feed)?;
+        let index_price = price_feed.get_index_price()?;
+        let cap = market.max_mark_index_deviation_bps as u64;
+
+        let deviation = mark_price(market, ctx.accounts.order_book.as_deref(), index_price)?
+            .map(|mark| mark_index_deviation_bps(mark, index_price))
+            .unwrap_or(0);
+
+        // Trip ReduceOnly past the cap and only lift it once the mark has
+        // converged to within half the cap, so it does not flap at the edge
+        if cap > 0 && deviation > cap {
+            market.reduce_only_flags |= REDUCE_ONLY_MARK_DEVIATION;
+        } else if cap == 0 || deviation <= cap / 2 {
+            market.reduce_only_flags &= !REDUCE_ONLY_MARK_DEVIATION;
+        }
+        Ok(())
+    }
+
+    pub fn initialize_margin_account(ctx: Context<InitializeMarginAccount>, sub_account_id: u16) -> Result<()> {
+        require!(sub_account_id < MAX_SUB_ACCOUNTS, ErrorCode::InvalidSubAccount);
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.authority = ctx.accounts.authority.key();
+        margin_account.sub_account_id = sub_account_id;
+        margin_account.stats = Default::default();
+        margin_account.collateral = 0;
+        margin_account.paper_balance = 0;
+        margin_account.created_at = Clock::get()?.unix_timestamp;
+        margin_account.in_use = false;
+        margin_account.next_position_id = 0;
+        margin_account.position_count = 0;
+        margin_account.bump = ctx.bumps["margin_account"];
+        margin_account.order_rate = Default::default();
+        margin_account.hedger = false;
+        Ok(())
+    }
+
+    pub fn set_trade_history(ctx: Context<SetTradeHistory>, _sub_account_id: u16, enabled: bool) -> Result<()> {
+        ctx.accounts.margin_account.stats.history_enabled = enabled;
+        Ok(())
+    }
+
+    pub fn open_trade_history_page(ctx: Context<OpenTradeHistoryPage>, _sub_account_id: u16) -> Result<()> {
+        let stats = &ctx.accounts.margin_account.stats;
+        let page = &mut ctx.accounts.trade_history;
+        page.margin_account = ctx.accounts.margin_account.key();
+        page.page_index = TradeHistoryPage::page_for(stats.history_head);
+        page.records = Vec::new();
+        page.bump = ctx.bumps["trade_history"];
+        Ok(())
+    }
+
+    pub fn set_leverage_ramp(
+        ctx: Context<MarketAdmin>,
+        basis: RampBasis,
+        start_leverage: u8,
+        step_leverage: u8,
+        step_interval: u64,
+    ) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        require!(
+            step_interval == 0 || (start_leverage > 0 && start_leverage <= market.max_leverage && step_leverage > 0),
+            ErrorCode::ParameterOutOfBounds
+        );
+        market.leverage_ramp = LeverageRamp {
+            basis,
+            start_leverage,
+            step_leverage,
+            step_interval,
+        };
+        Ok(())
+    }
+
+    pub fn set_position_max_age(ctx: Context<MarketAdmin>, max_position_age: i64, expiry_fee_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(max_position_age >= 0, ErrorCode::ParameterOutOfBounds);
+        require!(expiry_fee_bps <= MAX_FEE_BPS, ErrorCode::ParameterOutOfBounds);
+        let market = &mut ctx.accounts.market;
+        market.max_position_age = max_position_age;
+        market.expiry_fee_bps = expiry_fee_bps;
+        Ok(())
+    }
+
+    /// Lets the owner put an earlier expiry on their own position. Expiry can
+    /// only be brought forward, never pushed past the market's limit.
+    pub fn set_position_expiry(
+        ctx: Context<SetPositionExpiry>,
+        expires_at: i64,
+        _sub_account_id: u16,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let position = &mut ctx.accounts.position;
+        require!(
+            expires_at > now && (position.expires_at == 0 || expires_at < position.expires_at),
+            ErrorCode::InvalidExpiry
+        );
+        position.expires_at = expires_at;
+        Ok(())
+    }
+
+    pub fn expire_position(ctx: Context<ExpirePosition>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        let price_feed = market.load_price_feed(&ctx.accounts.price_feed)?;
+        let oracle_price = price_feed.get_adjusted_price()?;
+
+        let position = &mut ctx.accounts.position;
+        require!(position.expires_at != 0 && now >= position.expires_at, ErrorCode::PositionNotExpired);
+        let current_price = market.exit_price(position.side, position.base_size, oracle_price)?;
+
+        // Bring mining rewards and funding up to date before open interest
+        // changes. Unclaimed rewards of an expired position are forfeited.
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        let refund = incident_refund(
+            market,
+            ctx.accounts.incident_registry.as_ref(),
+            ctx.accounts.funding_history.as_ref(),
+            position,
+            closed_size,
+        )?;
+        market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+        sync_coverage_open_interest(market, ctx.accounts.insurance_coverage.as_mut())?;
+
+        // Close at the exit price; an underwater position returns nothing
+        let pnl = market.position_pnl(position, current_price)?
+            .checked_add(refund.unwrap_or(0))
             .ok_or(ErrorCode::MathOverflow)?;
-            
-        let margin_ratio = (self.margin as u128)
-            .checked_mul(10000)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_div(position_value)
+        market.last_settled_price = current_price;
+        market.last_oracle_price = oracle_price;
+        require_margin_covers_loss(position, position.margin, pnl)?;
+        let equity = if pnl > 0 {
+            position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?
+        } else {
+            position.margin.saturating_sub(pnl.unsigned_abs())
+        };
+        let notional = (position.base_size as u128)
+            .checked_mul(current_price as u128)
             .ok_or(ErrorCode::MathOverflow)?;
-            
-        Ok(margin_ratio as u16)
+        // Positions opened during an oracle incident close fee-free
+        let keeper_fee = if refund.is_some() {
+            0
+        } else {
+            market.keeper_reward((notional * market.expiry_fee_bps as u128 / 10000) as u64).min(equity)
+        };
+        let owner_amount = equity - keeper_fee;
+        position.record_exit(closed_size, current_price, pnl);
+        market.realize_pnl(pnl);
+        emit!(PositionClosed {
+            record: position.record(position.key(), now),
+            reason: CloseReason::Expiry,
+            closed_size,
+            remaining_size: 0,
+            exit_price: current_price,
+        });
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Close,
+            position.record(position.key(), now),
+            closed_size,
+            current_price,
+        )?;
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.keeper.to_account_info(),
+        )?;
+
+        // Keepers are not paid in virtual balance; their fee stays with the market
+        if ctx.accounts.market.paper_trading {
+            return ctx.accounts.market.pay_paper(&mut ctx.accounts.margin_account, owner_amount);
+        }
+
+        let market_key = ctx.accounts.market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        for (destination, amount) in [
+            (&ctx.accounts.keeper_token_account, keeper_fee),
+            (&ctx.accounts.owner_token_account, owner_amount),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: destination.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                amount,
+            )?;
+        }
+
+        check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())
+    }
+
+    /// Sets the primary and fallback oracles, each with the network it
+    /// belongs to, and the primary's grace period before failing over.
+    pub fn set_oracle_failover(
+        ctx: Context<MarketAdmin>,
+        oracle: Pubkey,
+        oracle_source: OracleSource,
+        fallback_oracle: Pubkey,
+        fallback_oracle_source: OracleSource,
+        grace_period: i64,
+    ) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(grace_period > 0 && oracle != fallback_oracle, ErrorCode::ParameterOutOfBounds);
+        let market = &mut ctx.accounts.market;
+        // Once set, the primary only changes through the `set_oracle` timelock
+        require!(
+            market.oracle == Pubkey::default() || (oracle == market.oracle && oracle_source == market.oracle_source),
+            ErrorCode::OracleRotationRequired
+        );
+        market.oracle = oracle;
+        market.oracle_source = oracle_source;
+        market.fallback_oracle = fallback_oracle;
+        market.fallback_oracle_source = fallback_oracle_source;
+        market.oracle_grace_period = grace_period;
+        Ok(())
+    }
+
+    /// Permissionless crank that fails over to the fallback oracle once the
+    /// primary has not updated for the grace period, and back once it has.
+    pub fn update_oracle_status(ctx: Context<UpdateOracleStatus>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        let last_update = PriceFeed::last_publish_time(market.oracle_source, &ctx.accounts.price_feed, now)?;
+        let primary_stale = now.saturating_sub(last_update) > market.oracle_grace_period;
+
+        if primary_stale && !market.oracle_failover_active() {
+            // Only fail over to a fallback that is itself live
+            market.load_price_feed(&ctx.accounts.fallback_price_feed)?.get_index_price()?;
+            market.reduce_only_flags |= REDUCE_ONLY_ORACLE_FAILOVER;
+            emit!(OracleFailover {
+                market: market.key(),
+                primary_last_update: last_update,
+                active: true,
+                timestamp: now,
+            });
+        } else if !primary_stale && market.oracle_failover_active() {
+            market.reduce_only_flags &= !REDUCE_ONLY_ORACLE_FAILOVER;
+            emit!(OracleFailover {
+                market: market.key(),
+                primary_last_update: last_update,
+                active: false,
+                timestamp: now,
+            });
+        }
+        Ok(())
     }
 
-    pub fn can_be_liquidated(&self, current_price: u64, maintenance_margin_ratio: u16) -> Result<bool> {
-        let health_ratio = self.get_health_ratio(current_price)?;
-        Ok(health_ratio < maintenance_margin_ratio)
-    }
+    pub fn initialize_protocol_config(ctx: Context<InitializeProtocolConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.protocol_config;
+        config.admin = ctx.accounts.admin.key();
+        config.withdrawals_only = false;
+        config.withdrawals_only_since = 0;
+        config.collateral_vault = Pubkey::default();
+        config.bump = ctx.bumps["protocol_config"];
+        config.market_templates = Default::default();
+        config.recovery = AuthorityRecovery {
+            last_activity: Clock::get()?.unix_timestamp,
+            ..AuthorityRecovery::default()
+        };
+        config.hook_programs = [Pubkey::default(); MAX_HOOK_PROGRAMS];
+        config.order_rate_limits = OrderRateLimits::default();
+        config.market_makers = [Pubkey::default(); MAX_MARKET_MAKERS];
+        config.treasury = Pubkey::default();
+        config.fee_split = FeeSplit::default();
+        config.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        config.referral_vault = Pubkey::default();
+        config.referral_fee_share_bps = 0;
+        Ok(())
+    }
+
+    pub fn set_withdrawals_only(ctx: Context<ProtocolAdmin>, enabled: bool) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let config = &mut ctx.accounts.protocol_config;
+        config.withdrawals_only = enabled;
+        config.withdrawals_only_since = if enabled { Clock::get()?.unix_timestamp } else { 0 };
+        Ok(())
+    }
+
+    /// Closes one of the caller's positions at the market's last oracle
+    /// price and returns its remaining equity. Order book fills do not move
+    /// that price, so a self-cross cannot choose the exit. Only available while the
+    /// protocol is in withdrawals-only mode, and charges no fee.
+    pub fn emergency_withdraw(ctx: Context<EmergencyWithdraw>, _sub_account_id: u16) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let market = &mut ctx.accounts.market;
+        let position = &mut ctx.accounts.position;
+        let now = Clock::get()?.unix_timestamp;
+
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+
+        // A market that never traded has no oracle price; return the margin as is
+        let exit_price = market.last_oracle_price;
+        let pnl = if exit_price > 0 {
+            market.position_pnl(position, exit_price)?
+        } else {
+            0
+        };
+        let equity = if pnl > 0 {
+            position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?
+        } else {
+            position.margin.saturating_sub(pnl.unsigned_abs())
+        };
+        let amount = equity.min(market.vault_balance(ctx.accounts.market_vault.amount));
+        position.record_exit(closed_size, exit_price, pnl);
+        market.realize_pnl(pnl);
+        emit!(PositionClosed {
+            record: position.record(position.key(), now),
+            reason: CloseReason::EmergencyWithdraw,
+            closed_size,
+            remaining_size: 0,
+            exit_price,
+        });
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.owner.to_account_info(),
+        )?;
+
+        if amount > 0 {
+            let market_key = ctx.accounts.market.key();
+            let seeds = &[
+                b"vault_authority".as_ref(),
+                market_key.as_ref(),
+                &[ctx.bumps["vault_authority"]],
+            ];
+            let signer = &[&seeds[..]];
+            let transfer = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.market_vault.to_account_info(),
+                    to: ctx.accounts.owner_token_account.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                signer,
+            );
+            pay_trader(
+                &mut ctx.accounts.market,
+                &mut ctx.accounts.margin_account,
+                transfer,
+                amount,
+            )?;
+        }
+
+        ctx.accounts.margin_account.unlock();
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+        Ok(())
+    }
+
+    pub fn initialize_funding_history(ctx: Context<InitializeFundingHistory>) -> Result<()> {
+        let history = &mut ctx.accounts.funding_history;
+        history.market = ctx.accounts.market.key();
+        history.head = 0;
+        history.checkpoints = vec![FundingCheckpoint {
+            timestamp: Clock::get()?.unix_timestamp,
+            funding_rate: 0,
+            cumulative_index: ctx.accounts.market.cumulative_funding_long,
+        }];
+        history.bump = ctx.bumps["funding_history"];
+        Ok(())
+    }
+
+    /// Change in the cumulative funding index between two times, from the
+    /// retained checkpoints. Multiply by a position's notional / 10000 to get
+    /// the funding it paid (long) or received (short) over the window.
+    pub fn view_funding_between(ctx: Context<ViewFundingHistory>, start_time: i64, end_time: i64) -> Result<i64> {
+        require!(start_time <= end_time, ErrorCode::ParameterOutOfBounds);
+        let history = &ctx.accounts.funding_history;
+        let delta = history.index_at(end_time)?
+            .checked_sub(history.index_at(start_time)?)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(delta)
+    }
+
+    pub fn set_fee_curve(
+        ctx: Context<MarketAdmin>,
+        basis: FeeCurveBasis,
+        threshold_bps: u16,
+        slope_bps: u16,
+        max_surcharge_bps: u16,
+    ) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(max_surcharge_bps <= MAX_FEE_BPS, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.fee_curve = FeeCurve {
+            basis,
+            threshold_bps,
+            slope_bps,
+            max_surcharge_bps,
+        };
+        Ok(())
+    }
+
+    pub fn initialize_insurance_fund(ctx: Context<InitializeInsuranceFund>) -> Result<()> {
+        let fund = &mut ctx.accounts.insurance_fund;
+        fund.market = ctx.accounts.market.key();
+        fund.vault = ctx.accounts.insurance_vault.key();
+        fund.total_deposits = 0;
+        fund.total_bad_debt = 0;
+        fund.covered_by_fund = 0;
+        fund.covered_by_backstop = 0;
+        fund.uncovered_bad_debt = 0;
+        fund.total_withdrawn = 0;
+        fund.bump = ctx.bumps["insurance_fund"];
+        Ok(())
+    }
+
+    /// Creates the market's insurance coverage account. From then on every
+    /// instruction that moves its open interest or its insurance fund has
+    /// to pass it.
+    pub fn initialize_insurance_coverage(ctx: Context<InitializeInsuranceCoverage>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.market.recovery.record_activity(now);
+        let market = &mut ctx.accounts.market;
+        let coverage = &mut ctx.accounts.insurance_coverage;
+        coverage.market = market.key();
+        coverage.bump = ctx.bumps["insurance_coverage"];
+        coverage.record_insurance(ctx.accounts.insurance_fund.balance(), now);
+        coverage.record_open_interest(market.open_interest_notional(), now);
+        market.has_insurance_coverage = true;
+        Ok(())
+    }
+
+    pub fn deposit_insurance(ctx: Context<DepositInsurance>, amount: u64) -> Result<()> {
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.depositor_token_account.to_account_info(),
+                    to: ctx.accounts.insurance_vault.to_account_info(),
+                    authority: ctx.accounts.depositor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        let fund = &mut ctx.accounts.insurance_fund;
+        fund.total_deposits = fund.total_deposits.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        sync_coverage_insurance(&ctx.accounts.market, &ctx.accounts.insurance_fund, ctx.accounts.insurance_coverage.as_mut())?;
+        Ok(())
+    }
+
+    pub fn initialize_insurance_backstop(ctx: Context<InitializeInsuranceBackstop>) -> Result<()> {
+        let backstop = &mut ctx.accounts.insurance_backstop;
+        backstop.vault = ctx.accounts.backstop_vault.key();
+        backstop.total_deposits = 0;
+        backstop.total_covered = 0;
+        backstop.bump = ctx.bumps["insurance_backstop"];
+        Ok(())
+    }
+
+    pub fn deposit_backstop(ctx: Context<DepositBackstop>, amount: u64) -> Result<()> {
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.depositor_token_account.to_account_info(),
+                    to: ctx.accounts.backstop_vault.to_account_info(),
+                    authority: ctx.accounts.depositor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        let backstop = &mut ctx.accounts.insurance_backstop;
+        backstop.total_deposits = backstop.total_deposits.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Creates a market's LP vault. Trader PnL realized before this point
+    /// stays with the market vault; the LP vault settles what comes after.
+    pub fn initialize_lp_vault(ctx: Context<InitializeLpVault>) -> Result<()> {
+        require!(ctx.accounts.share_mint.supply == 0, ErrorCode::InvalidMarketState);
+        require!(!ctx.accounts.market.paper_trading, ErrorCode::PaperTradingMarket);
+        let market = &mut ctx.accounts.market;
+        market.lp_pnl_checkpoint = market.trader_realized_pnl;
+        market.lp_settled_pnl = 0;
+        market.lp_vault_equity = 0;
+        market.has_lp_vault = true;
+
+        let lp_vault = &mut ctx.accounts.lp_vault;
+        lp_vault.market = ctx.accounts.market.key();
+        lp_vault.vault = ctx.accounts.vault.key();
+        lp_vault.share_mint = ctx.accounts.share_mint.key();
+        lp_vault.bump = ctx.bumps["lp_vault"];
+
+        let share_price = &mut ctx.accounts.share_price;
+        share_price.lp_vault = lp_vault.key();
+        share_price.bump = ctx.bumps["share_price"];
+        share_price.update(0, 0, Clock::get()?.unix_timestamp)?;
+        Ok(())
+    }
+
+    pub fn deposit_liquidity(ctx: Context<DepositLiquidity>, amount: u64) -> Result<()> {
+        let (equity, price) = lp_vault_equity(&ctx.accounts.market, &ctx.accounts.price_feed, ctx.accounts.vault.amount)?;
+        let shares = LpVault::shares_for_deposit(amount, equity, ctx.accounts.share_mint.supply)?;
+        require!(shares > 0, ErrorCode::OrderTooSmall);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.provider_token_account.to_account_info(),
+                    to: ctx.accounts.vault.to_account_info(),
+                    authority: ctx.accounts.provider.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let market_key = ctx.accounts.lp_vault.market;
+        let seeds = &[b"lp_vault".as_ref(), market_key.as_ref(), &[ctx.accounts.lp_vault.bump]];
+        token::mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::MintTo {
+                    mint: ctx.accounts.share_mint.to_account_info(),
+                    to: ctx.accounts.provider_share_account.to_account_info(),
+                    authority: ctx.accounts.lp_vault.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            shares,
+        )?;
+
+        ctx.accounts.vault.reload()?;
+        ctx.accounts.share_mint.reload()?;
+        refresh_lp_share_price(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.share_price,
+            ctx.accounts.vault.amount,
+            ctx.accounts.share_mint.supply,
+            price,
+        )
+    }
+
+    /// Burns `shares` for their slice of the LP vault's equity. Fails while
+    /// the vault's tokens cannot cover it, or if what is left would put open
+    /// interest over the utilization cap.
+    pub fn withdraw_liquidity(ctx: Context<WithdrawLiquidity>, shares: u64) -> Result<()> {
+        let market = &ctx.accounts.market;
+        let (equity, price) = lp_vault_equity(market, &ctx.accounts.price_feed, ctx.accounts.vault.amount)?;
+        let amount = LpVault::amount_for_shares(shares, equity, ctx.accounts.share_mint.supply)?;
+        require!(amount <= ctx.accounts.vault.amount, ErrorCode::InsufficientVaultBalance);
+        let open_interest = market.long_open_interest.max(market.short_open_interest);
+        market.check_lp_utilization(equity - amount, open_interest, price)?;
+
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Burn {
+                    mint: ctx.accounts.share_mint.to_account_info(),
+                    from: ctx.accounts.provider_share_account.to_account_info(),
+                    authority: ctx.accounts.provider.to_account_info(),
+                },
+            ),
+            shares,
+        )?;
+
+        let market_key = ctx.accounts.lp_vault.market;
+        let seeds = &[b"lp_vault".as_ref(), market_key.as_ref(), &[ctx.accounts.lp_vault.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.vault.to_account_info(),
+                    to: ctx.accounts.provider_token_account.to_account_info(),
+                    authority: ctx.accounts.lp_vault.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+
+        ctx.accounts.vault.reload()?;
+        ctx.accounts.share_mint.reload()?;
+        refresh_lp_share_price(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.share_price,
+            ctx.accounts.vault.amount,
+            ctx.accounts.share_mint.supply,
+            price,
+        )
+    }
+
+    /// Permissionless refresh of the share price and the equity the market's
+    /// utilization cap is checked against, e.g. as the oracle price moves or
+    /// after the vault receives tokens outside of deposits and withdrawals.
+    pub fn settle_lp_share_price(ctx: Context<SettleLpSharePrice>) -> Result<()> {
+        let price = ctx.accounts.market.load_price_feed(&ctx.accounts.price_feed)?.get_adjusted_price()?;
+        refresh_lp_share_price(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.share_price,
+            ctx.accounts.vault.amount,
+            ctx.accounts.share_mint.supply,
+            price,
+        )
+    }
+
+    /// Permissionless crank that settles trader PnL realized since the last
+    /// settlement between the LP vault and the market vault. Net trader gains
+    /// are paid in from the LP vault, up to its balance, and net trader
+    /// losses are paid out to it.
+    pub fn settle_lp_pnl(ctx: Context<SettleLpPnl>) -> Result<()> {
+        require!(!ctx.accounts.market.paper_trading, ErrorCode::PaperTradingMarket);
+        let unsettled = ctx.accounts.market.lp_unsettled_pnl();
+        let market_key = ctx.accounts.market.key();
+        let settled = if unsettled > 0 {
+            let amount = unsettled.unsigned_abs().min(ctx.accounts.vault.amount);
+            let seeds = &[b"lp_vault".as_ref(), market_key.as_ref(), &[ctx.accounts.lp_vault.bump]];
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.vault.to_account_info(),
+                        to: ctx.accounts.market_vault.to_account_info(),
+                        authority: ctx.accounts.lp_vault.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                amount,
+            )?;
+            amount as i64
+        } else {
+            let amount = unsettled.unsigned_abs().min(ctx.accounts.market_vault.amount);
+            let seeds = &[
+                b"vault_authority".as_ref(),
+                market_key.as_ref(),
+                &[ctx.bumps["vault_authority"]],
+            ];
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.vault.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                amount,
+            )?;
+            -(amount as i64)
+        };
+
+        let market = &mut ctx.accounts.market;
+        market.lp_pnl_checkpoint = market.lp_pnl_checkpoint.saturating_add(settled);
+        market.lp_settled_pnl = market.lp_settled_pnl.saturating_add(settled);
+        emit!(LpPnlSettled {
+            market: market_key,
+            amount: settled,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        let price = market.load_price_feed(&ctx.accounts.price_feed)?.get_adjusted_price()?;
+        ctx.accounts.vault.reload()?;
+        refresh_lp_share_price(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.share_price,
+            ctx.accounts.vault.amount,
+            ctx.accounts.share_mint.supply,
+            price,
+        )?;
+        check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())
+    }
+
+    /// Sets the vAMM's virtual base reserve at balanced open interest; 0
+    /// turns the vAMM off. Deeper pools move the mark less per unit of
+    /// taker flow. The depth must exceed the current net open interest.
+    pub fn set_vamm_depth(ctx: Context<MarketAdmin>, base_depth: u64) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        require!(
+            base_depth == 0 || market.net_open_interest().unsigned_abs() < base_depth,
+            ErrorCode::ParameterOutOfBounds
+        );
+        require!(base_depth <= i64::MAX as u64, ErrorCode::ParameterOutOfBounds);
+        market.vamm.base_depth = base_depth;
+        Ok(())
+    }
+
+    /// Caps each side's open interest, valued at the oracle price, at
+    /// `max_utilization_bps` of the LP vault's equity. Market orders past the
+    /// cap fail, as do LP withdrawals that would leave the vault over it.
+    /// Zero removes the cap.
+    pub fn set_lp_utilization_cap(ctx: Context<MarketAdmin>, max_utilization_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(max_utilization_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.lp_max_utilization_bps = max_utilization_bps;
+        Ok(())
+    }
+
+    pub fn register_integrator(
+        ctx: Context<RegisterIntegrator>,
+        key: Pubkey,
+        fee_share_bps: u16,
+        max_orders_per_window: u32,
+    ) -> Result<()> {
+        require!(fee_share_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        let integrator = &mut ctx.accounts.integrator;
+        integrator.key = key;
+        integrator.fee_destination = ctx.accounts.fee_destination.key();
+        integrator.fee_share_bps = fee_share_bps;
+        integrator.max_orders_per_window = max_orders_per_window;
+        integrator.window_start = 0;
+        integrator.orders_in_window = 0;
+        integrator.total_volume = 0;
+        integrator.total_fees_earned = 0;
+        integrator.active = true;
+        integrator.bump = ctx.bumps["integrator"];
+        Ok(())
+    }
+
+    /// Adjusts an integrator's terms, or revokes it with `active = false`.
+    pub fn update_integrator(
+        ctx: Context<UpdateIntegrator>,
+        fee_share_bps: u16,
+        max_orders_per_window: u32,
+        active: bool,
+    ) -> Result<()> {
+        require!(fee_share_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        let integrator = &mut ctx.accounts.integrator;
+        integrator.fee_share_bps = fee_share_bps;
+        integrator.max_orders_per_window = max_orders_per_window;
+        integrator.active = active;
+        Ok(())
+    }
+
+    pub fn set_collateral_vault(ctx: Context<SetCollateralVault>) -> Result<()> {
+        ctx.accounts.protocol_config.collateral_vault = ctx.accounts.collateral_vault.key();
+        Ok(())
+    }
+
+    /// Sets the taker fee discounts by 30-day volume, replacing the current
+    /// tiers. Tiers must rise in both `min_volume` and discount.
+    pub fn set_fee_tiers(ctx: Context<ProtocolAdmin>, tiers: Vec<FeeTier>) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(tiers.len() <= MAX_FEE_TIERS, ErrorCode::ParameterOutOfBounds);
+        require!(tiers.iter().all(|tier| tier.discount_bps <= 10000), ErrorCode::ParameterOutOfBounds);
+        require!(
+            tiers.windows(2).all(|pair| pair[0].min_volume < pair[1].min_volume && pair[0].discount_bps < pair[1].discount_bps),
+            ErrorCode::ParameterOutOfBounds
+        );
+        let config = &mut ctx.accounts.protocol_config;
+        config.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        config.fee_tiers[..tiers.len()].copy_from_slice(&tiers);
+        Ok(())
+    }
+
+    /// Creates the wallet's volume stats. Market orders that pass it count
+    /// towards, and are discounted by, the wallet's fee tier.
+    pub fn initialize_trader_stats(ctx: Context<InitializeTraderStats>) -> Result<()> {
+        let stats = &mut ctx.accounts.trader_stats;
+        stats.authority = ctx.accounts.authority.key();
+        stats.daily_volume = [0; VOLUME_DAYS];
+        stats.last_day = 0;
+        stats.bump = ctx.bumps["trader_stats"];
+        Ok(())
+    }
+
+    /// Sets the referral vault, which holds referral rewards until they are
+    /// claimed, and the share of a referred order's fee paid to the code.
+    /// A share of 0 turns referral rewards off.
+    pub fn configure_referrals(ctx: Context<ConfigureReferrals>, fee_share_bps: u16) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(fee_share_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        let config = &mut ctx.accounts.protocol_config;
+        config.referral_vault = ctx.accounts.referral_vault.key();
+        config.referral_fee_share_bps = fee_share_bps;
+        Ok(())
+    }
+
+    /// Registers `code` for the signer. Codes are first come, first served.
+    pub fn create_referral_code(ctx: Context<CreateReferralCode>, code: [u8; REFERRAL_CODE_LEN]) -> Result<()> {
+        require!(code != [0; REFERRAL_CODE_LEN], ErrorCode::ParameterOutOfBounds);
+        let referral_code = &mut ctx.accounts.referral_code;
+        referral_code.owner = ctx.accounts.owner.key();
+        referral_code.code = code;
+        referral_code.referred_volume = 0;
+        referral_code.unclaimed_rewards = 0;
+        referral_code.total_rewards = 0;
+        referral_code.bump = ctx.bumps["referral_code"];
+        Ok(())
+    }
+
+    /// Pays a code's unclaimed rewards out of the referral vault to its owner.
+    pub fn claim_referral_rewards(ctx: Context<ClaimReferralRewards>) -> Result<()> {
+        let amount = ctx.accounts.referral_code.unclaimed_rewards;
+        require!(amount > 0, ErrorCode::NoReferralRewards);
+        ctx.accounts.referral_code.unclaimed_rewards = 0;
+
+        let seeds = &[b"referral_authority".as_ref(), &[ctx.bumps["referral_authority"]]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.referral_vault.to_account_info(),
+                    to: ctx.accounts.owner_token_account.to_account_info(),
+                    authority: ctx.accounts.referral_authority.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )
+    }
+
+    /// Sets the quote token account that receives protocol fees.
+    pub fn set_protocol_treasury(ctx: Context<SetProtocolTreasury>) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        ctx.accounts.protocol_config.treasury = ctx.accounts.treasury.key();
+        Ok(())
+    }
+
+    /// Sets the protocol's default fee split. Markets listed from a template
+    /// start with it; existing markets keep theirs until the admin calls
+    /// `apply_protocol_fee_split` on them.
+    pub fn set_protocol_fee_split(ctx: Context<ProtocolAdmin>, fee_split: FeeSplit) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        fee_split.validate()?;
+        ctx.accounts.protocol_config.fee_split = fee_split;
+        Ok(())
+    }
+
+    /// Moves `amount` of the market's protocol fees out of its vault to the
+    /// protocol treasury. Stakers' fees are kept apart for `roll_epoch`.
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        require!(!market.paper_trading, ErrorCode::PaperTradingMarket);
+        require!(amount > 0 && amount <= market.protocol_fees_accrued, ErrorCode::InsufficientFees);
+        market.protocol_fees_accrued -= amount;
+
+        let market_key = market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.market_vault.to_account_info(),
+                    to: ctx.accounts.treasury.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+        emit!(FeesWithdrawn {
+            market: market_key,
+            treasury: ctx.accounts.treasury.key(),
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())
+    }
+
+    /// Overwrites a market's fee split with the protocol's.
+    pub fn apply_protocol_fee_split(ctx: Context<ApplyProtocolFeeSplit>) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let fee_split = ctx.accounts.protocol_config.fee_split;
+        ctx.accounts.market.apply_fee_split(&fee_split);
+        Ok(())
+    }
+
+    /// Swaps any token into the quote token through a Jupiter route and
+    /// credits the output to a sub-account's collateral. The route's accounts
+    /// are the remaining accounts; the output is measured on the user's quote
+    /// account and must be at least `min_amount_out`, while at most
+    /// `max_amount_in` may leave the source account.
+    pub fn swap_and_deposit<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapAndDeposit<'info>>,
+        route_data: Vec<u8>,
+        max_amount_in: u64,
+        min_amount_out: u64,
+        _sub_account_id: u16,
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let source_before = ctx.accounts.source_token_account.amount;
+        let quote_before = ctx.accounts.user_quote_account.amount;
+
+        swap::invoke_jupiter_route(&ctx.accounts.jupiter_program, ctx.remaining_accounts, route_data)?;
+
+        ctx.accounts.source_token_account.reload()?;
+        ctx.accounts.user_quote_account.reload()?;
+        let amount_in = source_before.saturating_sub(ctx.accounts.source_token_account.amount);
+        let amount_out = ctx.accounts.user_quote_account.amount.saturating_sub(quote_before);
+        require!(amount_in <= max_amount_in, ErrorCode::SlippageExceeded);
+        require!(amount_out >= min_amount_out && amount_out > 0, ErrorCode::SlippageExceeded);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.user_quote_account.to_account_info(),
+                    to: ctx.accounts.collateral_vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            amount_out,
+        )?;
+
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.collateral = margin_account.collateral
+            .checked_add(amount_out)
+            .ok_or(ErrorCode::MathOverflow)?;
+        margin_account.unlock();
+        Ok(())
+    }
+
+    pub fn set_priority_lanes(
+        ctx: Context<MarketAdmin>,
+        cascade_threshold: u16,
+        priority_slots: u64,
+        min_keeper_stake: u64,
+    ) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let lanes = &mut ctx.accounts.market.priority_lanes;
+        lanes.cascade_threshold = cascade_threshold;
+        lanes.priority_slots = priority_slots;
+        lanes.min_keeper_stake = min_keeper_stake;
+        lanes.cascade_start_slot = 0;
+        lanes.window_start_slot = 0;
+        lanes.window_count = 0;
+        Ok(())
+    }
+
+    pub fn initialize_arb_vault(ctx: Context<InitializeArbVault>, operator: Pubkey, max_market_exposure_bps: u16) -> Result<()> {
+        require!(max_market_exposure_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        require!(ctx.accounts.share_mint.supply == 0, ErrorCode::InvalidMarketState);
+        let vault = &mut ctx.accounts.arb_vault;
+        vault.operator = operator;
+        vault.token_account = ctx.accounts.token_account.key();
+        vault.share_mint = ctx.accounts.share_mint.key();
+        vault.deployed_margin = 0;
+        vault.max_market_exposure_bps = max_market_exposure_bps;
+        vault.bump = ctx.bumps["arb_vault"];
+
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.authority = vault.key();
+        margin_account.sub_account_id = 0;
+        margin_account.stats = Default::default();
+        margin_account.collateral = 0;
+        margin_account.paper_balance = 0;
+        margin_account.created_at = Clock::get()?.unix_timestamp;
+        margin_account.in_use = false;
+        margin_account.next_position_id = 0;
+        margin_account.position_count = 0;
+        margin_account.bump = ctx.bumps["margin_account"];
+        margin_account.order_rate = Default::default();
+        margin_account.hedger = false;
+        Ok(())
+    }
+
+    /// Mints shares against the vault's equity at the oracle price. Every
+    /// position the vault holds is passed in the remaining accounts with its
+    /// market and price feed, as for `view_portfolio_health`.
+    pub fn deposit_arb_vault<'info>(
+        ctx: Context<'_, '_, '_, 'info, ArbVaultDeposit<'info>>,
+        amount: u64,
+    ) -> Result<()> {
+        let positions = load_valued_positions(
+            ctx.remaining_accounts,
+            &ctx.accounts.margin_account.key(),
+            &ctx.accounts.margin_account,
+        )?;
+        let equity = ctx.accounts.arb_vault.revalue(ctx.accounts.token_account.amount, &positions);
+        let shares = LpVault::shares_for_deposit(amount, equity, ctx.accounts.share_mint.supply)?;
+        require!(shares > 0, ErrorCode::OrderTooSmall);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.depositor_token_account.to_account_info(),
+                    to: ctx.accounts.token_account.to_account_info(),
+                    authority: ctx.accounts.depositor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        let seeds = &[b"funding_arb_vault".as_ref(), &[ctx.accounts.arb_vault.bump]];
+        token::mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::MintTo {
+                    mint: ctx.accounts.share_mint.to_account_info(),
+                    to: ctx.accounts.depositor_share_account.to_account_info(),
+                    authority: ctx.accounts.arb_vault.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            shares,
+        )?;
+        Ok(())
+    }
+
+    /// Redeems shares for their slice of vault equity, valued as in
+    /// `deposit_arb_vault`. Only idle funds can be paid out; the operator
+    /// closes positions to free up more.
+    pub fn withdraw_arb_vault<'info>(
+        ctx: Context<'_, '_, '_, 'info, ArbVaultDeposit<'info>>,
+        shares: u64,
+    ) -> Result<()> {
+        let positions = load_valued_positions(
+            ctx.remaining_accounts,
+            &ctx.accounts.margin_account.key(),
+            &ctx.accounts.margin_account,
+        )?;
+        let idle = ctx.accounts.token_account.amount;
+        let equity = ctx.accounts.arb_vault.revalue(idle, &positions);
+        let amount = LpVault::amount_for_shares(shares, equity, ctx.accounts.share_mint.supply)?;
+        require!(amount <= idle, ErrorCode::InsufficientCollateral);
+
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Burn {
+                    mint: ctx.accounts.share_mint.to_account_info(),
+                    from: ctx.accounts.depositor_share_account.to_account_info(),
+                    authority: ctx.accounts.depositor.to_account_info(),
+                },
+            ),
+            shares,
+        )?;
+        let seeds = &[b"funding_arb_vault".as_ref(), &[ctx.accounts.arb_vault.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.token_account.to_account_info(),
+                    to: ctx.accounts.depositor_token_account.to_account_info(),
+                    authority: ctx.accounts.arb_vault.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+        Ok(())
+    }
+
+    /// Opens a position for the vault on the side of `market` that receives
+    /// funding, within the vault's per-market exposure cap. Every position
+    /// the vault already holds is passed in the remaining accounts, laid out
+    /// as for `deposit_arb_vault`.
+    pub fn arb_vault_open<'info>(
+        ctx: Context<'_, '_, '_, 'info, ArbVaultOpen<'info>>,
+        size: u64,
+        price: u64,
+        leverage: u8,
+    ) -> Result<()> {
+        // Paper funding is virtual; the vault deploys real quote only
+        require!(!ctx.accounts.market.paper_trading, ErrorCode::PaperTradingMarket);
+        require_launch_registration(&ctx.accounts.market, None)?;
+        let side = FundingArbVault::receiving_side(&ctx.accounts.market)
+            .ok_or(ErrorCode::NoFundingToCollect)?;
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+
+        let market_key = ctx.accounts.market.key();
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let valued = load_valued_positions(
+            ctx.remaining_accounts,
+            &margin_account_key,
+            &ctx.accounts.margin_account,
+        )?;
+        let equity = ctx.accounts.arb_vault.revalue(ctx.accounts.token_account.amount, &valued);
+        let mut positions: Vec<Position> = valued.into_iter().map(|(position, _)| position).collect();
+        let position_key = ctx.accounts.position.key();
+        let position_id = ctx.accounts.margin_account.open_position()?;
+        ctx.accounts.position.set_inner(Position::new(
+            market_key,
+            margin_account_key,
+            position_id,
+            side,
+            leverage,
+            Clock::get()?.unix_timestamp,
+            ctx.bumps["position"],
+        ));
+        ctx.accounts.position.funding_exempt = ctx.accounts.margin_account.hedger;
+        let (required_margin, fee, _) = open_market_order(
+            &mut ctx.accounts.market,
+            market_key,
+            margin_account_key,
+            &mut ctx.accounts.margin_account.stats,
+            None,
+            ctx.accounts.order_book.as_deref(),
+            &ctx.accounts.price_feed,
+            ctx.accounts.market_vault.amount,
+            0,
+            &mut ctx.accounts.position,
+            position_key,
+            side,
+            size,
+            size,
+            price,
+            None,
+            leverage,
+            [0; 32],
+        )?;
+        positions.push(Position::clone(&ctx.accounts.position));
+        ctx.accounts.arb_vault.check_exposure(&market_key, &positions, equity)?;
+
+        let seeds = &[b"funding_arb_vault".as_ref(), &[ctx.accounts.arb_vault.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.token_account.to_account_info(),
+                    to: ctx.accounts.market_vault.to_account_info(),
+                    authority: ctx.accounts.arb_vault.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            required_margin.checked_add(fee).ok_or(ErrorCode::MathOverflow)?,
+        )?;
+
+        let vault = &mut ctx.accounts.arb_vault;
+        vault.deployed_margin = vault.deployed_margin
+            .checked_add(required_margin)
+            .ok_or(ErrorCode::MathOverflow)?;
+        ctx.accounts.margin_account.unlock();
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+        let position = &ctx.accounts.position;
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            &ctx.accounts.market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Open,
+            position.record(position_key, Clock::get()?.unix_timestamp),
+            position.base_size,
+            position.entry_price,
+        )?;
+        Ok(())
+    }
+
+    /// Closes one of the vault's positions at the exit price and returns
+    /// its equity to the vault's idle balance.
+    pub fn arb_vault_close(ctx: Context<ArbVaultClose>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        require!(!ctx.accounts.market.is_paused(now), ErrorCode::MarketPaused);
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let market = &mut ctx.accounts.market;
+        let oracle_price = market.load_price_feed(&ctx.accounts.price_feed)?.get_adjusted_price()?;
+
+        let position = &mut ctx.accounts.position;
+        let current_price = market.exit_price(position.side, position.base_size, oracle_price)?;
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+
+        let pnl = market.position_pnl(position, current_price)?;
+        market.last_settled_price = current_price;
+        market.last_oracle_price = oracle_price;
+        require_margin_covers_loss(position, position.margin, pnl)?;
+        let equity = if pnl > 0 {
+            position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?
+        } else {
+            position.margin.saturating_sub(pnl.unsigned_abs())
+        };
+        let position_margin = position.margin;
+        position.record_exit(closed_size, current_price, pnl);
+        market.realize_pnl(pnl);
+        emit!(PositionClosed {
+            record: position.record(position.key(), now),
+            reason: CloseReason::ArbVaultClose,
+            closed_size,
+            remaining_size: 0,
+            exit_price: current_price,
+        });
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Close,
+            position.record(position.key(), now),
+            closed_size,
+            current_price,
+        )?;
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.operator.to_account_info(),
+        )?;
+
+        if equity > 0 {
+            let market_key = ctx.accounts.market.key();
+            let seeds = &[
+                b"vault_authority".as_ref(),
+                market_key.as_ref(),
+                &[ctx.bumps["vault_authority"]],
+            ];
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.token_account.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                equity,
+            )?;
+            check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())?;
+        }
+
+        let vault = &mut ctx.accounts.arb_vault;
+        vault.deployed_margin = vault.deployed_margin.saturating_sub(position_margin);
+        ctx.accounts.margin_account.unlock();
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+        Ok(())
+    }
+
+    /// Sets the fixed-point precision every oracle price is normalized to.
+    /// Entry and liquidation prices are stored in this precision, so it can
+    /// only change while the market has no open positions.
+    pub fn set_price_precision(ctx: Context<MarketAdmin>, decimals: u8, rounding: PriceRounding) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(decimals <= MAX_PRICE_DECIMALS, ErrorCode::ParameterOutOfBounds);
+        let market = &mut ctx.accounts.market;
+        require!(
+            market.open_interest(Side::Long) == 0 && market.open_interest(Side::Short) == 0,
+            ErrorCode::InvalidMarketState
+        );
+        market.price_decimals = decimals;
+        market.price_rounding = rounding;
+        Ok(())
+    }
+
+    /// Caps the notional the market may trade over a rolling `window` of
+    /// seconds. A zero window removes the cap.
+    pub fn set_notional_cap(ctx: Context<MarketAdmin>, window: i64, max_notional: u64) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(window >= 0, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.notional_cap = NotionalCap {
+            window,
+            max_notional,
+            window_start: Clock::get()?.unix_timestamp,
+            current: 0,
+            previous: 0,
+        };
+        Ok(())
+    }
+
+    /// Summarizes a margin account's positions and health in one market at
+    /// the current oracle price, for lending protocols and other third parties.
+    /// Every position account of the margin account, in any market, is passed
+    /// in the remaining accounts so that none can be left out.
+    pub fn attest_positions<'info>(
+        ctx: Context<'_, '_, '_, 'info, AttestPositions<'info>>,
+    ) -> Result<PositionAttestation> {
+        let market = &ctx.accounts.market;
+        // Third parties rely on the price, so it must come from the market's oracle
+        let oracle_price = market.load_price_feed(&ctx.accounts.price_feed)?.get_index_price()?;
+        let positions = load_all_positions(
+            ctx.remaining_accounts,
+            &ctx.accounts.margin_account.key(),
+            ctx.accounts.margin_account.position_count,
+        )?;
+        PositionAttestation::build(
+            market,
+            market.key(),
+            ctx.accounts.margin_account.key(),
+            ctx.accounts.margin_account.authority,
+            &positions,
+            oracle_price,
+            &Clock::get()?,
+        )
+    }
+
+    pub fn set_market_template(
+        ctx: Context<ProtocolAdmin>,
+        preset: MarketPreset,
+        template: MarketTemplate,
+    ) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        template.validate()?;
+        ctx.accounts.protocol_config.market_templates[preset as usize] = MarketTemplate {
+            configured: true,
+            ..template
+        };
+        Ok(())
+    }
+
+    pub fn set_min_coverage(ctx: Context<MarketAdmin>, min_coverage_bps: u64) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        market.min_coverage_bps = min_coverage_bps;
+        if min_coverage_bps == 0 {
+            market.reduce_only_flags &= !REDUCE_ONLY_LOW_COVERAGE;
+        }
+        Ok(())
+    }
+
+    /// Permissionless crank that puts the market in ReduceOnly once the
+    /// vault plus insurance no longer covers net trader PnL by the market's
+    /// minimum ratio, and lifts it once coverage has recovered.
+    pub fn update_coverage_status(ctx: Context<UpdateCoverageStatus>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        if market.min_coverage_bps == 0 {
+            return Ok(());
+        }
+        let current_price = market.load_price_feed(&ctx.accounts.price_feed)?.get_index_price()?;
+
+        let insurance = match (&ctx.accounts.insurance_fund, &ctx.accounts.insurance_vault) {
+            (Some(fund), Some(vault)) => {
+                require_keys_eq!(vault.key(), fund.vault, ErrorCode::InsuranceAccountsRequired);
+                vault.amount
+            }
+            _ => 0,
+        };
+        let resources = market.vault_balance(ctx.accounts.market_vault.amount).saturating_add(insurance);
+        let net_pnl = market.net_trader_pnl(current_price)?;
+        let coverage_bps = if net_pnl > 0 {
+            (resources as u128 * 10000 / net_pnl as u128).min(u64::MAX as u128) as u64
+        } else {
+            u64::MAX
+        };
+
+        let now = Clock::get()?.unix_timestamp;
+        let active = market.reduce_only_flags & REDUCE_ONLY_LOW_COVERAGE != 0;
+        if !active && coverage_bps < market.min_coverage_bps {
+            market.reduce_only_flags |= REDUCE_ONLY_LOW_COVERAGE;
+            emit!(CoverageReduceOnly { market: market.key(), coverage_bps, active: true, timestamp: now });
+        } else if active && coverage_bps >= market.min_coverage_bps.saturating_add(COVERAGE_HYSTERESIS_BPS) {
+            market.reduce_only_flags &= !REDUCE_ONLY_LOW_COVERAGE;
+            emit!(CoverageReduceOnly { market: market.key(), coverage_bps, active: false, timestamp: now });
+        }
+        Ok(())
+    }
+
+    /// Closes `size_to_close` of a position at the exit price. PnL and
+    /// margin are settled in proportion to the closed size and paid out; the
+    /// rest stays open. Closing the whole size removes the position, and its
+    /// unclaimed mining rewards are forfeited.
+    pub fn reduce_position(
+        ctx: Context<ReducePosition>,
+        size_to_close: u64,
+        _sub_account_id: u16,
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        let oracle_price = market.load_price_feed(&ctx.accounts.price_feed)?.get_adjusted_price()?;
+
+        let position = &mut ctx.accounts.position;
+        require!(size_to_close > 0 && size_to_close <= position.base_size, ErrorCode::InvalidReduceSize);
+        let closes_all = size_to_close == position.base_size;
+        let current_price = market.exit_price(position.side, size_to_close, oracle_price)?;
+
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        market.charge_deferred_funding(position, size_to_close);
+        let incident = incident_refund(
+            market,
+            ctx.accounts.incident_registry.as_ref(),
+            ctx.accounts.funding_history.as_ref(),
+            position,
+            size_to_close,
+        )?;
+        let refund = incident.unwrap_or(0);
+        let side = position.side;
+        let pnl = market.position_pnl(position, current_price)?;
+        market.last_settled_price = current_price;
+        market.last_oracle_price = oracle_price;
+
+        let (closed_margin, closed_pnl) = if closes_all {
+            market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+            let pnl = pnl.checked_add(refund).ok_or(ErrorCode::MathOverflow)?;
+            position.record_exit(size_to_close, current_price, pnl);
+            (position.margin, pnl)
+        } else {
+            // Keep rewards earned so far on the part that stays open
+            market.mining.settle_position(position)?;
+            let margin = (position.margin as u128 * size_to_close as u128 / position.base_size as u128) as u64;
+            let pnl = ((pnl as i128 * size_to_close as i128 / position.base_size as i128) as i64)
+                .checked_add(refund)
+                .ok_or(ErrorCode::MathOverflow)?;
+            let notional = position.notional;
+            position.base_size -= size_to_close;
+            position.margin -= margin;
+            position.notional = position.base_size.saturating_mul(position.entry_price);
+            position.refresh_liquidation_price(market.liquidation_threshold);
+            market.remove_open_interest(position.side, size_to_close, notional - position.notional, margin);
+            position.record_exit(size_to_close, current_price, pnl);
+            (margin, pnl)
+        };
+        require_margin_covers_loss(position, closed_margin, closed_pnl)?;
+        market.realize_pnl(closed_pnl);
+        emit!(PositionClosed {
+            record: position.record(position.key(), now),
+            reason: CloseReason::Reduce,
+            closed_size: size_to_close,
+            remaining_size: if closes_all { 0 } else { position.base_size },
+            exit_price: current_price,
+        });
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Close,
+            position.record(position.key(), now),
+            size_to_close,
+            current_price,
+        )?;
+        if closes_all {
+            close_position_account(
+                &mut ctx.accounts.position,
+                &mut ctx.accounts.margin_account,
+                ctx.accounts.owner.to_account_info(),
+            )?;
+        }
+
+        let equity = if closed_pnl > 0 {
+            closed_margin.checked_add(closed_pnl as u64).ok_or(ErrorCode::MathOverflow)?
+        } else {
+            closed_margin.saturating_sub(closed_pnl.unsigned_abs())
+        };
+        // Positions opened during an oracle incident close fee-free
+        let fee = if incident.is_some() {
+            0
+        } else {
+            ctx.accounts.market.close_fee(size_to_close.saturating_mul(current_price), now).min(equity)
+        };
+        ctx.accounts.market.accrue_fee(FeeKind::Close, side, FeeRole::Taker, fee, now)?;
+        let equity = equity - fee;
+
+        if equity > 0 {
+            let market_key = ctx.accounts.market.key();
+            let seeds = &[
+                b"vault_authority".as_ref(),
+                market_key.as_ref(),
+                &[ctx.bumps["vault_authority"]],
+            ];
+            let signer = &[&seeds[..]];
+            let transfer = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.market_vault.to_account_info(),
+                    to: ctx.accounts.owner_token_account.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                signer,
+            );
+            pay_trader(
+                &mut ctx.accounts.market,
+                &mut ctx.accounts.margin_account,
+                transfer,
+                equity,
+            )?;
+        }
+
+        ctx.accounts.margin_account.unlock();
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+        Ok(())
+    }
+
+    /// Switches a market between real and paper trading. Only allowed while
+    /// the market holds nothing, real or virtual: no open interest, no
+    /// escrowed orders and an empty vault.
+    pub fn set_paper_trading(ctx: Context<SetPaperTrading>, enabled: bool) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        require!(
+            market.long_open_interest == 0
+                && market.short_open_interest == 0
+                && market.paper_vault == 0
+                && ctx.accounts.market_vault.amount == 0,
+            ErrorCode::InvalidMarketState
+        );
+        market.paper_trading = enabled;
+        Ok(())
+    }
+
+    /// Credits virtual quote to one of the caller's sub-accounts for use on
+    /// paper-trading markets.
+    pub fn paper_faucet(ctx: Context<PaperFaucet>, amount: u64, _sub_account_id: u16) -> Result<()> {
+        let margin_account = &mut ctx.accounts.margin_account;
+        let balance = margin_account.paper_balance.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        require!(balance <= MAX_PAPER_BALANCE, ErrorCode::PaperBalanceLimit);
+        margin_account.paper_balance = balance;
+        Ok(())
+    }
+
+    /// Names a key that can take over the market authority once the
+    /// authority has been inactive for `inactivity_period` seconds. A default
+    /// key removes recovery.
+    pub fn set_market_recovery(
+        ctx: Context<MarketAdmin>,
+        recovery_authority: Pubkey,
+        inactivity_period: i64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.market.recovery.configure(recovery_authority, inactivity_period, now)
+    }
+
+    pub fn claim_market_authority(ctx: Context<ClaimMarketAuthority>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        let claimant = ctx.accounts.recovery_authority.key();
+        market.recovery.claim(&claimant, now)?;
+        emit!(AuthorityRecovered {
+            account: market.key(),
+            previous_authority: market.authority,
+            new_authority: claimant,
+            timestamp: now,
+        });
+        market.authority = claimant;
+        market.pending_authority = Pubkey::default();
+        Ok(())
+    }
+
+    /// First step of handing the market authority to `new_authority`, which
+    /// takes over only once it signs `accept_authority`. Proposing again
+    /// replaces the pending key, and the default key withdraws it.
+    pub fn propose_authority(ctx: Context<MarketAdmin>, new_authority: Pubkey) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        ctx.accounts.market.pending_authority = new_authority;
+        Ok(())
+    }
+
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        let new_authority = ctx.accounts.new_authority.key();
+        emit!(AuthorityTransferred {
+            market: market.key(),
+            previous_authority: market.authority,
+            new_authority,
+            timestamp: now,
+        });
+        market.authority = new_authority;
+        market.pending_authority = Pubkey::default();
+        market.recovery.record_activity(now);
+        Ok(())
+    }
+
+    /// Protocol-admin counterpart of `set_market_recovery`.
+    pub fn set_protocol_recovery(
+        ctx: Context<ProtocolAdmin>,
+        recovery_authority: Pubkey,
+        inactivity_period: i64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.protocol_config.recovery.configure(recovery_authority, inactivity_period, now)
+    }
+
+    pub fn claim_protocol_admin(ctx: Context<ClaimProtocolAdmin>) -> Result<()> {
+        let config = &mut ctx.accounts.protocol_config;
+        let now = Clock::get()?.unix_timestamp;
+        let claimant = ctx.accounts.recovery_authority.key();
+        config.recovery.claim(&claimant, now)?;
+        emit!(AuthorityRecovered {
+            account: config.key(),
+            previous_authority: config.admin,
+            new_authority: claimant,
+            timestamp: now,
+        });
+        config.admin = claimant;
+        Ok(())
+    }
+
+    pub fn initialize_trigger_registry(ctx: Context<InitializeTriggerRegistry>) -> Result<()> {
+        let registry = &mut ctx.accounts.trigger_registry;
+        registry.market = ctx.accounts.market.key();
+        registry.pending = Vec::new();
+        registry.bump = ctx.bumps["trigger_registry"];
+        Ok(())
+    }
+
+    /// Creates the market's oracle incident registry. From then on every
+    /// close instruction has to pass it.
+    pub fn initialize_incident_registry(ctx: Context<InitializeIncidentRegistry>) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let registry = &mut ctx.accounts.incident_registry;
+        registry.market = ctx.accounts.market.key();
+        registry.incidents = Vec::new();
+        registry.bump = ctx.bumps["incident_registry"];
+        ctx.accounts.market.has_incident_registry = true;
+        Ok(())
+    }
+
+    /// Guardian declares that the oracle diverged between `start` and
+    /// `end`. Positions opened in that window can then be closed without
+    /// fees and with the window's funding reversed.
+    pub fn declare_oracle_incident(ctx: Context<DeclareOracleIncident>, start: i64, end: i64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.incident_registry.declare(start, end, now)?;
+        emit!(OracleIncidentDeclared {
+            market: ctx.accounts.market.key(),
+            start,
+            end,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    pub fn set_trigger_tip(ctx: Context<MarketAdmin>, trigger_tip_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(trigger_tip_bps <= MAX_FEE_BPS, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.trigger_tip_bps = trigger_tip_bps;
+        Ok(())
+    }
+
+    /// Scales expiry fees and trigger tips by `multiplier_bps` (10000 is 1x,
+    /// up to `MAX_KEEPER_REWARD_MULTIPLIER_BPS`), so keepers stay profitable
+    /// when priority fees spike. Rewards never exceed the closing equity.
+    pub fn set_keeper_reward_multiplier(ctx: Context<MarketAdmin>, multiplier_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(
+            (10000..=MAX_KEEPER_REWARD_MULTIPLIER_BPS).contains(&multiplier_bps),
+            ErrorCode::ParameterOutOfBounds
+        );
+        ctx.accounts.market.keeper_reward_multiplier_bps = multiplier_bps;
+        Ok(())
+    }
+
+    /// Places a stop-loss that closes the whole position once the oracle
+    /// price reaches `trigger_price`, until `expiry_ts` if one is given. The
+    /// owner pays the trigger account's rent; a position holds at most one
+    /// stop-loss.
+    pub fn place_stop_loss(
+        ctx: Context<PlaceStopLoss>,
+        trigger_price: u64,
+        _sub_account_id: u16,
+        expiry_ts: Option<i64>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        place_trigger_order(
+            &mut accounts.trigger_order,
+            &mut accounts.trigger_registry,
+            &accounts.position,
+            accounts.margin_account.key(),
+            TriggerKind::StopLoss,
+            trigger_price,
+            expiry_ts,
+            ctx.bumps["trigger_order"],
+        )
+    }
+
+    /// Places a take-profit that closes the whole position once the oracle
+    /// price reaches `trigger_price` in its favour, until `expiry_ts` if one
+    /// is given. A position holds at most one take-profit, alongside its
+    /// stop-loss.
+    pub fn place_take_profit(
+        ctx: Context<PlaceTakeProfit>,
+        trigger_price: u64,
+        _sub_account_id: u16,
+        expiry_ts: Option<i64>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        place_trigger_order(
+            &mut accounts.trigger_order,
+            &mut accounts.trigger_registry,
+            &accounts.position,
+            accounts.margin_account.key(),
+            TriggerKind::TakeProfit,
+            trigger_price,
+            expiry_ts,
+            ctx.bumps["trigger_order"],
+        )
+    }
+
+    /// Closes one of the caller's trigger orders and returns its rent. Also
+    /// how an order left behind by a position closed another way is removed.
+    pub fn cancel_trigger_order(ctx: Context<CancelTriggerOrder>, _sub_account_id: u16) -> Result<()> {
+        let trigger_order = ctx.accounts.trigger_order.key();
+        ctx.accounts.trigger_registry.deregister(&trigger_order);
+        Ok(())
+    }
+
+    /// Permissionless crank that removes a trigger order past its expiry, so
+    /// expired orders do not hold slots in the market's registry. The keeper
+    /// receives the trigger account's rent.
+    pub fn remove_expired_trigger_order(ctx: Context<RemoveExpiredTriggerOrder>) -> Result<()> {
+        require!(
+            ctx.accounts.trigger_order.is_expired(Clock::get()?.unix_timestamp),
+            ErrorCode::TriggerOrderNotExpired
+        );
+        let trigger_order = ctx.accounts.trigger_order.key();
+        ctx.accounts.trigger_registry.deregister(&trigger_order);
+        Ok(())
+    }
+
+    /// Permissionless crank that closes a position at the exit price once
+    /// its trigger order has fired, unless the order has expired. The keeper
+    /// receives the rent of the trigger and position accounts plus
+    /// `trigger_tip_bps` of the equity; the owner receives the rest.
+    pub fn execute_trigger_order(ctx: Context<ExecuteTriggerOrder>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        require!(!ctx.accounts.trigger_order.is_expired(now), ErrorCode::OrderExpired);
+        let oracle_price = market.load_price_feed(&ctx.accounts.price_feed)?.get_adjusted_price()?;
+
+        // Triggers on the oracle price and closes at the exit price
+        let position = &mut ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+        let trigger_order = &ctx.accounts.trigger_order;
+        require!(trigger_order.is_triggered(oracle_price), ErrorCode::TriggerNotReached);
+        let current_price = market.exit_price(position.side, position.base_size, oracle_price)?;
+        let reason = match trigger_order.kind {
+            TriggerKind::StopLoss => CloseReason::StopLoss,
+            TriggerKind::TakeProfit => CloseReason::TakeProfit,
+        };
+        ctx.accounts.trigger_registry.deregister(&trigger_order.key());
+
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        let refund = incident_refund(
+            market,
+            ctx.accounts.incident_registry.as_ref(),
+            ctx.accounts.funding_history.as_ref(),
+            position,
+            closed_size,
+        )?;
+        let side = position.side;
+        market.remove_open_interest(side, position.base_size, position.notional, position.margin);
+        sync_coverage_open_interest(market, ctx.accounts.insurance_coverage.as_mut())?;
+
+        let pnl = market.position_pnl(position, current_price)?
+            .checked_add(refund.unwrap_or(0))
+            .ok_or(ErrorCode::MathOverflow)?;
+        market.last_settled_price = current_price;
+        market.last_oracle_price = oracle_price;
+        require_margin_covers_loss(position, position.margin, pnl)?;
+        let equity = if pnl > 0 {
+            position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?
+        } else {
+            position.margin.saturating_sub(pnl.unsigned_abs())
+        };
+        position.record_exit(closed_size, current_price, pnl);
+        market.realize_pnl(pnl);
+        emit!(PositionClosed {
+            record: position.record(position.key(), now),
+            reason,
+            closed_size,
+            remaining_size: 0,
+            exit_price: current_price,
+        });
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Close,
+            position.record(position.key(), now),
+            closed_size,
+            current_price,
+        )?;
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.keeper.to_account_info(),
+        )?;
+
+        // Keepers are not paid in virtual balance; their tip stays with the
+        // market. Positions opened during an oracle incident close fee-free.
+        let (keeper_tip, fee) = if refund.is_some() {
+            (0, 0)
+        } else {
+            let market = &ctx.accounts.market;
+            let tip = (equity as u128 * market.trigger_tip_bps as u128 / 10000) as u64;
+            let keeper_tip = market.keeper_reward(tip).min(equity);
+            (keeper_tip, market.close_fee(closed_size.saturating_mul(current_price), now).min(equity - keeper_tip))
+        };
+        ctx.accounts.market.accrue_fee(FeeKind::Close, side, FeeRole::Taker, fee, now)?;
+        let owner_amount = equity - keeper_tip - fee;
+        if ctx.accounts.market.paper_trading {
+            return ctx.accounts.market.pay_paper(&mut ctx.accounts.margin_account, owner_amount);
+        }
+
+        let market_key = ctx.accounts.market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        for (destination, amount) in [
+            (&ctx.accounts.keeper_token_account, keeper_tip),
+            (&ctx.accounts.owner_token_account, owner_amount),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: destination.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    &[&seeds[..]],
+                ),
+                amount,
+            )?;
+        }
+        check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())
+    }
+
+    pub fn set_funding_payment_cap(ctx: Context<MarketAdmin>, max_funding_payment_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(max_funding_payment_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.max_funding_payment_bps = max_funding_payment_bps;
+        Ok(())
+    }
+
+    /// Permissionless check that the vault holds everything the market owes:
+    /// position margin, escrowed order collateral and accrued fees net of
+    /// settled trader PnL, and that the insurance vault holds the fund's
+    /// balance when passed. A violation halts the market rather than
+    /// failing, so the halt and its `SolvencyViolation` event persist.
+    pub fn assert_solvency(ctx: Context<AssertSolvency>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let escrowed = match &ctx.accounts.order_book {
+            Some(book) => book.bids.iter().chain(book.asks.iter()).map(|order| order.locked_amount()).sum(),
+            None => 0,
+        };
+        let market_key = ctx.accounts.market.key();
+        let market = &mut ctx.accounts.market;
+        if !market.check_solvency(market_key, ctx.accounts.market_vault.amount, escrowed, now) {
+            return Ok(());
+        }
+
+        if let (Some(fund), Some(vault)) = (&ctx.accounts.insurance_fund, &ctx.accounts.insurance_vault) {
+            require_keys_eq!(vault.key(), fund.vault, ErrorCode::InsuranceAccountsRequired);
+            if vault.amount < fund.balance() {
+                market.paused_until = now;
+                market.pause_ratified = true;
+                emit!(SolvencyViolation {
+                    market: market_key,
+                    vault_balance: vault.amount,
+                    required: fund.balance(),
+                    timestamp: now,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `amount` of margin to an open position. Allowed while the market
+    /// is paused or in withdrawals-only mode, since it only lowers risk.
+    pub fn deposit_margin(ctx: Context<DepositMargin>, amount: u64, _sub_account_id: u16) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        require!(amount > 0, ErrorCode::OrderTooSmall);
+        let position = &mut ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+        ctx.accounts.market.add_margin(position, amount)?;
+
+        let transfer = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.owner_token_account.to_account_info(),
+                to: ctx.accounts.market_vault.to_account_info(),
+                authority: ctx.accounts.owner.to_account_info(),
+            },
+        );
+        collect_from_trader(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.margin_account,
+            transfer,
+            amount,
+        )?;
+
+        ctx.accounts.margin_account.unlock();
+        Ok(())
+    }
+
+    /// Withdraws `amount` of margin from an open position to the owner; see
+    /// `Market::remove_margin` for how much may come out.
+    pub fn withdraw_margin(ctx: Context<WithdrawMargin>, amount: u64, _sub_account_id: u16) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        require!(amount > 0, ErrorCode::OrderTooSmall);
+        let market = &mut ctx.accounts.market;
+        require!(!market.is_paused(Clock::get()?.unix_timestamp), ErrorCode::MarketPaused);
+        let current_price = market
+            .load_price_feed(&ctx.accounts.price_feed)?
+            .get_price_for(ctx.accounts.position.side)?;
+        let position = &mut ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+        market.remove_margin(position, amount, current_price)?;
+
+        let market_key = market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        let signer = &[&seeds[..]];
+        let transfer = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.market_vault.to_account_info(),
+                to: ctx.accounts.owner_token_account.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            },
+            signer,
+        );
+        pay_trader(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.margin_account,
+            transfer,
+            amount,
+        )?;
+
+        ctx.accounts.margin_account.unlock();
+        Ok(())
+    }
+
+    /// Queues `oracle`, a feed of `oracle_source`, to replace the market's
+    /// primary oracle once `ORACLE_ROTATION_DELAY` has passed. Queueing the
+    /// default key cancels a pending rotation.
+    pub fn set_oracle(ctx: Context<MarketAdmin>, oracle: Pubkey, oracle_source: OracleSource) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.market.recovery.record_activity(now);
+        let market = &mut ctx.accounts.market;
+        if oracle == Pubkey::default() {
+            market.oracle_rotation = OracleRotation::default();
+            return Ok(());
+        }
+        require!(market.oracle != Pubkey::default(), ErrorCode::InvalidOracle);
+        require!(
+            oracle != market.oracle && oracle != market.fallback_oracle,
+            ErrorCode::ParameterOutOfBounds
+        );
+        market.oracle_rotation.queue(oracle, oracle_source, now)
+    }
+
+    /// Permissionless crank that switches to the queued oracle once its
+    /// timelock has passed, if its price is within
+    /// `MAX_ORACLE_ROTATION_DEVIATION_BPS` of the feed the market uses now.
+    pub fn apply_oracle_rotation(ctx: Context<ApplyOracleRotation>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        let current_price = market.load_price_feed(&ctx.accounts.price_feed)?.get_index_price()?;
+        let new_price = market.load_price_feed(&ctx.accounts.new_price_feed)?.get_index_price()?;
+        market.oracle_rotation.check_ready(now, current_price, new_price)?;
+
+        emit!(OracleRotated {
+            market: market.key(),
+            old_oracle: market.oracle,
+            new_oracle: market.oracle_rotation.pending_oracle,
+            old_price: current_price,
+            new_price,
+            timestamp: now,
+        });
+        market.oracle = market.oracle_rotation.pending_oracle;
+        market.oracle_source = market.oracle_rotation.pending_source;
+        market.oracle_rotation = OracleRotation::default();
+        Ok(())
+    }
+
+    /// Puts the market into `Settling` ahead of a redeploy, naming the
+    /// market its positions migrate to. The default key returns it to
+    /// `Active`. Paper-trading markets hold no tokens to migrate.
+    pub fn set_market_successor(ctx: Context<MarketAdmin>, successor: Pubkey) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        require!(market.status != MarketStatus::Expired, ErrorCode::MarketExpired);
+        if successor == Pubkey::default() {
+            market.status = MarketStatus::Active;
+            market.successor = Pubkey::default();
+            return Ok(());
+        }
+        require!(!market.paper_trading, ErrorCode::PaperTradingMarket);
+        require!(successor != market.key(), ErrorCode::ParameterOutOfBounds);
+        market.status = MarketStatus::Settling;
+        market.successor = successor;
+        Ok(())
+    }
+
+    /// Delists the market at `settlement_price`. Trading, liquidations and
+    /// funding stop for good; keepers then close every open position at that
+    /// price with `settle_expired_position`, and once none is left the
+    /// authority can close the market with `close_market`.
+    pub fn expire_market(ctx: Context<MarketAdmin>, settlement_price: u64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.market.recovery.record_activity(now);
+        let market = &mut ctx.accounts.market;
+        require!(market.status != MarketStatus::Expired, ErrorCode::MarketExpired);
+        require!(settlement_price > 0, ErrorCode::InvalidPrice);
+        market.status = MarketStatus::Expired;
+        market.settlement_price = settlement_price;
+        market.last_settled_price = settlement_price;
+        emit!(MarketExpired {
+            market: market.key(),
+            settlement_price,
+            long_open_interest: market.long_open_interest,
+            short_open_interest: market.short_open_interest,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    /// Closes a position of an expired market at its settlement price and
+    /// pays what it is worth to the owner. Permissionless; the keeper gets
+    /// the position account's rent. A loss beyond the margin leaves the
+    /// owner nothing and is not collected.
+    pub fn settle_expired_position(ctx: Context<SettleExpiredPosition>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        let settlement_price = market.settlement_price;
+        let position = &mut ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+
+        // Funding stopped with trading, so this only settles what was owed
+        // at expiry. Unclaimed mining rewards are forfeited, as on a close.
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+        sync_coverage_open_interest(market, ctx.accounts.insurance_coverage.as_mut())?;
+
+        let pnl = market.position_pnl(position, settlement_price)?.max(-(position.margin as i64));
+        let equity = if pnl > 0 {
+            position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?
+        } else {
+            position.margin - pnl.unsigned_abs()
+        };
+        position.record_exit(closed_size, settlement_price, pnl);
+        market.realize_pnl(pnl);
+        emit!(PositionClosed {
+            record: position.record(position.key(), now),
+            reason: CloseReason::MarketExpiry,
+            closed_size,
+            remaining_size: 0,
+            exit_price: settlement_price,
+        });
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Close,
+            position.record(position.key(), now),
+            closed_size,
+            settlement_price,
+        )?;
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.keeper.to_account_info(),
+        )?;
+        if equity == 0 {
+            return Ok(());
+        }
+
+        let market_key = ctx.accounts.market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        let signer = &[&seeds[..]];
+        let transfer = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.market_vault.to_account_info(),
+                to: ctx.accounts.owner_token_account.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            },
+            signer,
+        );
+        pay_trader(&mut ctx.accounts.market, &mut ctx.accounts.margin_account, transfer, equity)
+    }
+
+    /// Closes an expired market once every position is settled, the
+    /// insurance slice is swept and, if it has an LP vault with shares
+    /// outstanding, the LP vault's PnL is settled. Fees still accrued, the protocol's and any
+    /// stakers' not yet rolled into an epoch, go to the protocol treasury,
+    /// whatever else is left in the vault, rounding dust, to
+    /// `authority_token_account`, and the rent of the vault and market
+    /// accounts to the authority.
+    pub fn close_market(ctx: Context<CloseMarket>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        require!(
+            market.long_open_interest == 0 && market.short_open_interest == 0 && market.pending_insurance == 0,
+            ErrorCode::MarketNotSettled
+        );
+        if market.has_order_book {
+            let order_book = ctx.accounts.order_book.as_ref().ok_or(ErrorCode::OrderBookRequired)?;
+            require!(
+                order_book.orders(Side::Long).is_empty() && order_book.orders(Side::Short).is_empty(),
+                ErrorCode::MarketNotSettled
+            );
+        }
+        if market.has_lp_vault {
+            let lp_vault = ctx.accounts.lp_vault.as_ref().ok_or(ErrorCode::LpVaultRequired)?;
+            let share_mint = ctx.accounts.lp_share_mint.as_ref().ok_or(ErrorCode::LpVaultRequired)?;
+            require_keys_eq!(share_mint.key(), lp_vault.share_mint, ErrorCode::LpVaultRequired);
+            require!(
+                market.lp_unsettled_pnl() == 0 || share_mint.supply == 0,
+                ErrorCode::MarketNotSettled
+            );
+        }
+
+        let market_key = market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        let signer = &[&seeds[..]];
+        let fees = market.total_fee_accrued
+            .saturating_add(market.protocol_fees_accrued)
+            .min(ctx.accounts.market_vault.amount);
+        if fees > 0 {
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.treasury.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    signer,
+                ),
+                fees,
+            )?;
+            emit!(FeesWithdrawn {
+                market: market_key,
+                treasury: ctx.accounts.treasury.key(),
+                amount: fees,
+                timestamp: Clock::get()?.unix_timestamp,
+            });
+        }
+        let dust = ctx.accounts.market_vault.amount - fees;
+        if dust > 0 {
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.authority_token_account.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    signer,
+                ),
+                dust,
+            )?;
+        }
+        token::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::CloseAccount {
+                account: ctx.accounts.market_vault.to_account_info(),
+                destination: ctx.accounts.authority.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            },
+            signer,
+        ))
+    }
+
+    /// Takes an open position out of a settling market without closing it:
+    /// funding is settled, the position leaves open interest and its margin
+    /// moves to the successor's vault, recorded in a `PositionExport` for
+    /// `import_position`. Unclaimed mining rewards are forfeited, as on a close.
+    pub fn export_position(ctx: Context<ExportPosition>, _sub_account_id: u16) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        let position = &mut ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+        require!(!position.resting_order, ErrorCode::PositionHasRestingOrder);
+
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let size = position.base_size;
+        market.charge_deferred_funding(position, size);
+        market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+        ctx.accounts.position_export.set_inner(PositionExport::new(
+            position,
+            market.successor,
+            now,
+            ctx.bumps["position_export"],
+        ));
+        let margin = position.margin;
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.owner.to_account_info(),
+        )?;
+
+        if margin > 0 {
+            let market_key = ctx.accounts.market.key();
+            let seeds = &[
+                b"vault_authority".as_ref(),
+                market_key.as_ref(),
+                &[ctx.bumps["vault_authority"]],
+            ];
+            let signer = &[&seeds[..]];
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.successor_vault.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    signer,
+                ),
+                margin,
+            )?;
+            check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())?;
+        }
+
+        ctx.accounts.margin_account.unlock();
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+        Ok(())
+    }
+
+    /// Opens an exported position on the successor market with the same
+    /// side, size, entry price, leverage and margin, subject to this
+    /// market's leverage and open-interest limits, and closes the export.
+    pub fn import_position(ctx: Context<ImportPosition>, _sub_account_id: u16) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let market = &mut ctx.accounts.market;
+        let export = &ctx.accounts.position_export;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        require!(!market.is_reduce_only(), ErrorCode::MarketReduceOnly);
+        require!(export.leverage <= market.current_max_leverage(now), ErrorCode::LeverageTooHigh);
+        require!(
+            market.open_interest(export.side).saturating_add(export.base_size) <= market.max_position_size,
+            ErrorCode::ExceedsMaxPosition
+        );
+
+        let market_key = market.key();
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let position_id = ctx.accounts.margin_account.open_position()?;
+        let position = &mut ctx.accounts.position;
+        position.set_inner(Position::new(
+            market_key,
+            margin_account_key,
+            position_id,
+            export.side,
+            export.leverage,
+            now,
+            ctx.bumps["position"],
+        ));
+        position.tag = export.tag;
+        position.funding_exempt = ctx.accounts.margin_account.hedger;
+        market.add_position(position, export.base_size, export.entry_price, export.margin, now)?;
+
+        emit!(PositionMigrated {
+            from_market: export.from_market,
+            to_market: market_key,
+            position: position.key(),
+            tag: export.tag,
+            side: export.side,
+            base_size: export.base_size,
+            entry_price: export.entry_price,
+            margin: export.margin,
+            timestamp: now,
+        });
+        ctx.accounts.margin_account.unlock();
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+        Ok(())
+    }
+
+    /// Moves quote tokens into the collateral vault as cross-margin
+    /// collateral of a sub-account, shared by all its positions.
+    pub fn deposit_collateral(ctx: Context<TransferCollateral>, amount: u64, _sub_account_id: u16) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        require!(amount > 0, ErrorCode::OrderTooSmall);
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.user_token_account.to_account_info(),
+                    to: ctx.accounts.collateral_vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.collateral = margin_account.collateral
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        margin_account.unlock();
+        Ok(())
+    }
+
+    /// Withdraws cross-margin collateral. Every open position of the
+    /// sub-account is passed as remaining accounts (position, market, price
+    /// feed), and the account must stay healthy without the amount.
+    pub fn withdraw_collateral<'info>(
+        ctx: Context<'_, '_, '_, 'info, TransferCollateral<'info>>,
+        amount: u64,
+        _sub_account_id: u16,
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        require!(amount > 0, ErrorCode::OrderTooSmall);
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let health = portfolio_health(ctx.remaining_accounts, &margin_account_key, &ctx.accounts.margin_account)?;
+        require!(amount <= health.free_collateral(), ErrorCode::InsufficientCollateral);
+
+        let seeds = &[b"collateral_authority".as_ref(), &[ctx.bumps["collateral_authority"]]];
+        let signer = &[&seeds[..]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.collateral_vault.to_account_info(),
+                    to: ctx.accounts.user_token_account.to_account_info(),
+                    authority: ctx.accounts.collateral_authority.to_account_info(),
+                },
+                signer,
+            ),
+            amount,
+        )?;
+
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.collateral -= amount;
+        margin_account.unlock();
+        Ok(())
+    }
+
+    /// Deposits into several of the caller's sub-accounts with one token
+    /// transfer. Each sub-account is passed as a remaining account, in the
+    /// order of `amounts`.
+    pub fn deposit_collateral_multi<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositCollateralMulti<'info>>,
+        amounts: Vec<u64>,
+    ) -> Result<()> {
+        require!(
+            !amounts.is_empty() && amounts.len() <= MAX_SUB_ACCOUNTS as usize,
+            ErrorCode::ParameterOutOfBounds
+        );
+        require!(ctx.remaining_accounts.len() == amounts.len(), ErrorCode::ParameterOutOfBounds);
+        let mut total: u64 = 0;
+        for (info, &amount) in ctx.remaining_accounts.iter().zip(amounts.iter()) {
+            require!(amount > 0, ErrorCode::OrderTooSmall);
+            let mut margin_account: Account<'info, MarginAccount> = Account::try_from(info)?;
+            require_keys_eq!(margin_account.authority, ctx.accounts.user.key(), ErrorCode::Unauthorized);
+            require!(!margin_account.in_use, ErrorCode::MarginAccountInUse);
+            margin_account.collateral = margin_account.collateral
+                .checked_add(amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+            margin_account.exit(&crate::ID)?;
+            total = total.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.user_token_account.to_account_info(),
+                    to: ctx.accounts.collateral_vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            total,
+        )
+    }
+
+    /// Moves cross-margin collateral from one of the caller's sub-accounts to
+    /// another without leaving the vault. The source sub-account's open
+    /// positions are passed as in `withdraw_collateral`, and it must stay
+    /// healthy without the amount; the destination only gains collateral.
+    pub fn move_collateral<'info>(
+        ctx: Context<'_, '_, '_, 'info, MoveCollateral<'info>>,
+        amount: u64,
+        _from_sub_account_id: u16,
+        _to_sub_account_id: u16,
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.from_margin_account)?;
+        MarginAccount::lock(&mut ctx.accounts.to_margin_account)?;
+        require!(amount > 0, ErrorCode::OrderTooSmall);
+        let from_key = ctx.accounts.from_margin_account.key();
+        let health = portfolio_health(ctx.remaining_accounts, &from_key, &ctx.accounts.from_margin_account)?;
+        require!(amount <= health.free_collateral(), ErrorCode::InsufficientCollateral);
+
+        let from = &mut ctx.accounts.from_margin_account;
+        from.collateral -= amount;
+        from.unlock();
+        let to = &mut ctx.accounts.to_margin_account;
+        to.collateral = to.collateral.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        to.unlock();
+        Ok(())
+    }
+
+    /// What `liquidate_position` would pay out if it ran now on `position`,
+    /// at the exit price. The market and position are read-only here, so
+    /// settling their funding below is never written back.
+    pub fn view_liquidation_outcome(ctx: Context<ViewLiquidationOutcome>) -> Result<LiquidationOutcome> {
+        let market = &mut ctx.accounts.market;
+        let oracle_price = market
+            .load_price_feed(&ctx.accounts.price_feed)?
+            .get_price_for(ctx.accounts.position.side)?;
+        let position = &mut ctx.accounts.position;
+        let current_price = market.exit_price(position.side, position.base_size, oracle_price)?;
+        let liquidatable = position.is_liquidatable(current_price);
+
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        let pnl = market.position_pnl(position, current_price)?;
+        if pnl < 0 && pnl.unsigned_abs() > position.margin {
+            return Ok(LiquidationOutcome {
+                liquidatable,
+                oracle_price,
+                exit_price: market.bankruptcy_price(position),
+                liquidator_fee: 0,
+                insurance_fee: 0,
+                owner_amount: 0,
+                shortfall: pnl.unsigned_abs() - position.margin,
+            });
+        }
+
+        let remaining_margin = if pnl > 0 {
+            position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?
+        } else {
+            position.margin - pnl.unsigned_abs()
+        };
+        let (liquidator_fee, insurance_fee) = market.liquidation_fee(position.margin, remaining_margin);
+        Ok(LiquidationOutcome {
+            liquidatable,
+            oracle_price,
+            exit_price: current_price,
+            liquidator_fee,
+            insurance_fee,
+            owner_amount: remaining_margin - liquidator_fee - insurance_fee,
+            shortfall: 0,
+        })
+    }
+
+    /// Health of a sub-account across all its positions, passed as in
+    /// `withdraw_collateral`.
+    pub fn view_portfolio_health<'info>(
+        ctx: Context<'_, '_, '_, 'info, ViewPortfolioHealth<'info>>,
+    ) -> Result<PortfolioHealth> {
+        portfolio_health(ctx.remaining_accounts, &ctx.accounts.margin_account.key(), &ctx.accounts.margin_account)
+    }
+
+    /// Previews a market order of `size` before it is sent: the margin and
+    /// fee it would take, where the position would end up and how healthy
+    /// the sub-account would be. Passing `position` previews adding to it;
+    /// otherwise a new position is assumed. The sub-account's open positions
+    /// are passed as in `withdraw_collateral`. Nothing is written back.
+    pub fn view_post_trade_health<'info>(
+        ctx: Context<'_, '_, '_, 'info, ViewPostTradeHealth<'info>>,
+        side: Side,
+        size: u64,
+        leverage: u8,
+    ) -> Result<PostTradeHealth> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        let price_feed = market.load_price_feed(&ctx.accounts.price_feed)?;
+        let price = price_feed.get_adjusted_price()?;
+        let risk_price = price_feed.get_price_for(side)?;
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let mut position = match &ctx.accounts.position {
+            Some(position) => {
+                require!(position.side == side, ErrorCode::ParameterOutOfBounds);
+                position.clone().into_inner()
+            }
+            None => Position::new(
+                market.key(),
+                margin_account_key,
+                ctx.accounts.margin_account.next_position_id,
+                side,
+                leverage,
+                now,
+                0,
+            ),
+        };
+        require!(position.leverage <= market.current_max_leverage(now), ErrorCode::LeverageTooHigh);
+        require!(size >= market.min_base_order_size, ErrorCode::OrderTooSmall);
+        require!(size <= market.max_position_size, ErrorCode::OrderTooLarge);
+
+        let fill_size = size.min(market.max_position_size.saturating_sub(market.open_interest(side)));
+        require!(fill_size > 0, ErrorCode::ExceedsMaxPosition);
+        // Priced as `open_market_order` fills it, on the vAMM where enabled
+        let fill_price = if market.vamm.is_enabled() {
+            market.vamm.fill_price(price, market.net_open_interest(), side, fill_size)?
+        } else {
+            price
+        };
+        let required_margin = banded_required_margin(fill_size, fill_price, position.leverage, price, risk_price)?;
+        let notional = fill_size.checked_mul(fill_price).ok_or(ErrorCode::MathOverflow)?;
+        let vault_balance = market.vault_balance(ctx.accounts.market_vault.amount);
+        let fee_discount_bps =
+            volume_fee_discount_bps(&ctx.accounts.protocol_config, ctx.accounts.trader_stats.as_deref(), now);
+        let fee = market.taker_fee(notional, vault_balance, fee_discount_bps, now);
+        market.add_position(&mut position, fill_size, fill_price, required_margin, now)?;
+
+        // The new margin comes from the wallet, so only a cross position
+        // changes the sub-account's valuation
+        let mut portfolio =
+            portfolio_health(ctx.remaining_accounts, &margin_account_key, &ctx.accounts.margin_account)?;
+        let market = &ctx.accounts.market;
+        if position.margin_mode == MarginMode::Cross && !market.paper_trading {
+            let maintenance = notional as u128 * market.maintenance_margin_fraction as u128 / math::BPS;
+            portfolio.equity = portfolio.equity.saturating_add(required_margin as i64);
+            portfolio.maintenance_requirement =
+                portfolio.maintenance_requirement.saturating_add(maintenance.min(u64::MAX as u128) as u64);
+        }
+        Ok(PostTradeHealth {
+            fill_size,
+            price: fill_price,
+            required_margin,
+            fee,
+            position_margin: position.margin,
+            liquidation_price: if position.margin_mode == MarginMode::Cross { 0 } else { position.liquidation_price },
+            liquidatable: position.is_liquidatable(risk_price),
+            portfolio,
+        })
+    }
+
+    /// Portfolio-level liquidation. Once a sub-account's equity over its
+    /// collateral and its cross positions is below their combined
+    /// maintenance margin, any keeper can close one of those positions at its
+    /// exit price from the edge of the oracle's confidence band against it, as
+    /// `liquidate_position` does.
+    /// What the position is worth goes to the collateral; a loss beyond its
+    /// margin is paid from the collateral, as far as it reaches. Keepers
+    /// repeat this until the account is healthy again.
+    pub fn liquidate_margin_account<'info>(
+        ctx: Context<'_, '_, '_, 'info, LiquidateMarginAccount<'info>>,
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let health = portfolio_health(ctx.remaining_accounts, &margin_account_key, &ctx.accounts.margin_account)?;
+        require!(!health.is_healthy(), ErrorCode::CannotLiquidate);
+
+        let market = &mut ctx.accounts.market;
+        require!(!market.paper_trading, ErrorCode::PaperTradingMarket);
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        let oracle_price = market
+            .load_price_feed(&ctx.accounts.price_feed)?
+            .get_price_for(ctx.accounts.position.side)?;
+
+        let position = &mut ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+        require!(position.margin_mode == MarginMode::Cross, ErrorCode::CannotLiquidate);
+        let current_price = market.exit_price(position.side, position.base_size, oracle_price)?;
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+
+        // Equity left in the position after the close; below zero the
+        // collateral covers the loss, and anything it cannot cover stays
+        // with the market as bad debt
+        let pnl = market.position_pnl(position, current_price)?;
+        let position_equity = position.margin as i128 + pnl as i128;
+        let covered = if position_equity < 0 {
+            (position_equity.unsigned_abs().min(u64::MAX as u128) as u64).min(ctx.accounts.margin_account.collateral)
+        } else {
+            0
+        };
+        let realized_pnl = if position_equity < 0 {
+            -(position.margin as i64) - covered as i64
+        } else {
+            pnl
+        };
+        let (liquidator_fee, insurance_fee) = market.liquidation_fee(
+            position.margin,
+            position_equity.clamp(0, u64::MAX as i128) as u64,
+        );
+        market.accrue_liquidation_fee(insurance_fee, now)?;
+        market.last_settled_price = current_price;
+        market.last_oracle_price = oracle_price;
+        position.record_exit(closed_size, current_price, realized_pnl - (liquidator_fee + insurance_fee) as i64);
+        market.realize_pnl(realized_pnl);
+        emit!(PositionClosed {
+            record: position.record(position.key(), now),
+            reason: CloseReason::Liquidation,
+            closed_size,
+            remaining_size: 0,
+            exit_price: current_price,
+        });
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Liquidation,
+            position.record(position.key(), now),
+            closed_size,
+            current_price,
+        )?;
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.liquidator.to_account_info(),
+        )?;
+
+        let market_key = ctx.accounts.market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        let signer = &[&seeds[..]];
+        if liquidator_fee > 0 {
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.liquidator_token_account.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    signer,
+                ),
+                liquidator_fee,
+            )?;
+        }
+
+        let amount = (position_equity.max(0) as u64) - liquidator_fee - insurance_fee;
+        if amount > 0 {
+            let transfer = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.market_vault.to_account_info(),
+                    to: ctx.accounts.collateral_vault.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                signer,
+            );
+            pay_trader(
+                &mut ctx.accounts.market,
+                &mut ctx.accounts.margin_account,
+                transfer,
+                amount,
+            )?;
+            let margin_account = &mut ctx.accounts.margin_account;
+            margin_account.collateral = margin_account.collateral
+                .checked_add(amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+        } else if covered > 0 {
+            let seeds = &[b"collateral_authority".as_ref(), &[ctx.bumps["collateral_authority"]]];
+            let signer = &[&seeds[..]];
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.collateral_vault.to_account_info(),
+                        to: ctx.accounts.market_vault.to_account_info(),
+                        authority: ctx.accounts.collateral_authority.to_account_info(),
+                    },
+                    signer,
+                ),
+                covered,
+            )?;
+            ctx.accounts.margin_account.collateral -= covered;
+        } else if liquidator_fee > 0 {
+            check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())?;
+        }
+
+        ctx.accounts.margin_account.unlock();
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+        Ok(())
+    }
+
+    pub fn initialize_withdrawal_policy(
+        ctx: Context<InitializeWithdrawalPolicy>,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+        single_signer_limit: u64,
+    ) -> Result<()> {
+        let policy = &mut ctx.accounts.withdrawal_policy;
+        policy.version = 0;
+        policy.request_count = 0;
+        policy.bump = ctx.bumps["withdrawal_policy"];
+        policy.spend_vault = ctx.accounts.spend_vault.key();
+        policy.period_start = 0;
+        policy.withdrawn_in_period = 0;
+        policy.configure(&signers, threshold, single_signer_limit)
+    }
+
+    /// Replaces the signers, threshold and single-signer limit. Proposed by
+    /// the protocol admin and co-signed by `threshold` of the current
+    /// signers, passed as signing remaining accounts. Requests opened under
+    /// the previous policy can no longer be executed.
+    pub fn set_withdrawal_policy(
+        ctx: Context<SetWithdrawalPolicy>,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+        single_signer_limit: u64,
+    ) -> Result<()> {
+        ctx.accounts.withdrawal_policy.check_approvals(ctx.remaining_accounts)?;
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        ctx.accounts.withdrawal_policy.configure(&signers, threshold, single_signer_limit)
+    }
+
+    /// Opens a request for a withdrawal above the single-signer limit. The
+    /// signers approve it in separate transactions with `approve_withdrawal`.
+    pub fn propose_withdrawal(ctx: Context<ProposeWithdrawal>, source: WithdrawalSource, amount: u64) -> Result<()> {
+        let policy = &mut ctx.accounts.withdrawal_policy;
+        require!(policy.is_signer(ctx.accounts.proposer.key), ErrorCode::Unauthorized);
+        require!(amount > 0, ErrorCode::OrderTooSmall);
+
+        let request = &mut ctx.accounts.withdrawal_request;
+        request.id = policy.request_count;
+        request.policy_version = policy.version;
+        request.proposer = ctx.accounts.proposer.key();
+        request.source = source;
+        request.source_vault = ctx.accounts.source_vault.key();
+        request.destination = ctx.accounts.destination.key();
+        request.amount = amount;
+        request.approval_count = 0;
+        request.executed = false;
+        request.created_at = Clock::get()?.unix_timestamp;
+        request.bump = ctx.bumps["withdrawal_request"];
+
+        policy.request_count = policy.request_count.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn approve_withdrawal(ctx: Context<ApproveWithdrawal>) -> Result<()> {
+        let policy = &ctx.accounts.withdrawal_policy;
+        let request = &mut ctx.accounts.withdrawal_request;
+        require!(policy.is_signer(ctx.accounts.signer.key), ErrorCode::Unauthorized);
+        require!(request.policy_version == policy.version, ErrorCode::WithdrawalRequestMismatch);
+        require!(!request.executed, ErrorCode::WithdrawalAlreadyExecuted);
+        request.approval_count = request.approval_count.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+
+        let approval = &mut ctx.accounts.approval;
+        approval.request = request.key();
+        approval.signer = ctx.accounts.signer.key();
+        approval.approved_at = Clock::get()?.unix_timestamp;
+        approval.bump = ctx.bumps["approval"];
+        Ok(())
+    }
+
+    /// Moves tokens out of the policy's treasury spend vault. Amounts past
+    /// the single-signer limit need an approved `withdrawal_request`.
+    pub fn withdraw_treasury(ctx: Context<WithdrawTreasury>, amount: u64) -> Result<()> {
+        ctx.accounts.withdrawal_policy.authorize(
+            ctx.accounts.signer.key,
+            ctx.accounts.withdrawal_request.as_deref_mut(),
+            WithdrawalSource::Treasury,
+            &ctx.accounts.source_vault.key(),
+            &ctx.accounts.destination.key(),
+            amount,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        let seeds = &[b"treasury".as_ref(), &[ctx.accounts.treasury.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.source_vault.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    authority: ctx.accounts.treasury.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+
+        emit!(FundsWithdrawn {
+            source: WithdrawalSource::Treasury,
+            source_vault: ctx.accounts.source_vault.key(),
+            destination: ctx.accounts.destination.key(),
+            amount,
+            signer: ctx.accounts.signer.key(),
+            request: ctx.accounts.withdrawal_request.as_ref().map(|request| request.key()),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    /// Moves tokens out of a market's insurance fund, up to its balance.
+    /// Amounts past the single-signer limit need an approved
+    /// `withdrawal_request`.
+    pub fn withdraw_insurance(ctx: Context<WithdrawInsurance>, amount: u64) -> Result<()> {
+        ctx.accounts.withdrawal_policy.authorize(
+            ctx.accounts.signer.key,
+            ctx.accounts.withdrawal_request.as_deref_mut(),
+            WithdrawalSource::Insurance,
+            &ctx.accounts.insurance_vault.key(),
+            &ctx.accounts.destination.key(),
+            amount,
+            Clock::get()?.unix_timestamp,
+        )?;
+        let fund = &mut ctx.accounts.insurance_fund;
+        require!(amount <= fund.balance(), ErrorCode::InsufficientCollateral);
+        fund.total_withdrawn = fund.total_withdrawn.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        sync_coverage_insurance(&ctx.accounts.market, fund, ctx.accounts.insurance_coverage.as_mut())?;
+
+        let seeds = &[b"insurance_fund".as_ref(), fund.market.as_ref(), &[fund.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.insurance_vault.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    authority: fund.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+
+        emit!(FundsWithdrawn {
+            source: WithdrawalSource::Insurance,
+            source_vault: ctx.accounts.insurance_vault.key(),
+            destination: ctx.accounts.destination.key(),
+            amount,
+            signer: ctx.accounts.signer.key(),
+            request: ctx.accounts.withdrawal_request.as_ref().map(|request| request.key()),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    /// Moves a position between isolated and cross margin. Every open
+    /// position of the sub-account is passed as remaining accounts, as in
+    /// `withdraw_collateral`, and the portfolio must be healthy afterwards.
+    /// A position leaving cross margin must also be safe on its own margin.
+    pub fn set_margin_mode<'info>(
+        ctx: Context<'_, '_, '_, 'info, SetMarginMode<'info>>,
+        mode: MarginMode,
+        _sub_account_id: u16,
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let market = &mut ctx.accounts.market;
+        require!(!market.paper_trading, ErrorCode::PaperTradingMarket);
+        require!(!market.is_paused(Clock::get()?.unix_timestamp), ErrorCode::MarketPaused);
+        let current_price = market
+            .load_price_feed(&ctx.accounts.price_feed)?
+            .get_price_for(ctx.accounts.position.side)?;
+
+        let position = &mut ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+        require!(position.margin_mode != mode, ErrorCode::InvalidMarketState);
+        market.settle_funding(position)?;
+        position.margin_mode = mode;
+        if mode == MarginMode::Isolated {
+            position.refresh_liquidation_price(market.liquidation_threshold);
+            require!(!position.is_liquidatable(current_price), ErrorCode::InsufficientCollateral);
+        }
+        // The valuation reads positions from account data, so the new mode
+        // has to be written out first
+        ctx.accounts.position.exit(&crate::ID)?;
+
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let health = portfolio_health(ctx.remaining_accounts, &margin_account_key, &ctx.accounts.margin_account)?;
+        require!(health.is_healthy(), ErrorCode::InsufficientCollateral);
+        ctx.accounts.margin_account.unlock();
+        Ok(())
+    }
+
+    /// Starts or ends a launch phase. While `cap` is non-zero, only the first
+    /// `cap` sub-accounts to register with `register_launch_trader` can open
+    /// positions; 0 opens the market to everyone.
+    pub fn set_launch_trader_cap(ctx: Context<MarketAdmin>, cap: u32) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        ctx.accounts.market.launch_trader_cap = cap;
+        Ok(())
+    }
+
+    pub fn register_launch_trader(ctx: Context<RegisterLaunchTrader>, _sub_account_id: u16) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        require!(
+            market.launch_trader_cap > 0 && market.launch_trader_count < market.launch_trader_cap,
+            ErrorCode::LaunchCapReached
+        );
+        market.launch_trader_count += 1;
+
+        let registration = &mut ctx.accounts.launch_registration;
+        registration.market = market.key();
+        registration.margin_account = ctx.accounts.margin_account.key();
+        registration.registered_at = Clock::get()?.unix_timestamp;
+        registration.bump = ctx.bumps["launch_registration"];
+        Ok(())
+    }
+
+    /// Sets the insurance fund's slice of trading fees, in bps.
+    pub fn set_insurance_funding(ctx: Context<MarketAdmin>, fee_share_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(fee_share_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.insurance_fee_share_bps = fee_share_bps;
+        Ok(())
+    }
+
+    /// Sets the liquidation fee, in bps of the liquidated position's margin,
+    /// and the share of it paid to the liquidator; the insurance fund gets
+    /// the rest.
+    pub fn set_liquidation_fee(ctx: Context<MarketAdmin>, fee_bps: u16, liquidator_share_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(fee_bps <= MAX_LIQUIDATION_FEE_BPS, ErrorCode::ParameterOutOfBounds);
+        require!(liquidator_share_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        let market = &mut ctx.accounts.market;
+        market.liquidation_fee_bps = fee_bps;
+        market.liquidator_share_bps = liquidator_share_bps;
+        Ok(())
+    }
+
+    /// Permissionless crank that moves the fee slices and liquidation fees
+    /// held in the market vault into the market's insurance fund.
+    pub fn sweep_insurance(ctx: Context<SweepInsurance>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let amount = market.pending_insurance;
+        if amount == 0 {
+            return Ok(());
+        }
+        market.pending_insurance = 0;
+        let fund = &mut ctx.accounts.insurance_fund;
+        fund.total_deposits = fund.total_deposits.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        sync_coverage_insurance(market, fund, ctx.accounts.insurance_coverage.as_mut())?;
+
+        let market_key = market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.market_vault.to_account_info(),
+                    to: ctx.accounts.insurance_vault.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+        check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())
+    }
+
+    /// Sets the health, in bps of position value, below which keepers can
+    /// flag a margin call. It must sit above the maintenance margin so the
+    /// warning comes before liquidation; 0 turns margin calls off.
+    pub fn set_margin_call_threshold(ctx: Context<MarketAdmin>, threshold_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        require!(
+            threshold_bps == 0
+                || (threshold_bps > market.maintenance_margin_fraction && threshold_bps <= 10000),
+            ErrorCode::ParameterOutOfBounds
+        );
+        market.margin_call_bps = threshold_bps;
+        Ok(())
+    }
+
+    /// Permissionless crank that flags an isolated position whose health
+    /// fell below the market's margin call threshold, and clears the flag
+    /// once it has recovered. Each change emits `MarginCall`.
+    pub fn flag_margin_call(ctx: Context<FlagMarginCall>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        let current_price = market
+            .load_price_feed(&ctx.accounts.price_feed)?
+            .get_price_for(ctx.accounts.position.side)?;
+
+        let position = &mut ctx.accounts.position;
+        let health_bps = market.position_health_bps(position, current_price)?;
+        let below = market.margin_call_bps > 0
+            && position.margin_mode == MarginMode::Isolated
+            && health_bps < market.margin_call_bps as u64;
+        if below == position.margin_call {
+            return Ok(());
+        }
+        position.margin_call = below;
+        emit!(MarginCall {
+            market: market.key(),
+            position: position.key(),
+            owner: position.owner,
+            tag: position.tag,
+            health_bps,
+            threshold_bps: market.margin_call_bps,
+            active: below,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        Ok(())
+    }
+
+    /// Adds `program` to the hook programs markets may call.
+    pub fn approve_hook_program(ctx: Context<ProtocolAdmin>, program: Pubkey) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let config = &mut ctx.accounts.protocol_config;
+        require!(program != Pubkey::default(), ErrorCode::ParameterOutOfBounds);
+        if config.is_approved_hook(&program) {
+            return Ok(());
+        }
+        let slot = config.hook_programs
+            .iter_mut()
+            .find(|slot| **slot == Pubkey::default())
+            .ok_or(ErrorCode::ParameterOutOfBounds)?;
+        *slot = program;
+        Ok(())
+    }
+
+    /// Removes `program` from the approved hooks. Markets that use it stop
+    /// calling it at once.
+    pub fn revoke_hook_program(ctx: Context<ProtocolAdmin>, program: Pubkey) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        for slot in ctx.accounts.protocol_config.hook_programs.iter_mut() {
+            if *slot == program {
+                *slot = Pubkey::default();
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the program the market calls on position opens, closes and
+    /// liquidations. It must be an approved hook; the default key removes it.
+    pub fn set_market_hook(ctx: Context<SetMarketHook>, program: Pubkey) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(
+            program == Pubkey::default() || ctx.accounts.protocol_config.is_approved_hook(&program),
+            ErrorCode::HookProgramNotApproved
+        );
+        ctx.accounts.market.hook_program = program;
+        Ok(())
+    }
+
+    /// Liquidates every eligible position passed in the remaining accounts,
+    /// `LIQUIDATION_CRANK_ACCOUNTS` per position: the position, its margin
+    /// account and the owner's token account. Positions that are not
+    /// liquidatable are skipped, as are bankrupt ones, whose shortfall needs
+    /// `liquidate_position` with the insurance accounts. Each position is
+    /// checked and closed at its exit price from the band edge against its
+    /// side, as in `liquidate_position`. The cranker is paid the liquidator's share of
+    /// every liquidation fee in one transfer.
+    pub fn crank_liquidations<'info>(ctx: Context<'_, '_, '_, 'info, CrankLiquidations<'info>>) -> Result<()> {
+        let accounts = ctx.remaining_accounts;
+        require!(
+            !accounts.is_empty()
+                && accounts.len().is_multiple_of(LIQUIDATION_CRANK_ACCOUNTS)
+                && accounts.len() / LIQUIDATION_CRANK_ACCOUNTS <= MAX_CRANK_LIQUIDATIONS_PER_IX,
+            ErrorCode::PositionAccountsMismatch
+        );
+        let now = Clock::get()?.unix_timestamp;
+        let market_key = ctx.accounts.market.key();
+        let market = &mut ctx.accounts.market;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        let price_feed = market.load_price_feed(&ctx.accounts.price_feed)?;
+        let (long_price, short_price) = (price_feed.get_price_for(Side::Long)?, price_feed.get_price_for(Side::Short)?);
+        market.accrue_mining(now)?;
+
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        let signer = &[&seeds[..]];
+        let mut cranker_fees: u64 = 0;
+        for chunk in accounts.chunks(LIQUIDATION_CRANK_ACCOUNTS) {
+            let [position_info, margin_info, owner_token_info] = chunk else {
+                return err!(ErrorCode::PositionAccountsMismatch);
+            };
+            let mut position: Account<'info, Position> = Account::try_from(position_info)?;
+            let mut margin_account: Account<'info, MarginAccount> = Account::try_from(margin_info)?;
+            let owner_token_account: Account<'info, TokenAccount> = Account::try_from(owner_token_info)?;
+            require!(
+                position.market == market_key
+                    && position.owner == margin_info.key()
+                    && owner_token_account.owner == margin_account.authority
+                    && owner_token_account.mint == ctx.accounts.market_vault.mint
+                    && position_info.is_writable
+                    && margin_info.is_writable,
+                ErrorCode::PositionAccountsMismatch
+            );
+            let oracle_price = match position.side {
+                Side::Long => long_price,
+                Side::Short => short_price,
+            };
+            let current_price = ctx.accounts.market.exit_price(position.side, position.base_size, oracle_price)?;
+            if !position.is_liquidatable(current_price) {
+                continue;
+            }
+
+            // Funding is settled either way; a bankrupt position is left open
+            let market = &mut ctx.accounts.market;
+            market.settle_funding(&mut position)?;
+            let pnl = market.position_pnl(&position, current_price)?;
+            if pnl < 0 && pnl.unsigned_abs() > position.margin.saturating_sub(position.deferred_funding) {
+                position.exit(&crate::ID)?;
+                continue;
+            }
+            check_priority_lane(market, ctx.accounts.keeper_stake.as_ref(), ctx.accounts.cranker.key)?;
+
+            let closed_size = position.base_size;
+            market.charge_deferred_funding(&mut position, closed_size);
+            market.remove_open_interest(position.side, position.base_size, position.notional, position.margin);
+            let remaining_margin = if pnl > 0 {
+                position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?
+            } else {
+                position.margin - pnl.unsigned_abs()
+            };
+            let (liquidator_fee, insurance_fee) = market.liquidation_fee(position.margin, remaining_margin);
+            market.accrue_liquidation_fee(insurance_fee, now)?;
+            cranker_fees = cranker_fees.checked_add(liquidator_fee).ok_or(ErrorCode::MathOverflow)?;
+            market.last_settled_price = current_price;
+            market.last_oracle_price = oracle_price;
+            position.record_exit(closed_size, current_price, pnl - (liquidator_fee + insurance_fee) as i64);
+            market.realize_pnl(pnl);
+            let record = position.record(position_info.key(), now);
+            emit!(PositionClosed {
+                record: record.clone(),
+                reason: CloseReason::Liquidation,
+                closed_size,
+                remaining_size: 0,
+                exit_price: current_price,
+            });
+            notify_hook(
+                &ctx.accounts.protocol_config,
+                market,
+                ctx.accounts.hook_program.as_ref(),
+                HookEventKind::Liquidation,
+                record,
+                closed_size,
+                current_price,
+            )?;
+
+            let owner_amount = remaining_margin - liquidator_fee - insurance_fee;
+            close_position_account(&mut position, &mut margin_account, ctx.accounts.cranker.to_account_info())?;
+            position.exit(&crate::ID)?;
+            if owner_amount > 0 {
+                let transfer = CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: owner_token_info.clone(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    signer,
+                );
+                pay_trader(&mut ctx.accounts.market, &mut margin_account, transfer, owner_amount)?;
+            }
+            margin_account.exit(&crate::ID)?;
+        }
+
+        if cranker_fees > 0 {
+            token::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    token::Transfer {
+                        from: ctx.accounts.market_vault.to_account_info(),
+                        to: ctx.accounts.cranker_token_account.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                    signer,
+                ),
+                cranker_fees,
+            )?;
+            check_vault_solvency(&mut ctx.accounts.market, &ctx.accounts.market_vault.to_account_info())?;
+        }
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())
+    }
+
+    /// Limits how many orders a sub-account may place per slot and per
+    /// second, across all markets. Zero turns a limit off.
+    pub fn set_order_rate_limits(ctx: Context<ProtocolAdmin>, per_slot: u16, per_second: u16) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        ctx.accounts.protocol_config.order_rate_limits = OrderRateLimits { per_slot, per_second };
+        Ok(())
+    }
+
+    /// Exempts every sub-account of `authority` from the order rate limits.
+    pub fn register_market_maker(ctx: Context<ProtocolAdmin>, authority: Pubkey) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let config = &mut ctx.accounts.protocol_config;
+        require!(authority != Pubkey::default(), ErrorCode::ParameterOutOfBounds);
+        if config.is_market_maker(&authority) {
+            return Ok(());
+        }
+        let slot = config.market_makers
+            .iter_mut()
+            .find(|slot| **slot == Pubkey::default())
+            .ok_or(ErrorCode::ParameterOutOfBounds)?;
+        *slot = authority;
+        Ok(())
+    }
+
+    pub fn deregister_market_maker(ctx: Context<ProtocolAdmin>, authority: Pubkey) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        for slot in ctx.accounts.protocol_config.market_makers.iter_mut() {
+            if *slot == authority {
+                *slot = Pubkey::default();
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the interest rate component of funding and how far it may pull
+    /// the rate away from the mark/index premium, both per funding interval.
+    pub fn set_funding_params(ctx: Context<MarketAdmin>, interest_rate_bps: i16, premium_clamp_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let max_rate = ctx.accounts.market.max_funding_rate_bps;
+        require!(
+            interest_rate_bps.unsigned_abs() <= max_rate && premium_clamp_bps as u32 <= 2 * max_rate as u32,
+            ErrorCode::ParameterOutOfBounds
+        );
+        let premium = &mut ctx.accounts.market.premium;
+        premium.interest_rate_bps = interest_rate_bps;
+        premium.premium_clamp_bps = premium_clamp_bps;
+        Ok(())
+    }
+
+    /// Permissionless crank that samples the mark/index premium into the
+    /// current funding round's time-weighted average.
+    pub fn sample_premium(ctx: Context<SamplePremium>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let premium = mark_premium_bps(market, ctx.accounts.order_book.as_deref(), &ctx.accounts.price_feed)?;
+        market.premium.sample(Clock::get()?.unix_timestamp, premium);
+        Ok(())
+    }
+
+    /// Opens the market's next price history page. Anyone may pay for it.
+    pub fn open_price_history_page(ctx: Context<OpenPriceHistoryPage>) -> Result<()> {
+        let page = &mut ctx.accounts.price_history;
+        page.market = ctx.accounts.market.key();
+        page.page_index = PriceHistoryPage::page_for(ctx.accounts.market.price_history_head);
+        page.samples = Vec::new();
+        page.bump = ctx.bumps["price_history"];
+        Ok(())
+    }
+
+    /// Permissionless crank that appends the mark price, index price and
+    /// funding state to the market's price history, at most once per
+    /// `PRICE_SAMPLE_INTERVAL`. The sample also feeds the funding premium.
+    pub fn record_price_sample(ctx: Context<RecordPriceSample>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        if now - market.last_price_sample < PRICE_SAMPLE_INTERVAL {
+            return Ok(());
+        }
+        let (mark_price, index_price) =
+            mark_and_index_price(market, ctx.accounts.order_book.as_deref(), &ctx.accounts.price_feed)?;
+        ctx.accounts.price_history.append(PriceSample {
+            timestamp: now,
+            mark_price,
+            index_price,
+            funding_rate: market.funding_rate,
+            cumulative_funding_long: market.cumulative_funding_long,
+        })?;
+        market.premium.sample(now, math::premium_bps(mark_price, index_price));
+        market.price_history_head = market.price_history_head.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        market.last_price_sample = now;
+        Ok(())
+    }
+
+    /// Archives the market's revenue for a finished `epoch`, by source, to
+    /// its own `RevenueRecord`. Anyone may pay for it, but only while the
+    /// epoch is still in the market's ledger.
+    pub fn archive_revenue_epoch(ctx: Context<ArchiveRevenueEpoch>, epoch: u64) -> Result<()> {
+        require!(epoch < revenue_epoch(Clock::get()?.unix_timestamp), ErrorCode::RevenueEpochOpen);
+        let revenue = ctx.accounts.market.revenue.epoch(epoch).ok_or(ErrorCode::RevenueEpochUnavailable)?;
+        let record = &mut ctx.accounts.revenue_record;
+        record.market = ctx.accounts.market.key();
+        record.revenue = revenue;
+        record.bump = ctx.bumps["revenue_record"];
+        Ok(())
+    }
+
+    /// Sets the largest funding rate the market charges per funding
+    /// interval, either way. It applies from the next funding update.
+    pub fn set_max_funding_rate(ctx: Context<MarketAdmin>, max_funding_rate_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(
+            max_funding_rate_bps > 0 && max_funding_rate_bps <= MAX_FUNDING_RATE_BPS,
+            ErrorCode::ParameterOutOfBounds
+        );
+        ctx.accounts.market.max_funding_rate_bps = max_funding_rate_bps;
+        Ok(())
+    }
+
+    /// Sets the fee on resting limit-order fills, in bps of the filled
+    /// notional. A negative fee is a rebate paid from accrued fees into the
+    /// maker's margin. Makers never pay more than `fee_bps`, the taker rate.
+    pub fn set_maker_fee(ctx: Context<MarketAdmin>, maker_fee_bps: i16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(
+            maker_fee_bps.unsigned_abs() <= MAX_FEE_BPS && maker_fee_bps <= ctx.accounts.market.fee_bps as i16,
+            ErrorCode::ParameterOutOfBounds
+        );
+        ctx.accounts.market.maker_fee_bps = maker_fee_bps;
+        Ok(())
+    }
+
+    /// Sets the fee charged on closing a position, in bps of the closed
+    /// notional. Opening orders keep paying `fee_bps`.
+    pub fn set_close_fee(ctx: Context<MarketAdmin>, close_fee_bps: u16) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        require!(close_fee_bps <= MAX_FEE_BPS, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.close_fee_bps = close_fee_bps;
+        Ok(())
+    }
+
+    /// Schedules a fee holiday: from `start` until `end`, opening and
+    /// closing fees are cut by `discount_bps` (10000 waives them). Takes the
+    /// first slot that is unused or has ended.
+    pub fn schedule_fee_holiday(ctx: Context<MarketAdmin>, start: i64, end: i64, discount_bps: u16) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.market.recovery.record_activity(now);
+        require!(start < end && end > now && discount_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        let slot = ctx.accounts.market.fee_holidays.iter_mut()
+            .find(|holiday| holiday.end <= now)
+            .ok_or(ErrorCode::FeeHolidaysFull)?;
+        *slot = FeeHoliday { start, end, discount_bps };
+        Ok(())
+    }
+
+    /// Cancels the scheduled or running fee holiday that starts at `start`.
+    pub fn cancel_fee_holiday(ctx: Context<MarketAdmin>, start: i64) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let slot = ctx.accounts.market.fee_holidays.iter_mut()
+            .find(|holiday| holiday.end != 0 && holiday.start == start)
+            .ok_or(ErrorCode::FeeHolidayNotFound)?;
+        *slot = FeeHoliday::default();
+        Ok(())
+    }
+
+    /// Sets the reward, in quote, paid to the caller of each funding update
+    /// that goes through. It is scaled by the keeper reward multiplier and
+    /// limited to the fees the market has accrued.
+    pub fn set_funding_update_reward(ctx: Context<MarketAdmin>, reward: u64) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        ctx.accounts.market.funding_update_reward = reward;
+        Ok(())
+    }
+
+    /// Sets the underlying token the market's positions can be converted
+    /// into with `convert_to_spot`. The default key turns conversion off.
+    pub fn set_base_mint(ctx: Context<MarketAdmin>, base_mint: Pubkey) -> Result<()> {
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        ctx.accounts.market.base_mint = base_mint;
+        Ok(())
+    }
+
+    /// Closes a profitable position and spends all it releases, margin plus
+    /// PnL less the close fee, on the market's underlying token through a
+    /// Jupiter route, so the trader takes delivery of the win as spot. The
+    /// route's accounts are the remaining accounts; it may spend no more
+    /// than the payout, and the owner's spot account must gain at least
+    /// `min_amount_out`.
+    pub fn convert_to_spot<'info>(
+        ctx: Context<'_, '_, '_, 'info, ConvertToSpot<'info>>,
+        route_data: Vec<u8>,
+        min_amount_out: u64,
+        _sub_account_id: u16,
+    ) -> Result<()> {
+        MarginAccount::lock(&mut ctx.accounts.margin_account)?;
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+        require!(!market.is_paused(now), ErrorCode::MarketPaused);
+        require!(
+            !market.paper_trading && market.base_mint != Pubkey::default(),
+            ErrorCode::SpotConversionUnavailable
+        );
+        let oracle_price = market.load_price_feed(&ctx.accounts.price_feed)?.get_adjusted_price()?;
+
+        let position = &mut ctx.accounts.position;
+        require!(position.base_size > 0, ErrorCode::PositionNotFound);
+        let current_price = market.exit_price(position.side, position.base_size, oracle_price)?;
+        market.accrue_mining(now)?;
+        market.settle_funding(position)?;
+        let closed_size = position.base_size;
+        market.charge_deferred_funding(position, closed_size);
+        let incident = incident_refund(
+            market,
+            ctx.accounts.incident_registry.as_ref(),
+            ctx.accounts.funding_history.as_ref(),
+            position,
+            closed_size,
+        )?;
+        let pnl = market.position_pnl(position, current_price)?
+            .checked_add(incident.unwrap_or(0))
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(pnl > 0, ErrorCode::PositionNotProfitable);
+
+        let side = position.side;
+        market.remove_open_interest(side, position.base_size, position.notional, position.margin);
+        market.last_settled_price = current_price;
+        market.last_oracle_price = oracle_price;
+        let equity = position.margin.checked_add(pnl as u64).ok_or(ErrorCode::MathOverflow)?;
+        position.record_exit(closed_size, current_price, pnl);
+        market.realize_pnl(pnl);
+        emit!(PositionClosed {
+            record: position.record(position.key(), now),
+            reason: CloseReason::SpotConversion,
+            closed_size,
+            remaining_size: 0,
+            exit_price: current_price,
+        });
+        notify_hook(
+            &ctx.accounts.protocol_config,
+            market,
+            ctx.accounts.hook_program.as_ref(),
+            HookEventKind::Close,
+            position.record(position.key(), now),
+            closed_size,
+            current_price,
+        )?;
+        // Positions opened during an oracle incident close fee-free
+        let fee = if incident.is_some() {
+            0
+        } else {
+            market.close_fee(closed_size.saturating_mul(current_price), now).min(equity)
+        };
+        market.accrue_fee(FeeKind::Close, side, FeeRole::Taker, fee, now)?;
+        let payout = equity - fee;
+        close_position_account(
+            &mut ctx.accounts.position,
+            &mut ctx.accounts.margin_account,
+            ctx.accounts.owner.to_account_info(),
+        )?;
+        sync_coverage_open_interest(&ctx.accounts.market, ctx.accounts.insurance_coverage.as_mut())?;
+
+        let market_key = ctx.accounts.market.key();
+        let seeds = &[
+            b"vault_authority".as_ref(),
+            market_key.as_ref(),
+            &[ctx.bumps["vault_authority"]],
+        ];
+        let signer = &[&seeds[..]];
+        let transfer = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::Transfer {
+                from: ctx.accounts.market_vault.to_account_info(),
+                to: ctx.accounts.owner_quote_account.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            },
+            signer,
+        );
+        pay_trader(
+            &mut ctx.accounts.market,
+            &mut ctx.accounts.margin_account,
+            transfer,
+            payout,
+        )?;
+
+        // The route spends the payout from the owner's quote account, with
+        // the owner's signature, into the owner's spot account
+        ctx.accounts.owner_quote_account.reload()?;
+        let quote_before = ctx.accounts.owner_quote_account.amount;
+        let spot_before = ctx.accounts.spot_token_account.amount;
+        swap::invoke_jupiter_route(&ctx.accounts.jupiter_program, ctx.remaining_accounts, route_data)?;
+        ctx.accounts.owner_quote_account.reload()?;
+        ctx.accounts.spot_token_account.reload()?;
+        let amount_in = quote_before.saturating_sub(ctx.accounts.owner_quote_account.amount);
+        let amount_out = ctx.accounts.spot_token_account.amount.saturating_sub(spot_before);
+        require!(amount_in <= payout, ErrorCode::SlippageExceeded);
+        require!(amount_out >= min_amount_out && amount_out > 0, ErrorCode::SlippageExceeded);
+
+        ctx.accounts.margin_account.unlock();
+        Ok(())
+    }
+
+    /// Makes a sub-account a hedger, whose positions neither pay nor receive
+    /// funding, or stops it being one. Only for protocol-side hedging, such
+    /// as the LP vault's or a designated market maker's account, and only
+    /// while the sub-account has no open positions.
+    pub fn set_hedger(ctx: Context<SetHedger>, hedger: bool) -> Result<()> {
+        ctx.accounts.protocol_config.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let margin_account = &mut ctx.accounts.margin_account;
+        require!(margin_account.position_count == 0, ErrorCode::HedgerPositionsOpen);
+        margin_account.hedger = hedger;
+        Ok(())
+    }
+
+    /// Creates a `CustomOracle` whose prices `authority` pushes, read as
+    /// `price * 10^expo`. Markets use it with `OracleSource::Custom`.
+    pub fn initialize_custom_oracle(ctx: Context<InitializeCustomOracle>, expo: i32) -> Result<()> {
+        let oracle = &mut ctx.accounts.custom_oracle;
+        oracle.authority = ctx.accounts.authority.key();
+        oracle.price = 0;
+        oracle.conf = 0;
+        oracle.expo = expo;
+        oracle.publish_time = 0;
+        Ok(())
+    }
+
+    /// Publishes a new price to a custom oracle, stamped with the current time.
+    /// The confidence must be positive and at most `MAX_CUSTOM_ORACLE_CONF_BPS`
+    /// of the price.
+    pub fn update_custom_oracle(ctx: Context<UpdateCustomOracle>, price: i64, conf: u64) -> Result<()> {
+        require!(price > 0, ErrorCode::InvalidPrice);
+        require!(
+            conf > 0 && conf as u128 * 10000 <= price as u128 * MAX_CUSTOM_ORACLE_CONF_BPS as u128,
+            ErrorCode::InvalidConfidence
+        );
+        let oracle = &mut ctx.accounts.custom_oracle;
+        oracle.price = price;
+        oracle.conf = conf;
+        oracle.publish_time = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+
+    /// Sets how far the oracle's confidence interval may stretch before its
+    /// prices are rejected, and how many confidences (`k`, in bps) position
+    /// risk checks move the price against the position.
+    pub fn set_oracle_confidence(
+        ctx: Context<MarketAdmin>,
+        max_conf_bps: u16,
+        conf_multiplier_bps: u16,
+    ) -> Result<()> {
+        require!(max_conf_bps > 0 && max_conf_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        require!(conf_multiplier_bps <= MAX_ORACLE_CONF_MULTIPLIER_BPS, ErrorCode::ParameterOutOfBounds);
+        ctx.accounts.market.recovery.record_activity(Clock::get()?.unix_timestamp);
+        let market = &mut ctx.accounts.market;
+        market.max_oracle_conf_bps = max_conf_bps;
+        market.oracle_conf_multiplier_bps = conf_multiplier_bps;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum Side {
+    Long,
+    Short,
+}
+
+#[account]
+pub struct Market {
+    pub name: String,
+    pub authority: Pubkey,
+    pub min_base_order_size: u64,
+    pub tick_size: u64,
+    pub max_leverage: u8,
+    pub liquidation_threshold: u16,
+    pub maintenance_margin_fraction: u16,
+    pub long_open_interest: u64,  // total base size of open longs
+    pub short_open_interest: u64,
+    pub long_entry_notional: u64,  // total entry notional of open longs
+    pub short_entry_notional: u64,
+    pub is_initialized: bool,
+    pub total_fee_accrued: u64,  // stakers' fees, paid out by `roll_epoch`
+    pub max_position_size: u64,
+    pub funding_rate: i64,
+    pub last_funding_time: i64,
+    pub funding_interval: i64,  // in seconds
+    pub mining: MiningState,
+    pub fee_bps: u16,
+    pub param_queue: VecDeque<QueuedParamChange>,
+    pub guardian: Pubkey,
+    pub guardian_pause_duration: i64,  // in seconds
+    pub paused_until: i64,
+    pub pause_ratified: bool,
+    pub matching_policy: MatchingPolicy,
+    pub max_mark_index_deviation_bps: u16,  // 0 disables the check
+    pub reduce_only_flags: u8,
+    pub listed_at: i64,
+    pub total_volume: u64,  // cumulative traded notional
+    pub leverage_ramp: LeverageRamp,
+    pub max_position_age: i64,  // in seconds, 0 disables expiry
+    pub expiry_fee_bps: u16,  // paid to the keeper that closes an expired position
+    pub oracle: Pubkey,
+    pub fallback_oracle: Pubkey,
+    pub oracle_grace_period: i64,  // in seconds the primary may go without updating
+    pub last_settled_price: u64,  // price of the latest open, fill, or close
+    pub cumulative_funding_long: i64,  // funding paid per unit of entry notional by a long, in bps
+    pub cumulative_funding_short: i64,  // funding paid per unit of entry notional by a short, in bps
+    pub fee_curve: FeeCurve,
+    pub volume_window: VolumeWindow,
+    pub priority_lanes: PriorityLanes,
+    pub price_decimals: u8,  // every oracle price is normalized to this many decimals
+    pub price_rounding: PriceRounding,
+    pub notional_cap: NotionalCap,
+    pub min_coverage_bps: u64,  // (vault + insurance) over net trader PnL; 0 disables
+    pub paper_trading: bool,  // trades against virtual balances, no tokens move
+    pub paper_vault: u64,  // virtual quote held by a paper-trading market
+    pub recovery: AuthorityRecovery,
+    pub max_funding_payment_bps: u16,  // of margin, per funding interval; 0 disables
+    pub trigger_tip_bps: u16,  // of a triggered close's equity, paid to the executing keeper
+    pub total_margin: u64,  // margin of open positions, as of their last settlement
+    pub trader_realized_pnl: i64,  // PnL and funding settled into trader margin, net
+    pub oracle_rotation: OracleRotation,
+    pub status: MarketStatus,
+    pub successor: Pubkey,  // market positions migrate to while settling
+    pub has_incident_registry: bool,  // closes must then pass the registry
+    pub keeper_reward_multiplier_bps: u16,  // scales expiry fees and trigger tips; 10000 is 1x
+    pub launch_trader_cap: u32,  // 0 outside a launch phase
+    pub launch_trader_count: u32,  // sub-accounts registered for the launch phase
+    pub insurance_fee_share_bps: u16,  // slice of trading fees set aside for the insurance fund
+    pub liquidation_fee_bps: u16,  // of a liquidated position's margin, taken from what is left of it
+    pub liquidator_share_bps: u16,  // of the liquidation fee paid to the liquidator; the rest goes to insurance
+    pub pending_insurance: u64,  // fee slices and liquidation fees held in the vault until swept
+    pub depth_cap_bps: u16,  // max market order, in bps of two-sided book depth near the oracle; 0 disables
+    pub depth_band_bps: u16,  // how far from the oracle price resting orders count as depth
+    pub has_insurance_coverage: bool,  // open-interest and insurance instructions must then pass it
+    pub margin_call_bps: u16,  // health below which keepers flag a margin call; 0 disables
+    pub hook_program: Pubkey,  // called on position events while approved; default for none
+    pub premium: PremiumTwap,
+    pub has_order_book: bool,
+    pub price_history_head: u64,  // price samples written
+    pub last_price_sample: i64,
+    pub max_funding_rate_bps: u16,  // per funding interval, either way
+    pub close_fee_bps: u16,  // charged on closes, on top of `fee_bps` charged on opens
+    pub fee_accrual: FeeAccrual,
+    pub base_mint: Pubkey,  // underlying token winning positions can convert into; default for none
+    pub funding_update_reward: u64,  // paid from accrued fees to whoever cranks a funding update
+    pub settlement_price: u64,  // final price of an expired market; 0 until then
+    pub fee_holidays: [FeeHoliday; MAX_FEE_HOLIDAYS],
+    pub vault: Pubkey,  // quote token vault, owned by the market's vault authority PDA
+    pub revenue: RevenueLedger,
+    pub pending_authority: Pubkey,  // proposed by the authority, default when none
+    pub maker_fee_bps: i16,  // on resting limit-order fills; negative is a rebate
+    pub lp_pnl_checkpoint: i64,  // trader_realized_pnl the LP vault has settled up to
+    pub lp_settled_pnl: i64,  // net paid from the LP vault into the market vault
+    pub lp_vault_equity: u64,  // as of the LP vault's last refresh
+    pub lp_max_utilization_bps: u16,  // 0 for no cap
+    pub vamm: Vamm,
+    pub oracle_source: OracleSource,
+    pub fallback_oracle_source: OracleSource,
+    pub max_oracle_staleness_secs: i64,  // oldest oracle price the market accepts
+    pub max_oracle_conf_bps: u16,  // widest oracle confidence accepted, as a share of the price
+    pub oracle_conf_multiplier_bps: u16,  // confidences positions are risk-checked against, in bps
+    pub last_oracle_price: u64,  // oracle price behind the latest open, close, or liquidation
+    pub protocol_fee_share_bps: u16,  // slice of trading fees, after insurance, kept for the treasury
+    pub protocol_fees_accrued: u64,  // the treasury's fees, paid out by `withdraw_fees`
+    pub has_lp_vault: bool,
+    pub limit_price_band_bps: u16,  // limit orders are placed and matched within this much of the index
+}
+
+impl Market {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 8 + 1 + 2 + 2 + 8 + 8 + 8 + 8 + 1 + 8 + 8 + 8 + 8 + MiningState::LEN + 2 + 4 + QueuedParamChange::LEN * MAX_QUEUED_PARAM_CHANGES + 32 + 8 + 8 + 1 + 1 + 2 + 1 + 8 + 8 + LeverageRamp::LEN + 8 + 2 + 32 + 32 + 8 + 8 + 8 + 8 + FeeCurve::LEN + VolumeWindow::LEN + PriorityLanes::LEN + 1 + 1 + NotionalCap::LEN + 8 + 1 + 8 + AuthorityRecovery::LEN + 2 + 2 + 8 + 8 + OracleRotation::LEN + 1 + 32 + 1 + 2 + 4 + 4 + 2 + 2 + 2 + 8 + 2 + 2 + 1 + 2 + 32 + PremiumTwap::LEN + 1 + 8 + 8 + 2 + 2 + FeeAccrual::LEN + 32 + 8 + 8 + FeeHoliday::LEN * MAX_FEE_HOLIDAYS + 32 + RevenueLedger::LEN + 32 + 2 + 8 + 8 + 8 + 2 + Vamm::LEN + 1 + 1 + 8 + 2 + 2 + 8 + 2 + 8 + 1 + 2;
+
+    /// A guardian pause lapses at `paused_until` unless the authority has
+    /// ratified it, in which case it holds until explicitly lifted.
+    /// An expired market stays frozen for good.
+    pub fn is_paused(&self, now: i64) -> bool {
+        self.pause_ratified || now < self.paused_until || self.status == MarketStatus::Expired
+    }
+
+    /// Whether the cooldown after the last guardian pause has passed. Lifting
+    /// a pause resets `paused_until`, which ends the cooldown as well.
+    pub fn guardian_pause_available(&self, now: i64) -> bool {
+        self.paused_until == 0 || now >= self.paused_until.saturating_add(self.guardian_pause_duration)
+    }
+
+    /// While any reduce-only reason is active, or the market is settling,
+    /// new risk cannot be opened.
+    pub fn is_reduce_only(&self) -> bool {
+        self.reduce_only_flags != 0 || self.status != MarketStatus::Active
+    }
+
+    /// Leverage cap in force at `now`, following the listing ramp if one is set.
+    pub fn current_max_leverage(&self, now: i64) -> u8 {
+        self.leverage_ramp.max_leverage(self.max_leverage, now - self.listed_at, self.total_volume)
+    }
+
+    /// Expiry time for a position opened at `now`, or 0 if positions never expire.
+    pub fn position_expiry(&self, now: i64) -> i64 {
+        if self.max_position_age > 0 {
+            now.saturating_add(self.max_position_age)
+        } else {
+            0
+        }
+    }
+
+    /// Adds a trading fee paid by a `side` position on a `kind` order to the
+    /// market's accrued fees, setting aside `insurance_fee_share_bps` of it
+    /// for the insurance fund and `protocol_fee_share_bps` of the rest for
+    /// the treasury, and books it as maker or taker revenue.
+    pub fn accrue_fee(&mut self, kind: FeeKind, side: Side, role: FeeRole, fee: u64, now: i64) -> Result<()> {
+        self.fee_accrual.record(kind, side, role, fee);
+        if !self.paper_trading {
+            let source = match role {
+                FeeRole::Taker => RevenueSource::TakerFees,
+                FeeRole::Maker => RevenueSource::MakerFees,
+            };
+            self.revenue.record(now, source, fee);
+        }
+        let insurance = self.insurance_fee_share(fee);
+        let protocol = self.protocol_fee_share(fee - insurance);
+        self.pending_insurance = self.pending_insurance.checked_add(insurance).ok_or(ErrorCode::MathOverflow)?;
+        self.protocol_fees_accrued = self.protocol_fees_accrued
+            .checked_add(protocol)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.total_fee_accrued = self.total_fee_accrued
+            .checked_add(fee - insurance - protocol)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Takes `amount` of a taker fee accrued in the same instruction back out,
+    /// split between the insurance, protocol and staker slices as
+    /// `accrue_fee` split it.
+    pub fn release_fee(&mut self, amount: u64, now: i64) -> Result<()> {
+        self.revenue.release(now, RevenueSource::TakerFees, amount);
+        let insurance = self.insurance_fee_share(amount);
+        let protocol = self.protocol_fee_share(amount - insurance);
+        self.pending_insurance = self.pending_insurance.checked_sub(insurance).ok_or(ErrorCode::MathOverflow)?;
+        self.protocol_fees_accrued = self.protocol_fees_accrued
+            .checked_sub(protocol)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.total_fee_accrued = self.total_fee_accrued
+            .checked_sub(amount - insurance - protocol)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn apply_fee_split(&mut self, fee_split: &FeeSplit) {
+        self.insurance_fee_share_bps = fee_split.insurance_fee_share_bps;
+        self.protocol_fee_share_bps = fee_split.protocol_fee_share_bps;
+        self.liquidation_fee_bps = fee_split.liquidation_fee_bps;
+        self.liquidator_share_bps = fee_split.liquidator_share_bps;
+    }
+
+    /// Holds the insurance fund's cut of a liquidation fee in the vault until
+    /// swept, booked as liquidation penalty revenue.
+    pub fn accrue_liquidation_fee(&mut self, insurance_fee: u64, now: i64) -> Result<()> {
+        self.pending_insurance = self.pending_insurance.checked_add(insurance_fee).ok_or(ErrorCode::MathOverflow)?;
+        self.revenue.record(now, RevenueSource::LiquidationPenalties, insurance_fee);
+        Ok(())
+    }
+
+    // Paper-trading fees are virtual and fund nothing
+    fn insurance_fee_share(&self, fee: u64) -> u64 {
+        if self.paper_trading {
+            return 0;
+        }
+        (fee as u128 * self.insurance_fee_share_bps as u128 / 10000) as u64
+    }
+
+    fn protocol_fee_share(&self, fee: u64) -> u64 {
+        if self.paper_trading {
+            return 0;
+        }
+        (fee as u128 * self.protocol_fee_share_bps as u128 / 10000) as u64
+    }
+
+    /// Liquidation fee on a position with `margin`, at most `available`,
+    /// split into the liquidator's cut and the insurance fund's.
+    /// Paper-trading markets charge none.
+    pub fn liquidation_fee(&self, margin: u64, available: u64) -> (u64, u64) {
+        if self.paper_trading {
+            return (0, 0);
+        }
+        let fee = ((margin as u128 * self.liquidation_fee_bps as u128 / 10000) as u64).min(available);
+        let to_liquidator = (fee as u128 * self.liquidator_share_bps as u128 / 10000) as u64;
+        (to_liquidator, fee - to_liquidator)
+    }
+
+    /// A keeper reward of `base` scaled by the congestion multiplier.
+    pub fn keeper_reward(&self, base: u64) -> u64 {
+        (base as u128 * self.keeper_reward_multiplier_bps as u128 / 10000).min(u64::MAX as u128) as u64
+    }
+
+    /// Vault balance the market trades against: the token vault, or the
+    /// virtual vault of a paper-trading market.
+    pub fn vault_balance(&self, token_vault_amount: u64) -> u64 {
+        if self.paper_trading {
+            self.paper_vault
+        } else {
+            token_vault_amount
+        }
+    }
+
+    /// What a trader can pay into this market with: their token balance, or
+    /// the sub-account's virtual balance on a paper-trading market.
+    pub fn trader_balance(&self, margin_account: &MarginAccount, token_amount: u64) -> u64 {
+        if self.paper_trading {
+            margin_account.paper_balance
+        } else {
+            token_amount
+        }
+    }
+
+    /// Moves virtual quote from a sub-account into the paper vault.
+    pub fn collect_paper(&mut self, margin_account: &mut MarginAccount, amount: u64) -> Result<()> {
+        margin_account.paper_balance = margin_account.paper_balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::InsufficientCollateral)?;
+        self.paper_vault = self.paper_vault.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Moves virtual quote from the paper vault back to a sub-account.
+    pub fn pay_paper(&mut self, margin_account: &mut MarginAccount, amount: u64) -> Result<()> {
+        self.paper_vault = self.paper_vault
+            .checked_sub(amount)
+            .ok_or(ErrorCode::InsufficientVaultBalance)?;
+        margin_account.paper_balance = margin_account.paper_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn oracle_failover_active(&self) -> bool {
+        self.reduce_only_flags & REDUCE_ONLY_ORACLE_FAILOVER != 0
+    }
+
+    /// Whether `price_feed` is the market's configured oracle, or its fallback
+    /// while failed over. A market prices nothing until its oracle is set.
+    pub fn is_configured_oracle(&self, price_feed: &Pubkey) -> bool {
+        let oracle = if self.oracle_failover_active() { self.fallback_oracle } else { self.oracle };
+        oracle != Pubkey::default() && *price_feed == oracle
+    }
+
+    /// `is_configured_oracle` for price feeds passed outside the accounts
+    /// struct, such as in remaining accounts.
+    pub fn check_configured_oracle(&self, price_feed: &Pubkey) -> Result<()> {
+        require!(self.is_configured_oracle(price_feed), ErrorCode::InvalidOracle);
+        Ok(())
+    }
+
+    /// Counts traded notional, enforcing the market's rolling notional cap.
+    pub fn record_volume(&mut self, now: i64, notional: u64) -> Result<()> {
+        self.notional_cap.record(now, notional)?;
+        self.total_volume = self.total_volume.saturating_add(notional);
+        self.volume_window.record(now, notional);
+        Ok(())
+    }
+
+    /// Fee for closing `notional` of a position. Closes are free while the
+    /// market is settling, so traders can leave a delisted market at no cost.
+    pub fn close_fee(&self, notional: u64, now: i64) -> u64 {
+        if self.status == MarketStatus::Settling {
+            return 0;
+        }
+        (notional as u128 * self.holiday_fee_bps(self.close_fee_bps, now) as u128 / math::BPS) as u64
+    }
+
+    /// Fee escrowed with a resting limit order of `notional`. Makers with a
+    /// rebate pay nothing up front.
+    pub fn maker_fee(&self, notional: u64, now: i64) -> u64 {
+        if self.maker_fee_bps <= 0 {
+            return 0;
+        }
+        (notional as u128 * self.holiday_fee_bps(self.maker_fee_bps as u16, now) as u128 / math::BPS) as u64
+    }
+
+    /// Rebate owed to a maker filled for `notional`, at most the fees the
+    /// market has accrued to pay it from.
+    pub fn maker_rebate(&self, notional: u64) -> u64 {
+        if self.maker_fee_bps >= 0 {
+            return 0;
+        }
+        let rebate = notional as u128 * self.maker_fee_bps.unsigned_abs() as u128 / math::BPS;
+        rebate.min(self.total_fee_accrued as u128) as u64
+    }
+
+    /// Takes a maker rebate credited to a position's margin out of the
+    /// accrued fees; maker revenue for the epoch is booked net of it.
+    pub fn pay_maker_rebate(&mut self, rebate: u64, now: i64) {
+        self.total_fee_accrued -= rebate;
+        self.fee_accrual.record_rebate(rebate);
+        self.revenue.release(now, RevenueSource::MakerFees, rebate);
+    }
+
+    /// Taker fee rate for an order of `notional`, including the size surcharge.
+    pub fn taker_fee_bps(&self, notional: u64, vault_depth: u64, now: i64) -> u16 {
+        let reference = match self.fee_curve.basis {
+            FeeCurveBasis::RecentVolume => self.volume_window.previous,
+            FeeCurveBasis::VaultDepth => vault_depth,
+        };
+        self.holiday_fee_bps(self.fee_bps.saturating_add(self.fee_curve.surcharge_bps(notional, reference)), now)
+    }
+
+    /// Taker fee on an order of `notional`, less the wallet's fee tier
+    /// discount of `fee_discount_bps`.
+    pub fn taker_fee(&self, notional: u64, vault_depth: u64, fee_discount_bps: u16, now: i64) -> u64 {
+        let fee_bps = self.taker_fee_bps(notional, vault_depth, now);
+        let fee_bps = fee_bps - (fee_bps as u32 * fee_discount_bps as u32 / 10000) as u16;
+        ((notional as u128 * fee_bps as u128) / 10000) as u64
+    }
+
+    /// `fee_bps` less the largest discount of the fee holidays running at
+    /// `now`, when holidays overlap.
+    pub fn holiday_fee_bps(&self, fee_bps: u16, now: i64) -> u16 {
+        let discount_bps = self.fee_holidays.iter()
+            .filter(|holiday| holiday.is_active(now))
+            .map(|holiday| holiday.discount_bps)
+            .max()
+            .unwrap_or(0);
+        fee_bps - (fee_bps as u32 * discount_bps as u32 / 10000) as u16
+    }
+
+    /// Loads an oracle price normalized to the market's price precision,
+    /// rejecting one older than the market's staleness tolerance. Reading it
+    /// also rejects a confidence wider than the market allows.
+    pub fn load_price_feed(&self, price_feed: &AccountInfo) -> Result<PriceFeed> {
+        Ok(PriceFeed::load(
+            self.oracle_source_for(price_feed.key),
+            price_feed,
+            Clock::get()?.unix_timestamp,
+            self.max_oracle_staleness_secs,
+        )?
+        .with_precision(self.price_decimals, self.price_rounding)
+        .with_confidence(self.max_oracle_conf_bps, self.oracle_conf_multiplier_bps))
+    }
+
+    /// Source of one of the market's feeds: the fallback, the feed queued to
+    /// replace the primary, or else the primary.
+    pub fn oracle_source_for(&self, price_feed: &Pubkey) -> OracleSource {
+        if *price_feed == self.fallback_oracle {
+            self.fallback_oracle_source
+        } else if *price_feed == self.oracle_rotation.pending_oracle {
+            self.oracle_rotation.pending_source
+        } else {
+            self.oracle_source
+        }
+    }
+
+    /// Net unrealized PnL of all traders at `current_price` under the
+    /// base-size model; positive when traders are owed money overall.
+    pub fn net_trader_pnl(&self, current_price: u64) -> Result<i64> {
+        let long_value = self.long_open_interest as i128 * current_price as i128;
+        let short_value = self.short_open_interest as i128 * current_price as i128;
+        let net = (long_value - self.long_entry_notional as i128) + (self.short_entry_notional as i128 - short_value);
+        i64::try_from(net).map_err(|_| error!(ErrorCode::MathOverflow))
+    }
+
+    /// PnL is `base_size * price change`; leverage only affects margin.
+    pub fn position_pnl(&self, position: &Position, current_price: u64) -> Result<i64> {
+        calculate_pnl(position.side, position.base_size, position.entry_price, current_price)
+    }
+
+    /// Price at which `position`'s loss equals its margin.
+    pub fn bankruptcy_price(&self, position: &Position) -> u64 {
+        if position.base_size == 0 {
+            return position.entry_price;
+        }
+        // Round the move down so the margin covers the loss up to this price
+        let margin_move = position.margin as u128 / position.base_size as u128;
+        math::bankruptcy_price(
+            position.side == Side::Long,
+            position.entry_price,
+            margin_move.min(u64::MAX as u128) as u64,
+        )
+    }
+
+    /// Long open interest less short, in base units.
+    pub fn net_open_interest(&self) -> i64 {
+        (self.long_open_interest as i128 - self.short_open_interest as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    pub fn open_interest(&self, side: Side) -> u64 {
+        match side {
+            Side::Long => self.long_open_interest,
+            Side::Short => self.short_open_interest,
+        }
+    }
+
+    /// Price closing `size` of a `side` position fills at with the oracle at
+    /// `price`: the vAMM's exit price where it is enabled, else `price`
+    /// itself. Read before the close takes its size out of open interest.
+    pub fn exit_price(&self, side: Side, size: u64, price: u64) -> Result<u64> {
+        if !self.vamm.is_enabled() {
+            return Ok(price);
+        }
+        self.vamm.exit_price(price, self.net_open_interest(), side, size)
+    }
+
+    /// Equity of `position` at `current_price`, margin plus PnL and
+    /// unsettled funding, in bps of its value. `u64::MAX` for an empty position.
+    pub fn position_health_bps(&self, position: &Position, current_price: u64) -> Result<u64> {
+        let value = position.base_size as u128 * current_price as u128;
+        if value == 0 {
+            return Ok(u64::MAX);
+        }
+        let funding = self.unsettled_funding(position);
+        let equity = position.margin as i128 + self.position_pnl(position, current_price)? as i128 + funding as i128
+            - position.deferred_funding as i128;
+        Ok((equity.max(0) as u128 * math::BPS / value).min(u64::MAX as u128) as u64)
+    }
+
+    /// Entry notional of all open positions, both sides together.
+    pub fn open_interest_notional(&self) -> u64 {
+        self.long_entry_notional.saturating_add(self.short_entry_notional)
+    }
+
+    /// Brings mining rewards up to `now`. Must run before open interest changes.
+    pub fn accrue_mining(&mut self, now: i64) -> Result<()> {
+        self.mining.accrue(now, self.long_open_interest, self.short_open_interest)
+    }
+
+    /// Cumulative funding paid per unit of entry notional by a position on
+    /// `side`, in bps. Negative while that side has been receiving.
+    pub fn cumulative_funding(&self, side: Side) -> i64 {
+        match side {
+            Side::Long => self.cumulative_funding_long,
+            Side::Short => self.cumulative_funding_short,
+        }
+    }
+
+    /// Applies one funding round at `funding_rate` bps: longs pay it and
+    /// shorts receive it when positive, the other way round when negative.
+    /// No position is touched; each settles the change in its side's index
+    /// the next time it is.
+    pub fn apply_funding_rate(&mut self, funding_rate: i64) -> Result<()> {
+        self.cumulative_funding_long = self.cumulative_funding_long
+            .checked_add(funding_rate)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.cumulative_funding_short = self.cumulative_funding_short
+            .checked_sub(funding_rate)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Funding `position` has received since it last settled, negative when
+    /// it owes, not counting what was deferred.
+    pub fn unsettled_funding(&self, position: &Position) -> i64 {
+        if position.funding_exempt {
+            return 0;
+        }
+        let paid = self.cumulative_funding(position.side).saturating_sub(position.funding_index);
+        math::funding_payment(position.notional, paid, true)
+    }
+
+    /// Charges `position` the funding rounds applied since it was last
+    /// settled, from the change in its side's cumulative funding index.
+    /// Funding owed beyond the margin leaves the position with none; charges
+    /// above the market's per-interval cap are deferred to later rounds.
+    /// Hedger positions only move their index forward.
+    pub fn settle_funding(&mut self, position: &mut Position) -> Result<()> {
+        let funding_index = self.cumulative_funding(position.side);
+        let owes = funding_index != position.funding_index || position.deferred_funding > 0;
+        if owes && position.base_size > 0 && !position.funding_exempt {
+            let amount = self.unsettled_funding(position)
+                .checked_sub(position.deferred_funding as i64)
+                .ok_or(ErrorCode::MathOverflow)?;
+            let amount = self.cap_funding_payment(position, amount);
+            let margin = position.margin;
+            position.margin = match math::apply_funding(position.margin, amount) {
+                Some(margin) => margin,
+                None if amount < 0 => 0,
+                None => return err!(ErrorCode::MathOverflow),
+            };
+            self.settle_margin_change(margin, position.margin);
+            position.total_funding_paid = position.total_funding_paid.saturating_sub(amount);
+        }
+        position.funding_index = funding_index;
+        position.last_funding_timestamp = self.last_funding_time;
+        Ok(())
+    }
+
+    /// Charges the deferred funding that goes with closing `size` of
+    /// `position`; see `Position::charge_deferred_funding`.
+    pub fn charge_deferred_funding(&mut self, position: &mut Position, size: u64) {
+        let margin = position.margin;
+        position.charge_deferred_funding(size);
+        self.settle_margin_change(margin, position.margin);
+    }
+
+    /// Funding moves margin without moving tokens, so it counts as PnL
+    /// settled to the trader.
+    fn settle_margin_change(&mut self, before: u64, after: u64) {
+        let change = after as i64 - before as i64;
+        self.total_margin = (self.total_margin as i64).saturating_add(change).max(0) as u64;
+        self.trader_realized_pnl = self.trader_realized_pnl.saturating_add(change);
+    }
+
+    /// Records the PnL a closing position realized against the vault.
+    pub fn realize_pnl(&mut self, pnl: i64) {
+        self.trader_realized_pnl = self.trader_realized_pnl.saturating_add(pnl);
+    }
+
+    /// What the vault must hold by the market's own accounting: the margin
+    /// of open positions, `escrowed` order collateral and both fee buckets, less
+    /// the net PnL and funding already settled to traders, plus what the LP
+    /// vault has settled in.
+    pub fn required_vault_balance(&self, escrowed: u64) -> u64 {
+        let required = self.total_margin as i128 + escrowed as i128 + self.total_fee_accrued as i128
+            + self.protocol_fees_accrued as i128 + self.pending_insurance as i128 - self.trader_realized_pnl as i128 + self.lp_settled_pnl as i128;
+        required.clamp(0, u64::MAX as i128) as u64
+    }
+
+    /// Trader PnL realized since the LP vault last settled; positive when
+    /// the LP vault owes the market vault.
+    pub fn lp_unsettled_pnl(&self) -> i64 {
+        self.trader_realized_pnl.saturating_sub(self.lp_pnl_checkpoint)
+    }
+
+    /// Fails if `open_interest` on one side, valued at `price`, would use
+    /// more than `lp_max_utilization_bps` of `lp_equity`.
+    pub fn check_lp_utilization(&self, lp_equity: u64, open_interest: u64, price: u64) -> Result<()> {
+        if self.lp_max_utilization_bps == 0 {
+            return Ok(());
+        }
+        let notional = open_interest as u128 * price as u128;
+        require!(
+            notional * 10000 <= lp_equity as u128 * self.lp_max_utilization_bps as u128,
+            ErrorCode::LpUtilizationExceeded
+        );
+        Ok(())
+    }
+
+    /// Halts the market and emits `SolvencyViolation` if the vault holds
+    /// less than its tracked liabilities. The halt holds until the authority
+    /// unpauses the market. Returns whether the vault is solvent.
+    pub fn check_solvency(&mut self, market_key: Pubkey, vault_amount: u64, escrowed: u64, now: i64) -> bool {
+        let vault_balance = self.vault_balance(vault_amount);
+        let required = self.required_vault_balance(escrowed);
+        if vault_balance >= required {
+            return true;
+        }
+        self.paused_until = now;
+        self.pause_ratified = true;
+        emit!(SolvencyViolation {
+            market: market_key,
+            vault_balance,
+            required,
+            timestamp: now,
+        });
+        false
+    }
+
+    /// Limits a funding charge to `max_funding_payment_bps` of the margin for
+    /// each funding round since the position last settled, and defers the
+    /// rest to later rounds. Nothing new is charged within the same round.
+    fn cap_funding_payment(&self, position: &mut Position, amount: i64) -> i64 {
+        if self.max_funding_payment_bps == 0 || amount >= 0 {
+            position.deferred_funding = 0;
+            return amount;
+        }
+        let elapsed = self.last_funding_time.saturating_sub(position.last_funding_timestamp);
+        let rounds = if elapsed > 0 { (elapsed / self.funding_interval.max(1)).max(1) } else { 0 };
+        let cap = position.margin as u128 * self.max_funding_payment_bps as u128 * rounds as u128 / 10000;
+        let charge = (amount.unsigned_abs() as u128).min(cap) as u64;
+        position.deferred_funding = amount.unsigned_abs() - charge;
+        -(charge as i64)
+    }
+
+    /// Adds `size` at `price` with `margin` posted to `position`, after
+    /// settling its funding and mining rewards, and counts it in the
+    /// market's open interest.
+    pub fn open_position(&mut self, position: &mut Position, size: u64, price: u64, margin: u64, now: i64) -> Result<()> {
+        self.add_position(position, size, price, margin, now)?;
+        let notional = size.checked_mul(price).ok_or(ErrorCode::MathOverflow)?;
+        position.pending_rewards = position.pending_rewards
+            .checked_add(self.mining.volume_reward(notional)?)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// `open_position` without the volume reward, for size that was not
+    /// traded, such as a position migrated from another market.
+    pub fn add_position(&mut self, position: &mut Position, size: u64, price: u64, margin: u64, now: i64) -> Result<()> {
+        self.accrue_mining(now)?;
+        self.settle_funding(position)?;
+        self.mining.settle_position(position)?;
+        let notional = size.checked_mul(price).ok_or(ErrorCode::MathOverflow)?;
+        if position.base_size == 0 {
+            position.creation_time = now;
+            position.expires_at = self.position_expiry(now);
+        }
+        position.increase(size, price, margin, self.liquidation_threshold)?;
+        self.total_margin = self.total_margin.checked_add(margin).ok_or(ErrorCode::MathOverflow)?;
+
+        let (open_interest, entry_notional) = match position.side {
+            Side::Long => (&mut self.long_open_interest, &mut self.long_entry_notional),
+            Side::Short => (&mut self.short_open_interest, &mut self.short_entry_notional),
+        };
+        *open_interest = open_interest.checked_add(size).ok_or(ErrorCode::MathOverflow)?;
+        *entry_notional = entry_notional.checked_add(notional).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Tops up `position` with `amount` of margin after settling its funding,
+    /// moving its liquidation price away from the market.
+    pub fn add_margin(&mut self, position: &mut Position, amount: u64) -> Result<()> {
+        self.settle_funding(position)?;
+        position.margin = position.margin.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        self.total_margin = self.total_margin.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        position.refresh_liquidation_price(self.liquidation_threshold);
+        Ok(())
+    }
+
+    /// Takes `amount` of margin out of `position` after settling its
+    /// funding. The rest must still cover the initial margin of the
+    /// position's leverage plus any deferred funding, and keep its equity at
+    /// `current_price` above `maintenance_margin_fraction` of its value, so
+    /// the position is not left liquidatable.
+    pub fn remove_margin(&mut self, position: &mut Position, amount: u64, current_price: u64) -> Result<()> {
+        self.settle_funding(position)?;
+        let initial_margin = position.notional / position.leverage.max(1) as u64;
+        let margin = position.margin
+            .checked_sub(amount)
+            .filter(|margin| *margin >= initial_margin.saturating_add(position.deferred_funding))
+            .ok_or(ErrorCode::InsufficientCollateral)?;
+
+        let equity = margin as i128 + self.position_pnl(position, current_price)? as i128;
+        let value = position.base_size as u128 * current_price as u128;
+        require!(
+            equity.max(0) as u128 * math::BPS >= value * self.maintenance_margin_fraction as u128,
+            ErrorCode::InsufficientCollateral
+        );
+
+        position.margin = margin;
+        self.total_margin = self.total_margin.saturating_sub(amount);
+        position.refresh_liquidation_price(self.liquidation_threshold);
+        require!(!position.is_liquidatable(current_price), ErrorCode::InsufficientCollateral);
+        Ok(())
+    }
+
+    /// Takes a closed `size` with its entry `notional` out of open interest,
+    /// and the `margin` that backed it out of the market's total.
+    pub fn remove_open_interest(&mut self, side: Side, size: u64, notional: u64, margin: u64) {
+        self.total_margin = self.total_margin.saturating_sub(margin);
+        let (open_interest, entry_notional) = match side {
+            Side::Long => (&mut self.long_open_interest, &mut self.long_entry_notional),
+            Side::Short => (&mut self.short_open_interest, &mut self.short_entry_notional),
+        };
+        *open_interest = open_interest.saturating_sub(size);
+        *entry_notional = entry_notional.saturating_sub(notional);
+    }
+}
+
+/// How resting orders at the same price share a fill.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum MatchingPolicy {
+    /// Strict price-time priority
+    PriceTime,
+    /// Fills at a price level are split in proportion to resting size
+    ProRata,
+}
+
+/// Lifecycle of a market.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum MarketStatus {
+    Active,
+    /// Winding down ahead of a redeploy: no new risk, and open positions
+    /// can be exported to the successor market
+    Settling,
+    /// Delisted at `settlement_price`: trading is frozen while keepers
+    /// settle the open positions, after which the market can be closed
+    Expired,
+}
+
+/// Parameter changes for `update_market_params`; `None` leaves a field as
+/// it is.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct MarketParamsUpdate {
+    pub min_base_order_size: Option<u64>,
+    pub tick_size: Option<u64>,
+    pub max_leverage: Option<u8>,
+    pub liquidation_threshold: Option<u16>,  // in bps
+    pub maintenance_margin_fraction: Option<u16>,  // in bps
+    pub max_position_size: Option<u64>,
+    pub max_oracle_staleness_secs: Option<i64>,
+}
+
+/// Trading status the market authority sets with `set_market_status`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum TradingStatus {
+    Active,
+    /// Orders may only reduce positions; liquidations and funding continue
+    ReduceOnly,
+    /// No orders, liquidations or funding updates
+    Paused,
+}
+
+/// Where a position's margin would go if it were liquidated now, as
+/// returned by `view_liquidation_outcome`. A `shortfall` is bad debt left
+/// for the insurance fund and backstop to cover.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct LiquidationOutcome {
+    pub liquidatable: bool,
+    pub oracle_price: u64,
+    pub exit_price: u64,  // the bankruptcy price when the oracle gapped past it
+    pub liquidator_fee: u64,
+    pub insurance_fee: u64,
+    pub owner_amount: u64,
+    pub shortfall: u64,
+}
+
+#[derive(Accounts)]
+pub struct InitializeMarket<'info> {
+    #[account(init, payer = authority, space = Market::LEN)]
+    pub market: Account<'info, Market>,
+    #[account(token::authority = vault_authority)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeMarketFromTemplate<'info> {
+    #[account(init, payer = authority, space = Market::LEN)]
+    pub market: Account<'info, Market>,
+    #[account(token::authority = vault_authority)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ConfigureMining<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    pub authority: Signer<'info>,
+    #[account(token::authority = vault_authority)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct ClaimMiningRewards<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, address = market.mining.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = reward_vault.mint)]
+    pub owner_reward_account: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeStakePool<'info> {
+    #[account(has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = authority,
+        space = StakePool::LEN,
+        seeds = [b"stake_pool", market.key().as_ref()],
+        bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(token::authority = vault_authority)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    #[account(token::authority = vault_authority)]
+    pub distribution_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    #[account(mut, has_one = stake_vault)]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = StakerAccount::LEN,
+        seeds = [b"staker", stake_pool.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub staker: Account<'info, StakerAccount>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Unstake<'info> {
+    #[account(mut, has_one = stake_vault)]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        mut,
+        has_one = owner @ ErrorCode::Unauthorized,
+        seeds = [b"staker", stake_pool.key().as_ref(), owner.key().as_ref()],
+        bump = staker.bump
+    )]
+    pub staker: Account<'info, StakerAccount>,
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", stake_pool.market.as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RollEpoch<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        has_one = market,
+        has_one = distribution_vault,
+        seeds = [b"stake_pool", market.key().as_ref()],
+        bump = stake_pool.bump
+    )]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        init,
+        payer = cranker,
+        space = EpochDistribution::LEN,
+        seeds = [b"epoch", stake_pool.key().as_ref(), &stake_pool.current_epoch.to_le_bytes()],
+        bump
+    )]
+    pub epoch_distribution: Account<'info, EpochDistribution>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub distribution_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub cranker: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimEpochFees<'info> {
+    #[account(has_one = distribution_vault)]
+    pub stake_pool: Account<'info, StakePool>,
+    #[account(
+        mut,
+        has_one = owner @ ErrorCode::Unauthorized,
+        constraint = staker.pool == stake_pool.key() @ ErrorCode::InvalidMarketState
+    )]
+    pub staker: Account<'info, StakerAccount>,
+    #[account(
+        mut,
+        seeds = [b"epoch", stake_pool.key().as_ref(), &staker.next_claim_epoch.to_le_bytes()],
+        bump = epoch_distribution.bump
+    )]
+    pub epoch_distribution: Account<'info, EpochDistribution>,
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub distribution_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", stake_pool.market.as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeTreasury<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        init,
+        payer = admin,
+        space = Treasury::LEN,
+        seeds = [b"treasury"],
+        bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+    #[account(token::authority = treasury)]
+    pub lock_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct LockTokens<'info> {
+    #[account(seeds = [b"treasury"], bump = treasury.bump, has_one = lock_vault)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = VoteLock::LEN,
+        seeds = [b"vote_lock", owner.key().as_ref()],
+        bump
+    )]
+    pub vote_lock: Account<'info, VoteLock>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub lock_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnlockTokens<'info> {
+    #[account(seeds = [b"treasury"], bump = treasury.bump, has_one = lock_vault)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(
+        mut,
+        has_one = owner @ ErrorCode::Unauthorized,
+        seeds = [b"vote_lock", owner.key().as_ref()],
+        bump = vote_lock.bump
+    )]
+    pub vote_lock: Account<'info, VoteLock>,
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub lock_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeSpend<'info> {
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(
+        init,
+        payer = proposer,
+        space = SpendProposal::LEN,
+        seeds = [b"spend_proposal".as_ref(), &treasury.proposal_count.to_le_bytes()],
+        bump
+    )]
+    pub proposal: Account<'info, SpendProposal>,
+    /// Any treasury-owned vault except the one holding locked vote tokens
+    #[account(
+        token::authority = treasury,
+        constraint = source_vault.key() != treasury.lock_vault @ ErrorCode::InvalidVault
+    )]
+    pub source_vault: Account<'info, TokenAccount>,
+    #[account(token::mint = source_vault.mint)]
+    pub destination: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct VoteOnSpend<'info> {
+    #[account(mut)]
+    pub proposal: Account<'info, SpendProposal>,
+    #[account(
+        seeds = [b"vote_lock", voter.key().as_ref()],
+        bump = vote_lock.bump
+    )]
+    pub vote_lock: Account<'info, VoteLock>,
+    #[account(
+        init,
+        payer = voter,
+        space = VoteRecord::LEN,
+        seeds = [b"vote", proposal.key().as_ref(), voter.key().as_ref()],
+        bump
+    )]
+    pub vote_record: Account<'info, VoteRecord>,
+    #[account(mut)]
+    pub voter: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveSpendAsCouncil<'info> {
+    #[account(seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(mut)]
+    pub proposal: Account<'info, SpendProposal>,
+    pub council_member: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeSpendVote<'info> {
+    #[account(seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(mut)]
+    pub proposal: Account<'info, SpendProposal>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteSpend<'info> {
+    #[account(seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(mut, has_one = source_vault, has_one = destination)]
+    pub proposal: Account<'info, SpendProposal>,
+    #[account(mut)]
+    pub source_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(side: Side, size: u64, min_fill_size: u64, price: u64, max_slippage_bps: u16, leverage: u8, sub_account_id: u16)]
+pub struct PlaceOrder<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        init,
+        payer = user,
+        space = Position::LEN,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &margin_account.next_position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    /// Required when the market enforces a mark/index deviation cap
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    /// Required when the sub-account has trade history enabled
+    #[account(
+        mut,
+        seeds = [b"trade_history", margin_account.key().as_ref(), &trade_history.page_index.to_le_bytes()],
+        bump = trade_history.bump
+    )]
+    pub trade_history: Option<Account<'info, TradeHistoryPage>>,
+    /// Integrator the order is routed through, if any
+    #[account(mut, seeds = [b"integrator", integrator.key.as_ref()], bump = integrator.bump)]
+    pub integrator: Option<Account<'info, Integrator>>,
+    #[account(mut)]
+    pub integrator_fee_account: Option<Account<'info, TokenAccount>>,
+    /// Launch-phase seat, required while the market has a trader cap
+    #[account(
+        seeds = [b"launch_registration", market.key().as_ref(), margin_account.key().as_ref()],
+        bump = launch_registration.bump
+    )]
+    pub launch_registration: Option<Account<'info, LaunchRegistration>>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+    /// The wallet's volume stats, for a fee tier discount
+    #[account(mut, seeds = [b"trader_stats", user.key().as_ref()], bump = trader_stats.bump)]
+    pub trader_stats: Option<Account<'info, TraderStats>>,
+    /// Referral code the order was referred by, if any
+    #[account(mut, seeds = [b"referral_code", referral_code.code.as_ref()], bump = referral_code.bump)]
+    pub referral_code: Option<Account<'info, ReferralCode>>,
+    /// Required with a referral code
+    #[account(mut, address = protocol_config.referral_vault @ ErrorCode::ReferralsDisabled)]
+    pub referral_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(size: u64, min_fill_size: u64, price: u64, sub_account_id: u16)]
+pub struct IncreasePosition<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    /// Required when the market enforces a mark/index deviation cap
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    /// Required when the sub-account has trade history enabled
+    #[account(
+        mut,
+        seeds = [b"trade_history", margin_account.key().as_ref(), &trade_history.page_index.to_le_bytes()],
+        bump = trade_history.bump
+    )]
+    pub trade_history: Option<Account<'info, TradeHistoryPage>>,
+    /// Integrator the order is routed through, if any
+    #[account(mut, seeds = [b"integrator", integrator.key.as_ref()], bump = integrator.bump)]
+    pub integrator: Option<Account<'info, Integrator>>,
+    #[account(mut)]
+    pub integrator_fee_account: Option<Account<'info, TokenAccount>>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+    /// The wallet's volume stats, for a fee tier discount
+    #[account(mut, seeds = [b"trader_stats", user.key().as_ref()], bump = trader_stats.bump)]
+    pub trader_stats: Option<Account<'info, TraderStats>>,
+    /// Referral code the order was referred by, if any
+    #[account(mut, seeds = [b"referral_code", referral_code.code.as_ref()], bump = referral_code.bump)]
+    pub referral_code: Option<Account<'info, ReferralCode>>,
+    /// Required with a referral code
+    #[account(mut, address = protocol_config.referral_vault @ ErrorCode::ReferralsDisabled)]
+    pub referral_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct MultiOrderLeg {
+    pub side: Side,
+    pub size: u64,
+    pub price: u64,
+    pub leverage: u8,
+    pub tag: [u8; 32],
+}
+
+#[derive(Accounts)]
+#[instruction(orders: Vec<MultiOrderLeg>, sub_account_id: u16)]
+pub struct PlaceOrdersMulti<'info> {
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    /// Required when the sub-account has trade history enabled
+    #[account(
+        mut,
+        seeds = [b"trade_history", margin_account.key().as_ref(), &trade_history.page_index.to_le_bytes()],
+        bump = trade_history.bump
+    )]
+    pub trade_history: Option<Account<'info, TradeHistoryPage>>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidatePosition<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    /// Sub-account that owns the liquidated position
+    #[account(
+        mut,
+        seeds = [
+            b"margin_account",
+            margin_account.authority.as_ref(),
+            &margin_account.sub_account_id.to_le_bytes(),
+        ],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, token::authority = margin_account.authority)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// Receives the liquidator's share of the liquidation fee
+    #[account(mut, token::mint = market_vault.mint)]
+    pub liquidator_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    /// Insurance accounts, required when the liquidation leaves a shortfall
+    #[account(mut, seeds = [b"insurance_fund", market.key().as_ref()], bump = insurance_fund.bump)]
+    pub insurance_fund: Option<Account<'info, InsuranceFund>>,
+    #[account(mut)]
+    pub insurance_vault: Option<Account<'info, TokenAccount>>,
+    #[account(mut, seeds = [b"insurance_backstop"], bump = insurance_backstop.bump)]
+    pub insurance_backstop: Option<Account<'info, InsuranceBackstop>>,
+    #[account(mut)]
+    pub backstop_vault: Option<Account<'info, TokenAccount>>,
+    pub liquidator: Signer<'info>,
+    /// Liquidator's stake in the market's fee staking pool, required while
+    /// a cascade reserves liquidations for staked keepers
+    pub keeper_stake: Option<Account<'info, StakerAccount>>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeParamChange<'info> {
+    #[account(mut, seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = proposer,
+        space = ParamProposal::LEN,
+        seeds = [b"param_proposal".as_ref(), &treasury.proposal_count.to_le_bytes()],
+        bump
+    )]
+    pub proposal: Account<'info, ParamProposal>,
+    #[account(
+        seeds = [b"vote_lock", proposer.key().as_ref()],
+        bump = vote_lock.bump
+    )]
+    pub vote_lock: Account<'info, VoteLock>,
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct VoteOnParamChange<'info> {
+    #[account(mut)]
+    pub proposal: Account<'info, ParamProposal>,
+    #[account(
+        seeds = [b"vote_lock", voter.key().as_ref()],
+        bump = vote_lock.bump
+    )]
+    pub vote_lock: Account<'info, VoteLock>,
+    #[account(
+        init,
+        payer = voter,
+        space = VoteRecord::LEN,
+        seeds = [b"vote", proposal.key().as_ref(), voter.key().as_ref()],
+        bump
+    )]
+    pub vote_record: Account<'info, VoteRecord>,
+    #[account(mut)]
+    pub voter: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct QueueParamChange<'info> {
+    #[account(seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(mut, has_one = market)]
+    pub proposal: Account<'info, ParamProposal>,
+}
+
+#[derive(Accounts)]
+pub struct ApplyParamChanges<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+}
+
+#[derive(Accounts)]
+pub struct MarketAdmin<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PauseMarket<'info> {
+    #[account(mut, has_one = guardian @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    pub guardian: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeOrderBook<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = authority,
+        space = OrderBook::LEN,
+        seeds = [b"order_book", market.key().as_ref()],
+        bump
+    )]
+    pub order_book: Account<'info, OrderBook>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(side: Side, price: u64, size: u64, leverage: u8, sub_account_id: u16)]
+pub struct PlaceLimitOrder<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut, seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Account<'info, OrderBook>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        init,
+        payer = user,
+        space = Position::LEN,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &margin_account.next_position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    /// Launch-phase seat, required while the market has a trader cap
+    #[account(
+        seeds = [b"launch_registration", market.key().as_ref(), margin_account.key().as_ref()],
+        bump = launch_registration.bump
+    )]
+    pub launch_registration: Option<Account<'info, LaunchRegistration>>,
+}
+
+#[derive(Accounts)]
+#[instruction(side: Side, order_id: u64, sub_account_id: u16)]
+pub struct CancelOrder<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(mut, seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Account<'info, OrderBook>,
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    /// Position the order fills into; closed with the order if nothing filled
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ViewBookDepth<'info> {
+    #[account(seeds = [b"order_book", order_book.market.as_ref()], bump = order_book.bump)]
+    pub order_book: Account<'info, OrderBook>,
+}
+
+#[derive(Accounts)]
+pub struct MatchOrders<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut, seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Account<'info, OrderBook>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMarkDeviation<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// Required once the market has an order book, whose mid is the mark price
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct InitializeMarginAccount<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = MarginAccount::LEN,
+        seeds = [b"margin_account", authority.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct SetTradeHistory<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin_account", authority.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct OpenTradeHistoryPage<'info> {
+    #[account(
+        seeds = [b"margin_account", authority.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        init,
+        payer = authority,
+        space = TradeHistoryPage::LEN,
+        seeds = [
+            b"trade_history".as_ref(),
+            margin_account.key().as_ref(),
+            &TradeHistoryPage::page_for(margin_account.stats.history_head).to_le_bytes(),
+        ],
+        bump
+    )]
+    pub trade_history: Account<'info, TradeHistoryPage>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(expires_at: i64, sub_account_id: u16)]
+pub struct SetPositionExpiry<'info> {
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExpirePosition<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    /// Sub-account that owns the expired position
+    #[account(
+        mut,
+        seeds = [
+            b"margin_account",
+            margin_account.authority.as_ref(),
+            &margin_account.sub_account_id.to_le_bytes(),
+        ],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, token::authority = margin_account.authority)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    #[account(mut, token::authority = keeper)]
+    pub keeper_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    #[account(seeds = [b"incident_registry", market.key().as_ref()], bump = incident_registry.bump)]
+    pub incident_registry: Option<Account<'info, IncidentRegistry>>,
+    #[account(seeds = [b"funding_history", market.key().as_ref()], bump = funding_history.bump)]
+    pub funding_history: Option<Account<'info, FundingHistory>>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct SettleExpiredPosition<'info> {
+    #[account(mut, constraint = market.status == MarketStatus::Expired @ ErrorCode::MarketNotExpired)]
+    pub market: Account<'info, Market>,
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    /// Sub-account that owns the settled position
+    #[account(
+        mut,
+        seeds = [
+            b"margin_account",
+            margin_account.authority.as_ref(),
+            &margin_account.sub_account_id.to_le_bytes(),
+        ],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, token::authority = margin_account.authority)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseMarket<'info> {
+    #[account(
+        mut,
+        has_one = authority @ ErrorCode::Unauthorized,
+        constraint = market.status == MarketStatus::Expired @ ErrorCode::MarketNotExpired,
+        close = authority
+    )]
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(mut, token::authority = authority)]
+    pub authority_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    /// Receives the market's accrued fees
+    #[account(mut, address = protocol_config.treasury @ ErrorCode::InvalidTreasury)]
+    pub treasury: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// Required when the market has an order book, which must be empty
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    /// Required when the market has an LP vault, whose PnL must be settled
+    /// unless no shares are left outstanding
+    #[account(seeds = [b"lp_vault", market.key().as_ref()], bump = lp_vault.bump)]
+    pub lp_vault: Option<Account<'info, LpVault>>,
+    pub lp_share_mint: Option<Account<'info, Mint>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateOracleStatus<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// CHECK: Primary oracle, parsed in the PriceFeed implementation
+    #[account(address = market.oracle @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    /// CHECK: Fallback oracle, parsed in the PriceFeed implementation
+    #[account(address = market.fallback_oracle @ ErrorCode::InvalidOracle)]
+    pub fallback_price_feed: AccountInfo<'info>,
+}
+
+/// One execution against an order. Limit orders (`order_id` set) can fill
+/// over several matches; a market order fills once and `remaining_size` is
+/// the part that was cancelled.
+#[event]
+pub struct OrderFilled {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub tag: [u8; 32],  // caller reference given with the order
+    pub order_id: Option<u64>,
+    pub side: Side,
+    pub price: u64,
+    pub fill_size: u64,
+    pub filled_size: u64,
+    pub remaining_size: u64,
+    pub timestamp: i64,
+}
+
+/// A position closed in part or in full. `record` carries its
+/// size-weighted entry and exit prices over every fill and close so far.
+#[event]
+pub struct PositionClosed {
+    pub record: PositionRecord,
+    pub reason: CloseReason,
+    pub closed_size: u64,
+    pub remaining_size: u64,
+    pub exit_price: u64,
+}
+
+/// The authority delisted a market at a final price.
+#[event]
+pub struct MarketExpired {
+    pub market: Pubkey,
+    pub settlement_price: u64,
+    pub long_open_interest: u64,  // left to settle
+    pub short_open_interest: u64,
+    pub timestamp: i64,
+}
+
+/// A recovery key took over a market authority or the protocol admin.
+#[event]
+pub struct AuthorityRecovered {
+    pub account: Pubkey,  // market or protocol config
+    pub previous_authority: Pubkey,
+    pub new_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+/// The LP vault settled realized trader PnL with the market vault.
+#[event]
+pub struct LpPnlSettled {
+    pub market: Pubkey,
+    pub amount: i64,  // paid into the market vault; negative when paid out to the LP vault
+    pub timestamp: i64,
+}
+
+/// The protocol admin moved accrued fees from a market to the treasury.
+#[event]
+pub struct FeesWithdrawn {
+    pub market: Pubkey,
+    pub treasury: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// A proposed key accepted the market authority.
+#[event]
+pub struct AuthorityTransferred {
+    pub market: Pubkey,
+    pub previous_authority: Pubkey,
+    pub new_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+/// A liquidation where the oracle had already moved past the position's
+/// bankruptcy price. The position closed at `bankruptcy_price` and
+/// `shortfall` went to the insurance waterfall.
+#[event]
+pub struct BankruptcyLiquidation {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub tag: [u8; 32],
+    pub side: Side,
+    pub bankruptcy_price: u64,
+    pub oracle_price: u64,
+    pub shortfall: u64,
+}
+
+#[event]
+pub struct OracleFailover {
+    pub market: Pubkey,
+    pub primary_last_update: i64,
+    pub active: bool,  // false when the primary has recovered
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct OracleRotated {
+    pub market: Pubkey,
+    pub old_oracle: Pubkey,
+    pub new_oracle: Pubkey,
+    pub old_price: u64,
+    pub new_price: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PositionMigrated {
+    pub from_market: Pubkey,
+    pub to_market: Pubkey,
+    pub position: Pubkey,  // the new position on `to_market`
+    pub tag: [u8; 32],
+    pub side: Side,
+    pub base_size: u64,
+    pub entry_price: u64,
+    pub margin: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct OracleIncidentDeclared {
+    pub market: Pubkey,
+    pub start: i64,
+    pub end: i64,
+    pub timestamp: i64,
+}
+
+#[derive(Accounts)]
+pub struct ApplyOracleRotation<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// CHECK: The market's configured oracle, parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    /// CHECK: Queued oracle, parsed in the PriceFeed implementation
+    #[account(address = market.oracle_rotation.pending_oracle @ ErrorCode::InvalidOracle)]
+    pub new_price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeProtocolConfig<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = ProtocolConfig::LEN,
+        seeds = [b"protocol_config"],
+        bump
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetHedger<'info> {
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub margin_account: Account<'info, MarginAccount>,
+}
+
+#[derive(Accounts)]
+pub struct ProtocolAdmin<'info> {
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct EmergencyWithdraw<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = protocol_config.withdrawals_only @ ErrorCode::NotWithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, token::authority = owner)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeFundingHistory<'info> {
+    #[account(has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = authority,
+        space = FundingHistory::LEN,
+        seeds = [b"funding_history", market.key().as_ref()],
+        bump
+    )]
+    pub funding_history: Account<'info, FundingHistory>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ViewFundingHistory<'info> {
+    #[account(seeds = [b"funding_history", funding_history.market.as_ref()], bump = funding_history.bump)]
+    pub funding_history: Account<'info, FundingHistory>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeInsuranceFund<'info> {
+    #[account(has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = authority,
+        space = InsuranceFund::LEN,
+        seeds = [b"insurance_fund", market.key().as_ref()],
+        bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(token::authority = insurance_fund)]
+    pub insurance_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeInsuranceCoverage<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(seeds = [b"insurance_fund", market.key().as_ref()], bump = insurance_fund.bump)]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(
+        init,
+        payer = authority,
+        space = InsuranceCoverage::LEN,
+        seeds = [b"insurance_coverage", market.key().as_ref()],
+        bump
+    )]
+    pub insurance_coverage: Account<'info, InsuranceCoverage>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositInsurance<'info> {
+    #[account(mut, seeds = [b"insurance_fund", insurance_fund.market.as_ref()], bump = insurance_fund.bump)]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(mut, address = insurance_fund.vault)]
+    pub insurance_vault: Account<'info, TokenAccount>,
+    pub depositor: Signer<'info>,
+    #[account(mut)]
+    pub depositor_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    #[account(address = insurance_fund.market)]
+    pub market: Account<'info, Market>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeInsuranceBackstop<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        init,
+        payer = admin,
+        space = InsuranceBackstop::LEN,
+        seeds = [b"insurance_backstop"],
+        bump
+    )]
+    pub insurance_backstop: Account<'info, InsuranceBackstop>,
+    #[account(token::authority = insurance_backstop)]
+    pub backstop_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositBackstop<'info> {
+    #[account(mut, seeds = [b"insurance_backstop"], bump = insurance_backstop.bump)]
+    pub insurance_backstop: Account<'info, InsuranceBackstop>,
+    #[account(mut, address = insurance_backstop.vault)]
+    pub backstop_vault: Account<'info, TokenAccount>,
+    pub depositor: Signer<'info>,
+    #[account(mut)]
+    pub depositor_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeLpVault<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = authority,
+        space = LpVault::LEN,
+        seeds = [b"lp_vault", market.key().as_ref()],
+        bump
+    )]
+    pub lp_vault: Account<'info, LpVault>,
+    #[account(
+        init,
+        payer = authority,
+        space = LpSharePrice::LEN,
+        seeds = [b"lp_share_price", lp_vault.key().as_ref()],
+        bump
+    )]
+    pub share_price: Account<'info, LpSharePrice>,
+    #[account(token::authority = lp_vault)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mint::authority = lp_vault)]
+    pub share_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositLiquidity<'info> {
+    #[account(
+        seeds = [b"lp_vault", lp_vault.market.as_ref()],
+        bump = lp_vault.bump,
+        has_one = market,
+        has_one = vault,
+        has_one = share_mint
+    )]
+    pub lp_vault: Account<'info, LpVault>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    #[account(mut, seeds = [b"lp_share_price", lp_vault.key().as_ref()], bump = share_price.bump)]
+    pub share_price: Account<'info, LpSharePrice>,
+    #[account(mut)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub share_mint: Account<'info, Mint>,
+    pub provider: Signer<'info>,
+    #[account(mut, token::mint = vault.mint)]
+    pub provider_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = share_mint)]
+    pub provider_share_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawLiquidity<'info> {
+    #[account(
+        seeds = [b"lp_vault", lp_vault.market.as_ref()],
+        bump = lp_vault.bump,
+        has_one = market,
+        has_one = vault,
+        has_one = share_mint
+    )]
+    pub lp_vault: Account<'info, LpVault>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    #[account(mut, seeds = [b"lp_share_price", lp_vault.key().as_ref()], bump = share_price.bump)]
+    pub share_price: Account<'info, LpSharePrice>,
+    #[account(mut)]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub share_mint: Account<'info, Mint>,
+    pub provider: Signer<'info>,
+    #[account(mut, token::mint = vault.mint)]
+    pub provider_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = share_mint, token::authority = provider)]
+    pub provider_share_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SettleLpSharePrice<'info> {
+    #[account(
+        seeds = [b"lp_vault", lp_vault.market.as_ref()],
+        bump = lp_vault.bump,
+        has_one = market,
+        has_one = vault,
+        has_one = share_mint
+    )]
+    pub lp_vault: Account<'info, LpVault>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    #[account(mut, seeds = [b"lp_share_price", lp_vault.key().as_ref()], bump = share_price.bump)]
+    pub share_price: Account<'info, LpSharePrice>,
+    pub vault: Account<'info, TokenAccount>,
+    pub share_mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct SettleLpPnl<'info> {
+    #[account(
+        seeds = [b"lp_vault", lp_vault.market.as_ref()],
+        bump = lp_vault.bump,
+        has_one = market,
+        has_one = vault,
+        has_one = share_mint
+    )]
+    pub lp_vault: Account<'info, LpVault>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    #[account(mut, seeds = [b"lp_share_price", lp_vault.key().as_ref()], bump = share_price.bump)]
+    pub share_price: Account<'info, LpSharePrice>,
+    #[account(mut)]
+    pub vault: Account<'info, TokenAccount>,
+    pub share_mint: Account<'info, Mint>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(key: Pubkey)]
+pub struct RegisterIntegrator<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        init,
+        payer = admin,
+        space = Integrator::LEN,
+        seeds = [b"integrator", key.as_ref()],
+        bump
+    )]
+    pub integrator: Account<'info, Integrator>,
+    pub fee_destination: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateIntegrator<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut, seeds = [b"integrator", integrator.key.as_ref()], bump = integrator.bump)]
+    pub integrator: Account<'info, Integrator>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetCollateralVault<'info> {
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(token::authority = collateral_authority)]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the collateral vault
+    #[account(seeds = [b"collateral_authority"], bump)]
+    pub collateral_authority: AccountInfo<'info>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeTraderStats<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = TraderStats::LEN,
+        seeds = [b"trader_stats", authority.key().as_ref()],
+        bump
+    )]
+    pub trader_stats: Account<'info, TraderStats>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ConfigureReferrals<'info> {
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(token::authority = referral_authority)]
+    pub referral_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the referral vault
+    #[account(seeds = [b"referral_authority"], bump)]
+    pub referral_authority: AccountInfo<'info>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(code: [u8; REFERRAL_CODE_LEN])]
+pub struct CreateReferralCode<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = ReferralCode::LEN,
+        seeds = [b"referral_code", code.as_ref()],
+        bump
+    )]
+    pub referral_code: Account<'info, ReferralCode>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralRewards<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        mut,
+        seeds = [b"referral_code", referral_code.code.as_ref()],
+        bump = referral_code.bump,
+        has_one = owner @ ErrorCode::Unauthorized
+    )]
+    pub referral_code: Account<'info, ReferralCode>,
+    #[account(mut, address = protocol_config.referral_vault @ ErrorCode::ReferralsDisabled)]
+    pub referral_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the referral vault
+    #[account(seeds = [b"referral_authority"], bump)]
+    pub referral_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    pub owner: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct SetProtocolTreasury<'info> {
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub treasury: Account<'info, TokenAccount>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(mut, address = protocol_config.treasury @ ErrorCode::InvalidTreasury)]
+    pub treasury: Account<'info, TokenAccount>,
+    pub admin: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ApplyProtocolFeeSplit<'info> {
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin @ ErrorCode::Unauthorized
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(route_data: Vec<u8>, max_amount_in: u64, min_amount_out: u64, sub_account_id: u16)]
+pub struct SwapAndDeposit<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(mut, token::authority = user)]
+    pub source_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::authority = user, token::mint = collateral_vault.mint)]
+    pub user_quote_account: Account<'info, TokenAccount>,
+    #[account(mut, address = protocol_config.collateral_vault)]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    /// CHECK: Only the whitelisted Jupiter program can be invoked
+    #[account(address = JUPITER_PROGRAM_ID @ ErrorCode::InvalidSwapProgram)]
+    pub jupiter_program: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeArbVault<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        init,
+        payer = admin,
+        space = FundingArbVault::LEN,
+        seeds = [b"funding_arb_vault"],
+        bump
+    )]
+    pub arb_vault: Account<'info, FundingArbVault>,
+    #[account(
+        init,
+        payer = admin,
+        space = MarginAccount::LEN,
+        seeds = [b"margin_account", arb_vault.key().as_ref(), &0u16.to_le_bytes()],
+        bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(token::authority = arb_vault)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(mint::authority = arb_vault)]
+    pub share_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ArbVaultDeposit<'info> {
+    #[account(mut, seeds = [b"funding_arb_vault"], bump = arb_vault.bump, has_one = token_account, has_one = share_mint)]
+    pub arb_vault: Account<'info, FundingArbVault>,
+    #[account(
+        seeds = [b"margin_account", arb_vault.key().as_ref(), &0u16.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(mut)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub share_mint: Account<'info, Mint>,
+    pub depositor: Signer<'info>,
+    #[account(mut, token::mint = token_account.mint)]
+    pub depositor_token_account: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = share_mint)]
+    pub depositor_share_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ArbVaultOpen<'info> {
+    #[account(
+        mut,
+        seeds = [b"funding_arb_vault"],
+        bump = arb_vault.bump,
+        has_one = operator @ ErrorCode::Unauthorized,
+        has_one = token_account
+    )]
+    pub arb_vault: Account<'info, FundingArbVault>,
+    #[account(mut)]
+    pub operator: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", arb_vault.key().as_ref(), &0u16.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(mut)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = operator,
+        space = Position::LEN,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &margin_account.next_position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    /// Required when the market enforces a mark/index deviation cap
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct ArbVaultClose<'info> {
+    #[account(
+        mut,
+        seeds = [b"funding_arb_vault"],
+        bump = arb_vault.bump,
+        has_one = operator @ ErrorCode::Unauthorized,
+        has_one = token_account
+    )]
+    pub arb_vault: Account<'info, FundingArbVault>,
+    #[account(mut)]
+    pub operator: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", arb_vault.key().as_ref(), &0u16.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(mut)]
+    pub token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct AttestPositions<'info> {
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [
+            b"margin_account",
+            margin_account.authority.as_ref(),
+            &margin_account.sub_account_id.to_le_bytes(),
+        ],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateCoverageStatus<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    /// Counted towards coverage when passed
+    #[account(seeds = [b"insurance_fund", market.key().as_ref()], bump = insurance_fund.bump)]
+    pub insurance_fund: Option<Account<'info, InsuranceFund>>,
+    pub insurance_vault: Option<Account<'info, TokenAccount>>,
+}
+
+/// Emitted when low vault coverage puts a market into ReduceOnly
+/// (`active`) or when coverage has recovered.
+#[event]
+pub struct CoverageReduceOnly {
+    pub market: Pubkey,
+    pub coverage_bps: u64,
+    pub active: bool,
+    pub timestamp: i64,
+}
+
+/// Emitted when a vault holds less than the market's accounting says it
+/// should; the market is halted until the authority unpauses it.
+#[event]
+pub struct SolvencyViolation {
+    pub market: Pubkey,
+    pub vault_balance: u64,
+    pub required: u64,
+    pub timestamp: i64,
+}
+
+#[derive(Accounts)]
+pub struct AssertSolvency<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// Escrowed order collateral is only counted when passed
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    #[account(seeds = [b"insurance_fund", market.key().as_ref()], bump = insurance_fund.bump)]
+    pub insurance_fund: Option<Account<'info, InsuranceFund>>,
+    pub insurance_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(size_to_close: u64, sub_account_id: u16)]
+pub struct ReducePosition<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, token::authority = owner)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    #[account(seeds = [b"incident_registry", market.key().as_ref()], bump = incident_registry.bump)]
+    pub incident_registry: Option<Account<'info, IncidentRegistry>>,
+    #[account(seeds = [b"funding_history", market.key().as_ref()], bump = funding_history.bump)]
+    pub funding_history: Option<Account<'info, FundingHistory>>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+#[instruction(route_data: Vec<u8>, min_amount_out: u64, sub_account_id: u16)]
+pub struct ConvertToSpot<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    /// Receives the payout, which the route then spends
+    #[account(mut, token::authority = owner, token::mint = market_vault.mint)]
+    pub owner_quote_account: Account<'info, TokenAccount>,
+    #[account(mut, token::authority = owner, token::mint = market.base_mint)]
+    pub spot_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    /// CHECK: Only the whitelisted Jupiter program can be invoked
+    #[account(address = JUPITER_PROGRAM_ID @ ErrorCode::InvalidSwapProgram)]
+    pub jupiter_program: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    #[account(seeds = [b"incident_registry", market.key().as_ref()], bump = incident_registry.bump)]
+    pub incident_registry: Option<Account<'info, IncidentRegistry>>,
+    #[account(seeds = [b"funding_history", market.key().as_ref()], bump = funding_history.bump)]
+    pub funding_history: Option<Account<'info, FundingHistory>>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaperTrading<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    pub authority: Signer<'info>,
+    #[account(address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, sub_account_id: u16)]
+pub struct PaperFaucet<'info> {
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimMarketAuthority<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    pub recovery_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(mut, constraint = market.pending_authority == new_authority.key() @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    pub new_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimProtocolAdmin<'info> {
+    #[account(mut, seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub recovery_authority: Signer<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(trigger_price: u64, sub_account_id: u16)]
+pub struct PlaceStopLoss<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        init,
+        payer = owner,
+        space = TriggerOrder::LEN,
+        seeds = [b"trigger_order", position.key().as_ref(), TriggerKind::StopLoss.seed()],
+        bump
+    )]
+    pub trigger_order: Account<'info, TriggerOrder>,
+    #[account(
+        mut,
+        seeds = [b"trigger_registry", market.key().as_ref()],
+        bump = trigger_registry.bump
+    )]
+    pub trigger_registry: Account<'info, TriggerRegistry>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(trigger_price: u64, sub_account_id: u16)]
+pub struct PlaceTakeProfit<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        init,
+        payer = owner,
+        space = TriggerOrder::LEN,
+        seeds = [b"trigger_order", position.key().as_ref(), TriggerKind::TakeProfit.seed()],
+        bump
+    )]
+    pub trigger_order: Account<'info, TriggerOrder>,
+    #[account(
+        mut,
+        seeds = [b"trigger_registry", market.key().as_ref()],
+        bump = trigger_registry.bump
+    )]
+    pub trigger_registry: Account<'info, TriggerRegistry>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeTriggerRegistry<'info> {
+    #[account(has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = authority,
+        space = TriggerRegistry::LEN,
+        seeds = [b"trigger_registry", market.key().as_ref()],
+        bump
+    )]
+    pub trigger_registry: Account<'info, TriggerRegistry>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeIncidentRegistry<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = authority,
+        space = IncidentRegistry::LEN,
+        seeds = [b"incident_registry", market.key().as_ref()],
+        bump
+    )]
+    pub incident_registry: Account<'info, IncidentRegistry>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeclareOracleIncident<'info> {
+    #[account(has_one = guardian @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"incident_registry", market.key().as_ref()],
+        bump = incident_registry.bump
+    )]
+    pub incident_registry: Account<'info, IncidentRegistry>,
+    pub guardian: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct CancelTriggerOrder<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"trigger_order", trigger_order.position.as_ref(), trigger_order.kind.seed()],
+        bump = trigger_order.bump,
+        constraint = trigger_order.owner == margin_account.key() @ ErrorCode::Unauthorized
+    )]
+    pub trigger_order: Account<'info, TriggerOrder>,
+    #[account(
+        mut,
+        seeds = [b"trigger_registry", trigger_order.market.as_ref()],
+        bump = trigger_registry.bump
+    )]
+    pub trigger_registry: Account<'info, TriggerRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveExpiredTriggerOrder<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    #[account(
+        mut,
+        close = keeper,
+        seeds = [b"trigger_order", trigger_order.position.as_ref(), trigger_order.kind.seed()],
+        bump = trigger_order.bump
+    )]
+    pub trigger_order: Account<'info, TriggerOrder>,
+    #[account(
+        mut,
+        seeds = [b"trigger_registry", trigger_order.market.as_ref()],
+        bump = trigger_registry.bump
+    )]
+    pub trigger_registry: Account<'info, TriggerRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTriggerOrder<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        mut,
+        close = keeper,
+        has_one = position,
+        seeds = [b"trigger_order", position.key().as_ref(), trigger_order.kind.seed()],
+        bump = trigger_order.bump
+    )]
+    pub trigger_order: Account<'info, TriggerOrder>,
+    #[account(
+        mut,
+        seeds = [b"trigger_registry", market.key().as_ref()],
+        bump = trigger_registry.bump
+    )]
+    pub trigger_registry: Account<'info, TriggerRegistry>,
+    /// Sub-account that owns the position
+    #[account(
+        mut,
+        seeds = [
+            b"margin_account",
+            margin_account.authority.as_ref(),
+            &margin_account.sub_account_id.to_le_bytes(),
+        ],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, token::authority = margin_account.authority)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+    #[account(mut, token::authority = keeper)]
+    pub keeper_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    #[account(seeds = [b"incident_registry", market.key().as_ref()], bump = incident_registry.bump)]
+    pub incident_registry: Option<Account<'info, IncidentRegistry>>,
+    #[account(seeds = [b"funding_history", market.key().as_ref()], bump = funding_history.bump)]
+    pub funding_history: Option<Account<'info, FundingHistory>>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(amount: u64, sub_account_id: u16)]
+pub struct DepositMargin<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, token::authority = owner)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct ExportPosition<'info> {
+    #[account(mut, constraint = market.status == MarketStatus::Settling @ ErrorCode::MarketNotSettling)]
+    pub market: Account<'info, Market>,
+    #[account(address = market.successor @ ErrorCode::InvalidMarketState)]
+    pub successor_market: Account<'info, Market>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(
+        init,
+        payer = owner,
+        space = PositionExport::LEN,
+        seeds = [b"position_export", position.key().as_ref()],
+        bump
+    )]
+    pub position_export: Account<'info, PositionExport>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(
+        mut,
+        address = successor_market.vault @ ErrorCode::InvalidVault,
+        token::mint = market_vault.mint,
+        token::authority = successor_vault_authority
+    )]
+    pub successor_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the successor market's token accounts
+    #[account(seeds = [b"vault_authority", successor_market.key().as_ref()], bump)]
+    pub successor_vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct ImportPosition<'info> {
+    #[account(mut, address = position_export.to_market @ ErrorCode::InvalidMarketState)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        close = owner,
+        constraint = position_export.owner == margin_account.key() @ ErrorCode::Unauthorized
+    )]
+    pub position_export: Account<'info, PositionExport>,
+    #[account(
+        init,
+        payer = owner,
+        space = Position::LEN,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &margin_account.next_position_id.to_le_bytes()],
+        bump
+    )]
+    pub position: Account<'info, Position>,
+    pub system_program: Program<'info, System>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, sub_account_id: u16)]
+pub struct WithdrawMargin<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, token::authority = owner)]
+    pub owner_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, sub_account_id: u16)]
+pub struct TransferCollateral<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(mut, token::authority = user, token::mint = collateral_vault.mint)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = protocol_config.collateral_vault)]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the collateral vault
+    #[account(seeds = [b"collateral_authority"], bump)]
+    pub collateral_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct DepositCollateralMulti<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub user: Signer<'info>,
+    #[account(mut, token::authority = user, token::mint = collateral_vault.mint)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = protocol_config.collateral_vault)]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, from_sub_account_id: u16, to_sub_account_id: u16)]
+pub struct MoveCollateral<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref(), &from_sub_account_id.to_le_bytes()],
+        bump = from_margin_account.bump
+    )]
+    pub from_margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", user.key().as_ref(), &to_sub_account_id.to_le_bytes()],
+        bump = to_margin_account.bump,
+        constraint = to_sub_account_id != from_sub_account_id @ ErrorCode::SameSubAccount
+    )]
+    pub to_margin_account: Account<'info, MarginAccount>,
+}
+
+#[derive(Accounts)]
+pub struct ViewLiquidationOutcome<'info> {
+    pub market: Account<'info, Market>,
+    #[account(constraint = position.market == market.key() @ ErrorCode::PositionNotFound)]
+    pub position: Account<'info, Position>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ViewPostTradeHealth<'info> {
+    pub market: Account<'info, Market>,
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub margin_account: Account<'info, MarginAccount>,
+    /// Position the order would add to, if any
+    #[account(
+        constraint = position.market == market.key() @ ErrorCode::PositionNotFound,
+        constraint = position.owner == margin_account.key() @ ErrorCode::PositionNotFound
+    )]
+    pub position: Option<Account<'info, Position>>,
+    #[account(address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    /// The wallet's volume stats, for a fee tier discount
+    #[account(seeds = [b"trader_stats", margin_account.authority.as_ref()], bump = trader_stats.bump)]
+    pub trader_stats: Option<Account<'info, TraderStats>>,
+}
+
+#[derive(Accounts)]
+pub struct ViewPortfolioHealth<'info> {
+    pub margin_account: Account<'info, MarginAccount>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidateMarginAccount<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    /// Sub-account that owns the liquidated position
+    #[account(
+        mut,
+        seeds = [
+            b"margin_account",
+            margin_account.authority.as_ref(),
+            &margin_account.sub_account_id.to_le_bytes(),
+        ],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    #[account(mut, address = protocol_config.collateral_vault, token::mint = market_vault.mint)]
+    pub collateral_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the collateral vault
+    #[account(seeds = [b"collateral_authority"], bump)]
+    pub collateral_authority: AccountInfo<'info>,
+    /// Receives the liquidator's share of the liquidation fee
+    #[account(mut, token::mint = market_vault.mint)]
+    pub liquidator_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeWithdrawalPolicy<'info> {
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        init,
+        payer = admin,
+        space = WithdrawalPolicy::LEN,
+        seeds = [b"withdrawal_policy"],
+        bump
+    )]
+    pub withdrawal_policy: Account<'info, WithdrawalPolicy>,
+    #[account(seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    /// Treasury vault for `withdraw_treasury`; never the locked vote tokens
+    #[account(
+        token::authority = treasury,
+        constraint = spend_vault.key() != treasury.lock_vault @ ErrorCode::InvalidVault
+    )]
+    pub spend_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetWithdrawalPolicy<'info> {
+    #[account(mut, seeds = [b"protocol_config"], bump = protocol_config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut, seeds = [b"withdrawal_policy"], bump = withdrawal_policy.bump)]
+    pub withdrawal_policy: Account<'info, WithdrawalPolicy>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeWithdrawal<'info> {
+    #[account(mut, seeds = [b"withdrawal_policy"], bump = withdrawal_policy.bump)]
+    pub withdrawal_policy: Account<'info, WithdrawalPolicy>,
+    #[account(
+        init,
+        payer = proposer,
+        space = WithdrawalRequest::LEN,
+        seeds = [b"withdrawal_request".as_ref(), &withdrawal_policy.request_count.to_le_bytes()],
+        bump
+    )]
+    pub withdrawal_request: Account<'info, WithdrawalRequest>,
+    pub source_vault: Account<'info, TokenAccount>,
+    #[account(token::mint = source_vault.mint)]
+    pub destination: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct InitializeMarket<'info> {
-    #[account(init, payer = authority, space = 8 + 32 + 32 + 8 + 8 + 1 + 2 + 2 + 8 + 8 + 1 + 8)]
-    pub market: Account<'info, Market>,
+pub struct ApproveWithdrawal<'info> {
+    #[account(seeds = [b"withdrawal_policy"], bump = withdrawal_policy.bump)]
+    pub withdrawal_policy: Account<'info, WithdrawalPolicy>,
+    #[account(
+        mut,
+        seeds = [b"withdrawal_request", &withdrawal_request.id.to_le_bytes()],
+        bump = withdrawal_request.bump
+    )]
+    pub withdrawal_request: Account<'info, WithdrawalRequest>,
+    #[account(
+        init,
+        payer = signer,
+        space = WithdrawalApproval::LEN,
+        seeds = [b"withdrawal_approval", withdrawal_request.key().as_ref(), signer.key().as_ref()],
+        bump
+    )]
+    pub approval: Account<'info, WithdrawalApproval>,
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub signer: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct PlaceOrder<'info> {
+pub struct WithdrawTreasury<'info> {
+    #[account(mut, seeds = [b"withdrawal_policy"], bump = withdrawal_policy.bump)]
+    pub withdrawal_policy: Account<'info, WithdrawalPolicy>,
+    #[account(seeds = [b"treasury"], bump = treasury.bump)]
+    pub treasury: Account<'info, Treasury>,
+    #[account(mut, address = withdrawal_policy.spend_vault @ ErrorCode::InvalidVault)]
+    pub source_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    pub signer: Signer<'info>,
+    /// Approved request, required above the single-signer limit
+    #[account(
+        mut,
+        seeds = [b"withdrawal_request", &withdrawal_request.id.to_le_bytes()],
+        bump = withdrawal_request.bump
+    )]
+    pub withdrawal_request: Option<Account<'info, WithdrawalRequest>>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawInsurance<'info> {
+    #[account(mut, seeds = [b"withdrawal_policy"], bump = withdrawal_policy.bump)]
+    pub withdrawal_policy: Account<'info, WithdrawalPolicy>,
+    #[account(mut, seeds = [b"insurance_fund", insurance_fund.market.as_ref()], bump = insurance_fund.bump)]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(mut, address = insurance_fund.vault)]
+    pub insurance_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    pub signer: Signer<'info>,
+    /// Approved request, required above the single-signer limit
+    #[account(
+        mut,
+        seeds = [b"withdrawal_request", &withdrawal_request.id.to_le_bytes()],
+        bump = withdrawal_request.bump
+    )]
+    pub withdrawal_request: Option<Account<'info, WithdrawalRequest>>,
+    pub token_program: Program<'info, Token>,
+    #[account(address = insurance_fund.market)]
+    pub market: Account<'info, Market>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+}
+
+#[event]
+pub struct FundsWithdrawn {
+    pub source: WithdrawalSource,
+    pub source_vault: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub signer: Pubkey,
+    pub request: Option<Pubkey>,
+    pub timestamp: i64,
+}
+
+#[derive(Accounts)]
+#[instruction(mode: MarginMode, sub_account_id: u16)]
+pub struct SetMarginMode<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), margin_account.key().as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(sub_account_id: u16)]
+pub struct RegisterLaunchTrader<'info> {
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub market: Account<'info, Market>,
     #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [b"margin_account", owner.key().as_ref(), &sub_account_id.to_le_bytes()],
+        bump = margin_account.bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+    #[account(
+        init,
+        payer = owner,
+        space = LaunchRegistration::LEN,
+        seeds = [b"launch_registration", market.key().as_ref(), margin_account.key().as_ref()],
+        bump
+    )]
+    pub launch_registration: Account<'info, LaunchRegistration>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SweepInsurance<'info> {
     #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(mut, seeds = [b"insurance_fund", market.key().as_ref()], bump = insurance_fund.bump)]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    #[account(mut, address = insurance_fund.vault)]
+    pub insurance_vault: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
     pub market_vault: Account<'info, TokenAccount>,
-    /// CHECK: Price feed account is verified in the PriceFeed implementation
-    pub price_feed: AccountInfo<'info>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
     pub token_program: Program<'info, Token>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
 }
 
 #[derive(Accounts)]
-pub struct LiquidatePosition<'info> {
-    #[account(mut)]
+pub struct FlagMarginCall<'info> {
     pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"position", market.key().as_ref(), position.owner.as_ref(), &position.position_id.to_le_bytes()],
+        bump = position.bump
+    )]
+    pub position: Account<'info, Position>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+/// Emitted when a position's health falls below the market's margin call
+/// threshold (`active`), and again when it recovers.
+#[event]
+pub struct MarginCall {
+    pub market: Pubkey,
+    pub position: Pubkey,
+    pub owner: Pubkey,  // margin account
+    pub tag: [u8; 32],
+    pub health_bps: u64,
+    pub threshold_bps: u16,
+    pub active: bool,
+    pub timestamp: i64,
+}
+
+#[derive(Accounts)]
+pub struct SetMarketHook<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub market: Account<'info, Market>,
+    #[account(seeds = [b"protocol_config"], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CrankLiquidations<'info> {
     #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.withdrawals_only @ ErrorCode::WithdrawalsOnly
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
     pub market_vault: Account<'info, TokenAccount>,
-    /// CHECK: Price feed account is verified in the PriceFeed implementation
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
     pub price_feed: AccountInfo<'info>,
+    /// Receives the liquidator's share of every liquidation fee
+    #[account(mut, token::mint = market_vault.mint)]
+    pub cranker_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub cranker: Signer<'info>,
     pub token_program: Program<'info, Token>,
+    /// Cranker's stake in the market's fee staking pool, required while
+    /// a cascade reserves liquidations for staked keepers
+    pub keeper_stake: Option<Account<'info, StakerAccount>>,
+    /// Required once the market has an insurance coverage account
+    #[account(mut, seeds = [b"insurance_coverage", market.key().as_ref()], bump = insurance_coverage.bump)]
+    pub insurance_coverage: Option<Account<'info, InsuranceCoverage>>,
+    /// Required while the market has an approved hook program
+    /// CHECK: Must be the market's hook program; it is only invoked
+    pub hook_program: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeCustomOracle<'info> {
+    #[account(init, payer = authority, space = CustomOracle::LEN)]
+    pub custom_oracle: Account<'info, CustomOracle>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateCustomOracle<'info> {
+    #[account(mut, has_one = authority @ ErrorCode::Unauthorized)]
+    pub custom_oracle: Account<'info, CustomOracle>,
+    pub authority: Signer<'info>,
 }
 
 #[error_code]
@@ -416,77 +8750,1056 @@ pub enum ErrorCode {
     InvalidFee,
     #[msg("Position margin too low")]
     MarginTooLow,
+    #[msg("Invalid liquidity mining configuration")]
+    InvalidMiningConfig,
+    #[msg("No mining rewards to claim")]
+    NoRewardsToClaim,
+    #[msg("Staking epoch has not finished yet")]
+    EpochNotFinished,
+    #[msg("Claim outstanding epochs before changing stake")]
+    UnclaimedEpochs,
+    #[msg("Insufficient stake")]
+    InsufficientStake,
+    #[msg("Invalid treasury configuration")]
+    InvalidTreasuryConfig,
+    #[msg("Lock must end in the future and cannot be shortened")]
+    InvalidLockDuration,
+    #[msg("Tokens are still locked")]
+    TokensLocked,
+    #[msg("No voting power for this proposal")]
+    NoVotingPower,
+    #[msg("Voting period has ended")]
+    VotingClosed,
+    #[msg("Voting period has not ended yet")]
+    VotingNotFinished,
+    #[msg("Proposal has not passed")]
+    ProposalNotPassed,
+    #[msg("Timelock has not elapsed")]
+    TimelockNotElapsed,
+    #[msg("Proposal already executed")]
+    ProposalAlreadyExecuted,
+    #[msg("Parameter value is outside the allowed bounds")]
+    ParameterOutOfBounds,
+    #[msg("Parameter change queue is full")]
+    ParamQueueFull,
+    #[msg("Order book side is full")]
+    OrderBookFull,
+    #[msg("Order not found")]
+    OrderNotFound,
+    #[msg("Invalid number of depth levels")]
+    InvalidDepthLevels,
+    #[msg("Market is in reduce-only mode")]
+    MarketReduceOnly,
+    #[msg("Mark price deviates too far from the oracle index")]
+    MarkIndexDeviation,
+    #[msg("Order book account is required for this market")]
+    OrderBookRequired,
+    #[msg("Invalid sub-account id")]
+    InvalidSubAccount,
+    #[msg("Current trade history page must be provided")]
+    TradeHistoryPageRequired,
+    #[msg("Trade history page is full")]
+    TradeHistoryPageFull,
+    #[msg("Expiry must be in the future and earlier than the current one")]
+    InvalidExpiry,
+    #[msg("Position has not expired")]
+    PositionNotExpired,
+    #[msg("Margin account is already in use by this instruction")]
+    MarginAccountInUse,
+    #[msg("Order legs do not match the accounts provided")]
+    InvalidOrderLegs,
+    #[msg("Price feed is not the market's oracle")]
+    InvalidOracle,
+    #[msg("Protocol is in withdrawals-only mode")]
+    WithdrawalsOnly,
+    #[msg("Protocol is not in withdrawals-only mode")]
+    NotWithdrawalsOnly,
+    #[msg("Funding history does not reach back to the requested time")]
+    FundingHistoryUnavailable,
+    #[msg("Insurance accounts are required to cover this shortfall")]
+    InsuranceAccountsRequired,
+    #[msg("Not enough LP shares")]
+    InsufficientShares,
+    #[msg("Integrator is not active")]
+    IntegratorInactive,
+    #[msg("Integrator order rate limit reached")]
+    IntegratorRateLimited,
+    #[msg("Fill price, or swap output or input, is outside the slippage bounds")]
+    SlippageExceeded,
+    #[msg("Swap program is not whitelisted")]
+    InvalidSwapProgram,
+    #[msg("Liquidations are reserved for staked keepers during this cascade window")]
+    PriorityLiquidationWindow,
+    #[msg("Market funding rate is zero; no side receives funding")]
+    NoFundingToCollect,
+    #[msg("Position would exceed the vault's per-market exposure cap")]
+    ExposureLimitExceeded,
+    #[msg("Market notional cap for the current window exceeded")]
+    NotionalCapExceeded,
+    #[msg("Market template has not been configured")]
+    TemplateNotConfigured,
+    #[msg("Reduce size must be between zero and the position size")]
+    InvalidReduceSize,
+    #[msg("Position accounts do not match the margin account's open positions")]
+    PositionAccountsMismatch,
+    #[msg("Margin account has too many open positions")]
+    TooManyPositions,
+    #[msg("Position account for a fill is missing")]
+    PositionAccountMissing,
+    #[msg("Faucet would take the paper balance over its limit")]
+    PaperBalanceLimit,
+    #[msg("Market vault balance is too low")]
+    InsufficientVaultBalance,
+    #[msg("Not available on paper-trading markets")]
+    PaperTradingMarket,
+    #[msg("Authority has been active within the recovery period")]
+    AuthorityStillActive,
+    #[msg("Oracle price has not reached the trigger price")]
+    TriggerNotReached,
+    #[msg("Trigger registry is full")]
+    TriggerRegistryFull,
+    #[msg("The primary oracle can only be changed with set_oracle")]
+    OracleRotationRequired,
+    #[msg("New oracle price deviates too far from the current oracle")]
+    OracleDeviationTooLarge,
+    #[msg("Market is not settling")]
+    MarketNotSettling,
+    #[msg("Position has a resting limit order")]
+    PositionHasRestingOrder,
+    #[msg("This market's incident registry must be passed")]
+    IncidentRegistryRequired,
+    #[msg("Invalid withdrawal signers or threshold")]
+    InvalidWithdrawalPolicy,
+    #[msg("Withdrawal above the single-signer limit needs an approved request")]
+    WithdrawalApprovalRequired,
+    #[msg("Withdrawal request does not match this withdrawal")]
+    WithdrawalRequestMismatch,
+    #[msg("Withdrawal request already executed")]
+    WithdrawalAlreadyExecuted,
+    #[msg("Cross-margin position loss exceeds its margin")]
+    CrossMarginShortfall,
+    #[msg("Market is in its launch phase and needs a launch registration")]
+    LaunchRegistrationRequired,
+    #[msg("Launch phase trader cap reached")]
+    LaunchCapReached,
+    #[msg("Order is larger than the market's share of book depth")]
+    OrderExceedsDepth,
+    #[msg("Insurance coverage account must be provided")]
+    InsuranceCoverageRequired,
+    #[msg("Market hook program must be provided")]
+    HookProgramRequired,
+    #[msg("Hook program is not approved by the protocol")]
+    HookProgramNotApproved,
+    #[msg("Sub-account placed too many orders in this slot or second")]
+    OrderRateLimited,
+    #[msg("Price history page is full")]
+    PriceHistoryPageFull,
+    #[msg("Market has no underlying token to convert into")]
+    SpotConversionUnavailable,
+    #[msg("Only a profitable position can be converted to spot")]
+    PositionNotProfitable,
+    #[msg("Hedger status can only change while the sub-account has no positions")]
+    HedgerPositionsOpen,
+    #[msg("Source and destination sub-accounts are the same")]
+    SameSubAccount,
+    #[msg("Market has been delisted")]
+    MarketExpired,
+    #[msg("Market has not been delisted")]
+    MarketNotExpired,
+    #[msg("Market still has open positions, resting orders or unswept insurance")]
+    MarketNotSettled,
+    #[msg("Every fee holiday slot is scheduled or running")]
+    FeeHolidaysFull,
+    #[msg("No fee holiday starts at that time")]
+    FeeHolidayNotFound,
+    #[msg("Token account is not the market's vault")]
+    InvalidVault,
+    #[msg("Revenue epoch has not ended")]
+    RevenueEpochOpen,
+    #[msg("Revenue epoch is no longer in the market's ledger")]
+    RevenueEpochUnavailable,
+    #[msg("Token account is not the protocol treasury")]
+    InvalidTreasury,
+    #[msg("Not enough accrued fees")]
+    InsufficientFees,
+    #[msg("Referral rewards are not configured")]
+    ReferralsDisabled,
+    #[msg("A trader cannot use their own referral code")]
+    SelfReferral,
+    #[msg("No referral rewards to claim")]
+    NoReferralRewards,
+    #[msg("Open interest would exceed the LP vault's utilization cap")]
+    LpUtilizationExceeded,
+    #[msg("Order would take the vAMM's whole base reserve")]
+    VammDepthExceeded,
+    #[msg("Order is past its expiry")]
+    OrderExpired,
+    #[msg("The guardian must wait out the cooldown after a pause")]
+    GuardianPauseCooldown,
+    #[msg("Too many resting orders from this account on this side of the book")]
+    TooManyOpenOrders,
+    #[msg("LP vault has shares outstanding but no equity")]
+    VaultInsolvent,
+    #[msg("LP vault and share mint are required for this market")]
+    LpVaultRequired,
+    #[msg("Confidence must be positive and within the bound on its share of the price")]
+    InvalidConfidence,
+    #[msg("Limit price is too far from the oracle index")]
+    LimitPriceOutsideBand,
+    #[msg("Trigger order has not expired")]
+    TriggerOrderNotExpired,
 }
 
-// Helper functions
+/// Sets up a new market account from `template`.
+fn init_market(market: &mut Market, authority: Pubkey, vault: Pubkey, name: String, template: &MarketTemplate) -> Result<()> {
+    market.name = name;
+    market.authority = authority;
+    market.vault = vault;
+    market.min_base_order_size = template.min_base_order_size;
+    market.tick_size = template.tick_size;
+    market.max_leverage = template.max_leverage;
+    market.liquidation_threshold = template.liquidation_threshold;
+    market.maintenance_margin_fraction = template.maintenance_margin_fraction;
+    market.long_open_interest = 0;
+    market.short_open_interest = 0;
+    market.long_entry_notional = 0;
+    market.short_entry_notional = 0;
+    market.is_initialized = true;
+    market.total_fee_accrued = 0;
+    market.max_position_size = template.max_position_size;
+    market.funding_rate = 0;
+    market.last_funding_time = Clock::get()?.unix_timestamp;
+    market.funding_interval = template.funding_interval;
+    market.mining = MiningState::default();
+    market.fee_bps = template.fee_bps;
+    market.max_funding_rate_bps = template.max_funding_rate_bps;
+    market.close_fee_bps = 0;
+    market.fee_accrual = FeeAccrual::default();
+    market.base_mint = Pubkey::default();
+    market.funding_update_reward = 0;
+    market.settlement_price = 0;
+    market.fee_holidays = Default::default();
+    market.revenue = RevenueLedger::default();
+    market.pending_authority = Pubkey::default();
+    market.maker_fee_bps = template.fee_bps as i16;
+    market.lp_pnl_checkpoint = 0;
+    market.lp_settled_pnl = 0;
+    market.lp_vault_equity = 0;
+    market.lp_max_utilization_bps = 0;
+    market.vamm = Vamm::default();
+    market.oracle_source = OracleSource::default();
+    market.fallback_oracle_source = OracleSource::default();
+    market.max_oracle_staleness_secs = template.max_oracle_staleness_secs;
+    market.max_oracle_conf_bps = DEFAULT_MAX_ORACLE_CONF_BPS;
+    market.oracle_conf_multiplier_bps = DEFAULT_ORACLE_CONF_MULTIPLIER_BPS;
+    market.param_queue = VecDeque::new();
+    market.guardian = authority;
+    market.guardian_pause_duration = DEFAULT_GUARDIAN_PAUSE_DURATION;
+    market.paused_until = 0;
+    market.pause_ratified = false;
+    market.matching_policy = MatchingPolicy::PriceTime;
+    market.max_mark_index_deviation_bps = 0;
+    market.reduce_only_flags = 0;
+    market.listed_at = Clock::get()?.unix_timestamp;
+    market.total_volume = 0;
+    market.leverage_ramp = LeverageRamp::default();
+    market.max_position_age = 0;
+    market.expiry_fee_bps = 0;
+    market.oracle = Pubkey::default();
+    market.fallback_oracle = Pubkey::default();
+    market.oracle_grace_period = 0;
+    market.last_settled_price = 0;
+    market.last_oracle_price = 0;
+    market.protocol_fee_share_bps = 0;
+    market.protocol_fees_accrued = 0;
+    market.has_lp_vault = false;
+    market.limit_price_band_bps = DEFAULT_LIMIT_PRICE_BAND_BPS;
+    market.cumulative_funding_long = 0;
+    market.cumulative_funding_short = 0;
+    market.fee_curve = FeeCurve::default();
+    market.volume_window = VolumeWindow {
+        window_start: market.listed_at,
+        current: 0,
+        previous: 0,
+    };
+    market.priority_lanes = PriorityLanes::default();
+    market.price_decimals = 0;
+    market.price_rounding = PriceRounding::Down;
+    market.notional_cap = NotionalCap::default();
+    market.min_coverage_bps = 0;
+    market.paper_trading = false;
+    market.paper_vault = 0;
+    market.recovery = AuthorityRecovery {
+        last_activity: market.listed_at,
+        ..AuthorityRecovery::default()
+    };
+    market.max_funding_payment_bps = 0;
+    market.trigger_tip_bps = 0;
+    market.total_margin = 0;
+    market.trader_realized_pnl = 0;
+    market.oracle_rotation = OracleRotation::default();
+    market.status = MarketStatus::Active;
+    market.successor = Pubkey::default();
+    market.has_incident_registry = false;
+    market.keeper_reward_multiplier_bps = 10000;
+    market.launch_trader_cap = 0;
+    market.launch_trader_count = 0;
+    market.insurance_fee_share_bps = 0;
+    market.liquidation_fee_bps = 0;
+    market.liquidator_share_bps = 0;
+    market.pending_insurance = 0;
+    market.depth_cap_bps = 0;
+    market.depth_band_bps = 0;
+    market.has_insurance_coverage = false;
+    market.margin_call_bps = 0;
+    market.hook_program = Pubkey::default();
+    market.premium = PremiumTwap::new(market.last_funding_time);
+    market.has_order_book = false;
+    market.price_history_head = 0;
+    market.last_price_sample = 0;
+    Ok(())
+    }
+
+// Helper functions; the math itself lives in `memeperp_math`
 fn calculate_required_margin(size: u64, price: u64, leverage: u8) -> u64 {
-    (size * price) / leverage as u64
+    math::required_margin(size, price, leverage)
 }
 
-fn calculate_liquidation_price(
-    side: Side,
-    entry_price: u64,
-    leverage: u8,
-    liquidation_threshold: u16,
-) -> Result<u64> {
-    let threshold = liquidation_threshold as f64 / 10000.0;
-    let price = entry_price as f64;
-    
-    let liquidation_price = match side {
-        Side::Long => {
-            price * (1.0 - (1.0 - threshold) * leverage as f64)
-        }
-        Side::Short => {
-            price * (1.0 + (1.0 - threshold) * leverage as f64)
-        }
-    };
-    
-    Ok(liquidation_price as u64)
+/// Initial margin for `size` filled at `fill_price`, plus what the position
+/// would lose as the oracle `price` moves to the confidence band's edge
+/// `risk_price`, so it opens with its full initial margin at the band edge.
+fn banded_required_margin(size: u64, fill_price: u64, leverage: u8, price: u64, risk_price: u64) -> Result<u64> {
+    let band_loss = size.checked_mul(price.abs_diff(risk_price)).ok_or(ErrorCode::MathOverflow)?;
+    Ok(calculate_required_margin(size, fill_price, leverage)
+        .checked_add(band_loss)
+        .ok_or(ErrorCode::MathOverflow)?)
 }
 
+/// PnL in quote units: the base size times the price change in the
+/// position's favor.
 fn calculate_pnl(
     side: Side,
-    size: u64,
+    base_size: u64,
     entry_price: u64,
     current_price: u64,
-    leverage: u8,
 ) -> Result<i64> {
-    let pnl = match side {
-        Side::Long => {
-            ((current_price as i128 - entry_price as i128) * size as i128 * leverage as i128) / entry_price as i128
-        }
-        Side::Short => {
-            ((entry_price as i128 - current_price as i128) * size as i128 * leverage as i128) / entry_price as i128
-        }
-    };
-    
-    Ok(pnl as i64)
+    Ok(math::pnl(side == Side::Long, base_size, entry_price, current_price).ok_or(ErrorCode::MathOverflow)?)
+}
+
+fn mark_index_deviation_bps(mark_price: u64, index_price: u64) -> u64 {
+    math::mark_index_deviation_bps(mark_price, index_price)
+}
+
+/// Taker fee discount for the wallet behind `trader_stats`, by its 30-day
+/// volume. Orders without the account get none.
+fn volume_fee_discount_bps(config: &ProtocolConfig, trader_stats: Option<&TraderStats>, now: i64) -> u16 {
+    trader_stats.map_or(0, |stats| config.fee_tier_discount_bps(stats.volume(now)))
 }
 
-fn apply_funding_to_position(
+/// Counts a filled market order towards the wallet's fee tier. Paper-trading
+/// volume does not count.
+fn record_trader_volume(market: &Market, trader_stats: Option<&mut Account<TraderStats>>, now: i64, notional: u64) {
+    if let Some(stats) = trader_stats.filter(|_| !market.paper_trading) {
+        stats.record(now, notional);
+    }
+}
+
+/// Validates a market order and opens it into the new, empty `position`,
+/// returning the margin and fee the trader owes and the order's notional.
+/// The caller collects the funds.
+fn open_market_order(
+    market: &mut Market,
+    market_key: Pubkey,
+    owner: Pubkey,
+    stats: &mut UserStats,
+    trade_history: Option<&mut TradeHistoryPage>,
+    order_book: Option<&OrderBook>,
+    price_feed: &AccountInfo,
+    vault_depth: u64,
+    fee_discount_bps: u16,
     position: &mut Position,
-    funding_rate: i64,
-    is_long: bool,
-) -> Result<()> {
-    let funding_amount = if is_long {
-        -((position.size as i128 * position.entry_price as i128 * funding_rate as i128) / 10000) as i64
+    position_key: Pubkey,
+    side: Side,
+    size: u64,
+    min_fill_size: u64,
+    price: u64,
+    max_slippage_bps: Option<u16>,
+    leverage: u8,
+    tag: [u8; 32],
+) -> Result<(u64, u64, u64)> {
+    let now = Clock::get()?.unix_timestamp;
+    require!(!market.is_paused(now), ErrorCode::MarketPaused);
+    require!(!market.is_reduce_only(), ErrorCode::MarketReduceOnly);
+
+    // Get current price from the market's oracle
+    let price_feed = market.load_price_feed(price_feed)?;
+    let current_price = price_feed.get_adjusted_price()?;
+    let risk_price = price_feed.get_price_for(side)?;
+    check_mark_index_deviation(market, order_book, price_feed.get_index_price()?)?;
+
+    // Validate order parameters
+    require!(leverage <= market.current_max_leverage(now), ErrorCode::LeverageTooHigh);
+    require!(size >= market.min_base_order_size, ErrorCode::OrderTooSmall);
+    require!(size <= market.max_position_size, ErrorCode::OrderTooLarge);
+    require!(price.is_multiple_of(market.tick_size), ErrorCode::InvalidPrice);
+    check_depth_order_cap(market, order_book, size, current_price)?;
+
+    // Fill as much as the open-interest cap allows, but at least `min_fill_size`;
+    // the unfilled rest of the order is cancelled
+    let requested_size = size;
+    let size = size.min(market.max_position_size.saturating_sub(market.open_interest(side)));
+    require!(size > 0 && size >= min_fill_size, ErrorCode::ExceedsMaxPosition);
+
+    // With a vAMM the order fills at its average price across the size,
+    // which moves the mark with the flow
+    let fill_price = if market.vamm.is_enabled() {
+        market.vamm.fill_price(current_price, market.net_open_interest(), side, size)?
     } else {
-        ((position.size as i128 * position.entry_price as i128 * funding_rate as i128) / 10000) as i64
+        current_price
     };
+    if let Some(max_slippage_bps) = max_slippage_bps {
+        check_slippage(side, fill_price, price, max_slippage_bps)?;
+    }
 
-    position.margin = if funding_amount > 0 {
-        position.margin.checked_add(funding_amount as u64)
-            .ok_or(ErrorCode::MathOverflow)?
-    } else {
-        position.margin.checked_sub((-funding_amount) as u64)
-            .ok_or(ErrorCode::MathOverflow)?
+    // Calculate required margin, covering the move to the band edge
+    let required_margin = banded_required_margin(size, fill_price, leverage, current_price, risk_price)?;
+
+    // Calculate and collect fees (taker fee rate of notional)
+    let notional = size.checked_mul(fill_price).ok_or(ErrorCode::MathOverflow)?;
+    let fee = market.taker_fee(notional, vault_depth, fee_discount_bps, now);
+    market.accrue_fee(FeeKind::Open, side, FeeRole::Taker, fee, now)?;
+
+    market.record_volume(now, notional)?;
+    market.last_settled_price = current_price;
+    market.last_oracle_price = current_price;
+    stats.record_trade(notional, fee)?;
+
+    // Sub-accounts that opted into on-chain history append to their current page
+    if stats.history_enabled {
+        let page = trade_history.ok_or(ErrorCode::TradeHistoryPageRequired)?;
+        require!(
+            page.page_index == TradeHistoryPage::page_for(stats.history_head),
+            ErrorCode::TradeHistoryPageRequired
+        );
+        page.append(TradeRecord {
+            market: market_key,
+            kind: TradeKind::Open,
+            side,
+            base_size: size,
+            price: fill_price,
+            fee,
+            timestamp: now,
+        })?;
+        stats.history_head = stats.history_head.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+    }
+
+    market.open_position(position, size, fill_price, required_margin, now)?;
+    // Checked once the fill and its margin are booked, against the open
+    // interest the order leaves
+    market.check_lp_utilization(market.lp_vault_equity, market.open_interest(side), current_price)?;
+    emit!(OrderFilled {
+        market: market_key,
+        owner,
+        position: position_key,
+        tag,
+        order_id: None,
+        side,
+        price: fill_price,
+        fill_size: size,
+        filled_size: size,
+        remaining_size: requested_size - size,
+        timestamp: now,
+    });
+
+    Ok((required_margin, fee, notional))
+}
+
+/// Pays a liquidation shortfall into the market vault from the market's
+/// insurance fund, then from the protocol backstop, and attributes it to the
+/// market. Whatever neither pool can cover is recorded as uncovered bad debt.
+fn cover_shortfall(ctx: &mut Context<LiquidatePosition>, shortfall: u64) -> Result<()> {
+    let accounts = &mut ctx.accounts;
+    let (Some(fund), Some(fund_vault), Some(backstop), Some(backstop_vault)) = (
+        accounts.insurance_fund.as_mut(),
+        accounts.insurance_vault.as_ref(),
+        accounts.insurance_backstop.as_mut(),
+        accounts.backstop_vault.as_ref(),
+    ) else {
+        return err!(ErrorCode::InsuranceAccountsRequired);
+    };
+    require_keys_eq!(fund_vault.key(), fund.vault, ErrorCode::InsuranceAccountsRequired);
+    require_keys_eq!(backstop_vault.key(), backstop.vault, ErrorCode::InsuranceAccountsRequired);
+
+    let from_fund = shortfall.min(fund_vault.amount);
+    let from_backstop = (shortfall - from_fund).min(backstop_vault.amount);
+
+    let market_key = accounts.market.key();
+    if from_fund > 0 {
+        let seeds = &[b"insurance_fund".as_ref(), market_key.as_ref(), &[fund.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: fund_vault.to_account_info(),
+                    to: accounts.market_vault.to_account_info(),
+                    authority: fund.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            from_fund,
+        )?;
+    }
+    if from_backstop > 0 {
+        let seeds = &[b"insurance_backstop".as_ref(), &[backstop.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: backstop_vault.to_account_info(),
+                    to: accounts.market_vault.to_account_info(),
+                    authority: backstop.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            from_backstop,
+        )?;
+        backstop.total_covered = backstop.total_covered
+            .checked_add(from_backstop)
+            .ok_or(ErrorCode::MathOverflow)?;
+    }
+
+    fund.record_shortfall(shortfall, from_fund, from_backstop)?;
+    sync_coverage_insurance(&accounts.market, fund, accounts.insurance_coverage.as_mut())
+}
+
+/// During a liquidation cascade the first slots are reserved for staked
+/// keepers. Records one liquidation and, inside the reserved window, checks
+/// that `liquidator` has enough stake in the market's fee staking pool.
+fn check_priority_lane(
+    market: &mut Account<Market>,
+    keeper_stake: Option<&Account<StakerAccount>>,
+    liquidator: &Pubkey,
+) -> Result<()> {
+    if !market.priority_lanes.record_liquidation(Clock::get()?.slot) {
+        return Ok(());
+    }
+    let keeper_stake = keeper_stake.ok_or(ErrorCode::PriorityLiquidationWindow)?;
+    let (stake_pool, _) = Pubkey::find_program_address(&[b"stake_pool", market.key().as_ref()], &crate::ID);
+    require!(
+        keeper_stake.owner == *liquidator
+            && keeper_stake.pool == stake_pool
+            && keeper_stake.active_stake >= market.priority_lanes.min_keeper_stake,
+        ErrorCode::PriorityLiquidationWindow
+    );
+    Ok(())
+}
+
+/// Passes a position event to the market's hook program, if it has one the
+/// protocol still approves. The hook gets the event and the market account,
+/// read-only and not signing, so it has no authority over funds. Once a
+/// market sets a hook, instructions that emit hook events must pass it.
+fn notify_hook<'info>(
+    config: &ProtocolConfig,
+    market: &Account<'info, Market>,
+    hook_program: Option<&UncheckedAccount<'info>>,
+    kind: HookEventKind,
+    record: PositionRecord,
+    size: u64,
+    price: u64,
+) -> Result<()> {
+    if !config.is_approved_hook(&market.hook_program) {
+        return Ok(());
+    }
+    let hook_program = hook_program.ok_or(ErrorCode::HookProgramRequired)?;
+    require_keys_eq!(hook_program.key(), market.hook_program, ErrorCode::HookProgramRequired);
+    hook::invoke_hook(hook_program, &market.to_account_info(), &HookEvent { kind, record, size, price })
+}
+
+/// Brings the market's insurance coverage account up to date after its open
+/// interest moved. Once a market has one, instructions that move open
+/// interest must pass it, so the ratio never goes stale.
+fn sync_coverage_open_interest(market: &Market, coverage: Option<&mut Account<InsuranceCoverage>>) -> Result<()> {
+    let Some(coverage) = coverage else {
+        require!(!market.has_insurance_coverage, ErrorCode::InsuranceCoverageRequired);
+        return Ok(());
+    };
+    coverage.record_open_interest(market.open_interest_notional(), Clock::get()?.unix_timestamp);
+    Ok(())
+}
+
+/// Same as `sync_coverage_open_interest`, after the insurance fund's balance moved.
+fn sync_coverage_insurance(
+    market: &Market,
+    fund: &InsuranceFund,
+    coverage: Option<&mut Account<InsuranceCoverage>>,
+) -> Result<()> {
+    let Some(coverage) = coverage else {
+        require!(!market.has_insurance_coverage, ErrorCode::InsuranceCoverageRequired);
+        return Ok(());
+    };
+    coverage.record_insurance(fund.balance(), Clock::get()?.unix_timestamp);
+    Ok(())
+}
+
+/// Rejects opening orders while the mark has drifted past the market's
+/// deviation cap from the oracle index. A one-sided book has no mark to check.
+fn check_mark_index_deviation(
+    market: &Market,
+    order_book: Option<&OrderBook>,
+    index_price: u64,
+) -> Result<()> {
+    if market.max_mark_index_deviation_bps == 0 {
+        return Ok(());
+    }
+    if let Some(mark_price) = mark_price(market, order_book, index_price)? {
+        require!(
+            mark_index_deviation_bps(mark_price, index_price) <= market.max_mark_index_deviation_bps as u64,
+            ErrorCode::MarkIndexDeviation
+        );
+    }
+    Ok(())
+}
+
+/// Rejects a limit order priced, or a match filling, further than the
+/// market's band from the oracle index.
+fn check_limit_price_band(market: &Market, price: u64, index_price: u64) -> Result<()> {
+    require!(
+        mark_index_deviation_bps(price, index_price) <= market.limit_price_band_bps as u64,
+        ErrorCode::LimitPriceOutsideBand
+    );
+    Ok(())
+}
+
+/// The vAMM's mark at `index_price` where it is enabled, else the order
+/// book mid. `None` for a market with neither, or a book with an empty side.
+fn mark_price(market: &Market, order_book: Option<&OrderBook>, index_price: u64) -> Result<Option<u64>> {
+    if market.vamm.is_enabled() {
+        return Ok(Some(market.vamm.mark_price(index_price, market.net_open_interest())?));
+    }
+    require!(!market.has_order_book || order_book.is_some(), ErrorCode::OrderBookRequired);
+    Ok(order_book.and_then(|book| book.mid_price()))
+}
+
+/// Mark and index price: `mark_price` and the oracle index. A market
+/// without a mark marks at the index.
+fn mark_and_index_price(market: &Market, order_book: Option<&OrderBook>, price_feed: &AccountInfo) -> Result<(u64, u64)> {
+    let index_price = market.load_price_feed(price_feed)?.get_index_price()?;
+    let mark_price = mark_price(market, order_book, index_price)?.unwrap_or(index_price);
+    Ok((mark_price, index_price))
+}
+
+/// Premium of the mark price over the oracle index, in bps.
+fn mark_premium_bps(market: &Market, order_book: Option<&OrderBook>, price_feed: &AccountInfo) -> Result<i64> {
+    let (mark_price, index_price) = mark_and_index_price(market, order_book, price_feed)?;
+    Ok(math::premium_bps(mark_price, index_price))
+}
+
+/// Rejects an order landing after its `expiry_ts`, if it has one.
+fn check_order_expiry(expiry_ts: Option<i64>, now: i64) -> Result<()> {
+    if let Some(expiry_ts) = expiry_ts {
+        require!(now <= expiry_ts, ErrorCode::OrderExpired);
+    }
+    Ok(())
+}
+
+/// Rejects a fill more than `max_slippage_bps` worse than the `price` the
+/// trader expected: above it for a long, below it for a short.
+fn check_slippage(side: Side, fill_price: u64, price: u64, max_slippage_bps: u16) -> Result<()> {
+    let within = match side {
+        Side::Long => fill_price as u128 * math::BPS <= price as u128 * (math::BPS + max_slippage_bps as u128),
+        Side::Short => fill_price as u128 * math::BPS >= price as u128 * math::BPS.saturating_sub(max_slippage_bps as u128),
     };
+    require!(within, ErrorCode::SlippageExceeded);
+    Ok(())
+}
+
+/// Rejects a market order larger than the market's share of resting depth
+/// within its band of `oracle_price`, counting bids and asks together.
+fn check_depth_order_cap(
+    market: &Market,
+    order_book: Option<&OrderBook>,
+    size: u64,
+    oracle_price: u64,
+) -> Result<()> {
+    if market.depth_cap_bps == 0 {
+        return Ok(());
+    }
+    let order_book = order_book.ok_or(ErrorCode::OrderBookRequired)?;
+    let depth = order_book.depth_within(oracle_price, market.depth_band_bps);
+    let cap = (depth as u128 * market.depth_cap_bps as u128 / 10000) as u64;
+    require!(size <= cap, ErrorCode::OrderExceedsDepth);
+    Ok(())
+}
+
+/// Creates the position PDA for `position_id` at `info`, for instructions
+/// that open a variable number of positions and so cannot declare them.
+fn create_position_account<'info>(
+    payer: AccountInfo<'info>,
+    info: &AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    market: Pubkey,
+    owner: Pubkey,
+    position_id: u64,
+    side: Side,
+    leverage: u8,
+) -> Result<Account<'info, Position>> {
+    let id = position_id.to_le_bytes();
+    let (address, bump) = Pubkey::find_program_address(
+        &[b"position", market.as_ref(), owner.as_ref(), &id],
+        &crate::ID,
+    );
+    require_keys_eq!(info.key(), address, ErrorCode::PositionAccountMissing);
+    let seeds = &[b"position".as_ref(), market.as_ref(), owner.as_ref(), &id, &[bump]];
+    anchor_lang::system_program::create_account(
+        CpiContext::new_with_signer(
+            system_program,
+            anchor_lang::system_program::CreateAccount { from: payer, to: info.clone() },
+            &[&seeds[..]],
+        ),
+        Rent::get()?.minimum_balance(Position::LEN),
+        Position::LEN as u64,
+        &crate::ID,
+    )?;
+    let mut position: Account<'info, Position> = Account::try_from_unchecked(info)?;
+    position.set_inner(Position::new(market, owner, position_id, side, leverage, Clock::get()?.unix_timestamp, bump));
+    Ok(position)
+}
+
+/// Collects the margin and fee of a filled market order from the trader,
+/// after checking they can pay both. Orders routed through an integrator
+/// pay its fee share directly; paper-trading fees are virtual and are not shared.
+fn collect_market_order<'info>(
+    market: &mut Account<'info, Market>,
+    margin_account: &mut Account<'info, MarginAccount>,
+    user: &Signer<'info>,
+    user_token_account: &Account<'info, TokenAccount>,
+    market_vault: &Account<'info, TokenAccount>,
+    token_program: &Program<'info, Token>,
+    integrator: Option<&mut Account<'info, Integrator>>,
+    integrator_fee_account: Option<&Account<'info, TokenAccount>>,
+    protocol_config: &ProtocolConfig,
+    referral_code: Option<&mut Account<'info, ReferralCode>>,
+    referral_vault: Option<&Account<'info, TokenAccount>>,
+    required_margin: u64,
+    fee: u64,
+    notional: u64,
+) -> Result<()> {
+    // Verify user has enough collateral (including fees)
+    let amount = required_margin.checked_add(fee).ok_or(ErrorCode::MathOverflow)?;
+    let balance = market.trader_balance(margin_account, user_token_account.amount);
+    require!(balance >= amount, ErrorCode::InsufficientCollateral);
+
+    let now = Clock::get()?.unix_timestamp;
+    let mut integrator_fee = 0;
+    let paper_trading = market.paper_trading;
+    if let Some(integrator) = integrator.filter(|_| !paper_trading) {
+        let destination = integrator_fee_account.ok_or(ErrorCode::IntegratorInactive)?;
+        require_keys_eq!(destination.key(), integrator.fee_destination, ErrorCode::IntegratorInactive);
+        integrator_fee = integrator.record_order(now, notional, fee)?;
+        if integrator_fee > 0 {
+            token::transfer(
+                CpiContext::new(
+                    token_program.to_account_info(),
+                    token::Transfer {
+                        from: user_token_account.to_account_info(),
+                        to: destination.to_account_info(),
+                        authority: user.to_account_info(),
+                    },
+                ),
+                integrator_fee,
+            )?;
+            market.release_fee(integrator_fee, now)?;
+        }
+    }
+
+    // The referrer's share comes out of what is left after the integrator's
+    let mut referral_fee = 0;
+    if let Some(referral_code) = referral_code.filter(|_| !paper_trading) {
+        require_keys_neq!(referral_code.owner, user.key(), ErrorCode::SelfReferral);
+        let referral_vault = referral_vault.ok_or(ErrorCode::ReferralsDisabled)?;
+        referral_fee = ((fee - integrator_fee) as u128 * protocol_config.referral_fee_share_bps as u128 / 10000) as u64;
+        referral_code.record_order(notional, referral_fee)?;
+        if referral_fee > 0 {
+            token::transfer(
+                CpiContext::new(
+                    token_program.to_account_info(),
+                    token::Transfer {
+                        from: user_token_account.to_account_info(),
+                        to: referral_vault.to_account_info(),
+                        authority: user.to_account_info(),
+                    },
+                ),
+                referral_fee,
+            )?;
+            market.release_fee(referral_fee, now)?;
+        }
+    }
+
+    // Transfer margin and fees
+    let transfer = CpiContext::new(
+        token_program.to_account_info(),
+        token::Transfer {
+            from: user_token_account.to_account_info(),
+            to: market_vault.to_account_info(),
+            authority: user.to_account_info(),
+        },
+    );
+    collect_from_trader(market, margin_account, transfer, amount - integrator_fee - referral_fee)
+}
+
+/// Moves `amount` from a trader into the market vault. On a paper-trading
+/// market the sub-account's virtual balance pays instead and `transfer` is
+/// never invoked.
+fn collect_from_trader<'info>(
+    market: &mut Market,
+    margin_account: &mut MarginAccount,
+    transfer: CpiContext<'_, '_, '_, 'info, token::Transfer<'info>>,
+    amount: u64,
+) -> Result<()> {
+    if market.paper_trading {
+        return market.collect_paper(margin_account, amount);
+    }
+    token::transfer(transfer, amount)
+}
+
+/// Pays `amount` out of the market vault to a trader, crediting the
+/// sub-account's virtual balance instead on a paper-trading market.
+fn pay_trader<'info>(
+    market: &mut Account<'info, Market>,
+    margin_account: &mut MarginAccount,
+    transfer: CpiContext<'_, '_, '_, 'info, token::Transfer<'info>>,
+    amount: u64,
+) -> Result<()> {
+    if market.paper_trading {
+        market.pay_paper(margin_account, amount)?;
+        let market_key = market.key();
+        market.check_solvency(market_key, 0, 0, Clock::get()?.unix_timestamp);
+        return Ok(());
+    }
+    let vault = transfer.accounts.from.clone();
+    token::transfer(transfer, amount)?;
+    check_vault_solvency(market, &vault)
+}
+
+/// Equity of a market's LP vault holding `vault_amount`, marked at the
+/// oracle price, which is returned alongside it.
+fn lp_vault_equity(market: &Market, price_feed: &AccountInfo, vault_amount: u64) -> Result<(u64, u64)> {
+    let price = market.load_price_feed(price_feed)?.get_adjusted_price()?;
+    let equity = LpVault::equity(vault_amount, market.lp_unsettled_pnl(), market.net_trader_pnl(price)?);
+    Ok((equity, price))
+}
 
+/// Re-marks the LP vault at `price` and records its equity on both the
+/// share price account and the market.
+fn refresh_lp_share_price(
+    market: &mut Market,
+    share_price: &mut LpSharePrice,
+    vault_amount: u64,
+    total_shares: u64,
+    price: u64,
+) -> Result<()> {
+    let equity = LpVault::equity(vault_amount, market.lp_unsettled_pnl(), market.net_trader_pnl(price)?);
+    market.lp_vault_equity = equity;
+    share_price.update(equity, total_shares, Clock::get()?.unix_timestamp)
+}
+
+/// Solvency check after tokens leave the vault. Escrowed order collateral
+/// is left out, so this only catches a vault short of its open positions
+/// and fees; `assert_solvency` checks the full amount.
+fn check_vault_solvency(market: &mut Account<Market>, vault: &AccountInfo) -> Result<()> {
+    let vault_amount = token::accessor::amount(vault)?;
+    let market_key = market.key();
+    market.check_solvency(market_key, vault_amount, 0, Clock::get()?.unix_timestamp);
+    Ok(())
+}
+
+/// While a market has a launch trader cap, new positions need the sub-account's
+/// launch registration, whose seeds the accounts struct checks. Instructions
+/// that take no registration pass `None` and are closed to such markets.
+fn require_launch_registration(market: &Market, registration: Option<&Account<LaunchRegistration>>) -> Result<()> {
+    require!(
+        market.launch_trader_cap == 0 || registration.is_some(),
+        ErrorCode::LaunchRegistrationRequired
+    );
+    Ok(())
+}
+
+/// A cross position's loss beyond its margin is owed from the sub-account's
+/// collateral, which only `liquidate_margin_account` draws on; other closes
+/// of such a position fail until margin is added or the portfolio is
+/// liquidated.
+fn require_margin_covers_loss(position: &Position, margin: u64, pnl: i64) -> Result<()> {
+    require!(
+        position.margin_mode == MarginMode::Isolated || pnl >= 0 || pnl.unsigned_abs() <= margin,
+        ErrorCode::CrossMarginShortfall
+    );
     Ok(())
 }
 
+/// Releases a position that has been settled in full, refunding its rent to
+/// `rent_destination`. While a limit order that fills into it still rests on
+/// the book the account stays open, emptied.
+fn close_position_account<'info>(
+    position: &mut Account<'info, Position>,
+    margin_account: &mut MarginAccount,
+    rent_destination: AccountInfo<'info>,
+) -> Result<()> {
+    if position.resting_order {
+        position.clear();
+        return Ok(());
+    }
+    margin_account.close_position();
+    position.close(rent_destination)
+}
+
+/// Funding to hand back with `closed_size` of `position` if it was opened
+/// during a declared oracle incident: the reverse of what that share paid or
+/// received between its opening and the end of the incident. `None` for
+/// any other position. Once a market has an incident registry, the closes
+/// that consult it must pass it, so keepers cannot skip the fee waiver.
+fn incident_refund(
+    market: &Market,
+    registry: Option<&Account<IncidentRegistry>>,
+    funding_history: Option<&Account<FundingHistory>>,
+    position: &Position,
+    closed_size: u64,
+) -> Result<Option<i64>> {
+    let Some(registry) = registry else {
+        require!(!market.has_incident_registry, ErrorCode::IncidentRegistryRequired);
+        return Ok(None);
+    };
+    let Some(incident) = registry.incident_at(position.creation_time) else {
+        return Ok(None);
+    };
+    let history = funding_history.ok_or(ErrorCode::FundingHistoryUnavailable)?;
+    let funding_rate = history.index_at(incident.end)?
+        .checked_sub(history.index_at(position.creation_time)?)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let notional = (position.notional as u128 * closed_size as u128 / position.base_size as u128) as u64;
+    let received = math::funding_payment(notional, funding_rate, position.side == Side::Long);
+    Ok(Some(received.checked_neg().ok_or(ErrorCode::MathOverflow)?))
+}
+
+/// Fills in a newly created trigger order for `position` and lists it in
+/// the market's registry.
+fn place_trigger_order(
+    trigger_order: &mut Account<TriggerOrder>,
+    registry: &mut TriggerRegistry,
+    position: &Account<Position>,
+    owner: Pubkey,
+    kind: TriggerKind,
+    trigger_price: u64,
+    expiry_ts: Option<i64>,
+    bump: u8,
+) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    require!(position.base_size > 0, ErrorCode::PositionNotFound);
+    require!(trigger_price > 0, ErrorCode::InvalidPrice);
+    require!(expiry_ts.is_none_or(|expiry_ts| expiry_ts > now), ErrorCode::OrderExpired);
+    **trigger_order = TriggerOrder {
+        market: position.market,
+        position: position.key(),
+        owner,
+        kind,
+        side: position.side,
+        trigger_price,
+        created_at: now,
+        expires_at: expiry_ts.unwrap_or(0),
+        bump,
+    };
+    registry.register(trigger_order.key())
+}
+
 #[derive(Accounts)]
 pub struct UpdateFunding<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
+    #[account(mut, seeds = [b"funding_history", market.key().as_ref()], bump = funding_history.bump)]
+    pub funding_history: Account<'info, FundingHistory>,
+    /// Required once the market has an order book, whose mid is the mark price
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+    pub keeper: Signer<'info>,
+    #[account(mut, token::authority = keeper)]
+    pub keeper_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = market.vault @ ErrorCode::InvalidVault)]
+    pub market_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the market's token accounts
+    #[account(seeds = [b"vault_authority", market.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct OpenPriceHistoryPage<'info> {
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = payer,
+        space = PriceHistoryPage::LEN,
+        seeds = [
+            b"price_history".as_ref(),
+            market.key().as_ref(),
+            &PriceHistoryPage::page_for(market.price_history_head).to_le_bytes(),
+        ],
+        bump
+    )]
+    pub price_history: Account<'info, PriceHistoryPage>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(epoch: u64)]
+pub struct ArchiveRevenueEpoch<'info> {
+    pub market: Account<'info, Market>,
+    #[account(
+        init,
+        payer = payer,
+        space = RevenueRecord::LEN,
+        seeds = [b"revenue".as_ref(), market.key().as_ref(), &epoch.to_le_bytes()],
+        bump
+    )]
+    pub revenue_record: Account<'info, RevenueRecord>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RecordPriceSample<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// The market's current page
+    #[account(
+        mut,
+        seeds = [
+            b"price_history".as_ref(),
+            market.key().as_ref(),
+            &PriceHistoryPage::page_for(market.price_history_head).to_le_bytes(),
+        ],
+        bump = price_history.bump
+    )]
+    pub price_history: Account<'info, PriceHistoryPage>,
+    /// Required once the market has an order book, whose mid is the mark price
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SamplePremium<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    #[account(seeds = [b"order_book", market.key().as_ref()], bump = order_book.bump)]
+    pub order_book: Option<Account<'info, OrderBook>>,
+    /// CHECK: Must be the market's oracle, or its fallback while failed over; parsed in the PriceFeed implementation
+    #[account(constraint = market.is_configured_oracle(price_feed.key) @ ErrorCode::InvalidOracle)]
+    pub price_feed: AccountInfo<'info>,
 }
\ No newline at end of file
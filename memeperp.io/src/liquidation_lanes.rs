@@ -0,0 +1,44 @@
+use anchor_lang::prelude::*;
+
+/// Keeper priority during liquidation cascades. A cascade starts once
+/// `cascade_threshold` liquidations land within `priority_slots` slots of
+/// each other; only staked keepers may liquidate for the next
+/// `priority_slots` slots, after which anyone can again.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PriorityLanes {
+    pub cascade_threshold: u16,  // 0 disables priority lanes
+    pub priority_slots: u64,
+    pub min_keeper_stake: u64,
+    pub cascade_start_slot: u64,  // 0 when no cascade is under way
+    pub window_start_slot: u64,
+    pub window_count: u16,  // liquidations since `window_start_slot`
+}
+
+impl PriorityLanes {
+    pub const LEN: usize = 2 + 8 + 8 + 8 + 8 + 2;
+
+    /// Records a liquidation at `slot` and returns whether liquidations are
+    /// currently reserved for staked keepers.
+    pub fn record_liquidation(&mut self, slot: u64) -> bool {
+        if self.cascade_threshold == 0 {
+            return false;
+        }
+        if self.cascade_start_slot != 0 {
+            if slot < self.cascade_start_slot.saturating_add(self.priority_slots) {
+                return true;
+            }
+            self.cascade_start_slot = 0;
+        }
+
+        if self.window_count == 0 || slot >= self.window_start_slot.saturating_add(self.priority_slots) {
+            self.window_start_slot = slot;
+            self.window_count = 0;
+        }
+        self.window_count = self.window_count.saturating_add(1);
+        if self.window_count >= self.cascade_threshold {
+            self.cascade_start_slot = slot.max(1);
+            self.window_count = 0;
+        }
+        false
+    }
+}
@@ -0,0 +1,87 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+// Share prices are quote units per share, with 6 decimals of precision
+pub const SHARE_PRICE_PRECISION: u128 = 1_000_000;
+
+/// Pool of quote tokens owned by liquidity providers of a market. Providers
+/// hold `share_mint` tokens, each a pro-rata claim on the vault's equity.
+/// The vault is the counterparty to the market's traders: it pays out their
+/// net realized gains and takes in their net realized losses.
+#[account]
+pub struct LpVault {
+    pub market: Pubkey,
+    pub vault: Pubkey,  // token account owned by this PDA
+    pub share_mint: Pubkey,  // mint authority is this PDA
+    pub bump: u8,
+}
+
+impl LpVault {
+    pub const LEN: usize = 8 + 32 + 32 + 32 + 1;
+
+    /// Equity of a vault holding `vault_amount`, less the trader PnL owed to
+    /// or by it: `unsettled_pnl` realized but not yet settled, and
+    /// `unrealized_pnl` on open positions. Both are positive when traders
+    /// are ahead.
+    pub fn equity(vault_amount: u64, unsettled_pnl: i64, unrealized_pnl: i64) -> u64 {
+        let equity = vault_amount as i128 - unsettled_pnl as i128 - unrealized_pnl as i128;
+        equity.clamp(0, u64::MAX as i128) as u64
+    }
+
+    /// Shares minted for depositing `amount`: one per token into an empty
+    /// vault, otherwise pro rata to equity. Fails while shares are
+    /// outstanding against no equity, as neither side could be valued fairly.
+    pub fn shares_for_deposit(amount: u64, equity: u64, total_shares: u64) -> Result<u64> {
+        if total_shares == 0 {
+            return Ok(amount);
+        }
+        require!(equity > 0, ErrorCode::VaultInsolvent);
+        let shares = (amount as u128)
+            .checked_mul(total_shares as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            / equity as u128;
+        u64::try_from(shares).map_err(|_| error!(ErrorCode::MathOverflow))
+    }
+
+    pub fn amount_for_shares(shares: u64, equity: u64, total_shares: u64) -> Result<u64> {
+        require!(shares <= total_shares, ErrorCode::InsufficientShares);
+        let amount = (shares as u128)
+            .checked_mul(equity as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            / total_shares as u128;
+        Ok(amount as u64)
+    }
+}
+
+/// Oracle-style view of an LP vault's share price, refreshed on every
+/// deposit, withdrawal, and settlement so other protocols can price shares
+/// from a single account read.
+#[account]
+pub struct LpSharePrice {
+    pub lp_vault: Pubkey,
+    pub price: u64,  // quote per share, scaled by SHARE_PRICE_PRECISION
+    pub vault_equity: u64,
+    pub total_shares: u64,
+    pub updated_at: i64,
+    pub bump: u8,
+}
+
+impl LpSharePrice {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 8 + 8 + 1;
+
+    pub fn update(&mut self, vault_equity: u64, total_shares: u64, now: i64) -> Result<()> {
+        self.price = if total_shares == 0 {
+            SHARE_PRICE_PRECISION as u64
+        } else {
+            let price = (vault_equity as u128)
+                .checked_mul(SHARE_PRICE_PRECISION)
+                .ok_or(ErrorCode::MathOverflow)?
+                / total_shares as u128;
+            u64::try_from(price).map_err(|_| error!(ErrorCode::MathOverflow))?
+        };
+        self.vault_equity = vault_equity;
+        self.total_shares = total_shares;
+        self.updated_at = now;
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use anchor_lang::prelude::*;
+use crate::protocol_config::OrderRateLimits;
+use crate::ErrorCode;
+
+pub const MAX_SUB_ACCOUNTS: u16 = 32;
+pub const MAX_POSITIONS_PER_ACCOUNT: u16 = 32;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct UserStats {
+    pub total_volume: u64,  // notional traded
+    pub trade_count: u64,
+    pub fees_paid: u64,
+    pub history_enabled: bool,
+    pub history_head: u64,  // number of trade records written
+}
+
+impl UserStats {
+    pub const LEN: usize = 8 + 8 + 8 + 1 + 8;
+
+    pub fn record_trade(&mut self, notional: u64, fee: u64) -> Result<()> {
+        self.total_volume = self.total_volume.checked_add(notional).ok_or(ErrorCode::MathOverflow)?;
+        self.trade_count = self.trade_count.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        self.fees_paid = self.fees_paid.checked_add(fee).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+/// Orders a sub-account placed in its latest slot and second, counted
+/// against the protocol's `OrderRateLimits`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct OrderRateWindow {
+    pub slot: u64,
+    pub slot_orders: u16,
+    pub second: i64,
+    pub second_orders: u16,
+}
+
+impl OrderRateWindow {
+    pub const LEN: usize = 8 + 2 + 8 + 2;
+
+    /// Adds `orders` to the counts for the current slot and second, starting
+    /// each count over when its window has moved on.
+    pub fn record(&mut self, orders: u16, limits: &OrderRateLimits, clock: &Clock) -> Result<()> {
+        if self.slot != clock.slot {
+            self.slot = clock.slot;
+            self.slot_orders = 0;
+        }
+        if self.second != clock.unix_timestamp {
+            self.second = clock.unix_timestamp;
+            self.second_orders = 0;
+        }
+        self.slot_orders = self.slot_orders.saturating_add(orders);
+        self.second_orders = self.second_orders.saturating_add(orders);
+        require!(
+            limits.per_slot == 0 || self.slot_orders <= limits.per_slot,
+            ErrorCode::OrderRateLimited
+        );
+        require!(
+            limits.per_second == 0 || self.second_orders <= limits.per_second,
+            ErrorCode::OrderRateLimited
+        );
+        Ok(())
+    }
+}
+
+/// A numbered trading sub-account of a wallet. Positions and orders are
+/// owned by the sub-account, so one wallet can run segregated strategies.
+#[account]
+pub struct MarginAccount {
+    pub authority: Pubkey,
+    pub sub_account_id: u16,
+    pub stats: UserStats,
+    pub collateral: u64,  // quote held for this sub-account in the collateral vault
+    pub paper_balance: u64,  // virtual quote for paper-trading markets
+    pub created_at: i64,
+    pub in_use: bool,  // set for the duration of an instruction that mutates margin state
+    pub next_position_id: u64,  // id of the next position account the sub-account opens
+    pub position_count: u16,  // position accounts currently open, in any market
+    pub bump: u8,
+    pub order_rate: OrderRateWindow,
+    // Approved by the protocol admin for protocol-side hedging: its
+    // positions neither pay nor receive funding
+    pub hedger: bool,
+}
+
+impl MarginAccount {
+    pub const LEN: usize = 8 + 32 + 2 + UserStats::LEN + 8 + 8 + 8 + 1 + 8 + 2 + 1 + OrderRateWindow::LEN + 1;
+
+    /// Claims the sub-account for the current instruction. The flag is
+    /// written to account data right away so that a nested invocation on the
+    /// same sub-account (e.g. through a CPI made mid-instruction) sees it and
+    /// fails instead of reading margin state that is half updated. Every
+    /// successful `lock` must be paired with `unlock` before returning; a
+    /// failed instruction rolls the flag back with everything else.
+    pub fn lock(account: &mut Account<MarginAccount>) -> Result<()> {
+        require!(!account.in_use, ErrorCode::MarginAccountInUse);
+        account.in_use = true;
+        account.exit(&crate::ID)
+    }
+
+    pub fn unlock(&mut self) {
+        self.in_use = false;
+    }
+
+    /// Claims the id for a new position account, which the caller creates at
+    /// the address derived from `next_position_id` before this is called.
+    pub fn open_position(&mut self) -> Result<u64> {
+        require!(self.position_count < MAX_POSITIONS_PER_ACCOUNT, ErrorCode::TooManyPositions);
+        let position_id = self.next_position_id;
+        self.next_position_id = position_id.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        self.position_count += 1;
+        Ok(position_id)
+    }
+
+    pub fn close_position(&mut self) {
+        self.position_count = self.position_count.saturating_sub(1);
+    }
+}
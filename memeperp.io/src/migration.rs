@@ -0,0 +1,42 @@
+use anchor_lang::prelude::*;
+use crate::position::Position;
+use crate::Side;
+
+/// An open position taken out of a settling market, waiting to be opened on
+/// its successor. Lives in its own PDA (seeds: `"position_export"`, exported
+/// position) from `export_position` until `import_position` consumes it; the
+/// margin has already moved to the successor's vault.
+#[account]
+pub struct PositionExport {
+    pub from_market: Pubkey,
+    pub to_market: Pubkey,
+    pub owner: Pubkey,  // margin account
+    pub side: Side,
+    pub base_size: u64,
+    pub entry_price: u64,
+    pub leverage: u8,
+    pub margin: u64,
+    pub exported_at: i64,
+    pub tag: [u8; 32],
+    pub bump: u8,
+}
+
+impl PositionExport {
+    pub const LEN: usize = 8 + 32 + 32 + 32 + 1 + 8 + 8 + 1 + 8 + 8 + 32 + 1;
+
+    pub fn new(position: &Position, to_market: Pubkey, now: i64, bump: u8) -> Self {
+        Self {
+            from_market: position.market,
+            to_market,
+            owner: position.owner,
+            side: position.side,
+            base_size: position.base_size,
+            entry_price: position.entry_price,
+            leverage: position.leverage,
+            margin: position.margin,
+            exported_at: now,
+            tag: position.tag,
+            bump,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use anchor_lang::prelude::*;
+use crate::{ErrorCode, Position, Side};
+
+// Reward indices are stored with 12 decimals of extra precision
+pub const REWARD_INDEX_PRECISION: u128 = 1_000_000_000_000;
+// Volume mode pays `emission_rate` reward units per 1M units of notional
+pub const VOLUME_REWARD_SCALE: u128 = 1_000_000;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Default)]
+pub enum EmissionMode {
+    #[default]
+    Disabled,
+    Volume,
+    OpenInterest,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct MiningState {
+    pub mode: EmissionMode,
+    pub reward_vault: Pubkey,
+    pub emission_rate: u64,  // per second (OpenInterest) or per 1M notional (Volume)
+    pub long_weight_bps: u16,
+    pub short_weight_bps: u16,
+    pub long_reward_index: u128,
+    pub short_reward_index: u128,
+    pub last_accrual_time: i64,
+}
+
+impl MiningState {
+    pub const LEN: usize = 1 + 32 + 8 + 2 + 2 + 16 + 16 + 8;
+
+    /// Advances the per-side reward indices up to `now` using the open
+    /// interest that was held since the last accrual. Must be called before
+    /// open interest on either side changes.
+    pub fn accrue(&mut self, now: i64, long_open_interest: u64, short_open_interest: u64) -> Result<()> {
+        if self.mode == EmissionMode::OpenInterest && now > self.last_accrual_time {
+            let elapsed = (now - self.last_accrual_time) as u128;
+            let emitted = (self.emission_rate as u128)
+                .checked_mul(elapsed)
+                .ok_or(ErrorCode::MathOverflow)?;
+
+            self.long_reward_index = advance_index(
+                self.long_reward_index,
+                emitted,
+                self.long_weight_bps,
+                long_open_interest,
+            )?;
+            self.short_reward_index = advance_index(
+                self.short_reward_index,
+                emitted,
+                self.short_weight_bps,
+                short_open_interest,
+            )?;
+        }
+        self.last_accrual_time = now;
+        Ok(())
+    }
+
+    pub fn reward_index(&self, side: Side) -> u128 {
+        match side {
+            Side::Long => self.long_reward_index,
+            Side::Short => self.short_reward_index,
+        }
+    }
+
+    pub fn volume_reward(&self, notional: u64) -> Result<u64> {
+        if self.mode != EmissionMode::Volume {
+            return Ok(0);
+        }
+        let reward = (notional as u128)
+            .checked_mul(self.emission_rate as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            / VOLUME_REWARD_SCALE;
+        u64::try_from(reward).map_err(|_| error!(ErrorCode::MathOverflow))
+    }
+
+    /// Moves rewards earned by `position` since its last snapshot into
+    /// `pending_rewards` and returns the new pending balance.
+    pub fn settle_position(&self, position: &mut Position) -> Result<u64> {
+        let index = self.reward_index(position.side);
+        let earned = index
+            .saturating_sub(position.reward_index)
+            .checked_mul(position.base_size as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            / REWARD_INDEX_PRECISION;
+        position.pending_rewards = position.pending_rewards
+            .checked_add(u64::try_from(earned).map_err(|_| error!(ErrorCode::MathOverflow))?)
+            .ok_or(ErrorCode::MathOverflow)?;
+        position.reward_index = index;
+        Ok(position.pending_rewards)
+    }
+}
+
+fn advance_index(index: u128, emitted: u128, weight_bps: u16, open_interest: u64) -> Result<u128> {
+    // Nothing is emitted to a side with no open interest
+    if open_interest == 0 {
+        return Ok(index);
+    }
+    let delta = emitted
+        .checked_mul(weight_bps as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        .checked_mul(REWARD_INDEX_PRECISION)
+        .ok_or(ErrorCode::MathOverflow)?
+        / 10000
+        / open_interest as u128;
+    let new_index = index.checked_add(delta).ok_or(ErrorCode::MathOverflow)?;
+    Ok(new_index)
+}
@@ -0,0 +1,56 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+/// Cap on the notional a market trades over a rolling `window`. The rolling
+/// total is estimated from the current fixed window plus the previous one,
+/// weighted by how much of it still overlaps the rolling window.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct NotionalCap {
+    pub window: i64,  // in seconds; 0 disables the cap
+    pub max_notional: u64,
+    pub window_start: i64,
+    pub current: u64,
+    pub previous: u64,
+}
+
+impl NotionalCap {
+    pub const LEN: usize = 8 + 8 + 8 + 8 + 8;
+
+    pub fn rolling_notional(&self, now: i64) -> u64 {
+        if self.window <= 0 {
+            return 0;
+        }
+        let elapsed = now - self.window_start;
+        let (current, previous, into_window) = if elapsed >= 2 * self.window {
+            (0, 0, 0)
+        } else if elapsed >= self.window {
+            (0, self.current, elapsed - self.window)
+        } else {
+            (self.current, self.previous, elapsed.max(0))
+        };
+        let overlap = (self.window - into_window) as u128;
+        let weighted_previous = (previous as u128 * overlap / self.window as u128) as u64;
+        current.saturating_add(weighted_previous)
+    }
+
+    /// Adds `notional` to the window, failing if the rolling total would
+    /// exceed the cap.
+    pub fn record(&mut self, now: i64, notional: u64) -> Result<()> {
+        if self.window <= 0 {
+            return Ok(());
+        }
+        require!(
+            self.rolling_notional(now).saturating_add(notional) <= self.max_notional,
+            ErrorCode::NotionalCapExceeded
+        );
+
+        let elapsed = now - self.window_start;
+        if elapsed >= self.window {
+            self.previous = if elapsed < 2 * self.window { self.current } else { 0 };
+            self.current = 0;
+            self.window_start = now - elapsed % self.window;
+        }
+        self.current = self.current.saturating_add(notional);
+        Ok(())
+    }
+}
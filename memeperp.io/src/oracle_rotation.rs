@@ -0,0 +1,42 @@
+use anchor_lang::prelude::*;
+use crate::price_feed::OracleSource;
+use crate::ErrorCode;
+
+// Delay between queueing a new primary oracle and switching to it
+pub const ORACLE_ROTATION_DELAY: i64 = 24 * 60 * 60;  // 1 day
+// Largest gap between the old and new feeds' prices at the switch
+pub const MAX_ORACLE_ROTATION_DEVIATION_BPS: u64 = 200;  // 2%
+
+/// A queued change of a market's primary oracle.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct OracleRotation {
+    pub pending_oracle: Pubkey,  // default when nothing is queued
+    pub pending_source: OracleSource,
+    pub eta: i64,  // earliest time the switch can be applied
+}
+
+impl OracleRotation {
+    pub const LEN: usize = 32 + 1 + 8;
+
+    pub fn queue(&mut self, oracle: Pubkey, source: OracleSource, now: i64) -> Result<()> {
+        self.pending_oracle = oracle;
+        self.pending_source = source;
+        self.eta = now.checked_add(ORACLE_ROTATION_DELAY).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Checks the timelock has passed and that `new_price` from the pending
+    /// feed agrees with `current_price` from the feed it replaces.
+    pub fn check_ready(&self, now: i64, current_price: u64, new_price: u64) -> Result<()> {
+        require!(self.pending_oracle != Pubkey::default(), ErrorCode::InvalidOracle);
+        require!(now >= self.eta, ErrorCode::TimelockNotElapsed);
+        let deviation_bps = (current_price.abs_diff(new_price) as u128 * 10000)
+            .checked_div(current_price as u128)
+            .ok_or(ErrorCode::InvalidPrice)?;
+        require!(
+            deviation_bps <= MAX_ORACLE_ROTATION_DEVIATION_BPS as u128,
+            ErrorCode::OracleDeviationTooLarge
+        );
+        Ok(())
+    }
+}
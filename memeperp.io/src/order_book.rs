@@ -0,0 +1,330 @@
+use anchor_lang::prelude::*;
+use crate::{ErrorCode, MatchingPolicy, Side};
+
+pub const MAX_ORDERS_PER_SIDE: usize = 64;
+pub const MAX_DEPTH_LEVELS: u8 = 32;
+pub const MAX_ORDERS_PER_OWNER: usize = 8;  // per side
+// The minimum order size doubles each time a side fills this many more slots
+pub const ORDERS_PER_MIN_SIZE_DOUBLING: usize = 16;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct Order {
+    pub order_id: u64,
+    pub owner: Pubkey,
+    pub position: Pubkey,  // position account the fills open into
+    pub price: u64,
+    pub filled_size: u64,
+    pub remaining_size: u64,
+    pub leverage: u8,
+    pub locked_margin: u64,  // escrowed in the market vault, for the remaining size
+    pub locked_fee: u64,
+    pub placed_at: i64,
+}
+
+impl Order {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 8 + 8 + 1 + 8 + 8 + 8;
+
+    pub fn locked_amount(&self) -> u64 {
+        self.locked_margin + self.locked_fee
+    }
+
+    /// Takes `base_size` off the order and returns the share of escrowed
+    /// margin and fee that goes with it. The rest stays escrowed until the
+    /// order completes or is cancelled; the last fill takes any rounding dust.
+    fn fill(&mut self, base_size: u64) -> (u64, u64) {
+        self.filled_size = self.filled_size.saturating_add(base_size.min(self.remaining_size));
+        if base_size >= self.remaining_size {
+            let parts = (self.locked_margin, self.locked_fee);
+            self.remaining_size = 0;
+            self.locked_margin = 0;
+            self.locked_fee = 0;
+            return parts;
+        }
+        let margin = (self.locked_margin as u128 * base_size as u128 / self.remaining_size as u128) as u64;
+        let fee = (self.locked_fee as u128 * base_size as u128 / self.remaining_size as u128) as u64;
+        self.remaining_size -= base_size;
+        self.locked_margin -= margin;
+        self.locked_fee -= fee;
+        (margin, fee)
+    }
+}
+
+/// Part of a resting order that was matched and opens into its position.
+pub struct Fill {
+    pub order_id: u64,
+    pub owner: Pubkey,
+    pub position: Pubkey,
+    pub side: Side,
+    pub price: u64,
+    pub base_size: u64,
+    pub leverage: u8,
+    pub margin: u64,
+    pub fee: u64,
+    pub filled_size: u64,  // order totals after this fill
+    pub remaining_size: u64,
+}
+
+/// Resting limit orders for a market. Bids want to open longs and asks want
+/// to open shorts; both sides are kept sorted best price first, and by
+/// order id (time priority) within a price.
+#[account]
+pub struct OrderBook {
+    pub market: Pubkey,
+    pub next_order_id: u64,
+    pub bids: Vec<Order>,
+    pub asks: Vec<Order>,
+    pub bump: u8,
+}
+
+impl OrderBook {
+    pub const LEN: usize = 8 + 32 + 8 + (4 + Order::LEN * MAX_ORDERS_PER_SIDE) * 2 + 1;
+
+    pub fn orders(&self, side: Side) -> &Vec<Order> {
+        match side {
+            Side::Long => &self.bids,
+            Side::Short => &self.asks,
+        }
+    }
+
+    pub fn orders_mut(&mut self, side: Side) -> &mut Vec<Order> {
+        match side {
+            Side::Long => &mut self.bids,
+            Side::Short => &mut self.asks,
+        }
+    }
+
+    pub fn insert(&mut self, side: Side, mut order: Order) -> Result<u64> {
+        order.order_id = self.next_order_id;
+        self.next_order_id = self.next_order_id.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+
+        let orders = self.orders_mut(side);
+        require!(orders.len() < MAX_ORDERS_PER_SIDE, ErrorCode::OrderBookFull);
+        require!(
+            orders.iter().filter(|resting| resting.owner == order.owner).count() < MAX_ORDERS_PER_OWNER,
+            ErrorCode::TooManyOpenOrders
+        );
+
+        // Insert behind every order at the same or a better price
+        let index = orders
+            .iter()
+            .position(|resting| match side {
+                Side::Long => resting.price < order.price,
+                Side::Short => resting.price > order.price,
+            })
+            .unwrap_or(orders.len());
+        let order_id = order.order_id;
+        orders.insert(index, order);
+        Ok(order_id)
+    }
+
+    pub fn remove(&mut self, side: Side, order_id: u64) -> Result<Order> {
+        let orders = self.orders_mut(side);
+        let index = orders
+            .iter()
+            .position(|order| order.order_id == order_id)
+            .ok_or(ErrorCode::OrderNotFound)?;
+        Ok(orders.remove(index))
+    }
+
+    /// Smallest order `side` accepts next: the market minimum, doubled for
+    /// every `ORDERS_PER_MIN_SIZE_DOUBLING` orders already resting there, so
+    /// filling the book with minimum-size orders gets expensive.
+    pub fn min_order_size(&self, side: Side, market_min_size: u64) -> u64 {
+        let doublings = (self.orders(side).len() / ORDERS_PER_MIN_SIZE_DOUBLING) as u32;
+        market_min_size.saturating_mul(1u64 << doublings)
+    }
+
+    /// Midpoint of the best bid and ask, if both sides have orders.
+    pub fn mid_price(&self) -> Option<u64> {
+        let best_bid = self.bids.first()?.price;
+        let best_ask = self.asks.first()?.price;
+        Some(((best_bid as u128 + best_ask as u128) / 2) as u64)
+    }
+
+    /// Matches the best bid level against the best ask level if they cross.
+    /// Fills execute at the price of whichever level was resting first, and
+    /// the matched quantity is allocated within each level by `policy`.
+    pub fn match_best_levels(&mut self, policy: MatchingPolicy) -> Option<Vec<Fill>> {
+        let best_bid = self.bids.first()?;
+        let best_ask = self.asks.first()?;
+        if best_bid.price < best_ask.price {
+            return None;
+        }
+        let price = if best_bid.order_id < best_ask.order_id { best_bid.price } else { best_ask.price };
+
+        let bid_level = level_len(&self.bids);
+        let ask_level = level_len(&self.asks);
+        let bid_total: u64 = self.bids[..bid_level].iter().map(|o| o.remaining_size).sum();
+        let ask_total: u64 = self.asks[..ask_level].iter().map(|o| o.remaining_size).sum();
+        let quantity = bid_total.min(ask_total);
+
+        let mut fills = Vec::new();
+        fill_level(&mut self.bids, bid_level, Side::Long, price, quantity, policy, &mut fills);
+        fill_level(&mut self.asks, ask_level, Side::Short, price, quantity, policy, &mut fills);
+        Some(fills)
+    }
+
+    /// Resting size on both sides priced within `band_bps` of `price`.
+    pub fn depth_within(&self, price: u64, band_bps: u16) -> u64 {
+        let band = price as u128 * band_bps as u128 / 10000;
+        let low = (price as u128).saturating_sub(band);
+        let high = price as u128 + band;
+        self.bids
+            .iter()
+            .chain(self.asks.iter())
+            .filter(|order| (low..=high).contains(&(order.price as u128)))
+            .fold(0u64, |depth, order| depth.saturating_add(order.remaining_size))
+    }
+
+    /// Aggregates resting orders into at most `levels` price levels per side.
+    pub fn depth(&self, levels: u8) -> BookDepth {
+        BookDepth {
+            bids: aggregate_levels(&self.bids, levels),
+            asks: aggregate_levels(&self.asks, levels),
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct DepthLevel {
+    pub price: u64,
+    pub base_size: u64,
+    pub order_count: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct BookDepth {
+    pub bids: Vec<DepthLevel>,
+    pub asks: Vec<DepthLevel>,
+}
+
+fn level_len(orders: &[Order]) -> usize {
+    let price = orders[0].price;
+    orders.iter().take_while(|order| order.price == price).count()
+}
+
+/// Splits `quantity` across the first `level_len` orders. Price-time fills
+/// them in queue order; pro-rata allocates by resting size and hands the
+/// rounding remainder out in queue order.
+fn allocate(orders: &[Order], quantity: u64, policy: MatchingPolicy) -> Vec<u64> {
+    let mut allocations = vec![0u64; orders.len()];
+    let mut remaining = quantity;
+
+    if policy == MatchingPolicy::ProRata {
+        let total: u128 = orders.iter().map(|o| o.remaining_size as u128).sum();
+        for (allocation, order) in allocations.iter_mut().zip(orders) {
+            *allocation = (quantity as u128 * order.remaining_size as u128 / total) as u64;
+            remaining -= *allocation;
+        }
+    }
+
+    for (allocation, order) in allocations.iter_mut().zip(orders) {
+        let extra = remaining.min(order.remaining_size - *allocation);
+        *allocation += extra;
+        remaining -= extra;
+    }
+    allocations
+}
+
+fn fill_level(
+    orders: &mut Vec<Order>,
+    level_len: usize,
+    side: Side,
+    price: u64,
+    quantity: u64,
+    policy: MatchingPolicy,
+    fills: &mut Vec<Fill>,
+) {
+    let allocations = allocate(&orders[..level_len], quantity, policy);
+    for (order, base_size) in orders.iter_mut().zip(allocations) {
+        if base_size == 0 {
+            continue;
+        }
+        let (margin, fee) = order.fill(base_size);
+        fills.push(Fill {
+            order_id: order.order_id,
+            owner: order.owner,
+            position: order.position,
+            side,
+            price,
+            base_size,
+            leverage: order.leverage,
+            margin,
+            fee,
+            filled_size: order.filled_size,
+            remaining_size: order.remaining_size,
+        });
+    }
+    orders.retain(|order| order.remaining_size > 0);
+}
+
+fn aggregate_levels(orders: &[Order], levels: u8) -> Vec<DepthLevel> {
+    let mut depth: Vec<DepthLevel> = Vec::new();
+    for order in orders {
+        match depth.last_mut() {
+            Some(level) if level.price == order.price => {
+                level.base_size = level.base_size.saturating_add(order.remaining_size);
+                level.order_count += 1;
+            }
+            _ => {
+                if depth.len() == levels as usize {
+                    break;
+                }
+                depth.push(DepthLevel {
+                    price: order.price,
+                    base_size: order.remaining_size,
+                    order_count: 1,
+                });
+            }
+        }
+    }
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book() -> OrderBook {
+        OrderBook { market: Pubkey::default(), next_order_id: 0, bids: Vec::new(), asks: Vec::new(), bump: 0 }
+    }
+
+    fn order(owner: Pubkey) -> Order {
+        Order {
+            order_id: 0,
+            owner,
+            position: Pubkey::default(),
+            price: 100,
+            filled_size: 0,
+            remaining_size: 1,
+            leverage: 1,
+            locked_margin: 0,
+            locked_fee: 0,
+            placed_at: 0,
+        }
+    }
+
+    #[test]
+    fn caps_resting_orders_per_owner_and_side() {
+        let mut book = book();
+        let owner = Pubkey::new_unique();
+        for _ in 0..MAX_ORDERS_PER_OWNER {
+            book.insert(Side::Long, order(owner)).unwrap();
+        }
+        assert_eq!(book.insert(Side::Long, order(owner)), Err(ErrorCode::TooManyOpenOrders.into()));
+        // The cap is per side, and other owners are unaffected
+        assert!(book.insert(Side::Short, order(owner)).is_ok());
+        assert!(book.insert(Side::Long, order(Pubkey::new_unique())).is_ok());
+    }
+
+    #[test]
+    fn raises_the_minimum_size_as_a_side_fills() {
+        let mut book = book();
+        assert_eq!(book.min_order_size(Side::Long, 10), 10);
+        for _ in 0..ORDERS_PER_MIN_SIZE_DOUBLING {
+            book.insert(Side::Long, order(Pubkey::new_unique())).unwrap();
+        }
+        assert_eq!(book.min_order_size(Side::Long, 10), 20);
+        assert_eq!(book.min_order_size(Side::Short, 10), 10);
+    }
+}
@@ -0,0 +1,122 @@
+//! Addresses of the program's PDAs. Seeds are part of the public interface:
+//! integrators can derive every account an instruction needs from these.
+
+use anchor_lang::prelude::*;
+use crate::referral::REFERRAL_CODE_LEN;
+use crate::trigger_order::TriggerKind;
+
+pub fn protocol_config() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"protocol_config"], &crate::ID)
+}
+
+pub fn collateral_authority() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"collateral_authority"], &crate::ID)
+}
+
+pub fn vault_authority(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault_authority", market.as_ref()], &crate::ID)
+}
+
+pub fn margin_account(authority: &Pubkey, sub_account_id: u16) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"margin_account", authority.as_ref(), &sub_account_id.to_le_bytes()],
+        &crate::ID,
+    )
+}
+
+pub fn trade_history(margin_account: &Pubkey, page_index: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"trade_history", margin_account.as_ref(), &page_index.to_le_bytes()],
+        &crate::ID,
+    )
+}
+
+pub fn position(market: &Pubkey, margin_account: &Pubkey, position_id: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"position", market.as_ref(), margin_account.as_ref(), &position_id.to_le_bytes()],
+        &crate::ID,
+    )
+}
+
+pub fn trigger_order(position: &Pubkey, kind: TriggerKind) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"trigger_order", position.as_ref(), kind.seed()], &crate::ID)
+}
+
+pub fn trigger_registry(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"trigger_registry", market.as_ref()], &crate::ID)
+}
+
+pub fn order_book(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"order_book", market.as_ref()], &crate::ID)
+}
+
+pub fn funding_history(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"funding_history", market.as_ref()], &crate::ID)
+}
+
+pub fn price_history(market: &Pubkey, page_index: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"price_history", market.as_ref(), &page_index.to_le_bytes()],
+        &crate::ID,
+    )
+}
+
+pub fn insurance_fund(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"insurance_fund", market.as_ref()], &crate::ID)
+}
+
+pub fn insurance_backstop() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"insurance_backstop"], &crate::ID)
+}
+
+pub fn lp_vault(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"lp_vault", market.as_ref()], &crate::ID)
+}
+
+pub fn lp_share_price(lp_vault: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"lp_share_price", lp_vault.as_ref()], &crate::ID)
+}
+
+pub fn integrator(key: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"integrator", key.as_ref()], &crate::ID)
+}
+
+pub fn funding_arb_vault() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"funding_arb_vault"], &crate::ID)
+}
+
+pub fn position_export(position: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"position_export", position.as_ref()], &crate::ID)
+}
+
+pub fn withdrawal_policy() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"withdrawal_policy"], &crate::ID)
+}
+
+pub fn withdrawal_request(request_id: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"withdrawal_request", &request_id.to_le_bytes()], &crate::ID)
+}
+
+pub fn withdrawal_approval(request: &Pubkey, signer: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"withdrawal_approval", request.as_ref(), signer.as_ref()], &crate::ID)
+}
+
+pub fn launch_registration(market: &Pubkey, margin_account: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"launch_registration", market.as_ref(), margin_account.as_ref()], &crate::ID)
+}
+
+pub fn insurance_coverage(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"insurance_coverage", market.as_ref()], &crate::ID)
+}
+
+pub fn trader_stats(authority: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"trader_stats", authority.as_ref()], &crate::ID)
+}
+
+pub fn referral_code(code: &[u8; REFERRAL_CODE_LEN]) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"referral_code", code.as_ref()], &crate::ID)
+}
+
+pub fn referral_authority() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"referral_authority"], &crate::ID)
+}
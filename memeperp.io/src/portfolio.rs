@@ -0,0 +1,146 @@
+use anchor_lang::prelude::*;
+use crate::margin_account::MarginAccount;
+use crate::position::{MarginMode, Position};
+use crate::{math, ErrorCode, Market, MarketStatus, Side};
+
+// Remaining accounts passed for each position: position, its market, the market's price feed
+pub const PORTFOLIO_ACCOUNTS_PER_POSITION: usize = 3;
+
+/// Health of a whole margin account: its cross-margin collateral plus every
+/// cross position's margin, PnL and unsettled funding at the edge of the
+/// oracle's confidence band against it, against the maintenance margin of
+/// those positions. Isolated positions
+/// stand alone and are left out.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PortfolioHealth {
+    pub collateral: u64,
+    pub equity: i64,
+    pub maintenance_requirement: u64,
+}
+
+impl PortfolioHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.equity >= self.maintenance_requirement as i64
+    }
+
+    /// Collateral that can be withdrawn with the account staying healthy.
+    pub fn free_collateral(&self) -> u64 {
+        let excess = self.equity.saturating_sub(self.maintenance_requirement as i64).max(0) as u64;
+        excess.min(self.collateral)
+    }
+}
+
+/// A sub-account after a hypothetical market order, as returned by
+/// `view_post_trade_health`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PostTradeHealth {
+    pub fill_size: u64,  // what the open-interest cap would let fill
+    pub price: u64,  // average fill price, on the vAMM where the market has one
+    pub required_margin: u64,
+    pub fee: u64,
+    pub position_margin: u64,
+    pub liquidation_price: u64,  // 0 for a cross position
+    pub liquidatable: bool,  // the position would be liquidatable as soon as it opened
+    pub portfolio: PortfolioHealth,
+}
+
+/// Values every position of `margin_account` from `accounts`, laid out as
+/// `PORTFOLIO_ACCOUNTS_PER_POSITION` accounts per position. Like
+/// `load_all_positions`, it fails unless each open position is passed
+/// exactly once. Isolated positions, and positions in paper-trading markets,
+/// which hold virtual margin, are left out of the valuation.
+pub fn portfolio_health<'info>(
+    accounts: &[AccountInfo<'info>],
+    margin_account_key: &Pubkey,
+    margin_account: &MarginAccount,
+) -> Result<PortfolioHealth> {
+    check_account_count(accounts, margin_account)?;
+    let mut equity = margin_account.collateral as i128;
+    let mut maintenance_requirement: u128 = 0;
+    let mut seen: Vec<Pubkey> = Vec::with_capacity(margin_account.position_count as usize);
+    for chunk in accounts.chunks(PORTFOLIO_ACCOUNTS_PER_POSITION) {
+        let (position, market) = load_chunk(chunk, margin_account_key, &mut seen)?;
+        if market.paper_trading || position.base_size == 0 || position.margin_mode == MarginMode::Isolated {
+            continue;
+        }
+        let price = mark_price(&market, &chunk[2], Some(position.side))?;
+        equity += position_value(&market, &position, price)?;
+        let value = position.base_size as u128 * price as u128;
+        maintenance_requirement += value * market.maintenance_margin_fraction as u128 / math::BPS;
+    }
+    Ok(PortfolioHealth {
+        collateral: margin_account.collateral,
+        equity: equity.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+        maintenance_requirement: maintenance_requirement.min(u64::MAX as u128) as u64,
+    })
+}
+
+/// Loads every position of `margin_account` from `accounts`, laid out as for
+/// `portfolio_health`, with what each is worth at the oracle price: its
+/// margin, PnL and unsettled funding, floored at zero since a position can't
+/// lose more than it holds. Positions in paper-trading markets are worth
+/// nothing here.
+pub fn load_valued_positions<'info>(
+    accounts: &[AccountInfo<'info>],
+    margin_account_key: &Pubkey,
+    margin_account: &MarginAccount,
+) -> Result<Vec<(Position, u64)>> {
+    check_account_count(accounts, margin_account)?;
+    let mut positions = Vec::with_capacity(margin_account.position_count as usize);
+    let mut seen: Vec<Pubkey> = Vec::with_capacity(margin_account.position_count as usize);
+    for chunk in accounts.chunks(PORTFOLIO_ACCOUNTS_PER_POSITION) {
+        let (position, market) = load_chunk(chunk, margin_account_key, &mut seen)?;
+        let value = if market.paper_trading || position.base_size == 0 {
+            0
+        } else {
+            let price = mark_price(&market, &chunk[2], None)?;
+            position_value(&market, &position, price)?.clamp(0, u64::MAX as i128) as u64
+        };
+        positions.push((position.into_inner(), value));
+    }
+    Ok(positions)
+}
+
+fn check_account_count(accounts: &[AccountInfo], margin_account: &MarginAccount) -> Result<()> {
+    require!(
+        accounts.len() == margin_account.position_count as usize * PORTFOLIO_ACCOUNTS_PER_POSITION,
+        ErrorCode::PositionAccountsMismatch
+    );
+    Ok(())
+}
+
+fn load_chunk<'info>(
+    chunk: &[AccountInfo<'info>],
+    margin_account_key: &Pubkey,
+    seen: &mut Vec<Pubkey>,
+) -> Result<(Account<'info, Position>, Account<'info, Market>)> {
+    let position: Account<'info, Position> = Account::try_from(&chunk[0])?;
+    require_keys_eq!(position.owner, *margin_account_key, ErrorCode::PositionAccountsMismatch);
+    require!(!seen.contains(chunk[0].key), ErrorCode::PositionAccountsMismatch);
+    seen.push(*chunk[0].key);
+
+    let market: Account<'info, Market> = Account::try_from(&chunk[1])?;
+    require_keys_eq!(position.market, market.key(), ErrorCode::PositionAccountsMismatch);
+    Ok((position, market))
+}
+
+/// Oracle price a position is valued at: the band edge against `side` for
+/// risk checks, or the index price without one.
+fn mark_price(market: &Market, price_feed: &AccountInfo, side: Option<Side>) -> Result<u64> {
+    // A delisted market's positions are worth what they settle at
+    if market.status == MarketStatus::Expired {
+        return Ok(market.settlement_price);
+    }
+    market.check_configured_oracle(price_feed.key)?;
+    let price_feed = market.load_price_feed(price_feed)?;
+    match side {
+        Some(side) => price_feed.get_price_for(side),
+        None => price_feed.get_adjusted_price(),
+    }
+}
+
+fn position_value(market: &Market, position: &Position, price: u64) -> Result<i128> {
+    let funding = market.unsettled_funding(position);
+    Ok(position.margin as i128 + market.position_pnl(position, price)? as i128 + funding as i128
+        - position.deferred_funding as i128)
+}
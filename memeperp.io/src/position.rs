@@ -0,0 +1,273 @@
+use anchor_lang::prelude::*;
+use crate::{calculate_pnl, math, ErrorCode, Side};
+
+/// One open position, in its own PDA (seeds: `"position"`, market, owner
+/// margin account, position id). Ids come from the owner's
+/// `MarginAccount::next_position_id`, so positions never contend for an id
+/// across traders.
+#[account]
+pub struct Position {
+    pub market: Pubkey,
+    pub owner: Pubkey,  // margin account
+    pub position_id: u64,
+    pub side: Side,
+    pub base_size: u64,
+    pub notional: u64,  // entry notional of the open size
+    pub entry_price: u64,  // size-weighted over every fill
+    pub leverage: u8,
+    pub margin: u64,
+    pub last_funding_timestamp: i64,
+    pub liquidation_price: u64,
+    pub realized_pnl: i64,
+    pub unrealized_pnl: i64,
+    pub last_update_price: u64,
+    pub creation_time: i64,
+    pub total_funding_paid: i64,
+    pub reward_index: u128,
+    pub pending_rewards: u64,
+    pub expires_at: i64,  // 0 if the position never expires
+    pub funding_index: i64,  // its side's cumulative funding index at the last settlement
+    pub resting_order: bool,  // a limit order that fills into this position is on the book
+    pub entry_size: u64,  // every fill, including size since closed
+    pub entry_notional: u64,
+    pub exit_size: u64,  // every close, partial or full
+    pub exit_notional: u64,
+    pub deferred_funding: u64,  // funding owed above the market's per-interval cap
+    pub margin_mode: MarginMode,
+    pub tag: [u8; 32],  // caller reference from the opening order, echoed in events
+    pub margin_call: bool,  // health fell below the market's margin call threshold
+    pub funding_exempt: bool,  // opened by a hedger account; neither pays nor receives funding
+    pub bump: u8,
+}
+
+impl Position {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 1 + 8 + 8 + 8 + 1 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 16 + 8 + 8 + 8 + 1 + 8 + 8 + 8 + 8 + 8 + 1 + 1 + 32 + 1 + 1;
+
+    /// An empty position; `Market::open_position` adds size to it.
+    pub fn new(market: Pubkey, owner: Pubkey, position_id: u64, side: Side, leverage: u8, now: i64, bump: u8) -> Self {
+        Self {
+            market,
+            owner,
+            position_id,
+            side,
+            base_size: 0,
+            notional: 0,
+            entry_price: 0,
+            leverage,
+            margin: 0,
+            last_funding_timestamp: now,
+            liquidation_price: 0,
+            realized_pnl: 0,
+            unrealized_pnl: 0,
+            last_update_price: 0,
+            creation_time: now,
+            total_funding_paid: 0,
+            reward_index: 0,
+            pending_rewards: 0,
+            expires_at: 0,
+            funding_index: 0,
+            resting_order: false,
+            entry_size: 0,
+            entry_notional: 0,
+            exit_size: 0,
+            exit_notional: 0,
+            deferred_funding: 0,
+            margin_mode: MarginMode::Isolated,
+            tag: [0; 32],
+            margin_call: false,
+            funding_exempt: false,
+            bump,
+        }
+    }
+
+    /// Adds `size` bought at `price` with `margin` posted, moving the entry
+    /// price to the size-weighted average.
+    pub fn increase(&mut self, size: u64, price: u64, margin: u64, liquidation_threshold: u16) -> Result<()> {
+        let notional = size.checked_mul(price).ok_or(ErrorCode::MathOverflow)?;
+        self.notional = self.notional.checked_add(notional).ok_or(ErrorCode::MathOverflow)?;
+        self.base_size = self.base_size.checked_add(size).ok_or(ErrorCode::MathOverflow)?;
+        self.margin = self.margin.checked_add(margin).ok_or(ErrorCode::MathOverflow)?;
+        self.entry_price = self.notional / self.base_size;
+        self.entry_size = self.entry_size.saturating_add(size);
+        self.entry_notional = self.entry_notional.saturating_add(notional);
+        self.last_update_price = price;
+        self.refresh_liquidation_price(liquidation_threshold);
+        Ok(())
+    }
+
+    /// Records `size` closed at `price` with `pnl` realized on it.
+    pub fn record_exit(&mut self, size: u64, price: u64, pnl: i64) {
+        self.exit_size = self.exit_size.saturating_add(size);
+        self.exit_notional = self.exit_notional.saturating_add(size.saturating_mul(price));
+        self.realized_pnl = self.realized_pnl.saturating_add(pnl);
+    }
+
+    /// Size-weighted entry price over every fill, including closed size.
+    pub fn average_entry_price(&self) -> u64 {
+        self.entry_notional.checked_div(self.entry_size).unwrap_or(0)
+    }
+
+    /// Size-weighted price of every close so far; 0 before the first.
+    pub fn average_exit_price(&self) -> u64 {
+        self.exit_notional.checked_div(self.exit_size).unwrap_or(0)
+    }
+
+    pub fn record(&self, position: Pubkey, now: i64) -> PositionRecord {
+        PositionRecord {
+            market: self.market,
+            owner: self.owner,
+            position,
+            side: self.side,
+            opened_at: self.creation_time,
+            timestamp: now,
+            entry_size: self.entry_size,
+            average_entry_price: self.average_entry_price(),
+            exit_size: self.exit_size,
+            average_exit_price: self.average_exit_price(),
+            realized_pnl: self.realized_pnl,
+            total_funding_paid: self.total_funding_paid,
+            tag: self.tag,
+        }
+    }
+
+    /// Empties a settled position whose account must stay open for the
+    /// limit order still resting on the book. Its record starts over.
+    pub fn clear(&mut self) {
+        self.base_size = 0;
+        self.notional = 0;
+        self.entry_price = 0;
+        self.margin = 0;
+        self.liquidation_price = 0;
+        self.unrealized_pnl = 0;
+        self.pending_rewards = 0;
+        self.expires_at = 0;
+        self.realized_pnl = 0;
+        self.total_funding_paid = 0;
+        self.entry_size = 0;
+        self.entry_notional = 0;
+        self.exit_size = 0;
+        self.exit_notional = 0;
+        self.deferred_funding = 0;
+    }
+
+    /// Recomputes the liquidation price from the margin held against the
+    /// open notional, so margin topped up after opening counts.
+    pub fn refresh_liquidation_price(&mut self, liquidation_threshold: u16) {
+        self.liquidation_price = math::liquidation_price_for_margin(
+            self.side == Side::Long,
+            self.entry_price,
+            self.notional,
+            self.margin,
+            self.leverage,
+            liquidation_threshold,
+        );
+    }
+
+    /// Charges the share of deferred funding that goes with closing `size`
+    /// against the margin. Funding the per-interval cap deferred is owed in
+    /// full when the position closes; whatever the margin cannot cover is
+    /// written off, as with uncapped funding.
+    pub fn charge_deferred_funding(&mut self, size: u64) {
+        let share = if size >= self.base_size {
+            self.deferred_funding
+        } else {
+            (self.deferred_funding as u128 * size as u128 / self.base_size as u128) as u64
+        };
+        let charged = share.min(self.margin);
+        self.margin -= charged;
+        self.deferred_funding -= share;
+        self.total_funding_paid = self.total_funding_paid.saturating_add(charged as i64);
+    }
+
+    /// Whether the position can be liquidated on its own. Cross-margin
+    /// positions never are: they are backed by the sub-account's collateral
+    /// and only liquidated once the whole portfolio is unhealthy.
+    pub fn is_liquidatable(&self, current_price: u64) -> bool {
+        self.margin_mode == MarginMode::Isolated
+            && self.base_size > 0
+            && math::is_liquidatable(self.side == Side::Long, self.liquidation_price, current_price)
+    }
+
+    pub fn update_unrealized_pnl(&mut self, current_price: u64) -> Result<()> {
+        self.unrealized_pnl = calculate_pnl(
+            self.side,
+            self.base_size,
+            self.entry_price,
+            current_price,
+        )?;
+        self.last_update_price = current_price;
+        Ok(())
+    }
+
+    pub fn get_health_ratio(&self, current_price: u64) -> Result<u16> {
+        Ok(math::health_ratio(self.margin, self.base_size, current_price).ok_or(ErrorCode::MathOverflow)?)
+    }
+
+    pub fn can_be_liquidated(&self, current_price: u64, maintenance_margin_ratio: u16) -> Result<bool> {
+        let health_ratio = self.get_health_ratio(current_price)?;
+        Ok(health_ratio < maintenance_margin_ratio)
+    }
+}
+
+/// How a position is margined. An isolated position risks only its own
+/// margin and is liquidated alone; a cross position also draws on the
+/// sub-account's cross-margin collateral and is valued with the portfolio.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum MarginMode {
+    Isolated,
+    Cross,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    Reduce,
+    Liquidation,
+    Expiry,
+    EmergencyWithdraw,
+    ArbVaultClose,
+    StopLoss,
+    TakeProfit,
+    SpotConversion,
+    MarketExpiry,
+}
+
+/// Reporting summary of a position's life so far, published with every
+/// close. Prices are size-weighted over all fills and closes.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PositionRecord {
+    pub market: Pubkey,
+    pub owner: Pubkey,  // margin account
+    pub position: Pubkey,
+    pub side: Side,
+    pub opened_at: i64,
+    pub timestamp: i64,
+    pub entry_size: u64,
+    pub average_entry_price: u64,
+    pub exit_size: u64,
+    pub average_exit_price: u64,
+    pub realized_pnl: i64,
+    pub total_funding_paid: i64,
+    pub tag: [u8; 32],
+}
+
+/// Reads every position account `owner` holds from `accounts`. Fails unless
+/// exactly `position_count` distinct accounts of the owner were passed, so a
+/// caller summarizing an account's exposure cannot leave a position out.
+pub fn load_all_positions<'info>(
+    accounts: &[AccountInfo<'info>],
+    owner: &Pubkey,
+    position_count: u16,
+) -> Result<Vec<Position>> {
+    require!(accounts.len() == position_count as usize, ErrorCode::PositionAccountsMismatch);
+    let mut positions = Vec::with_capacity(accounts.len());
+    for (index, info) in accounts.iter().enumerate() {
+        require!(
+            !accounts[..index].iter().any(|other| other.key == info.key),
+            ErrorCode::PositionAccountsMismatch
+        );
+        let position: Account<'info, Position> = Account::try_from(info)?;
+        require_keys_eq!(position.owner, *owner, ErrorCode::PositionAccountsMismatch);
+        positions.push(position.into_inner());
+    }
+    Ok(positions)
+}
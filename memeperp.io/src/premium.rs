@@ -0,0 +1,50 @@
+use anchor_lang::prelude::*;
+
+/// Time-weighted premium of the mark price over the oracle index for the
+/// current funding round. Each sample holds until the next one, so a sample
+/// counts for as long as it stood, however often the crank runs.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PremiumTwap {
+    pub interest_rate_bps: i16,  // per funding interval, paid by longs when positive
+    pub premium_clamp_bps: u16,  // how far the interest rate may pull funding off the premium
+    pub round_start: i64,
+    pub last_sample_time: i64,
+    pub last_premium_bps: i64,
+    pub cumulative_premium: i128,  // premium in bps times seconds since `round_start`
+}
+
+impl PremiumTwap {
+    pub const LEN: usize = 2 + 2 + 8 + 8 + 8 + 16;
+
+    pub fn new(now: i64) -> Self {
+        Self {
+            round_start: now,
+            last_sample_time: now,
+            ..Self::default()
+        }
+    }
+
+    /// Closes the previous sample at `now` and starts holding `premium_bps`.
+    pub fn sample(&mut self, now: i64, premium_bps: i64) {
+        let elapsed = now.saturating_sub(self.last_sample_time).max(0);
+        self.cumulative_premium = self.cumulative_premium
+            .saturating_add(self.last_premium_bps as i128 * elapsed as i128);
+        self.last_sample_time = now.max(self.last_sample_time);
+        self.last_premium_bps = premium_bps;
+    }
+
+    /// The round's time-weighted premium up to `now`, which starts a new
+    /// round. Falls back to the latest sample for a round with no duration.
+    pub fn close_round(&mut self, now: i64) -> i64 {
+        self.sample(now, self.last_premium_bps);
+        let duration = now.saturating_sub(self.round_start);
+        let twap = if duration > 0 {
+            (self.cumulative_premium / duration as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+        } else {
+            self.last_premium_bps
+        };
+        self.round_start = now;
+        self.cumulative_premium = 0;
+        twap
+    }
+}
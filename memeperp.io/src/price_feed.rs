@@ -1,76 +1,320 @@
 use anchor_lang::prelude::*;
-use pyth_sdk_solana::{load_price_feed_from_account_info, PriceFeed as PythPriceFeed};
-use std::time::{SystemTime, UNIX_EPOCH};
+use anchor_lang::solana_program::{hash::hash, pubkey};
+use memeperp_math::{normalize_price, Rounding};
+use pyth_sdk_solana::load_price_feed_from_account_info;
+use crate::Side;
 
+// Upper bound on a market's `max_oracle_staleness_secs`, and the age limit
+// for feeds read outside any market
+pub const MAX_ORACLE_STALENESS_SECS: i64 = 60;
+
+// How far ahead of the cluster clock a publish time may be, as validator and
+// oracle clocks drift apart by a few seconds
+pub const MAX_ORACLE_CLOCK_SKEW_SECS: i64 = 10;
+
+// Upper bound on a market's `oracle_conf_multiplier_bps`, the k in
+// `price - k * conf` and `price + k * conf`
+pub const MAX_ORACLE_CONF_MULTIPLIER_BPS: u16 = 50_000;  // 5x
+
+// Widest confidence a custom oracle's authority may publish, as a share of
+// the price
+pub const MAX_CUSTOM_ORACLE_CONF_BPS: u16 = 1_000;  // 10%
+
+pub const SWITCHBOARD_V2_PROGRAM_ID: Pubkey = pubkey!("SW1TCH7qEPTdLsDHRgPuMQjbQxKdH2aBStViMFnt64f");
+pub const PUMP_FUN_PROGRAM_ID: Pubkey = pubkey!("6EF8rrecthR5Dkzon8Nwu78hRvfCKubJ14M5uBEwF6P");
+
+// A pump.fun `BondingCurve` account: after the discriminator, virtual token
+// reserves, virtual SOL reserves, real token reserves, real SOL reserves and
+// total supply (all u64), then whether the curve has completed.
+const PUMP_FUN_CURVE_LEN: usize = 8 + 8 * 5 + 1;
+const PUMP_FUN_VIRTUAL_TOKEN_RESERVES: usize = 8;
+const PUMP_FUN_VIRTUAL_SOL_RESERVES: usize = 16;
+const PUMP_FUN_COMPLETE: usize = 8 + 8 * 5;
+// First 8 bytes of sha256("account:BondingCurve")
+const PUMP_FUN_CURVE_DISCRIMINATOR: [u8; 8] = [23, 183, 248, 55, 96, 216, 172, 96];
+// Pump.fun tokens have 6 decimals and SOL 9, so lamports per token base
+// unit are 10^-3 SOL per token. The ratio is kept with 12 more decimals.
+const PUMP_FUN_PRICE_DECIMALS: u32 = 12;
+const PUMP_FUN_EXPO: i32 = -(PUMP_FUN_PRICE_DECIMALS as i32) - 3;
+// A curve's spot price has no confidence of its own, so it is given the move
+// a buy of this many lamports would make, and thin curves read as uncertain
+const PUMP_FUN_CONF_LAMPORTS: u128 = 1_000_000_000;  // 1 SOL
+
+// Offsets into a Switchboard V2 `AggregatorAccountData`, a packed zero-copy
+// account: the latest confirmed round's open timestamp, result and standard
+// deviation. Decimals are an i128 mantissa and a u32 scale.
+const SWITCHBOARD_ROUND_OPEN_TIMESTAMP: usize = 358;
+const SWITCHBOARD_RESULT: usize = 366;
+const SWITCHBOARD_STD_DEVIATION: usize = 386;
+const SWITCHBOARD_DECIMAL_LEN: usize = 16 + 4;
+
+/// Oracle network a price feed account belongs to, which decides how the
+/// account is checked and parsed.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Default)]
+pub enum OracleSource {
+    #[default]
+    Pyth,
+    /// A Switchboard V2 aggregator
+    Switchboard,
+    /// A `CustomOracle` account of this program
+    Custom,
+    /// A pump.fun bonding curve, priced in SOL from its virtual reserves
+    PumpFun,
+}
+
+/// Price pushed on chain by its `authority`, for coins no oracle network
+/// lists yet. Reads as `price * 10^expo`, like a Pyth price.
+#[account]
+pub struct CustomOracle {
+    pub authority: Pubkey,
+    pub price: i64,
+    pub conf: u64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+impl CustomOracle {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 4 + 8;
+}
+
+/// A raw oracle price as published, before any staleness check.
+struct OraclePrice {
+    price: i64,
+    conf: u64,
+    expo: i32,
+    publish_time: i64,
+}
+
+impl OraclePrice {
+    fn load(source: OracleSource, price_account_info: &AccountInfo, now: i64) -> Result<Self> {
+        match source {
+            OracleSource::Pyth => {
+                let price_feed = load_price_feed_from_account_info(price_account_info)
+                    .map_err(|_| ErrorCode::InvalidPriceFeed)?;
+                let price = price_feed.get_price_unchecked();
+                Ok(Self {
+                    price: price.price,
+                    conf: price.conf,
+                    expo: price.expo,
+                    publish_time: price.publish_time,
+                })
+            }
+            OracleSource::Switchboard => Self::load_switchboard(price_account_info),
+            OracleSource::Custom => {
+                let oracle = Account::<CustomOracle>::try_from(price_account_info)
+                    .map_err(|_| ErrorCode::InvalidPriceFeed)?;
+                Ok(Self {
+                    price: oracle.price,
+                    conf: oracle.conf,
+                    expo: oracle.expo,
+                    publish_time: oracle.publish_time,
+                })
+            }
+            OracleSource::PumpFun => Self::load_pump_fun(price_account_info, now),
+        }
+    }
+
+    /// Spot price of a pump.fun token in SOL: the curve's virtual SOL
+    /// reserves over its virtual token reserves. The curve is read live, so
+    /// the price is published at `now`. A completed curve has migrated its
+    /// liquidity away and no longer prices the token.
+    ///
+    /// One trade can move a curve's spot price, so the confidence is how far
+    /// a buy of `PUMP_FUN_CONF_LAMPORTS` would move it: about `2 * buy / sol
+    /// reserves` of the price on a constant-product curve. The market's
+    /// confidence cap then rejects curves too thin to price margin, and its
+    /// band moves risk checks by that much against the position.
+    fn load_pump_fun(price_account_info: &AccountInfo, now: i64) -> Result<Self> {
+        require_keys_eq!(*price_account_info.owner, PUMP_FUN_PROGRAM_ID, ErrorCode::InvalidPriceFeed);
+        let data = price_account_info.try_borrow_data()?;
+        require!(
+            data.len() >= PUMP_FUN_CURVE_LEN && data[..8] == PUMP_FUN_CURVE_DISCRIMINATOR,
+            ErrorCode::InvalidPriceFeed
+        );
+        require!(data[PUMP_FUN_COMPLETE] == 0, ErrorCode::BondingCurveComplete);
+        let virtual_token_reserves = read_u64(&data, PUMP_FUN_VIRTUAL_TOKEN_RESERVES) as u128;
+        let virtual_sol_reserves = read_u64(&data, PUMP_FUN_VIRTUAL_SOL_RESERVES) as u128;
+        require!(virtual_token_reserves > 0, ErrorCode::InvalidPriceFeed);
+        let scale = 10u128.pow(PUMP_FUN_PRICE_DECIMALS);
+        let price = virtual_sol_reserves * scale / virtual_token_reserves;
+        let conf = (2 * PUMP_FUN_CONF_LAMPORTS * scale).div_ceil(virtual_token_reserves);
+        Ok(Self {
+            price: i64::try_from(price).map_err(|_| ErrorCode::MathOverflow)?,
+            conf: u64::try_from(conf).map_err(|_| ErrorCode::MathOverflow)?,
+            expo: PUMP_FUN_EXPO,
+            publish_time: now,
+        })
+    }
+
+    /// Reads the latest confirmed round of a Switchboard V2 aggregator. The
+    /// result is cut to fit an i64 mantissa, and the standard deviation is
+    /// rescaled to the result's exponent as the confidence.
+    fn load_switchboard(price_account_info: &AccountInfo) -> Result<Self> {
+        require_keys_eq!(*price_account_info.owner, SWITCHBOARD_V2_PROGRAM_ID, ErrorCode::InvalidPriceFeed);
+        let data = price_account_info.try_borrow_data()?;
+        require!(
+            data.len() >= SWITCHBOARD_STD_DEVIATION + SWITCHBOARD_DECIMAL_LEN
+                && data[..8] == hash(b"account:AggregatorAccountData").to_bytes()[..8],
+            ErrorCode::InvalidPriceFeed
+        );
+        let publish_time = i64::from_le_bytes(
+            data[SWITCHBOARD_ROUND_OPEN_TIMESTAMP..SWITCHBOARD_ROUND_OPEN_TIMESTAMP + 8].try_into().unwrap(),
+        );
+        let (mut mantissa, mut scale) = read_switchboard_decimal(&data, SWITCHBOARD_RESULT);
+        while i64::try_from(mantissa).is_err() {
+            require!(scale > 0, ErrorCode::MathOverflow);
+            mantissa /= 10;
+            scale -= 1;
+        }
+        let (deviation, deviation_scale) = read_switchboard_decimal(&data, SWITCHBOARD_STD_DEVIATION);
+        let deviation = if deviation_scale >= scale {
+            10i128.checked_pow(deviation_scale - scale).map_or(0, |divisor| deviation / divisor)
+        } else {
+            10i128.checked_pow(scale - deviation_scale)
+                .and_then(|factor| deviation.checked_mul(factor))
+                .unwrap_or(i128::MAX)
+        };
+        Ok(Self {
+            price: mantissa as i64,
+            conf: deviation.unsigned_abs().min(u64::MAX as u128) as u64,
+            expo: -(i32::try_from(scale).map_err(|_| ErrorCode::MathOverflow)?),
+            publish_time,
+        })
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_switchboard_decimal(data: &[u8], offset: usize) -> (i128, u32) {
+    let mantissa = i128::from_le_bytes(data[offset..offset + 16].try_into().unwrap());
+    let scale = u32::from_le_bytes(data[offset + 16..offset + 20].try_into().unwrap());
+    (mantissa, scale)
+}
+
+/// Rounding applied when an oracle price has more precision than the market.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Default)]
+pub enum PriceRounding {
+    #[default]
+    Down,
+    Up,
+    Nearest,
+}
+
+impl From<PriceRounding> for Rounding {
+    fn from(rounding: PriceRounding) -> Self {
+        match rounding {
+            PriceRounding::Down => Rounding::Down,
+            PriceRounding::Up => Rounding::Up,
+            PriceRounding::Nearest => Rounding::Nearest,
+        }
+    }
+}
+
+/// An oracle price checked for staleness at `timestamp`, the cluster time it
+/// was loaded at. It lives for one instruction, so it is not checked again.
 #[derive(Clone)]
 pub struct PriceFeed {
     pub price: i64,
     pub conf: u64,
     pub expo: i32,
     pub timestamp: i64,
-    pub next_update_time: i64,
+    pub decimals: u8,  // decimals of the normalized price
+    pub rounding: PriceRounding,
+    pub max_conf_bps: u16,  // widest conf accepted, as a share of the price
+    pub conf_multiplier_bps: u16,  // k in bps: 10000 moves side prices by one conf
 }
 
 impl PriceFeed {
-    pub fn new_from_pyth(price_account_info: &AccountInfo) -> Result<Self> {
-        let price_feed = load_price_feed_from_account_info(price_account_info)
-            .map_err(|_| ErrorCode::InvalidPriceFeed)?;
-        
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-            
-        let price = price_feed.get_current_price()
-            .ok_or(ErrorCode::StalePrice)?;
-            
-        // Ensure price is not too old (max 60 seconds)
-        require!(
-            current_time - price.publish_time < 60,
-            ErrorCode::StalePrice
-        );
-
+    /// Loads the price from a feed account of `source`, failing if it was
+    /// published `max_age` seconds or more before `now`, the `Clock`
+    /// sysvar's unix timestamp.
+    pub fn load(source: OracleSource, price_account_info: &AccountInfo, now: i64, max_age: i64) -> Result<Self> {
+        let price = OraclePrice::load(source, price_account_info, now)?;
+        check_price_age(price.publish_time, now, max_age)?;
         Ok(Self {
             price: price.price,
-            conf: price.conf as u64,
+            conf: price.conf,
             expo: price.expo,
-            timestamp: current_time,
-            next_update_time: current_time + 1, // Update every second
+            timestamp: now,
+            decimals: 0,
+            rounding: PriceRounding::Down,
+            max_conf_bps: 10000,
+            conf_multiplier_bps: 0,
         })
     }
 
+    pub fn new_from_pyth(price_account_info: &AccountInfo, clock: &Clock) -> Result<Self> {
+        Self::load(OracleSource::Pyth, price_account_info, clock.unix_timestamp, MAX_ORACLE_STALENESS_SECS)
+    }
+
+    /// Normalizes prices to `decimals` decimals, rounding with `rounding`.
+    pub fn with_precision(mut self, decimals: u8, rounding: PriceRounding) -> Self {
+        self.decimals = decimals;
+        self.rounding = rounding;
+        self
+    }
+
+    /// Rejects prices whose confidence is wider than `max_conf_bps` of the
+    /// price, and moves side prices `conf_multiplier_bps` of the confidence
+    /// against the position.
+    pub fn with_confidence(mut self, max_conf_bps: u16, conf_multiplier_bps: u16) -> Self {
+        self.max_conf_bps = max_conf_bps;
+        self.conf_multiplier_bps = conf_multiplier_bps;
+        self
+    }
+
+    /// Publish time of the latest price in the account, without any
+    /// staleness check. Used to detect an oracle that stopped updating.
+    pub fn last_publish_time(source: OracleSource, price_account_info: &AccountInfo, now: i64) -> Result<i64> {
+        Ok(OraclePrice::load(source, price_account_info, now)?.publish_time)
+    }
+
+    /// Index price, failing if the oracle's confidence is too wide to
+    /// trust it. Used where a price does not belong to one side.
     pub fn get_adjusted_price(&self) -> Result<u64> {
-        // Check if price needs update
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-            
+        self.check_confidence()?;
+        self.get_index_price()
+    }
+
+    /// Price a `side` position is risk-checked at: `price - k * conf` for
+    /// longs and `price + k * conf` for shorts, so an uncertain oracle makes
+    /// either side look worse rather than better.
+    pub fn get_price_for(&self, side: Side) -> Result<u64> {
+        let price = self.get_adjusted_price()?;
+        let band = (self.conf as u128 * self.conf_multiplier_bps as u128).div_ceil(10000);
+        let band = i64::try_from(band).map_err(|_| ErrorCode::MathOverflow)?;
+        // Round the band up so the price never moves less than k * conf
+        let band = normalize_price(band, self.expo, self.decimals, Rounding::Up).ok_or(ErrorCode::MathOverflow)?;
+        match side {
+            Side::Long => Ok(price.saturating_sub(band)),
+            Side::Short => Ok(price.checked_add(band).ok_or(ErrorCode::MathOverflow)?),
+        }
+    }
+
+    /// Fails if `conf / price` is over `max_conf_bps`.
+    pub fn check_confidence(&self) -> Result<()> {
+        require!(self.price >= 0, ErrorCode::NegativePrice);
         require!(
-            current_time <= self.next_update_time,
-            ErrorCode::StalePrice
+            self.conf as u128 * 10000 <= self.price as u128 * self.max_conf_bps as u128,
+            ErrorCode::ConfidenceTooWide
         );
+        Ok(())
+    }
 
+    /// Oracle price normalized to the feed's precision, without the
+    /// confidence check.
+    pub fn get_index_price(&self) -> Result<u64> {
         // Handle negative prices
         if self.price < 0 {
             return Err(error!(ErrorCode::NegativePrice));
         }
 
-        // Convert price to proper scale (handle exponent)
-        let scaled_price = if self.expo < 0 {
-            self.price as u64 / 10u64.pow(-self.expo as u32)
-        } else {
-            self.price as u64 * 10u64.pow(self.expo as u32)
-        };
-
-        // Apply confidence interval for safety (use 95% of price)
-        let safe_price = scaled_price
-            .checked_mul(95)
-            .ok_or(ErrorCode::MathOverflow)?
-            .checked_div(100)
+        let scaled_price = normalize_price(self.price, self.expo, self.decimals, self.rounding.into())
             .ok_or(ErrorCode::MathOverflow)?;
 
-        Ok(safe_price)
+        Ok(scaled_price)
     }
     
     pub fn validate_price_change(&self, old_price: u64, max_change_bps: u16) -> Result<()> {
@@ -92,6 +336,17 @@ impl PriceFeed {
     }
 }
 
+/// Fails for a price published `max_age` seconds or more before `now`, or
+/// more than `MAX_ORACLE_CLOCK_SKEW_SECS` after it.
+pub fn check_price_age(publish_time: i64, now: i64, max_age: i64) -> Result<()> {
+    require!(now.saturating_sub(publish_time) < max_age, ErrorCode::StalePrice);
+    require!(
+        publish_time <= now.saturating_add(MAX_ORACLE_CLOCK_SKEW_SECS),
+        ErrorCode::FuturePublishTime
+    );
+    Ok(())
+}
+
 #[error_code]
 pub enum ErrorCode {
     #[msg("Invalid price feed account")]
@@ -102,4 +357,175 @@ pub enum ErrorCode {
     NegativePrice,
     #[msg("Math overflow")]
     MathOverflow,
+    #[msg("Price change exceeds maximum allowed")]
+    ExcessivePriceChange,
+    #[msg("Bonding curve has completed and no longer prices the token")]
+    BondingCurveComplete,
+    #[msg("Oracle confidence interval is too wide relative to the price")]
+    ConfidenceTooWide,
+    #[msg("Price is published too far ahead of the cluster clock")]
+    FuturePublishTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(price: i64, expo: i32) -> PriceFeed {
+        PriceFeed {
+            price,
+            conf: 0,
+            expo,
+            timestamp: 1_000,
+            decimals: 6,
+            rounding: PriceRounding::Down,
+            max_conf_bps: 10000,
+            conf_multiplier_bps: 0,
+        }
+    }
+
+    #[test]
+    fn accepts_prices_younger_than_the_max_age() {
+        assert!(check_price_age(1_000, 1_000, 10).is_ok());
+        assert!(check_price_age(1_000, 1_000 + 9, 10).is_ok());
+        assert!(check_price_age(1_000, 1_000 + MAX_ORACLE_STALENESS_SECS - 1, MAX_ORACLE_STALENESS_SECS).is_ok());
+        // Oracles can publish slightly ahead of the cluster clock
+        assert!(check_price_age(1_005, 1_000, 10).is_ok());
+        assert!(check_price_age(1_000 + MAX_ORACLE_CLOCK_SKEW_SECS, 1_000, 10).is_ok());
+    }
+
+    #[test]
+    fn rejects_prices_published_past_the_clock_skew() {
+        assert_eq!(
+            check_price_age(1_000 + MAX_ORACLE_CLOCK_SKEW_SECS + 1, 1_000, 10),
+            Err(ErrorCode::FuturePublishTime.into())
+        );
+        assert_eq!(check_price_age(i64::MAX, 0, MAX_ORACLE_STALENESS_SECS), Err(ErrorCode::FuturePublishTime.into()));
+    }
+
+    #[test]
+    fn rejects_prices_at_or_past_the_max_age() {
+        assert_eq!(check_price_age(1_000, 1_000 + 10, 10), Err(ErrorCode::StalePrice.into()));
+        assert_eq!(
+            check_price_age(1_000, 1_000 + MAX_ORACLE_STALENESS_SECS, MAX_ORACLE_STALENESS_SECS),
+            Err(ErrorCode::StalePrice.into())
+        );
+        assert_eq!(check_price_age(0, i64::MAX, MAX_ORACLE_STALENESS_SECS), Err(ErrorCode::StalePrice.into()));
+        assert_eq!(check_price_age(i64::MIN, 0, MAX_ORACLE_STALENESS_SECS), Err(ErrorCode::StalePrice.into()));
+    }
+
+    #[test]
+    fn prices_a_loaded_feed_without_reading_the_clock() {
+        // 1.5 at 8 decimals, normalized to 6
+        assert_eq!(feed(150_000_000, -8).get_index_price(), Ok(1_500_000));
+        assert_eq!(feed(150_000_000, -8).get_adjusted_price(), Ok(1_500_000));
+        assert_eq!(feed(-1, -8).get_index_price(), Err(ErrorCode::NegativePrice.into()));
+    }
+
+    #[test]
+    fn rejects_prices_with_a_wide_confidence() {
+        // 1.5 with a 0.03 confidence is 2% of the price
+        let wide = PriceFeed { conf: 3_000_000, ..feed(150_000_000, -8) };
+        assert_eq!(wide.clone().with_confidence(200, 0).get_adjusted_price(), Ok(1_500_000));
+        assert_eq!(
+            wide.clone().with_confidence(199, 0).get_adjusted_price(),
+            Err(ErrorCode::ConfidenceTooWide.into())
+        );
+        assert_eq!(
+            wide.with_confidence(199, 0).get_price_for(Side::Long),
+            Err(ErrorCode::ConfidenceTooWide.into())
+        );
+    }
+
+    // Bonding curve account data with the given virtual reserves
+    fn curve(virtual_token_reserves: u64, virtual_sol_reserves: u64, complete: bool) -> Vec<u8> {
+        let mut data = vec![0u8; PUMP_FUN_CURVE_LEN];
+        data[..8].copy_from_slice(&PUMP_FUN_CURVE_DISCRIMINATOR);
+        data[PUMP_FUN_VIRTUAL_TOKEN_RESERVES..PUMP_FUN_VIRTUAL_TOKEN_RESERVES + 8]
+            .copy_from_slice(&virtual_token_reserves.to_le_bytes());
+        data[PUMP_FUN_VIRTUAL_SOL_RESERVES..PUMP_FUN_VIRTUAL_SOL_RESERVES + 8]
+            .copy_from_slice(&virtual_sol_reserves.to_le_bytes());
+        data[PUMP_FUN_COMPLETE] = complete as u8;
+        data
+    }
+
+    fn load_curve(mut data: Vec<u8>, owner: Pubkey) -> Result<PriceFeed> {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &owner, false, 0);
+        PriceFeed::load(OracleSource::PumpFun, &info, 1_000, MAX_ORACLE_STALENESS_SECS)
+    }
+
+    #[test]
+    fn matches_the_anchor_discriminator_of_a_bonding_curve() {
+        assert_eq!(PUMP_FUN_CURVE_DISCRIMINATOR[..], hash(b"account:BondingCurve").to_bytes()[..8]);
+    }
+
+    #[test]
+    fn prices_a_bonding_curve_from_its_virtual_reserves() {
+        let feed = load_curve(curve(1_073_000_000_000_000, 30_000_000_000, false), PUMP_FUN_PROGRAM_ID).unwrap();
+        // 30 SOL over 1.073B tokens is 2.7959e-8 SOL per token, or 2.7959e-14
+        // per token base unit
+        assert_eq!(feed.price, 27_958_993);
+        assert_eq!(feed.expo, -15);
+        assert_eq!(feed.timestamp, 1_000);
+        // A 1 SOL buy moves 30 SOL of reserves by 2/30 of the price
+        assert_eq!(feed.conf, 1_863_933);
+        assert_eq!(feed.with_precision(18, PriceRounding::Down).get_index_price(), Ok(27_958_993_000));
+    }
+
+    #[test]
+    fn rejects_a_curve_too_thin_for_the_confidence_cap() {
+        let fresh = load_curve(curve(1_073_000_000_000_000, 30_000_000_000, false), PUMP_FUN_PROGRAM_ID).unwrap();
+        assert_eq!(
+            fresh.with_precision(18, PriceRounding::Down).with_confidence(200, 10000).get_adjusted_price(),
+            Err(ErrorCode::ConfidenceTooWide.into())
+        );
+        // Near completion, 115 SOL of reserves moves 1.7% on a 1 SOL buy
+        let deep = load_curve(curve(279_913_043_478_260, 115_000_000_000, false), PUMP_FUN_PROGRAM_ID).unwrap();
+        assert_eq!((deep.price, deep.conf), (410_841_876, 7_145_077));
+        assert_eq!(
+            deep.with_precision(18, PriceRounding::Down).with_confidence(200, 10000).get_adjusted_price(),
+            Ok(410_841_876_000)
+        );
+    }
+
+    #[test]
+    fn rejects_accounts_that_are_not_live_bonding_curves() {
+        let live = curve(1_073_000_000_000_000, 30_000_000_000, false);
+        assert_eq!(
+            load_curve(live.clone(), Pubkey::new_unique()).err(),
+            Some(ErrorCode::InvalidPriceFeed.into())
+        );
+        let mut wrong_type = live.clone();
+        wrong_type[0] ^= 1;
+        assert_eq!(load_curve(wrong_type, PUMP_FUN_PROGRAM_ID).err(), Some(ErrorCode::InvalidPriceFeed.into()));
+        assert_eq!(
+            load_curve(live[..PUMP_FUN_CURVE_LEN - 1].to_vec(), PUMP_FUN_PROGRAM_ID).err(),
+            Some(ErrorCode::InvalidPriceFeed.into())
+        );
+        assert_eq!(
+            load_curve(curve(0, 30_000_000_000, false), PUMP_FUN_PROGRAM_ID).err(),
+            Some(ErrorCode::InvalidPriceFeed.into())
+        );
+        assert_eq!(
+            load_curve(curve(1_073_000_000_000_000, 30_000_000_000, true), PUMP_FUN_PROGRAM_ID).err(),
+            Some(ErrorCode::BondingCurveComplete.into())
+        );
+    }
+
+    #[test]
+    fn moves_side_prices_against_the_position() {
+        let wide = PriceFeed { conf: 3_000_000, ..feed(150_000_000, -8) };
+        // k = 2: 1.5 -/+ 0.06
+        let priced = wide.clone().with_confidence(10000, 20000);
+        assert_eq!(priced.get_price_for(Side::Long), Ok(1_440_000));
+        assert_eq!(priced.get_price_for(Side::Short), Ok(1_560_000));
+        // With k = 0 both sides see the index price
+        assert_eq!(wide.get_price_for(Side::Long), Ok(1_500_000));
+        // The band rounds up past the market's precision
+        let fine = PriceFeed { conf: 1, ..feed(150_000_000, -8) }.with_confidence(10000, 10000);
+        assert_eq!(fine.get_price_for(Side::Long), Ok(1_499_999));
+        assert_eq!(fine.get_price_for(Side::Short), Ok(1_500_001));
+    }
 }
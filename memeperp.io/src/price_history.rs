@@ -0,0 +1,45 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+pub const PRICE_HISTORY_PAGE_SIZE: usize = 60;
+pub const PRICE_SAMPLE_INTERVAL: i64 = 60;  // in seconds
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct PriceSample {
+    pub timestamp: i64,
+    pub mark_price: u64,  // order book mid, or the index without one
+    pub index_price: u64,
+    pub funding_rate: i64,  // rate of the current funding round, in bps
+    pub cumulative_funding_long: i64,
+}
+
+impl PriceSample {
+    pub const LEN: usize = 8 + 8 + 8 + 8 + 8;
+}
+
+/// Fixed-size page of a market's price and funding samples, taken at most
+/// once per `PRICE_SAMPLE_INTERVAL`. Page `n` holds samples
+/// `n * PRICE_HISTORY_PAGE_SIZE ..` of the sequence tracked by
+/// `Market::price_history_head`. Pages are never rewritten, so a disputed
+/// liquidation can be checked against the prices the market saw.
+#[account]
+pub struct PriceHistoryPage {
+    pub market: Pubkey,
+    pub page_index: u64,
+    pub samples: Vec<PriceSample>,
+    pub bump: u8,
+}
+
+impl PriceHistoryPage {
+    pub const LEN: usize = 8 + 32 + 8 + 4 + PriceSample::LEN * PRICE_HISTORY_PAGE_SIZE + 1;
+
+    pub fn page_for(head: u64) -> u64 {
+        head / PRICE_HISTORY_PAGE_SIZE as u64
+    }
+
+    pub fn append(&mut self, sample: PriceSample) -> Result<()> {
+        require!(self.samples.len() < PRICE_HISTORY_PAGE_SIZE, ErrorCode::PriceHistoryPageFull);
+        self.samples.push(sample);
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use anchor_lang::prelude::*;
+use crate::governance::{MAX_FEE_BPS, MAX_FUNDING_RATE_BPS};
+use crate::margin_account::MarginAccount;
+use crate::price_feed::MAX_ORACLE_STALENESS_SECS;
+use crate::recovery::AuthorityRecovery;
+use crate::{ErrorCode, MAX_LIQUIDATION_FEE_BPS};
+
+/// Named parameter sets for listing markets, from most to least permissive.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum MarketPreset {
+    Degen,
+    Standard,
+    Conservative,
+}
+
+pub const MARKET_PRESET_COUNT: usize = 3;
+pub const MAX_HOOK_PROGRAMS: usize = 4;
+pub const MAX_MARKET_MAKERS: usize = 16;
+pub const MAX_FEE_TIERS: usize = 4;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct MarketTemplate {
+    pub configured: bool,
+    pub min_base_order_size: u64,
+    pub tick_size: u64,
+    pub max_leverage: u8,
+    pub liquidation_threshold: u16,  // in bps
+    pub maintenance_margin_fraction: u16,  // in bps
+    pub max_position_size: u64,
+    pub funding_interval: i64,  // in seconds
+    pub fee_bps: u16,
+    pub max_funding_rate_bps: u16,  // per funding interval, either way
+    pub max_oracle_staleness_secs: i64,  // oldest oracle price the market accepts
+}
+
+impl MarketTemplate {
+    pub const LEN: usize = 1 + 8 + 8 + 1 + 2 + 2 + 8 + 8 + 2 + 2 + 8;
+
+    pub fn validate(&self) -> Result<()> {
+        require!(self.tick_size > 0, ErrorCode::ParameterOutOfBounds);
+        require!(self.max_leverage > 0, ErrorCode::ParameterOutOfBounds);
+        require!(self.liquidation_threshold <= 10000, ErrorCode::ParameterOutOfBounds);
+        require!(self.maintenance_margin_fraction <= 10000, ErrorCode::ParameterOutOfBounds);
+        require!(self.min_base_order_size <= self.max_position_size, ErrorCode::ParameterOutOfBounds);
+        require!(self.funding_interval > 0, ErrorCode::ParameterOutOfBounds);
+        require!(self.fee_bps <= MAX_FEE_BPS, ErrorCode::ParameterOutOfBounds);
+        require!(
+            self.max_funding_rate_bps > 0 && self.max_funding_rate_bps <= MAX_FUNDING_RATE_BPS,
+            ErrorCode::ParameterOutOfBounds
+        );
+        require!(
+            self.max_oracle_staleness_secs > 0 && self.max_oracle_staleness_secs <= MAX_ORACLE_STALENESS_SECS,
+            ErrorCode::ParameterOutOfBounds
+        );
+        Ok(())
+    }
+}
+
+/// How many orders a sub-account may place, per slot and per unix second.
+/// Zero leaves that limit off.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct OrderRateLimits {
+    pub per_slot: u16,
+    pub per_second: u16,
+}
+
+impl OrderRateLimits {
+    pub const LEN: usize = 2 + 2;
+}
+
+/// How market fees are split between the insurance fund, the protocol
+/// treasury, stakers and liquidators.
+/// The protocol's split is copied onto every market listed from a template,
+/// and the admin pushes changes to existing markets with
+/// `apply_protocol_fee_split`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeSplit {
+    pub insurance_fee_share_bps: u16,  // of trading fees
+    pub liquidation_fee_bps: u16,  // of a liquidated position's margin
+    pub liquidator_share_bps: u16,  // of the liquidation fee; the rest goes to insurance
+    pub protocol_fee_share_bps: u16,  // of trading fees after insurance; the rest goes to stakers
+}
+
+impl FeeSplit {
+    pub const LEN: usize = 2 + 2 + 2 + 2;
+
+    pub fn validate(&self) -> Result<()> {
+        require!(self.insurance_fee_share_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        require!(self.liquidation_fee_bps <= MAX_LIQUIDATION_FEE_BPS, ErrorCode::ParameterOutOfBounds);
+        require!(self.liquidator_share_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        require!(self.protocol_fee_share_bps <= 10000, ErrorCode::ParameterOutOfBounds);
+        Ok(())
+    }
+}
+
+/// Taker fee discount for wallets with at least `min_volume` of notional
+/// traded over the last 30 days. Unused tiers are default.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeTier {
+    pub min_volume: u64,
+    pub discount_bps: u16,  // off the taker fee rate
+}
+
+impl FeeTier {
+    pub const LEN: usize = 8 + 2;
+}
+
+/// Protocol-wide settings that apply to every market.
+#[account]
+pub struct ProtocolConfig {
+    pub admin: Pubkey,
+    // Last-resort kill switch: all trading stops, but users can still pull
+    // their collateral out against each market's last settled price
+    pub withdrawals_only: bool,
+    pub withdrawals_only_since: i64,
+    // Quote token account holding margin account collateral, owned by the
+    // `collateral_authority` PDA. Its mint is the protocol's quote token.
+    pub collateral_vault: Pubkey,
+    pub bump: u8,
+    // Indexed by `MarketPreset`
+    pub market_templates: [MarketTemplate; MARKET_PRESET_COUNT],
+    pub recovery: AuthorityRecovery,
+    // Programs markets may call on position events; unused slots are default
+    pub hook_programs: [Pubkey; MAX_HOOK_PROGRAMS],
+    pub order_rate_limits: OrderRateLimits,
+    // Wallets whose sub-accounts are exempt from the order rate limits;
+    // unused slots are default
+    pub market_makers: [Pubkey; MAX_MARKET_MAKERS],
+    // Quote token account receiving protocol fees; default until set
+    pub treasury: Pubkey,
+    pub fee_split: FeeSplit,
+    // Volume tiers in ascending order of `min_volume`
+    pub fee_tiers: [FeeTier; MAX_FEE_TIERS],
+    // Quote token account holding unclaimed referral rewards, owned by the
+    // `referral_authority` PDA; default until referrals are configured
+    pub referral_vault: Pubkey,
+    pub referral_fee_share_bps: u16,  // of a referred order's fee, after any integrator share
+}
+
+impl ProtocolConfig {
+    pub const LEN: usize = 8 + 32 + 1 + 8 + 32 + 1 + MarketTemplate::LEN * MARKET_PRESET_COUNT + AuthorityRecovery::LEN
+        + 32 * MAX_HOOK_PROGRAMS + OrderRateLimits::LEN + 32 * MAX_MARKET_MAKERS
+        + 32 + FeeSplit::LEN + FeeTier::LEN * MAX_FEE_TIERS
+        + 32 + 2;
+
+    pub fn market_template(&self, preset: MarketPreset) -> Result<&MarketTemplate> {
+        let template = &self.market_templates[preset as usize];
+        require!(template.configured, ErrorCode::TemplateNotConfigured);
+        Ok(template)
+    }
+
+    pub fn is_approved_hook(&self, program: &Pubkey) -> bool {
+        *program != Pubkey::default() && self.hook_programs.contains(program)
+    }
+
+    /// Taker fee discount for a wallet with `volume` of 30-day volume: that
+    /// of the highest tier it reaches.
+    pub fn fee_tier_discount_bps(&self, volume: u64) -> u16 {
+        self.fee_tiers
+            .iter()
+            .filter(|tier| tier.discount_bps > 0 && volume >= tier.min_volume)
+            .map(|tier| tier.discount_bps)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn is_market_maker(&self, authority: &Pubkey) -> bool {
+        *authority != Pubkey::default() && self.market_makers.contains(authority)
+    }
+
+    /// Counts `orders` placed by `margin_account` against the order rate
+    /// limits, unless its wallet is a registered market maker.
+    pub fn check_order_rate(&self, margin_account: &mut MarginAccount, orders: u16, clock: &Clock) -> Result<()> {
+        if self.is_market_maker(&margin_account.authority) {
+            return Ok(());
+        }
+        margin_account.order_rate.record(orders, &self.order_rate_limits, clock)
+    }
+}
@@ -0,0 +1,52 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+// Shortest inactivity period a recovery key can be configured with
+pub const MIN_RECOVERY_INACTIVITY: i64 = 30 * 24 * 60 * 60;  // 30 days
+
+/// Dead-man's switch for an authority key. The recovery key can take over
+/// only once the authority has gone `inactivity_period` seconds without
+/// signing an admin instruction.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct AuthorityRecovery {
+    pub recovery_authority: Pubkey,  // default when no recovery key is set
+    pub inactivity_period: i64,  // in seconds
+    pub last_activity: i64,  // last admin instruction signed by the authority
+}
+
+impl AuthorityRecovery {
+    pub const LEN: usize = 32 + 8 + 8;
+
+    pub fn record_activity(&mut self, now: i64) {
+        self.last_activity = now;
+    }
+
+    pub fn configure(&mut self, recovery_authority: Pubkey, inactivity_period: i64, now: i64) -> Result<()> {
+        require!(
+            recovery_authority == Pubkey::default() || inactivity_period >= MIN_RECOVERY_INACTIVITY,
+            ErrorCode::ParameterOutOfBounds
+        );
+        self.recovery_authority = recovery_authority;
+        self.inactivity_period = inactivity_period;
+        self.last_activity = now;
+        Ok(())
+    }
+
+    /// Checks that `claimant` may take over the authority at `now`, and
+    /// clears the recovery key so it cannot be used twice.
+    pub fn claim(&mut self, claimant: &Pubkey, now: i64) -> Result<()> {
+        require!(
+            self.recovery_authority != Pubkey::default() && self.recovery_authority == *claimant,
+            ErrorCode::Unauthorized
+        );
+        require!(
+            now >= self.last_activity.saturating_add(self.inactivity_period),
+            ErrorCode::AuthorityStillActive
+        );
+        *self = Self {
+            last_activity: now,
+            ..Self::default()
+        };
+        Ok(())
+    }
+}
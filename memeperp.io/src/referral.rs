@@ -0,0 +1,29 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+pub const REFERRAL_CODE_LEN: usize = 16;
+
+/// A referral code and the rewards it has earned. Orders that name the code
+/// pay the protocol's `referral_fee_share_bps` of their fee into the
+/// referral vault, where it waits for the owner to claim it.
+#[account]
+pub struct ReferralCode {
+    pub owner: Pubkey,
+    pub code: [u8; REFERRAL_CODE_LEN],
+    pub referred_volume: u64,
+    pub unclaimed_rewards: u64,
+    pub total_rewards: u64,
+    pub bump: u8,
+}
+
+impl ReferralCode {
+    pub const LEN: usize = 8 + 32 + REFERRAL_CODE_LEN + 8 + 8 + 8 + 1;
+
+    /// Books a referred order's volume and the `reward` it pays the code.
+    pub fn record_order(&mut self, notional: u64, reward: u64) -> Result<()> {
+        self.referred_volume = self.referred_volume.saturating_add(notional);
+        self.unclaimed_rewards = self.unclaimed_rewards.checked_add(reward).ok_or(ErrorCode::MathOverflow)?;
+        self.total_rewards = self.total_rewards.checked_add(reward).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
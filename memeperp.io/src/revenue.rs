@@ -0,0 +1,99 @@
+use anchor_lang::prelude::*;
+
+pub const REVENUE_EPOCH_DURATION: i64 = 86_400;  // in seconds
+pub const REVENUE_EPOCHS: usize = 8;
+
+/// Where a piece of protocol revenue came from.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum RevenueSource {
+    /// Fees on market orders and closes, net of integrator shares
+    TakerFees,
+    /// Fees on resting limit orders, charged as they fill
+    MakerFees,
+    /// The insurance fund's cut of liquidation fees
+    LiquidationPenalties,
+}
+
+/// Revenue a market collected during one epoch, by source.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct EpochRevenue {
+    pub epoch: u64,
+    pub taker_fees: u64,
+    pub maker_fees: u64,
+    pub liquidation_penalties: u64,
+}
+
+impl EpochRevenue {
+    pub const LEN: usize = 8 + 8 + 8 + 8;
+
+    fn source_mut(&mut self, source: RevenueSource) -> &mut u64 {
+        match source {
+            RevenueSource::TakerFees => &mut self.taker_fees,
+            RevenueSource::MakerFees => &mut self.maker_fees,
+            RevenueSource::LiquidationPenalties => &mut self.liquidation_penalties,
+        }
+    }
+}
+
+/// Epoch a revenue entry at `now` is attributed to.
+pub fn revenue_epoch(now: i64) -> u64 {
+    (now.max(0) / REVENUE_EPOCH_DURATION) as u64
+}
+
+/// A market's revenue over its latest `REVENUE_EPOCHS` epochs, one slot per
+/// epoch. A slot is reset the first time revenue lands in a new epoch, so
+/// older epochs have to be archived to a `RevenueRecord` before then.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct RevenueLedger {
+    pub epochs: [EpochRevenue; REVENUE_EPOCHS],
+}
+
+impl RevenueLedger {
+    pub const LEN: usize = EpochRevenue::LEN * REVENUE_EPOCHS;
+
+    pub fn record(&mut self, now: i64, source: RevenueSource, amount: u64) {
+        let epoch = revenue_epoch(now);
+        let slot = &mut self.epochs[epoch as usize % REVENUE_EPOCHS];
+        if slot.epoch != epoch {
+            *slot = EpochRevenue { epoch, ..Default::default() };
+        }
+        let total = slot.source_mut(source);
+        *total = total.saturating_add(amount);
+    }
+
+    /// Takes back `amount` recorded in the same instruction.
+    pub fn release(&mut self, now: i64, source: RevenueSource, amount: u64) {
+        let epoch = revenue_epoch(now);
+        let slot = &mut self.epochs[epoch as usize % REVENUE_EPOCHS];
+        if slot.epoch == epoch {
+            let total = slot.source_mut(source);
+            *total = total.saturating_sub(amount);
+        }
+    }
+
+    /// Revenue for `epoch`, or `None` once a later epoch has reused its slot.
+    pub fn epoch(&self, epoch: u64) -> Option<EpochRevenue> {
+        let slot = self.epochs[epoch as usize % REVENUE_EPOCHS];
+        if slot.epoch > epoch {
+            return None;
+        }
+        // A slot still holding an earlier epoch saw no revenue in this one
+        if slot.epoch < epoch {
+            return Some(EpochRevenue { epoch, ..Default::default() });
+        }
+        Some(slot)
+    }
+}
+
+/// A market's revenue for one finished epoch, archived by
+/// `archive_revenue_epoch` so it outlives the market's ledger slot.
+#[account]
+pub struct RevenueRecord {
+    pub market: Pubkey,
+    pub revenue: EpochRevenue,
+    pub bump: u8,
+}
+
+impl RevenueRecord {
+    pub const LEN: usize = 8 + 32 + EpochRevenue::LEN + 1;
+}
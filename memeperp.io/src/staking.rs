@@ -0,0 +1,91 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+#[account]
+pub struct StakePool {
+    pub market: Pubkey,
+    pub stake_vault: Pubkey,
+    pub distribution_vault: Pubkey,
+    pub epoch_duration: i64,  // in seconds
+    pub current_epoch: u64,
+    pub epoch_start_time: i64,
+    pub checkpointed_stake: u64,  // stake eligible for the current epoch
+    pub pending_stake: u64,  // stake added this epoch, eligible from the next one
+    pub bump: u8,
+}
+
+impl StakePool {
+    pub const LEN: usize = 8 + 32 + 32 + 32 + 8 + 8 + 8 + 8 + 8 + 1;
+
+    pub fn can_roll(&self, now: i64) -> bool {
+        now - self.epoch_start_time >= self.epoch_duration
+    }
+
+    /// Closes the current epoch and promotes stake added during it so that it
+    /// counts from the next epoch on.
+    pub fn roll(&mut self, now: i64) -> Result<()> {
+        self.checkpointed_stake = self.checkpointed_stake
+            .checked_add(self.pending_stake)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.pending_stake = 0;
+        self.current_epoch = self.current_epoch.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        self.epoch_start_time = now;
+        Ok(())
+    }
+}
+
+#[account]
+pub struct StakerAccount {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub active_stake: u64,
+    pub pending_stake: u64,
+    pub pending_epoch: u64,  // epoch in which `pending_stake` was added
+    pub next_claim_epoch: u64,
+    pub bump: u8,
+}
+
+impl StakerAccount {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 8 + 8 + 8 + 1;
+
+    pub fn total_stake(&self) -> u64 {
+        self.active_stake + self.pending_stake
+    }
+
+    /// Stake counted for `epoch`: everything active plus pending stake that was
+    /// added in an earlier epoch.
+    pub fn promote_pending(&mut self, epoch: u64) -> Result<()> {
+        if self.pending_stake > 0 && self.pending_epoch < epoch {
+            self.active_stake = self.active_stake
+                .checked_add(self.pending_stake)
+                .ok_or(ErrorCode::MathOverflow)?;
+            self.pending_stake = 0;
+        }
+        Ok(())
+    }
+}
+
+#[account]
+pub struct EpochDistribution {
+    pub pool: Pubkey,
+    pub epoch: u64,
+    pub total_fees: u64,
+    pub total_stake: u64,  // checkpointed stake the fees are split across
+    pub claimed: u64,
+    pub bump: u8,
+}
+
+impl EpochDistribution {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 8 + 8 + 1;
+
+    pub fn share_of(&self, stake: u64) -> Result<u64> {
+        if self.total_stake == 0 {
+            return Ok(0);
+        }
+        let share = (self.total_fees as u128)
+            .checked_mul(stake as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            / self.total_stake as u128;
+        Ok(share as u64)
+    }
+}
@@ -0,0 +1,32 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{instruction::{AccountMeta, Instruction}, program::invoke, pubkey};
+
+pub const JUPITER_PROGRAM_ID: Pubkey = pubkey!("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+
+/// Invokes a Jupiter route built off-chain. `route_accounts` are passed
+/// through as the route's accounts; the caller's signature carries over, but
+/// no program PDA signs, so a route cannot move program-owned funds.
+pub fn invoke_jupiter_route<'info>(
+    jupiter_program: &AccountInfo<'info>,
+    route_accounts: &[AccountInfo<'info>],
+    route_data: Vec<u8>,
+) -> Result<()> {
+    let accounts = route_accounts
+        .iter()
+        .map(|account| AccountMeta {
+            pubkey: account.key(),
+            is_signer: account.is_signer,
+            is_writable: account.is_writable,
+        })
+        .collect();
+    let instruction = Instruction {
+        program_id: JUPITER_PROGRAM_ID,
+        accounts,
+        data: route_data,
+    };
+
+    let mut infos = route_accounts.to_vec();
+    infos.push(jupiter_program.clone());
+    invoke(&instruction, &infos)?;
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use anchor_lang::prelude::*;
+use crate::{ErrorCode, Side};
+
+pub const TRADE_HISTORY_PAGE_SIZE: usize = 64;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum TradeKind {
+    Open,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct TradeRecord {
+    pub market: Pubkey,
+    pub kind: TradeKind,
+    pub side: Side,
+    pub base_size: u64,
+    pub price: u64,
+    pub fee: u64,
+    pub timestamp: i64,
+}
+
+impl TradeRecord {
+    pub const LEN: usize = 32 + 1 + 1 + 8 + 8 + 8 + 8;
+}
+
+/// Fixed-size page of a sub-account's trade history. Page `n` holds records
+/// `n * TRADE_HISTORY_PAGE_SIZE ..` of the sequence tracked by
+/// `UserStats::history_head`.
+#[account]
+pub struct TradeHistoryPage {
+    pub margin_account: Pubkey,
+    pub page_index: u64,
+    pub records: Vec<TradeRecord>,
+    pub bump: u8,
+}
+
+impl TradeHistoryPage {
+    pub const LEN: usize = 8 + 32 + 8 + 4 + TradeRecord::LEN * TRADE_HISTORY_PAGE_SIZE + 1;
+
+    pub fn page_for(head: u64) -> u64 {
+        head / TRADE_HISTORY_PAGE_SIZE as u64
+    }
+
+    pub fn append(&mut self, record: TradeRecord) -> Result<()> {
+        require!(self.records.len() < TRADE_HISTORY_PAGE_SIZE, ErrorCode::TradeHistoryPageFull);
+        self.records.push(record);
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use anchor_lang::prelude::*;
+
+pub const VOLUME_DAY: i64 = 86_400;  // in seconds
+pub const VOLUME_DAYS: usize = 30;
+
+/// A wallet's market-order volume over the last `VOLUME_DAYS` days, across
+/// all its sub-accounts, in one bucket per day. The total sets the wallet's
+/// fee tier on the protocol config.
+#[account]
+pub struct TraderStats {
+    pub authority: Pubkey,
+    pub daily_volume: [u64; VOLUME_DAYS],  // notional, indexed by day % VOLUME_DAYS
+    pub last_day: u64,  // day of the latest bucket written
+    pub bump: u8,
+}
+
+impl TraderStats {
+    pub const LEN: usize = 8 + 32 + 8 * VOLUME_DAYS + 8 + 1;
+
+    pub fn record(&mut self, now: i64, notional: u64) {
+        let day = volume_day(now);
+        // Clear the buckets of the days since the last write, which saw no volume
+        let stale = day.saturating_sub(self.last_day).min(VOLUME_DAYS as u64);
+        for offset in 1..=stale {
+            self.daily_volume[((self.last_day + offset) % VOLUME_DAYS as u64) as usize] = 0;
+        }
+        self.last_day = self.last_day.max(day);
+        let bucket = &mut self.daily_volume[(day % VOLUME_DAYS as u64) as usize];
+        *bucket = bucket.saturating_add(notional);
+    }
+
+    /// Volume over the `VOLUME_DAYS` days up to and including today.
+    pub fn volume(&self, now: i64) -> u64 {
+        let elapsed = volume_day(now).saturating_sub(self.last_day);
+        // Only the latest `VOLUME_DAYS - elapsed` days are still in the window
+        let live_days = (VOLUME_DAYS as u64).saturating_sub(elapsed);
+        (0..live_days)
+            .map(|age| self.daily_volume[((self.last_day + VOLUME_DAYS as u64 - age) % VOLUME_DAYS as u64) as usize])
+            .fold(0u64, u64::saturating_add)
+    }
+}
+
+fn volume_day(now: i64) -> u64 {
+    (now.max(0) / VOLUME_DAY) as u64
+}
@@ -0,0 +1,90 @@
+use anchor_lang::prelude::*;
+
+pub const MAX_COUNCIL_SIZE: usize = 5;
+
+#[account]
+pub struct Treasury {
+    pub admin: Pubkey,
+    pub governance_mint: Pubkey,
+    pub lock_vault: Pubkey,  // holds governance tokens locked for voting
+    pub council: [Pubkey; MAX_COUNCIL_SIZE],
+    pub council_size: u8,
+    pub council_threshold: u8,
+    pub quorum: u64,  // minimum locked tokens voting in favor
+    pub voting_period: i64,  // in seconds
+    pub timelock: i64,  // in seconds between approval and execution
+    pub proposal_count: u64,
+    pub bump: u8,
+}
+
+impl Treasury {
+    pub const LEN: usize = 8 + 32 + 32 + 32 + 32 * MAX_COUNCIL_SIZE + 1 + 1 + 8 + 8 + 8 + 8 + 1;
+
+    pub fn council_index(&self, member: &Pubkey) -> Option<usize> {
+        self.council[..self.council_size as usize]
+            .iter()
+            .position(|m| m == member)
+    }
+}
+
+/// Governance tokens locked until `unlock_time`. The locked amount is the
+/// holder's voting weight on any proposal whose voting ends before unlock.
+#[account]
+pub struct VoteLock {
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub unlock_time: i64,
+    pub bump: u8,
+}
+
+impl VoteLock {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 1;
+
+    pub fn voting_weight(&self, voting_ends_at: i64) -> u64 {
+        if self.unlock_time >= voting_ends_at {
+            self.amount
+        } else {
+            0
+        }
+    }
+}
+
+#[account]
+pub struct SpendProposal {
+    pub id: u64,
+    pub proposer: Pubkey,
+    pub source_vault: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub voting_ends_at: i64,
+    pub votes_for: u64,
+    pub votes_against: u64,
+    pub council_approvals: u8,  // bitmask over `Treasury::council`
+    pub approved_at: i64,  // 0 until the proposal passes
+    pub executed: bool,
+    pub bump: u8,
+}
+
+impl SpendProposal {
+    pub const LEN: usize = 8 + 8 + 32 + 32 + 32 + 8 + 8 + 8 + 8 + 1 + 8 + 1 + 1;
+
+    pub fn council_approval_count(&self) -> u8 {
+        self.council_approvals.count_ones() as u8
+    }
+
+    pub fn vote_passed(&self, quorum: u64) -> bool {
+        self.votes_for >= quorum && self.votes_for > self.votes_against
+    }
+}
+
+#[account]
+pub struct VoteRecord {
+    pub proposal: Pubkey,
+    pub voter: Pubkey,
+    pub weight: u64,
+    pub support: bool,
+}
+
+impl VoteRecord {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 1;
+}
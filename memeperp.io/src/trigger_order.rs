@@ -0,0 +1,80 @@
+use anchor_lang::prelude::*;
+use crate::{ErrorCode, Side};
+
+pub const MAX_PENDING_TRIGGERS: usize = 256;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum TriggerKind {
+    StopLoss,
+    TakeProfit,
+}
+
+impl TriggerKind {
+    /// Last PDA seed, so a position can hold one trigger of each kind.
+    pub fn seed(&self) -> &'static [u8] {
+        match self {
+            TriggerKind::StopLoss => b"stop_loss",
+            TriggerKind::TakeProfit => b"take_profit",
+        }
+    }
+}
+
+/// Order that closes a whole position once the oracle price crosses
+/// `trigger_price`, until `expires_at` if set. Each lives in its own PDA
+/// (seeds: `"trigger_order"`, position, kind seed) and is listed in the
+/// market's `TriggerRegistry` until it executes, is cancelled or is removed
+/// after expiring.
+#[account]
+pub struct TriggerOrder {
+    pub market: Pubkey,
+    pub position: Pubkey,
+    pub owner: Pubkey,  // margin account
+    pub kind: TriggerKind,
+    pub side: Side,  // side of the position it closes
+    pub trigger_price: u64,
+    pub created_at: i64,
+    pub expires_at: i64,  // 0 for no expiry
+    pub bump: u8,
+}
+
+impl TriggerOrder {
+    pub const LEN: usize = 8 + 32 + 32 + 32 + 1 + 1 + 8 + 8 + 8 + 1;
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at != 0 && now > self.expires_at
+    }
+
+    /// A stop-loss fires once the price moves against the position to the
+    /// trigger, a take-profit once it moves in its favour: a long stop-loss
+    /// at or below the trigger, a long take-profit at or above it, and the
+    /// other way round for shorts.
+    pub fn is_triggered(&self, price: u64) -> bool {
+        match (self.kind, self.side) {
+            (TriggerKind::StopLoss, Side::Long) | (TriggerKind::TakeProfit, Side::Short) => price <= self.trigger_price,
+            (TriggerKind::StopLoss, Side::Short) | (TriggerKind::TakeProfit, Side::Long) => price >= self.trigger_price,
+        }
+    }
+}
+
+/// Every pending trigger order of a market, so keepers can read them all
+/// from one account instead of scanning the program.
+#[account]
+pub struct TriggerRegistry {
+    pub market: Pubkey,
+    pub pending: Vec<Pubkey>,
+    pub bump: u8,
+}
+
+impl TriggerRegistry {
+    pub const LEN: usize = 8 + 32 + 4 + 32 * MAX_PENDING_TRIGGERS + 1;
+
+    pub fn register(&mut self, trigger_order: Pubkey) -> Result<()> {
+        require!(self.pending.len() < MAX_PENDING_TRIGGERS, ErrorCode::TriggerRegistryFull);
+        self.pending.push(trigger_order);
+        Ok(())
+    }
+
+    pub fn deregister(&mut self, trigger_order: &Pubkey) {
+        self.pending.retain(|pending| pending != trigger_order);
+    }
+}
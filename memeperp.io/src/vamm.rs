@@ -0,0 +1,70 @@
+use anchor_lang::prelude::*;
+use crate::{math, ErrorCode, Side};
+
+/// Virtual constant-product pool (x*y=k) that gives a market a mark price
+/// moving with taker flow. No tokens sit behind the reserves: the base
+/// reserve is `base_depth` less the traders' net long open interest, and the
+/// pool is re-pegged to the oracle index on every read, so the mark carries
+/// a premium that grows with the imbalance. A `base_depth` of 0 disables it.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct Vamm {
+    pub base_depth: u64,  // virtual base reserve while open interest is balanced
+}
+
+impl Vamm {
+    pub const LEN: usize = 8;
+
+    pub fn is_enabled(&self) -> bool {
+        self.base_depth > 0
+    }
+
+    /// Mark price at `index_price` with traders net long `net_base`. An
+    /// imbalance past the depth, which only closes can leave, marks as if
+    /// one unit of base reserve were left.
+    pub fn mark_price(&self, index_price: u64, net_base: i64) -> Result<u64> {
+        let net_base = net_base.min(self.base_depth as i64 - 1);
+        math::vamm_price(self.base_depth, index_price, net_base, net_base, math::Rounding::Nearest)
+            .ok_or(error!(ErrorCode::MathOverflow))
+    }
+
+    /// Average price a taker order of `size` on `side` fills at, rounded
+    /// against the taker. Fails if the order would take the whole base reserve.
+    pub fn fill_price(&self, index_price: u64, net_base: i64, side: Side, size: u64) -> Result<u64> {
+        let size = i64::try_from(size).map_err(|_| error!(ErrorCode::MathOverflow))?;
+        let (to, rounding) = match side {
+            Side::Long => (net_base.saturating_add(size), math::Rounding::Up),
+            Side::Short => (net_base.saturating_sub(size), math::Rounding::Down),
+        };
+        require!(to < self.base_depth as i64 && net_base < self.base_depth as i64, ErrorCode::VammDepthExceeded);
+        math::vamm_price(self.base_depth, index_price, net_base, to, rounding).ok_or(error!(ErrorCode::MathOverflow))
+    }
+
+    /// Average price closing `size` of a `side` position fills at, rounded
+    /// against the trader: a long's close sells into the pool and a short's
+    /// close buys from it, moving net open interest back. The part of a
+    /// close past the base reserve, which only an imbalance beyond the depth
+    /// can reach, fills at `index_price` rather than failing.
+    pub fn exit_price(&self, index_price: u64, net_base: i64, side: Side, size: u64) -> Result<u64> {
+        let size_base = i64::try_from(size).map_err(|_| error!(ErrorCode::MathOverflow))?;
+        let (to, rounding) = match side {
+            Side::Long => (net_base.saturating_sub(size_base), math::Rounding::Down),
+            Side::Short => (net_base.saturating_add(size_base), math::Rounding::Up),
+        };
+        // Net with one unit of base reserve left
+        let last = self.base_depth as i64 - 1;
+        let (low, high) = (net_base.min(to), net_base.max(to).min(last));
+        let on_curve = high.saturating_sub(low).max(0) as u64;
+        if on_curve == 0 {
+            return Ok(index_price);
+        }
+        let curve_price = math::vamm_price(self.base_depth, index_price, low, high, rounding)
+            .ok_or(error!(ErrorCode::MathOverflow))?;
+        if on_curve == size {
+            return Ok(curve_price);
+        }
+        let total = curve_price as u128 * on_curve as u128 + index_price as u128 * (size - on_curve) as u128;
+        math::fixed::mul_div(total, 1, size as u128, rounding)
+            .and_then(|price| u64::try_from(price).ok())
+            .ok_or(error!(ErrorCode::MathOverflow))
+    }
+}
@@ -0,0 +1,147 @@
+use anchor_lang::prelude::*;
+use crate::ErrorCode;
+
+pub const MAX_WITHDRAWAL_SIGNERS: usize = 10;
+// Window over which single-signer withdrawals add up against the limit
+pub const SINGLE_SIGNER_PERIOD: i64 = 86_400;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum WithdrawalSource {
+    Treasury,
+    Insurance,
+}
+
+/// Who may move funds out of the treasury and the insurance funds. Signers
+/// can withdraw up to `single_signer_limit` alone in each
+/// `SINGLE_SIGNER_PERIOD`, all together; anything more needs a
+/// `WithdrawalRequest` approved by `threshold` of the signers.
+#[account]
+pub struct WithdrawalPolicy {
+    pub signers: [Pubkey; MAX_WITHDRAWAL_SIGNERS],
+    pub signer_count: u8,
+    pub threshold: u8,
+    pub single_signer_limit: u64,
+    pub version: u64,  // bumped on every change; older requests no longer execute
+    pub request_count: u64,
+    pub bump: u8,
+    pub spend_vault: Pubkey,  // the treasury vault `withdraw_treasury` draws from
+    pub period_start: i64,
+    pub withdrawn_in_period: u64,  // single-signer withdrawals since `period_start`
+}
+
+impl WithdrawalPolicy {
+    pub const LEN: usize = 8 + 32 * MAX_WITHDRAWAL_SIGNERS + 1 + 1 + 8 + 8 + 8 + 1 + 32 + 8 + 8;
+
+    pub fn configure(&mut self, signers: &[Pubkey], threshold: u8, single_signer_limit: u64) -> Result<()> {
+        require!(
+            !signers.is_empty() && signers.len() <= MAX_WITHDRAWAL_SIGNERS,
+            ErrorCode::InvalidWithdrawalPolicy
+        );
+        require!(
+            threshold > 0 && threshold as usize <= signers.len(),
+            ErrorCode::InvalidWithdrawalPolicy
+        );
+        for (i, signer) in signers.iter().enumerate() {
+            require!(!signers[..i].contains(signer), ErrorCode::InvalidWithdrawalPolicy);
+        }
+        self.signers = [Pubkey::default(); MAX_WITHDRAWAL_SIGNERS];
+        self.signers[..signers.len()].copy_from_slice(signers);
+        self.signer_count = signers.len() as u8;
+        self.threshold = threshold;
+        self.single_signer_limit = single_signer_limit;
+        self.version = self.version.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn is_signer(&self, key: &Pubkey) -> bool {
+        self.signers[..self.signer_count as usize].contains(key)
+    }
+
+    /// Fails unless at least `threshold` distinct signers of the policy
+    /// signed the transaction among `accounts`.
+    pub fn check_approvals(&self, accounts: &[AccountInfo]) -> Result<()> {
+        let mut approvals: Vec<&Pubkey> = Vec::with_capacity(self.signer_count as usize);
+        for account in accounts {
+            if account.is_signer && self.is_signer(account.key) && !approvals.contains(&account.key) {
+                approvals.push(account.key);
+            }
+        }
+        require!(approvals.len() >= self.threshold as usize, ErrorCode::WithdrawalApprovalRequired);
+        Ok(())
+    }
+
+    /// Checks that `signer` may move `amount` from `source_vault` to
+    /// `destination` at `now`. Past what is left of the period's
+    /// single-signer limit this consumes `request`, which must describe the
+    /// same withdrawal and carry enough approvals.
+    pub fn authorize(
+        &mut self,
+        signer: &Pubkey,
+        request: Option<&mut WithdrawalRequest>,
+        source: WithdrawalSource,
+        source_vault: &Pubkey,
+        destination: &Pubkey,
+        amount: u64,
+        now: i64,
+    ) -> Result<()> {
+        require!(self.is_signer(signer), ErrorCode::Unauthorized);
+        if now >= self.period_start.saturating_add(SINGLE_SIGNER_PERIOD) {
+            self.period_start = now;
+            self.withdrawn_in_period = 0;
+        }
+        let withdrawn = self.withdrawn_in_period.saturating_add(amount);
+        if request.is_none() && withdrawn <= self.single_signer_limit {
+            self.withdrawn_in_period = withdrawn;
+            return Ok(());
+        }
+        let request = request.ok_or(ErrorCode::WithdrawalApprovalRequired)?;
+        require!(
+            request.policy_version == self.version
+                && request.source == source
+                && request.source_vault == *source_vault
+                && request.destination == *destination
+                && request.amount == amount,
+            ErrorCode::WithdrawalRequestMismatch
+        );
+        require!(!request.executed, ErrorCode::WithdrawalAlreadyExecuted);
+        require!(request.approval_count >= self.threshold, ErrorCode::WithdrawalApprovalRequired);
+        request.executed = true;
+        Ok(())
+    }
+}
+
+/// A withdrawal above the single-signer limit, collecting approvals across
+/// transactions. Lives at seeds `"withdrawal_request"`, request id (u64 LE).
+#[account]
+pub struct WithdrawalRequest {
+    pub id: u64,
+    pub policy_version: u64,
+    pub proposer: Pubkey,
+    pub source: WithdrawalSource,
+    pub source_vault: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub approval_count: u8,
+    pub executed: bool,
+    pub created_at: i64,
+    pub bump: u8,
+}
+
+impl WithdrawalRequest {
+    pub const LEN: usize = 8 + 8 + 8 + 32 + 1 + 32 + 32 + 8 + 1 + 1 + 8 + 1;
+}
+
+/// One signer's approval of a request. Its address (seeds:
+/// `"withdrawal_approval"`, request, signer) can only be created once, so
+/// no signer is counted twice.
+#[account]
+pub struct WithdrawalApproval {
+    pub request: Pubkey,
+    pub signer: Pubkey,
+    pub approved_at: i64,
+    pub bump: u8,
+}
+
+impl WithdrawalApproval {
+    pub const LEN: usize = 8 + 32 + 32 + 8 + 1;
+}
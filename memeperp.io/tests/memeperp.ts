@@ -2,7 +2,7 @@ import * as anchor from "@project-serum/anchor";
 import { Program } from "@project-serum/anchor";
 import { Memeperp } from "../target/types/memeperp";
 import { PublicKey, Keypair, SystemProgram } from "@solana/web3.js";
-import { TOKEN_PROGRAM_ID, Token } from "@solana/spl-token";
+import { TOKEN_PROGRAM_ID, Token, createAccount, createMint, getAccount, mintTo } from "@solana/spl-token";
 import { assert } from "chai";
 
 describe("memeperp", () => {
@@ -15,7 +15,10 @@ describe("memeperp", () => {
   let marketVault: Keypair;
   let userTokenAccount: Keypair;
   let mockPriceFeed: Keypair;
+  let marginAccount: PublicKey;
+  let protocolConfig: PublicKey;
   let mint: Token;
+  let treasurySpendVault: PublicKey;
   
   // Constants
   const INITIAL_MINT_AMOUNT = new anchor.BN("1000000000000"); // 1M tokens
@@ -27,6 +30,8 @@ describe("memeperp", () => {
   const MAX_POSITION_SIZE = new anchor.BN("100000000000"); // 100k tokens
   const FUNDING_INTERVAL = 3600; // 1 hour
   const MAX_PRICE_CHANGE_BPS = 1000; // 10%
+  const NO_TAG = Array(32).fill(0);
+  const MAX_SLIPPAGE_BPS = 100;
 
   before(async () => {
     // Initialize market and token accounts
@@ -34,6 +39,19 @@ describe("memeperp", () => {
     marketVault = Keypair.generate();
     userTokenAccount = Keypair.generate();
     mockPriceFeed = Keypair.generate();
+    [marginAccount] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("margin_account"),
+        provider.wallet.publicKey.toBuffer(),
+        new anchor.BN(0).toArrayLike(Buffer, "le", 2),
+      ],
+      program.programId
+    );
+
+    [protocolConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("protocol_config")],
+      program.programId
+    );
 
     // Create mock price feed
     await provider.connection.confirmTransaction(
@@ -41,6 +59,129 @@ describe("memeperp", () => {
     );
   });
 
+  function positionAddress(positionId: anchor.BN, owner = marginAccount, market = marketKeypair.publicKey): PublicKey {
+    return PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        market.toBuffer(),
+        owner.toBuffer(),
+        positionId.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    )[0];
+  }
+
+  // Position PDA that the sub-account's next order opens
+  async function nextPosition(owner = marginAccount): Promise<PublicKey> {
+    const account = await program.account.marginAccount.fetch(owner);
+    return positionAddress(account.nextPositionId, owner);
+  }
+
+  // A new market with a real quote vault, priced by a custom oracle with no
+  // decimals that the wallet publishes to. Risk checks use the oracle price
+  // itself, without a confidence band.
+  async function oracleMarket(name: string, price: number) {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const market = Keypair.generate();
+    const customOracle = Keypair.generate();
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), market.publicKey.toBuffer()],
+      program.programId
+    );
+    const quoteMint = await createMint(provider.connection, payer, provider.wallet.publicKey, null, 6);
+    const vault = await createAccount(provider.connection, payer, quoteMint, vaultAuthority, Keypair.generate());
+
+    await program.methods
+      .initializeMarket(name, new anchor.BN(1), new anchor.BN(1), 10, 9500, 500,
+        new anchor.BN(1_000_000), new anchor.BN(3600), 100, new anchor.BN(60))
+      .accounts({
+        market: market.publicKey,
+        marketVault: vault,
+        vaultAuthority,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([market])
+      .rpc();
+    await program.methods
+      .initializeCustomOracle(0)
+      .accounts({
+        customOracle: customOracle.publicKey,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([customOracle])
+      .rpc();
+    const publish = (price: number, conf = 1) =>
+      program.methods
+        .updateCustomOracle(new anchor.BN(price), new anchor.BN(conf))
+        .accounts({ customOracle: customOracle.publicKey, authority: provider.wallet.publicKey })
+        .rpc();
+    await publish(price);
+    const admin = { market: market.publicKey, authority: provider.wallet.publicKey };
+    await program.methods
+      .setOracleFailover(customOracle.publicKey, { custom: {} }, Keypair.generate().publicKey, { pyth: {} },
+        new anchor.BN(120))
+      .accounts(admin)
+      .rpc();
+    await program.methods.setOracleConfidence(200, 0).accounts(admin).rpc();
+
+    return { market: market.publicKey, vault, vaultAuthority, oracle: customOracle.publicKey, quoteMint, admin, publish };
+  }
+
+  // A new wallet with sub-account 0 and `amount` of `quoteMint`
+  async function fundedTrader(quoteMint: PublicKey, amount: number) {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const trader = Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(trader.publicKey, 1_000_000_000)
+    );
+    const [account] = PublicKey.findProgramAddressSync(
+      [Buffer.from("margin_account"), trader.publicKey.toBuffer(), new anchor.BN(0).toArrayLike(Buffer, "le", 2)],
+      program.programId
+    );
+    const tokens = await createAccount(provider.connection, payer, quoteMint, trader.publicKey);
+    await mintTo(provider.connection, payer, quoteMint, tokens, payer, amount);
+    await program.methods
+      .initializeMarginAccount(0)
+      .accounts({ marginAccount: account, authority: trader.publicKey, systemProgram: SystemProgram.programId })
+      .signers([trader])
+      .rpc();
+    return { trader, account, tokens };
+  }
+
+  // Opens `size` at the oracle price on a market from `oracleMarket`,
+  // returning the new position's address
+  async function openPosition(
+    m: { market: PublicKey; vault: PublicKey; oracle: PublicKey },
+    t: { trader: Keypair; account: PublicKey; tokens: PublicKey },
+    side: object,
+    size: number,
+    price: number,
+    leverage: number
+  ): Promise<PublicKey> {
+    const account = await program.account.marginAccount.fetch(t.account);
+    const positionKey = positionAddress(account.nextPositionId, t.account, m.market);
+    await program.methods
+      .placeOrder(side, new anchor.BN(size), new anchor.BN(size), new anchor.BN(price), MAX_SLIPPAGE_BPS, leverage, 0,
+        NO_TAG, null)
+      .accounts({
+        protocolConfig,
+        market: m.market,
+        user: t.trader.publicKey,
+        marginAccount: t.account,
+        position: positionKey,
+        userTokenAccount: t.tokens,
+        marketVault: m.vault,
+        priceFeed: m.oracle,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([t.trader])
+      .rpc();
+    return positionKey;
+  }
+
   it("Initializes the market", async () => {
     await program.methods
       .initializeMarket(
@@ -65,56 +206,201 @@ describe("memeperp", () => {
     assert.equal(market.maxLeverage, MAX_LEVERAGE);
   });
 
+  it("Creates a trading sub-account", async () => {
+    await program.methods
+      .initializeMarginAccount(0)
+      .accounts({
+        marginAccount,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const account = await program.account.marginAccount.fetch(marginAccount);
+    assert.equal(account.subAccountId, 0);
+    assert.equal(account.inUse, false);
+    assert.ok(account.authority.equals(provider.wallet.publicKey));
+  });
+
+  it("Initializes the protocol config", async () => {
+    await program.methods
+      .initializeProtocolConfig()
+      .accounts({
+        protocolConfig,
+        admin: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const config = await program.account.protocolConfig.fetch(protocolConfig);
+    assert.ok(config.admin.equals(provider.wallet.publicKey));
+    assert.equal(config.withdrawalsOnly, false);
+  });
+
+  it("Configures open-interest weighted mining", async () => {
+    const rewardVault = Keypair.generate();
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+
+    // Favor the short side 60/40 while it is the thinner book
+    await program.methods
+      .configureMining({ openInterest: {} }, new anchor.BN(1000), 4000, 6000)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        vaultAuthority,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.deepEqual(market.mining.mode, { openInterest: {} });
+    assert.equal(market.mining.longWeightBps, 4000);
+    assert.equal(market.mining.shortWeightBps, 6000);
+  });
+
+  it("Initializes the fee staking pool", async () => {
+    const stakeVault = Keypair.generate();
+    const distributionVault = Keypair.generate();
+    const [stakePool] = PublicKey.findProgramAddressSync(
+      [Buffer.from("stake_pool"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializeStakePool(new anchor.BN(7 * 24 * 3600))
+      .accounts({
+        market: marketKeypair.publicKey,
+        stakePool,
+        stakeVault: stakeVault.publicKey,
+        distributionVault: distributionVault.publicKey,
+        vaultAuthority,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const pool = await program.account.stakePool.fetch(stakePool);
+    assert.equal(pool.currentEpoch.toNumber(), 0);
+    assert.equal(pool.checkpointedStake.toNumber(), 0);
+  });
+
+  it("Initializes the DAO treasury", async () => {
+    const lockVault = Keypair.generate();
+    const [treasury] = PublicKey.findProgramAddressSync(
+      [Buffer.from("treasury")],
+      program.programId
+    );
+
+    await program.methods
+      .initializeTreasury(
+        [provider.wallet.publicKey],
+        1,
+        new anchor.BN("1000000000"),
+        new anchor.BN(3 * 24 * 3600),
+        new anchor.BN(2 * 24 * 3600)
+      )
+      .accounts({
+        protocolConfig,
+        treasury,
+        lockVault: lockVault.publicKey,
+        admin: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const account = await program.account.treasury.fetch(treasury);
+    assert.equal(account.councilSize, 1);
+    assert.equal(account.proposalCount.toNumber(), 0);
+  });
+
   it("Places a long position", async () => {
     const size = new anchor.BN(1000);
     const price = new anchor.BN(100);
     const leverage = 5;
+    const positionKey = await nextPosition();
 
     await program.methods
       .placeOrder(
         { long: {} },
         size,
+        size,
         price,
-        leverage
+        MAX_SLIPPAGE_BPS,
+        leverage,
+        0,
+        NO_TAG,
+        null
       )
       .accounts({
+        protocolConfig,
         market: marketKeypair.publicKey,
         user: provider.wallet.publicKey,
+        marginAccount,
+        position: positionKey,
         userTokenAccount: userTokenAccount.publicKey,
         marketVault: marketVault.publicKey,
         priceFeed: mockPriceFeed.publicKey,
         tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
       })
       .rpc();
 
     const market = await program.account.market.fetch(marketKeypair.publicKey);
-    assert.equal(market.longPositions.length, 1);
+    assert.equal(market.longOpenInterest.toNumber(), size.toNumber());
+    const position = await program.account.position.fetch(positionKey);
+    assert.ok(position.owner.equals(marginAccount));
+    assert.equal(position.baseSize.toNumber(), size.toNumber());
+    assert.equal(position.entrySize.toNumber(), size.toNumber());
+    assert.equal(position.exitSize.toNumber(), 0);
+    assert.equal(
+      position.notional.toString(),
+      position.baseSize.mul(position.entryPrice).toString()
+    );
   });
 
   it("Places a short position", async () => {
     const size = new anchor.BN(500);
     const price = new anchor.BN(100);
     const leverage = 3;
+    const positionKey = await nextPosition();
 
     await program.methods
       .placeOrder(
         { short: {} },
         size,
+        size,
         price,
-        leverage
+        MAX_SLIPPAGE_BPS,
+        leverage,
+        0,
+        NO_TAG,
+        null
       )
       .accounts({
+        protocolConfig,
         market: marketKeypair.publicKey,
         user: provider.wallet.publicKey,
+        marginAccount,
+        position: positionKey,
         userTokenAccount: userTokenAccount.publicKey,
         marketVault: marketVault.publicKey,
         priceFeed: mockPriceFeed.publicKey,
         tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
       })
       .rpc();
 
     const market = await program.account.market.fetch(marketKeypair.publicKey);
-    assert.equal(market.shortPositions.length, 1);
+    assert.equal(market.shortOpenInterest.toNumber(), size.toNumber());
+    const account = await program.account.marginAccount.fetch(marginAccount);
+    assert.equal(account.positionCount, 2);
   });
 
   it("Liquidates an underwater position", async () => {
@@ -122,37 +408,3125 @@ describe("memeperp", () => {
     const size = new anchor.BN(1000);
     const price = new anchor.BN(100);
     const leverage = 10;
+    const positionKey = await nextPosition();
 
     await program.methods
       .placeOrder(
         { long: {} },
         size,
+        size,
         price,
-        leverage
+        MAX_SLIPPAGE_BPS,
+        leverage,
+        0,
+        NO_TAG,
+        null
       )
       .accounts({
+        protocolConfig,
         market: marketKeypair.publicKey,
         user: provider.wallet.publicKey,
+        marginAccount,
+        position: positionKey,
         userTokenAccount: userTokenAccount.publicKey,
         marketVault: marketVault.publicKey,
         priceFeed: mockPriceFeed.publicKey,
         tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
       })
       .rpc();
 
     // Liquidate the position
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .liquidatePosition()
+      .accounts({
+        protocolConfig,
+        market: marketKeypair.publicKey,
+        marginAccount,
+        position: positionKey,
+        userTokenAccount: userTokenAccount.publicKey,
+        marketVault: marketVault.publicKey,
+        vaultAuthority,
+        liquidatorTokenAccount: userTokenAccount.publicKey,
+        priceFeed: mockPriceFeed.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        liquidator: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    assert.isNull(await program.account.position.fetchNullable(positionKey));
+    // The first position remains
+    assert.isNotNull(await program.account.position.fetchNullable(positionAddress(new anchor.BN(0))));
+  });
+
+  it("Proposes a bounded fee change", async () => {
+    const [treasury] = PublicKey.findProgramAddressSync(
+      [Buffer.from("treasury")],
+      program.programId
+    );
+    const treasuryAccount = await program.account.treasury.fetch(treasury);
+    const [proposal] = PublicKey.findProgramAddressSync(
+      [Buffer.from("param_proposal"), treasuryAccount.proposalCount.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const [voteLock] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vote_lock"), provider.wallet.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .proposeParamChange({ feeBps: { 0: 20 } })
+      .accounts({
+        treasury,
+        market: marketKeypair.publicKey,
+        proposal,
+        voteLock,
+        proposer: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const account = await program.account.paramProposal.fetch(proposal);
+    assert.deepEqual(account.change, { feeBps: { 0: 20 } });
+    assert.isFalse(account.queued);
+  });
+
+  it("Guardian pause blocks new orders", async () => {
+    await program.methods
+      .pauseMarket()
+      .accounts({
+        market: marketKeypair.publicKey,
+        guardian: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    try {
+      await program.methods
+        .placeOrder({ long: {} }, new anchor.BN(1000), new anchor.BN(1000), new anchor.BN(100), MAX_SLIPPAGE_BPS, 5, 0, NO_TAG, null)
+        .accounts({
+          protocolConfig,
+          market: marketKeypair.publicKey,
+          user: provider.wallet.publicKey,
+          marginAccount,
+          position: await nextPosition(),
+          userTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      assert.fail("order should be rejected while paused");
+    } catch (err) {
+      assert.include(err.toString(), "MarketPaused");
+    }
+
+    try {
+      await program.methods
+        .pauseMarket()
+        .accounts({ market: marketKeypair.publicKey, guardian: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("a pause in force cannot be extended");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidMarketState");
+    }
+
+    await program.methods
+      .unpauseMarket()
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Quotes order book depth from a view call", async () => {
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializeOrderBook()
+      .accounts({
+        market: marketKeypair.publicKey,
+        orderBook,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    for (const price of [9900, 9900, 9800]) {
+      await program.methods
+        .placeLimitOrder({ long: {} }, new anchor.BN(price), MIN_BASE_ORDER_SIZE, 5, 0, NO_TAG)
+        .accounts({
+          protocolConfig,
+          market: marketKeypair.publicKey,
+          orderBook,
+          user: provider.wallet.publicKey,
+          marginAccount,
+          position: await nextPosition(),
+          userTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          vaultAuthority,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    }
+
+    const depth = await program.methods
+      .viewBookDepth(5)
+      .accounts({ orderBook })
+      .view();
+    assert.equal(depth.bids.length, 2);
+    assert.equal(depth.bids[0].price.toNumber(), 9900);
+    assert.equal(depth.bids[0].orderCount, 2);
+    assert.equal(depth.asks.length, 0);
+  });
+
+  it("Matches crossing orders pro-rata", async () => {
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .setMatchingPolicy({ proRata: {} })
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    // One ask for a single order's worth crosses the two resting bids at 9900
     await program.methods
-      .liquidatePosition(new anchor.BN(0), { long: {} })
+      .placeLimitOrder({ short: {} }, new anchor.BN(9900), MIN_BASE_ORDER_SIZE, 5, 0, NO_TAG)
       .accounts({
+        protocolConfig,
         market: marketKeypair.publicKey,
+        orderBook,
+        user: provider.wallet.publicKey,
+        marginAccount,
+        position: await nextPosition(),
         userTokenAccount: userTokenAccount.publicKey,
         marketVault: marketVault.publicKey,
+        vaultAuthority,
         priceFeed: mockPriceFeed.publicKey,
         tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    // Fills open into the positions the resting orders were placed with
+    const resting = await program.account.orderBook.fetch(orderBook);
+    await program.methods
+      .matchOrders(4)
+      .accounts({ protocolConfig, market: marketKeypair.publicKey, orderBook, priceFeed: mockPriceFeed.publicKey })
+      .remainingAccounts(
+        [...resting.bids, ...resting.asks].map((order) => ({
+          pubkey: order.position,
+          isWritable: true,
+          isSigner: false,
+        }))
+      )
+      .rpc();
+
+    const book = await program.account.orderBook.fetch(orderBook);
+    assert.equal(book.asks.length, 0);
+    // Both bids are partially filled and keep the rest of their escrow
+    for (const bid of book.bids.slice(0, 2)) {
+      assert.equal(bid.filledSize.toNumber(), MIN_BASE_ORDER_SIZE.toNumber() / 2);
+      assert.equal(bid.remainingSize.toNumber(), MIN_BASE_ORDER_SIZE.toNumber() / 2);
+      assert.isAbove(bid.lockedMargin.toNumber(), 0);
+    }
+  });
+
+  it("Enters reduce-only when the mark drifts from the index", async () => {
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .setMarkDeviationCap(1) // 0.01%, tighter than any realistic book
+      .accounts({
+        protocolConfig,
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    await program.methods
+      .updateMarkDeviation()
+      .accounts({
+        market: marketKeypair.publicKey,
+        orderBook,
+        priceFeed: mockPriceFeed.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.maxMarkIndexDeviationBps, 1);
+
+    await program.methods
+      .setMarkDeviationCap(0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Opens the first trade history page for a sub-account", async () => {
+    const [tradeHistory] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trade_history"), marginAccount.toBuffer(), new anchor.BN(0).toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+
+    await program.methods
+      .setTradeHistory(0, true)
+      .accounts({
+        marginAccount,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    await program.methods
+      .openTradeHistoryPage(0)
+      .accounts({
+        marginAccount,
+        tradeHistory,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const page = await program.account.tradeHistoryPage.fetch(tradeHistory);
+    assert.equal(page.pageIndex.toNumber(), 0);
+    assert.equal(page.records.length, 0);
+
+    await program.methods
+      .setTradeHistory(0, false)
+      .accounts({
+        marginAccount,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Ramps leverage up after listing", async () => {
+    await program.methods
+      .setLeverageRamp({ time: {} }, 2, 1, new anchor.BN(3600))
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.leverageRamp.startLeverage, 2);
+    assert.equal(market.leverageRamp.stepInterval.toNumber(), 3600);
+
+    await program.methods
+      .setLeverageRamp({ time: {} }, 0, 0, new anchor.BN(0))
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Configures a maximum position age", async () => {
+    await program.methods
+      .setPositionMaxAge(new anchor.BN(24 * 60 * 60), 5)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
       })
       .rpc();
 
     const market = await program.account.market.fetch(marketKeypair.publicKey);
-    assert.equal(market.longPositions.length, 1); // The first position remains
+    assert.equal(market.maxPositionAge.toNumber(), 24 * 60 * 60);
+    assert.equal(market.expiryFeeBps, 5);
+
+    await program.methods
+      .setPositionMaxAge(new anchor.BN(0), 0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Rejects multi-market orders without accounts for every leg", async () => {
+    const legs = [
+      { side: { long: {} }, size: new anchor.BN(1000), price: new anchor.BN(100), leverage: 2, tag: NO_TAG },
+      { side: { short: {} }, size: new anchor.BN(1000), price: new anchor.BN(100), leverage: 2, tag: NO_TAG },
+    ];
+
+    try {
+      await program.methods
+        .placeOrdersMulti(legs, 0)
+        .accounts({
+          protocolConfig,
+          user: provider.wallet.publicKey,
+          marginAccount,
+          userTokenAccount: userTokenAccount.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .remainingAccounts([
+          { pubkey: marketKeypair.publicKey, isWritable: true, isSigner: false },
+          { pubkey: marketVault.publicKey, isWritable: true, isSigner: false },
+          { pubkey: mockPriceFeed.publicKey, isWritable: false, isSigner: false },
+          { pubkey: program.programId, isWritable: false, isSigner: false },
+          { pubkey: await nextPosition(), isWritable: true, isSigner: false },
+        ])
+        .rpc();
+      assert.fail("second leg has no accounts");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidOrderLegs");
+    }
+  });
+
+  it("Configures an oracle failover", async () => {
+    const fallback = Keypair.generate().publicKey;
+
+    await program.methods
+      .setOracleFailover(mockPriceFeed.publicKey, { pyth: {} }, fallback, { switchboard: {} }, new anchor.BN(120))
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.ok(market.oracle.equals(mockPriceFeed.publicKey));
+    assert.ok(market.fallbackOracle.equals(fallback));
+    assert.equal(market.oracleGracePeriod.toNumber(), 120);
+  });
+
+  it("Stops trading in withdrawals-only mode", async () => {
+    await program.methods
+      .setWithdrawalsOnly(true)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+
+    try {
+      await program.methods
+        .placeOrder({ long: {} }, new anchor.BN(1000), new anchor.BN(1000), new anchor.BN(100), MAX_SLIPPAGE_BPS, 2, 0, NO_TAG, null)
+        .accounts({
+          protocolConfig,
+          market: marketKeypair.publicKey,
+          user: provider.wallet.publicKey,
+          marginAccount,
+          position: await nextPosition(),
+          userTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      assert.fail("order should be rejected in withdrawals-only mode");
+    } catch (err) {
+      assert.include(err.toString(), "WithdrawalsOnly");
+    }
+
+    await program.methods
+      .setWithdrawalsOnly(false)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+  });
+
+  it("Checkpoints the cumulative funding index", async () => {
+    const [fundingHistory] = PublicKey.findProgramAddressSync(
+      [Buffer.from("funding_history"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializeFundingHistory()
+      .accounts({
+        market: marketKeypair.publicKey,
+        fundingHistory,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const history = await program.account.fundingHistory.fetch(fundingHistory);
+    assert.equal(history.checkpoints.length, 1);
+
+    const now = new anchor.BN(Math.floor(Date.now() / 1000) + 60);
+    const delta = await program.methods
+      .viewFundingBetween(history.checkpoints[0].timestamp, now)
+      .accounts({ fundingHistory })
+      .view();
+    assert.equal(delta.toNumber(), 0);
+  });
+
+  it("Configures a size-aware taker fee curve", async () => {
+    await program.methods
+      .setFeeCurve({ vaultDepth: {} }, 500, 100, 50)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.feeCurve.thresholdBps, 500);
+    assert.equal(market.feeCurve.maxSurchargeBps, 50);
+
+    await program.methods
+      .setFeeCurve({ recentVolume: {} }, 0, 0, 0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Initializes the market insurance fund", async () => {
+    const insuranceVault = Keypair.generate();
+    const [insuranceFund] = PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance_fund"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializeInsuranceFund()
+      .accounts({
+        market: marketKeypair.publicKey,
+        insuranceFund,
+        insuranceVault: insuranceVault.publicKey,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const fund = await program.account.insuranceFund.fetch(insuranceFund);
+    assert.ok(fund.market.equals(marketKeypair.publicKey));
+    assert.equal(fund.totalBadDebt.toNumber(), 0);
+  });
+
+  it("Publishes the LP share price", async () => {
+    const vault = Keypair.generate();
+    const shareMint = Keypair.generate();
+    const [lpVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("lp_vault"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [sharePrice] = PublicKey.findProgramAddressSync(
+      [Buffer.from("lp_share_price"), lpVault.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializeLpVault()
+      .accounts({
+        market: marketKeypair.publicKey,
+        lpVault,
+        sharePrice,
+        vault: vault.publicKey,
+        shareMint: shareMint.publicKey,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const price = await program.account.lpSharePrice.fetch(sharePrice);
+    assert.equal(price.price.toNumber(), 1_000_000); // 1.0 before any deposit
+    assert.equal(price.totalShares.toNumber(), 0);
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isTrue(market.hasLpVault);
+  });
+
+  it("Registers and revokes an integrator", async () => {
+    const integratorKey = Keypair.generate().publicKey;
+    const [integrator] = PublicKey.findProgramAddressSync(
+      [Buffer.from("integrator"), integratorKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .registerIntegrator(integratorKey, 2000, 100)
+      .accounts({
+        protocolConfig,
+        integrator,
+        feeDestination: userTokenAccount.publicKey,
+        admin: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    await program.methods
+      .updateIntegrator(2000, 100, false)
+      .accounts({ protocolConfig, integrator, admin: provider.wallet.publicKey })
+      .rpc();
+
+    const account = await program.account.integrator.fetch(integrator);
+    assert.equal(account.feeShareBps, 2000);
+    assert.equal(account.active, false);
+  });
+
+  it("Configures priority liquidation lanes", async () => {
+    await program.methods
+      .setPriorityLanes(10, new anchor.BN(20), new anchor.BN(1000))
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.priorityLanes.cascadeThreshold, 10);
+    assert.equal(market.priorityLanes.prioritySlots.toNumber(), 20);
+  });
+
+  it("Initializes the funding arbitrage vault", async () => {
+    const tokenAccount = Keypair.generate();
+    const shareMint = Keypair.generate();
+    const [arbVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("funding_arb_vault")],
+      program.programId
+    );
+    const [vaultMarginAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("margin_account"), arbVault.toBuffer(), new anchor.BN(0).toArrayLike(Buffer, "le", 2)],
+      program.programId
+    );
+
+    await program.methods
+      .initializeArbVault(provider.wallet.publicKey, 2500)
+      .accounts({
+        protocolConfig,
+        arbVault,
+        marginAccount: vaultMarginAccount,
+        tokenAccount: tokenAccount.publicKey,
+        shareMint: shareMint.publicKey,
+        admin: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const vault = await program.account.fundingArbVault.fetch(arbVault);
+    assert.equal(vault.maxMarketExposureBps, 2500);
+    assert.equal(vault.deployedMargin.toNumber(), 0);
+    const marginAccount = await program.account.marginAccount.fetch(vaultMarginAccount);
+    assert.ok(marginAccount.authority.equals(arbVault));
+  });
+
+  it("Rejects a price precision change with open positions", async () => {
+    try {
+      await program.methods
+        .setPricePrecision(6, { nearest: {} })
+        .accounts({
+          market: marketKeypair.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected the precision change to fail");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidMarketState");
+    }
+  });
+
+  it("Caps traded notional per window", async () => {
+    await program.methods
+      .setNotionalCap(new anchor.BN(300), new anchor.BN(1))
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.notionalCap.window.toNumber(), 300);
+    assert.equal(market.notionalCap.maxNotional.toNumber(), 1);
+
+    // Lift the cap again for the remaining tests
+    await program.methods
+      .setNotionalCap(new anchor.BN(0), new anchor.BN(0))
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Rejects an attestation priced from an unconfigured oracle", async () => {
+    try {
+      await program.methods
+        .attestPositions()
+        .accounts({
+          market: marketKeypair.publicKey,
+          marginAccount,
+          priceFeed: Keypair.generate().publicKey,
+        })
+        .view();
+      assert.fail("Expected the attestation to fail");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidOracle");
+    }
+  });
+
+  it("Lists a market from a configured template", async () => {
+    const templateMarket = Keypair.generate();
+    await program.methods
+      .setMarketTemplate({ conservative: {} }, {
+        configured: false,
+        minBaseOrderSize: new anchor.BN(MIN_BASE_ORDER_SIZE),
+        tickSize: new anchor.BN(TICK_SIZE),
+        maxLeverage: 3,
+        liquidationThreshold: 9000,
+        maintenanceMarginFraction: 1000,
+        maxPositionSize: new anchor.BN(1_000_000),
+        fundingInterval: new anchor.BN(3600),
+        feeBps: 20,
+        maxFundingRateBps: 10,
+        maxOracleStalenessSecs: new anchor.BN(30),
+      })
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+
+    const [templateVaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), templateMarket.publicKey.toBuffer()],
+      program.programId
+    );
+    const templateVault = await mint.createAccount(templateVaultAuthority);
+    await program.methods
+      .initializeMarketFromTemplate("PEPE/USD", { conservative: {} })
+      .accounts({
+        market: templateMarket.publicKey,
+        marketVault: templateVault,
+        vaultAuthority: templateVaultAuthority,
+        protocolConfig,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([templateMarket])
+      .rpc();
+
+    const market = await program.account.market.fetch(templateMarket.publicKey);
+    assert.equal(market.maxLeverage, 3);
+    assert.equal(market.feeBps, 20);
+    assert.isTrue(market.vault.equals(templateVault));
+  });
+
+  it("Bounds the levels matched per instruction", async () => {
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    try {
+      await program.methods
+        .matchOrders(9)
+        .accounts({ protocolConfig, market: marketKeypair.publicKey, orderBook, priceFeed: mockPriceFeed.publicKey })
+        .rpc();
+      assert.fail("Expected matching to be bounded");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+  });
+
+  it("Sets a minimum vault coverage ratio", async () => {
+    await program.methods
+      .setMinCoverage(new anchor.BN(15000))
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.minCoverageBps.toNumber(), 15000);
+  });
+
+  it("Rejects reducing a position by more than its size", async () => {
+    const positionKey = positionAddress(new anchor.BN(0));
+    const position = await program.account.position.fetch(positionKey);
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    try {
+      await program.methods
+        .reducePosition(position.baseSize.addn(1), 0)
+        .accounts({
+          market: marketKeypair.publicKey,
+          protocolConfig,
+          owner: provider.wallet.publicKey,
+          marginAccount,
+          position: positionKey,
+          ownerTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          vaultAuthority,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      assert.fail("Expected the reduction to fail");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidReduceSize");
+    }
+  });
+
+  it("Credits virtual balance from the paper-trading faucet", async () => {
+    const amount = new anchor.BN("1000000000");
+    await program.methods
+      .paperFaucet(amount, 0)
+      .accounts({ owner: provider.wallet.publicKey, marginAccount })
+      .rpc();
+
+    const account = await program.account.marginAccount.fetch(marginAccount);
+    assert.equal(account.paperBalance.toString(), amount.toString());
+
+    try {
+      await program.methods
+        .paperFaucet(new anchor.BN("1000000000000"), 0)
+        .accounts({ owner: provider.wallet.publicKey, marginAccount })
+        .rpc();
+      assert.fail("Expected the faucet to be capped");
+    } catch (err) {
+      assert.include(err.toString(), "PaperBalanceLimit");
+    }
+  });
+
+  it("Refuses a recovery claim while the authority is active", async () => {
+    const recovery = Keypair.generate();
+    await program.methods
+      .setMarketRecovery(recovery.publicKey, new anchor.BN(30 * 24 * 60 * 60))
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    try {
+      await program.methods
+        .claimMarketAuthority()
+        .accounts({
+          market: marketKeypair.publicKey,
+          recoveryAuthority: recovery.publicKey,
+        })
+        .signers([recovery])
+        .rpc();
+      assert.fail("Expected the claim to be refused");
+    } catch (err) {
+      assert.include(err.toString(), "AuthorityStillActive");
+    }
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.ok(market.authority.equals(provider.wallet.publicKey));
+  });
+
+  it("Keeps a stop-loss resting until the price reaches it", async () => {
+    const positionKey = positionAddress(new anchor.BN(0));
+    const [triggerOrder] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trigger_order"), positionKey.toBuffer(), Buffer.from("stop_loss")],
+      program.programId
+    );
+    const [triggerRegistry] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trigger_registry"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .initializeTriggerRegistry()
+      .accounts({
+        market: marketKeypair.publicKey,
+        triggerRegistry,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .placeStopLoss(new anchor.BN(1), 0, null)
+      .accounts({
+        market: marketKeypair.publicKey,
+        owner: provider.wallet.publicKey,
+        marginAccount,
+        position: positionKey,
+        triggerOrder,
+        triggerRegistry,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const order = await program.account.triggerOrder.fetch(triggerOrder);
+    assert.ok(order.position.equals(positionKey));
+    assert.equal(order.triggerPrice.toNumber(), 1);
+    let registry = await program.account.triggerRegistry.fetch(triggerRegistry);
+    assert.ok(registry.pending[0].equals(triggerOrder));
+
+    try {
+      await program.methods
+        .executeTriggerOrder()
+        .accounts({
+          market: marketKeypair.publicKey,
+          protocolConfig,
+          triggerOrder,
+          triggerRegistry,
+          marginAccount,
+          position: positionKey,
+          ownerTokenAccount: userTokenAccount.publicKey,
+          keeper: provider.wallet.publicKey,
+          keeperTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          vaultAuthority,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      assert.fail("Expected the stop-loss not to fire");
+    } catch (err) {
+      assert.include(err.toString(), "TriggerNotReached");
+    }
+
+    await program.methods
+      .cancelTriggerOrder(0)
+      .accounts({ owner: provider.wallet.publicKey, marginAccount, triggerOrder, triggerRegistry })
+      .rpc();
+    assert.isNull(await program.account.triggerOrder.fetchNullable(triggerOrder));
+    registry = await program.account.triggerRegistry.fetch(triggerRegistry);
+    assert.equal(registry.pending.length, 0);
+  });
+
+  it("Caps the funding charged per round", async () => {
+    await program.methods
+      .setFundingPaymentCap(200)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.maxFundingPaymentBps, 200);
+
+    try {
+      await program.methods
+        .setFundingPaymentCap(10001)
+        .accounts({
+          market: marketKeypair.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected the cap to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+  });
+
+  it("Lists a take-profit in the trigger registry", async () => {
+    const positionKey = positionAddress(new anchor.BN(0));
+    const [triggerOrder] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trigger_order"), positionKey.toBuffer(), Buffer.from("take_profit")],
+      program.programId
+    );
+    const [triggerRegistry] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trigger_registry"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .setTriggerTip(10)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    await program.methods
+      .placeTakeProfit(new anchor.BN("1000000000000"), 0, null)
+      .accounts({
+        market: marketKeypair.publicKey,
+        owner: provider.wallet.publicKey,
+        marginAccount,
+        position: positionKey,
+        triggerOrder,
+        triggerRegistry,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const order = await program.account.triggerOrder.fetch(triggerOrder);
+    assert.deepEqual(order.kind, { takeProfit: {} });
+    const registry = await program.account.triggerRegistry.fetch(triggerRegistry);
+    assert.ok(registry.pending.some((key) => key.equals(triggerOrder)));
+  });
+
+  it("Finds the vault solvent against tracked margin and fees", async () => {
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .assertSolvency()
+      .accounts({
+        market: marketKeypair.publicKey,
+        marketVault: marketVault.publicKey,
+        vaultAuthority,
+        orderBook,
+        insuranceFund: null,
+        insuranceVault: null,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isFalse(market.pauseRatified);
+  });
+
+  it("Tops up margin and moves the liquidation price away", async () => {
+    const positionKey = positionAddress(new anchor.BN(0));
+    const before = await program.account.position.fetch(positionKey);
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .depositMargin(before.margin, 0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        owner: provider.wallet.publicKey,
+        marginAccount,
+        position: positionKey,
+        ownerTokenAccount: userTokenAccount.publicKey,
+        marketVault: marketVault.publicKey,
+        vaultAuthority,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const after = await program.account.position.fetch(positionKey);
+    assert.isTrue(after.margin.gt(before.margin));
+    if ("long" in before.side) {
+      assert.isTrue(after.liquidationPrice.lt(before.liquidationPrice));
+    } else {
+      assert.isTrue(after.liquidationPrice.gt(before.liquidationPrice));
+    }
+  });
+
+  it("Timelocks an oracle rotation", async () => {
+    const newOracle = Keypair.generate().publicKey;
+    await program.methods
+      .setOracle(newOracle, { pyth: {} })
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.ok(market.oracleRotation.pendingOracle.equals(newOracle));
+
+    try {
+      await program.methods
+        .applyOracleRotation()
+        .accounts({
+          market: marketKeypair.publicKey,
+          priceFeed: mockPriceFeed.publicKey,
+          newPriceFeed: newOracle,
+        })
+        .rpc();
+      assert.fail("Expected the rotation to wait for its timelock");
+    } catch (err) {
+      assert.notInclude(err.toString(), "Expected the rotation");
+    }
+
+    await program.methods
+      .setOracle(PublicKey.default, { pyth: {} })
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.ok(market.oracleRotation.pendingOracle.equals(PublicKey.default));
+    assert.ok(market.oracle.equals(mockPriceFeed.publicKey));
+  });
+
+  it("Moves a market into settling for migration", async () => {
+    try {
+      await program.methods
+        .setMarketSuccessor(marketKeypair.publicKey)
+        .accounts({
+          market: marketKeypair.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected a market to be refused as its own successor");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    const successor = Keypair.generate().publicKey;
+    await program.methods
+      .setMarketSuccessor(successor)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.deepEqual(market.status, { settling: {} });
+    assert.ok(market.successor.equals(successor));
+
+    await program.methods
+      .setMarketSuccessor(PublicKey.default)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.deepEqual(market.status, { active: {} });
+  });
+
+  it("Refuses to withdraw margin the position needs", async () => {
+    const positionKey = positionAddress(new anchor.BN(0));
+    const position = await program.account.position.fetch(positionKey);
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    try {
+      await program.methods
+        .withdrawMargin(position.margin, 0)
+        .accounts({
+          market: marketKeypair.publicKey,
+          owner: provider.wallet.publicKey,
+          marginAccount,
+          position: positionKey,
+          ownerTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          vaultAuthority,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      assert.fail("Expected withdrawing all margin to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "InsufficientCollateral");
+    }
+  });
+
+  it("Records an oracle incident declared by the guardian", async () => {
+    const [incidentRegistry] = PublicKey.findProgramAddressSync(
+      [Buffer.from("incident_registry"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .initializeIncidentRegistry()
+      .accounts({
+        market: marketKeypair.publicKey,
+        incidentRegistry,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const now = Math.floor(Date.now() / 1000);
+    try {
+      await program.methods
+        .declareOracleIncident(new anchor.BN(now - 60), new anchor.BN(now + 3600))
+        .accounts({
+          market: marketKeypair.publicKey,
+          incidentRegistry,
+          guardian: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected an incident that has not ended to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .declareOracleIncident(new anchor.BN(now - 600), new anchor.BN(now - 60))
+      .accounts({
+        market: marketKeypair.publicKey,
+        incidentRegistry,
+        guardian: provider.wallet.publicKey,
+      })
+      .rpc();
+    const registry = await program.account.incidentRegistry.fetch(incidentRegistry);
+    assert.equal(registry.incidents.length, 1);
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isTrue(market.hasIncidentRegistry);
+  });
+
+  it("Nets a repeated order into the open position", async () => {
+    const positionKey = positionAddress(new anchor.BN(0));
+    const before = await program.account.position.fetch(positionKey);
+    const marginBefore = await program.account.marginAccount.fetch(marginAccount);
+    const size = new anchor.BN(100);
+    await program.methods
+      .increasePosition(size, size, new anchor.BN(100), 0, NO_TAG)
+      .accounts({
+        protocolConfig,
+        market: marketKeypair.publicKey,
+        user: provider.wallet.publicKey,
+        marginAccount,
+        position: positionKey,
+        userTokenAccount: userTokenAccount.publicKey,
+        marketVault: marketVault.publicKey,
+        priceFeed: mockPriceFeed.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const after = await program.account.position.fetch(positionKey);
+    assert.ok(after.baseSize.eq(before.baseSize.add(size)));
+    assert.isTrue(after.margin.gt(before.margin));
+    const marginAfter = await program.account.marginAccount.fetch(marginAccount);
+    assert.equal(marginAfter.positionCount, marginBefore.positionCount);
+  });
+
+  it("Bounds the keeper reward multiplier", async () => {
+    try {
+      await program.methods
+        .setKeeperRewardMultiplier(9000)
+        .accounts({
+          market: marketKeypair.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected a multiplier below 1x to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .setKeeperRewardMultiplier(20000)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.keeperRewardMultiplierBps, 20000);
+  });
+
+  it("Requires every open position to value a portfolio", async () => {
+    try {
+      await program.methods
+        .viewPortfolioHealth()
+        .accounts({ marginAccount })
+        .view();
+      assert.fail("Expected a portfolio without its positions to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "PositionAccountsMismatch");
+    }
+  });
+
+  it("Collects withdrawal approvals across transactions", async () => {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const [withdrawalPolicy] = PublicKey.findProgramAddressSync(
+      [Buffer.from("withdrawal_policy")],
+      program.programId
+    );
+    const [treasury] = PublicKey.findProgramAddressSync([Buffer.from("treasury")], program.programId);
+    const spendMint = await createMint(provider.connection, payer, provider.wallet.publicKey, null, 6);
+    treasurySpendVault = await createAccount(provider.connection, payer, spendMint, treasury, Keypair.generate());
+    await mintTo(provider.connection, payer, spendMint, treasurySpendVault, payer, 10_000);
+    await program.methods
+      .initializeWithdrawalPolicy([provider.wallet.publicKey], 1, new anchor.BN(1_000))
+      .accounts({
+        protocolConfig,
+        withdrawalPolicy,
+        treasury,
+        spendVault: treasurySpendVault,
+        admin: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const [withdrawalRequest] = PublicKey.findProgramAddressSync(
+      [Buffer.from("withdrawal_request"), new anchor.BN(0).toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    await program.methods
+      .proposeWithdrawal({ treasury: {} }, new anchor.BN(5_000))
+      .accounts({
+        withdrawalPolicy,
+        withdrawalRequest,
+        sourceVault: userTokenAccount.publicKey,
+        destination: userTokenAccount.publicKey,
+        proposer: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const [approval] = PublicKey.findProgramAddressSync(
+      [Buffer.from("withdrawal_approval"), withdrawalRequest.toBuffer(), provider.wallet.publicKey.toBuffer()],
+      program.programId
+    );
+    const approve = () =>
+      program.methods
+        .approveWithdrawal()
+        .accounts({
+          withdrawalPolicy,
+          withdrawalRequest,
+          approval,
+          signer: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+    await approve();
+    const request = await program.account.withdrawalRequest.fetch(withdrawalRequest);
+    assert.equal(request.approvalCount, 1);
+
+    try {
+      await approve();
+      assert.fail("Expected a second approval from the same signer to be rejected");
+    } catch (err) {
+      assert.notInclude(err.toString(), "Expected a second approval");
+    }
+  });
+
+  it("Caps single-signer withdrawals per period and needs the signers to change the policy", async () => {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const [withdrawalPolicy] = PublicKey.findProgramAddressSync(
+      [Buffer.from("withdrawal_policy")],
+      program.programId
+    );
+    const [treasury] = PublicKey.findProgramAddressSync([Buffer.from("treasury")], program.programId);
+    const spendMint = (await getAccount(provider.connection, treasurySpendVault)).mint;
+    const destination = await createAccount(provider.connection, payer, spendMint, provider.wallet.publicKey);
+    const withdraw = (amount: number, sourceVault = treasurySpendVault) =>
+      program.methods
+        .withdrawTreasury(new anchor.BN(amount))
+        .accounts({
+          withdrawalPolicy,
+          treasury,
+          sourceVault,
+          destination,
+          signer: provider.wallet.publicKey,
+          withdrawalRequest: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await withdraw(600);
+    try {
+      await withdraw(600);
+      assert.fail("two withdrawals past the limit in one period need approval");
+    } catch (err) {
+      assert.include(err.toString(), "WithdrawalApprovalRequired");
+    }
+    assert.equal(Number((await getAccount(provider.connection, destination)).amount), 600);
+
+    const otherVault = await createAccount(provider.connection, payer, spendMint, treasury, Keypair.generate());
+    try {
+      await withdraw(100, otherVault);
+      assert.fail("only the policy's spend vault can be drawn from");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidVault");
+    }
+
+    const setPolicy = () =>
+      program.methods
+        .setWithdrawalPolicy([provider.wallet.publicKey], 1, new anchor.BN(2_000))
+        .accounts({ protocolConfig, withdrawalPolicy, admin: provider.wallet.publicKey });
+    try {
+      await setPolicy().rpc();
+      assert.fail("the current signers must co-sign a policy change");
+    } catch (err) {
+      assert.include(err.toString(), "WithdrawalApprovalRequired");
+    }
+    await setPolicy()
+      .remainingAccounts([{ pubkey: provider.wallet.publicKey, isSigner: true, isWritable: false }])
+      .rpc();
+    const policy = await program.account.withdrawalPolicy.fetch(withdrawalPolicy);
+    assert.equal(policy.singleSignerLimit.toNumber(), 2_000);
+  });
+
+  it("Values the portfolio when a position switches to cross margin", async () => {
+    try {
+      await program.methods
+        .setMarginMode({ cross: {} }, 0)
+        .accounts({
+          market: marketKeypair.publicKey,
+          owner: provider.wallet.publicKey,
+          marginAccount,
+          position: positionAddress(new anchor.BN(0)),
+          priceFeed: mockPriceFeed.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected a switch without the portfolio's positions to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "PositionAccountsMismatch");
+    }
+    const position = await program.account.position.fetch(positionAddress(new anchor.BN(0)));
+    assert.deepEqual(position.marginMode, { isolated: {} });
+  });
+
+  it("Caps registrations during a launch phase", async () => {
+    await program.methods
+      .setLaunchTraderCap(1)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    const [launchRegistration] = PublicKey.findProgramAddressSync(
+      [Buffer.from("launch_registration"), marketKeypair.publicKey.toBuffer(), marginAccount.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .registerLaunchTrader(0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        owner: provider.wallet.publicKey,
+        marginAccount,
+        launchRegistration,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.launchTraderCount, 1);
+
+    await program.methods
+      .setLaunchTraderCap(0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Bounds the liquidation fee", async () => {
+    await program.methods
+      .setInsuranceFunding(2000)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    await program.methods
+      .setLiquidationFee(500, 4000)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.insuranceFeeShareBps, 2000);
+    assert.equal(market.liquidationFeeBps, 500);
+    assert.equal(market.liquidatorShareBps, 4000);
+
+    try {
+      await program.methods
+        .setLiquidationFee(6000, 4000)
+        .accounts({
+          market: marketKeypair.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected a 60% liquidation fee to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .setInsuranceFunding(0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    await program.methods
+      .setLiquidationFee(0, 0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Keeps an order's tag on the position", async () => {
+    const tag = Array.from(Buffer.alloc(32, 7));
+    const positionKey = await nextPosition();
+    await program.methods
+      .placeOrder({ short: {} }, new anchor.BN(1000), new anchor.BN(1000), new anchor.BN(100), MAX_SLIPPAGE_BPS, 2, 0, tag, null)
+      .accounts({
+        protocolConfig,
+        market: marketKeypair.publicKey,
+        user: provider.wallet.publicKey,
+        marginAccount,
+        position: positionKey,
+        userTokenAccount: userTokenAccount.publicKey,
+        marketVault: marketVault.publicKey,
+        priceFeed: mockPriceFeed.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const position = await program.account.position.fetch(positionKey);
+    assert.deepEqual(Array.from(position.tag), tag);
+  });
+
+  it("Configures the depth-based order cap", async () => {
+    await program.methods
+      .setDepthOrderCap(2500, 100)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.depthCapBps, 2500);
+    assert.equal(market.depthBandBps, 100);
+
+    try {
+      await program.methods
+        .setDepthOrderCap(2500, 0)
+        .accounts({
+          market: marketKeypair.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected a cap without a band to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .setDepthOrderCap(0, 0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Publishes the insurance coverage ratio", async () => {
+    const [insuranceFund] = PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance_fund"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [insuranceCoverage] = PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance_coverage"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .initializeInsuranceCoverage()
+      .accounts({
+        market: marketKeypair.publicKey,
+        insuranceFund,
+        insuranceCoverage,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    const coverage = await program.account.insuranceCoverage.fetch(insuranceCoverage);
+    assert.isTrue(market.hasInsuranceCoverage);
+    assert.ok(coverage.market.equals(marketKeypair.publicKey));
+    assert.equal(coverage.insuranceBalance.toNumber(), 0);
+    assert.equal(
+      coverage.openInterestNotional.toString(),
+      market.longEntryNotional.add(market.shortEntryNotional).toString()
+    );
+  });
+
+  it("Keeps the margin call threshold above maintenance", async () => {
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    try {
+      await program.methods
+        .setMarginCallThreshold(market.maintenanceMarginFraction)
+        .accounts({
+          market: marketKeypair.publicKey,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected a threshold at the maintenance margin to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .setMarginCallThreshold(market.maintenanceMarginFraction + 100)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    const updated = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(updated.marginCallBps, market.maintenanceMarginFraction + 100);
+
+    await program.methods
+      .setMarginCallThreshold(0)
+      .accounts({
+        market: marketKeypair.publicKey,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Only lets a market use an approved hook program", async () => {
+    const hookProgram = Keypair.generate().publicKey;
+    try {
+      await program.methods
+        .setMarketHook(hookProgram)
+        .accounts({
+          market: marketKeypair.publicKey,
+          protocolConfig,
+          authority: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("Expected an unapproved hook to be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "HookProgramNotApproved");
+    }
+
+    await program.methods
+      .approveHookProgram(hookProgram)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+    await program.methods
+      .setMarketHook(hookProgram)
+      .accounts({
+        market: marketKeypair.publicKey,
+        protocolConfig,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.ok(market.hookProgram.equals(hookProgram));
+
+    await program.methods
+      .setMarketHook(PublicKey.default)
+      .accounts({
+        market: marketKeypair.publicKey,
+        protocolConfig,
+        authority: provider.wallet.publicKey,
+      })
+      .rpc();
+    await program.methods
+      .revokeHookProgram(hookProgram)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+    const config = await program.account.protocolConfig.fetch(protocolConfig);
+    assert.isFalse(config.hookPrograms.some((key) => key.equals(hookProgram)));
+  });
+
+  it("Skips healthy positions in the liquidation crank", async () => {
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [insuranceCoverage] = PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance_coverage"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const position = positionAddress(new anchor.BN(0));
+    await program.methods
+      .crankLiquidations()
+      .accounts({
+        market: marketKeypair.publicKey,
+        protocolConfig,
+        marketVault: marketVault.publicKey,
+        vaultAuthority,
+        priceFeed: mockPriceFeed.publicKey,
+        crankerTokenAccount: userTokenAccount.publicKey,
+        cranker: provider.wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        insuranceCoverage,
+      })
+      .remainingAccounts([
+        { pubkey: position, isWritable: true, isSigner: false },
+        { pubkey: marginAccount, isWritable: true, isSigner: false },
+        { pubkey: userTokenAccount.publicKey, isWritable: true, isSigner: false },
+      ])
+      .rpc();
+
+    assert.isNotNull(await program.account.position.fetchNullable(position));
+  });
+
+  it("Rate limits orders unless the wallet is a registered market maker", async () => {
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [vaultAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault_authority"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .setOrderRateLimits(0, 1)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+
+    // Two bids below the book in one transaction land in the same second
+    const { nextPositionId } = await program.account.marginAccount.fetch(marginAccount);
+    const bid = (positionId: anchor.BN) =>
+      program.methods
+        .placeLimitOrder({ long: {} }, new anchor.BN(9800), MIN_BASE_ORDER_SIZE, 5, 0, NO_TAG)
+        .accounts({
+          protocolConfig,
+          market: marketKeypair.publicKey,
+          orderBook,
+          user: provider.wallet.publicKey,
+          marginAccount,
+          position: positionAddress(positionId),
+          userTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          vaultAuthority,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .instruction();
+    try {
+      await provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(await bid(nextPositionId), await bid(nextPositionId.addn(1)))
+      );
+      assert.fail("second order in the same second should be rate limited");
+    } catch (err) {
+      assert.include((err.logs ?? []).join("\n"), "OrderRateLimited");
+    }
+
+    await program.methods
+      .registerMarketMaker(provider.wallet.publicKey)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+    let config = await program.account.protocolConfig.fetch(protocolConfig);
+    assert.isTrue(config.marketMakers.some((key) => key.equals(provider.wallet.publicKey)));
+
+    await program.methods
+      .deregisterMarketMaker(provider.wallet.publicKey)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+    await program.methods
+      .setOrderRateLimits(0, 0)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+    config = await program.account.protocolConfig.fetch(protocolConfig);
+    assert.isFalse(config.marketMakers.some((key) => key.equals(provider.wallet.publicKey)));
+    assert.equal(config.orderRateLimits.perSecond, 0);
+  });
+
+  it("Samples the mark/index premium for funding", async () => {
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    try {
+      await program.methods
+        .setFundingParams(11, 0)
+        .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("interest rate above the funding cap should be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+    await program.methods
+      .setFundingParams(1, 5)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+
+    // The market has a book, so its mid must be sampled
+    try {
+      await program.methods
+        .samplePremium()
+        .accounts({ market: marketKeypair.publicKey, priceFeed: mockPriceFeed.publicKey })
+        .rpc();
+      assert.fail("sampling without the order book should fail");
+    } catch (err) {
+      assert.include(err.toString(), "OrderBookRequired");
+    }
+    await program.methods
+      .samplePremium()
+      .accounts({ market: marketKeypair.publicKey, orderBook, priceFeed: mockPriceFeed.publicKey })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.premium.interestRateBps, 1);
+    assert.equal(market.premium.premiumClampBps, 5);
+    assert.isTrue(market.premium.lastSampleTime.gte(market.premium.roundStart));
+  });
+
+  it("Appends price samples to the market's price history", async () => {
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), marketKeypair.publicKey.toBuffer()],
+      program.programId
+    );
+    const [priceHistory] = PublicKey.findProgramAddressSync(
+      [Buffer.from("price_history"), marketKeypair.publicKey.toBuffer(), new anchor.BN(0).toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    await program.methods
+      .openPriceHistoryPage()
+      .accounts({
+        market: marketKeypair.publicKey,
+        priceHistory,
+        payer: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    // A second call within the interval records nothing
+    for (let i = 0; i < 2; i++) {
+      await program.methods
+        .recordPriceSample()
+        .accounts({ market: marketKeypair.publicKey, priceHistory, orderBook, priceFeed: mockPriceFeed.publicKey })
+        .rpc();
+    }
+
+    const page = await program.account.priceHistoryPage.fetch(priceHistory);
+    assert.equal(page.samples.length, 1);
+    assert.isTrue(page.samples[0].indexPrice.gtn(0));
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.priceHistoryHead.toNumber(), 1);
+  });
+
+  it("Bounds the close fee and tracks fees by order type", async () => {
+    try {
+      await program.methods
+        .setCloseFee(101)
+        .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("close fee above 1% should be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+    await program.methods
+      .setCloseFee(0)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.closeFeeBps, 0);
+    const { openLong, openShort } = market.feeAccrual;
+    assert.isTrue(openLong.add(openShort).gtn(0));
+  });
+
+  it("Lets only the market authority name the underlying token", async () => {
+    const stranger = Keypair.generate();
+    try {
+      await program.methods
+        .setBaseMint(mint.publicKey)
+        .accounts({ market: marketKeypair.publicKey, authority: stranger.publicKey })
+        .signers([stranger])
+        .rpc();
+      assert.fail("only the market authority may set the base mint");
+    } catch (err) {
+      assert.include(err.toString(), "Unauthorized");
+    }
+
+    await program.methods
+      .setBaseMint(mint.publicKey)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.ok(market.baseMint.equals(mint.publicKey));
+
+    await program.methods
+      .setBaseMint(PublicKey.default)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.ok(market.baseMint.equals(PublicKey.default));
+  });
+
+  it("Marks a sub-account as a funding-exempt hedger", async () => {
+    const [hedgerAccount] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("margin_account"),
+        provider.wallet.publicKey.toBuffer(),
+        new anchor.BN(7).toArrayLike(Buffer, "le", 2),
+      ],
+      program.programId
+    );
+    await program.methods
+      .initializeMarginAccount(7)
+      .accounts({
+        marginAccount: hedgerAccount,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const outsider = Keypair.generate();
+    try {
+      await program.methods
+        .setHedger(true)
+        .accounts({ protocolConfig, admin: outsider.publicKey, marginAccount: hedgerAccount })
+        .signers([outsider])
+        .rpc();
+      assert.fail("only the protocol admin can approve hedgers");
+    } catch (err) {
+      assert.include(err.toString(), "Unauthorized");
+    }
+
+    await program.methods
+      .setHedger(true)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey, marginAccount: hedgerAccount })
+      .rpc();
+    let account = await program.account.marginAccount.fetch(hedgerAccount);
+    assert.isTrue(account.hedger);
+
+    await program.methods
+      .setHedger(false)
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey, marginAccount: hedgerAccount })
+      .rpc();
+    account = await program.account.marginAccount.fetch(hedgerAccount);
+    assert.isFalse(account.hedger);
+  });
+
+  it("Sets the funding update keeper reward", async () => {
+    const outsider = Keypair.generate();
+    try {
+      await program.methods
+        .setFundingUpdateReward(new anchor.BN(1_000))
+        .accounts({ market: marketKeypair.publicKey, authority: outsider.publicKey })
+        .signers([outsider])
+        .rpc();
+      assert.fail("only the market authority can set the reward");
+    } catch (err) {
+      assert.include(err.toString(), "Unauthorized");
+    }
+
+    await program.methods
+      .setFundingUpdateReward(new anchor.BN(1_000))
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.fundingUpdateReward.toNumber(), 1_000);
+  });
+
+  it("Refuses to move collateral within one sub-account", async () => {
+    try {
+      await program.methods
+        .moveCollateral(new anchor.BN(1), 0, 0)
+        .accounts({
+          user: provider.wallet.publicKey,
+          fromMarginAccount: marginAccount,
+          toMarginAccount: marginAccount,
+        })
+        .rpc();
+      assert.fail("a move needs two different sub-accounts");
+    } catch (err) {
+      assert.include(err.toString(), "SameSubAccount");
+    }
+  });
+
+  it("Sets the market trading status", async () => {
+    await program.methods
+      .setMarketStatus({ reduceOnly: {} })
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.notEqual(market.reduceOnlyFlags & 8, 0);
+
+    await program.methods
+      .setMarketStatus({ paused: {} })
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isTrue(market.pauseRatified);
+    assert.equal(market.reduceOnlyFlags & 8, 0);
+
+    await program.methods
+      .setMarketStatus({ active: {} })
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isFalse(market.pauseRatified);
+  });
+
+  it("Refuses to close a market that is not delisted", async () => {
+    try {
+      await program.methods
+        .closeMarket()
+        .accounts({
+          market: marketKeypair.publicKey,
+          authority: provider.wallet.publicKey,
+          authorityTokenAccount: marketVault.publicKey,
+          marketVault: marketVault.publicKey,
+          protocolConfig,
+          treasury: marketVault.publicKey,
+        })
+        .rpc();
+      assert.fail("an active market cannot be closed");
+    } catch (err) {
+      assert.include(err.toString(), "MarketNotExpired");
+    }
+  });
+
+  it("Schedules and cancels a fee holiday", async () => {
+    const now = Math.floor(Date.now() / 1000);
+    try {
+      await program.methods
+        .scheduleFeeHoliday(new anchor.BN(now + 100), new anchor.BN(now + 50), 10000)
+        .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("a holiday must end after it starts");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .scheduleFeeHoliday(new anchor.BN(now + 3600), new anchor.BN(now + 7200), 5000)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isTrue(market.feeHolidays.some((holiday) => holiday.discountBps === 5000));
+
+    await program.methods
+      .cancelFeeHoliday(new anchor.BN(now + 3600))
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isTrue(market.feeHolidays.every((holiday) => holiday.end.toNumber() === 0));
+  });
+
+  it("Updates market parameters within bounds", async () => {
+    try {
+      await program.methods
+        .updateMarketParams({
+          minBaseOrderSize: null,
+          tickSize: new anchor.BN(0),
+          maxLeverage: null,
+          liquidationThreshold: null,
+          maintenanceMarginFraction: null,
+          maxPositionSize: null,
+          maxOracleStalenessSecs: null,
+        })
+        .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("tick size must stay positive");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    const before = await program.account.market.fetch(marketKeypair.publicKey);
+    await program.methods
+      .updateMarketParams({
+        minBaseOrderSize: null,
+        tickSize: null,
+        maxLeverage: 5,
+        liquidationThreshold: null,
+        maintenanceMarginFraction: null,
+        maxPositionSize: null,
+        maxOracleStalenessSecs: null,
+      })
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.maxLeverage, 5);
+    assert.equal(market.tickSize.toString(), before.tickSize.toString());
+    assert.equal(market.maxPositionSize.toString(), before.maxPositionSize.toString());
+  });
+
+  it("Bounds the market's oracle staleness tolerance", async () => {
+    const update = (maxOracleStalenessSecs: anchor.BN) =>
+      program.methods
+        .updateMarketParams({
+          minBaseOrderSize: null,
+          tickSize: null,
+          maxLeverage: null,
+          liquidationThreshold: null,
+          maintenanceMarginFraction: null,
+          maxPositionSize: null,
+          maxOracleStalenessSecs,
+        })
+        .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+        .rpc();
+
+    for (const secs of [0, 61]) {
+      try {
+        await update(new anchor.BN(secs));
+        assert.fail(`a ${secs}s tolerance is out of bounds`);
+      } catch (err) {
+        assert.include(err.toString(), "ParameterOutOfBounds");
+      }
+    }
+
+    await update(new anchor.BN(20));
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.maxOracleStalenessSecs.toNumber(), 20);
+  });
+
+  it("Archives a finished revenue epoch", async () => {
+    const epoch = new anchor.BN(Math.floor(Date.now() / 1000 / 86400));
+    const revenuePda = (e: anchor.BN) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("revenue"), marketKeypair.publicKey.toBuffer(), e.toArrayLike(Buffer, "le", 8)],
+        program.programId
+      )[0];
+
+    try {
+      await program.methods
+        .archiveRevenueEpoch(epoch)
+        .accounts({
+          market: marketKeypair.publicKey,
+          revenueRecord: revenuePda(epoch),
+          payer: provider.wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      assert.fail("the current epoch has not ended");
+    } catch (err) {
+      assert.include(err.toString(), "RevenueEpochOpen");
+    }
+
+    const previous = epoch.subn(1);
+    await program.methods
+      .archiveRevenueEpoch(previous)
+      .accounts({
+        market: marketKeypair.publicKey,
+        revenueRecord: revenuePda(previous),
+        payer: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    const record = await program.account.revenueRecord.fetch(revenuePda(previous));
+    assert.isTrue(record.market.equals(marketKeypair.publicKey));
+    assert.equal(record.revenue.epoch.toString(), previous.toString());
+  });
+
+  it("Transfers the market authority in two steps", async () => {
+    const newAuthority = Keypair.generate();
+    const stranger = Keypair.generate();
+    await program.methods
+      .proposeAuthority(newAuthority.publicKey)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+
+    try {
+      await program.methods
+        .acceptAuthority()
+        .accounts({ market: marketKeypair.publicKey, newAuthority: stranger.publicKey })
+        .signers([stranger])
+        .rpc();
+      assert.fail("only the proposed key can accept");
+    } catch (err) {
+      assert.include(err.toString(), "Unauthorized");
+    }
+
+    await program.methods
+      .acceptAuthority()
+      .accounts({ market: marketKeypair.publicKey, newAuthority: newAuthority.publicKey })
+      .signers([newAuthority])
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isTrue(market.authority.equals(newAuthority.publicKey));
+    assert.isTrue(market.pendingAuthority.equals(PublicKey.default));
+
+    // Hand it back so later tests keep the provider as authority
+    await program.methods
+      .proposeAuthority(provider.wallet.publicKey)
+      .accounts({ market: marketKeypair.publicKey, authority: newAuthority.publicKey })
+      .signers([newAuthority])
+      .rpc();
+    await program.methods
+      .acceptAuthority()
+      .accounts({ market: marketKeypair.publicKey, newAuthority: provider.wallet.publicKey })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.isTrue(market.authority.equals(provider.wallet.publicKey));
+  });
+
+  it("Applies the protocol fee split to a market", async () => {
+    const treasury = await mint.createAccount(provider.wallet.publicKey);
+    await program.methods
+      .setProtocolTreasury()
+      .accounts({ protocolConfig, treasury, admin: provider.wallet.publicKey })
+      .rpc();
+
+    try {
+      await program.methods
+        .setProtocolFeeSplit({
+          insuranceFeeShareBps: 10001, liquidationFeeBps: 100, liquidatorShareBps: 5000, protocolFeeShareBps: 0,
+        })
+        .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("a share cannot exceed 100%");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .setProtocolFeeSplit({
+        insuranceFeeShareBps: 2000, liquidationFeeBps: 100, liquidatorShareBps: 5000, protocolFeeShareBps: 3000,
+      })
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+    await program.methods
+      .applyProtocolFeeSplit()
+      .accounts({ protocolConfig, market: marketKeypair.publicKey, admin: provider.wallet.publicKey })
+      .rpc();
+
+    const config = await program.account.protocolConfig.fetch(protocolConfig);
+    assert.isTrue(config.treasury.equals(treasury));
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.insuranceFeeShareBps, 2000);
+    assert.equal(market.liquidationFeeBps, 100);
+    assert.equal(market.liquidatorShareBps, 5000);
+    assert.equal(market.protocolFeeShareBps, 3000);
+  });
+
+  it("Refuses to withdraw more fees than accrued", async () => {
+    const config = await program.account.protocolConfig.fetch(protocolConfig);
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    try {
+      await program.methods
+        .withdrawFees(market.protocolFeesAccrued.addn(1))
+        .accounts({
+          protocolConfig,
+          market: marketKeypair.publicKey,
+          marketVault: marketVault.publicKey,
+          treasury: config.treasury,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("cannot withdraw fees the market has not accrued");
+    } catch (err) {
+      assert.include(err.toString(), "InsufficientFees");
+    }
+  });
+
+  it("Sets a maker fee up to the taker rate", async () => {
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    try {
+      await program.methods
+        .setMakerFee(market.feeBps + 1)
+        .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("makers cannot pay more than takers");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .setMakerFee(-2)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.makerFeeBps, -2);
+  });
+
+  it("Discounts taker fees by 30-day volume tier", async () => {
+    try {
+      await program.methods
+        .setFeeTiers([
+          { minVolume: new anchor.BN(1_000_000), discountBps: 2000 },
+          { minVolume: new anchor.BN(500_000), discountBps: 3000 },
+        ])
+        .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("tiers must rise in volume");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .setFeeTiers([
+        { minVolume: new anchor.BN(1_000_000), discountBps: 1000 },
+        { minVolume: new anchor.BN(10_000_000), discountBps: 2500 },
+      ])
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+    const config = await program.account.protocolConfig.fetch(protocolConfig);
+    assert.equal(config.feeTiers[1].discountBps, 2500);
+    assert.equal(config.feeTiers[2].discountBps, 0);
+
+    const [traderStats] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader_stats"), provider.wallet.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .initializeTraderStats()
+      .accounts({ traderStats, authority: provider.wallet.publicKey, systemProgram: SystemProgram.programId })
+      .rpc();
+    const stats = await program.account.traderStats.fetch(traderStats);
+    assert.isTrue(stats.authority.equals(provider.wallet.publicKey));
+    assert.equal(stats.dailyVolume.length, 30);
+  });
+
+  it("Registers a referral code and refuses an empty claim", async () => {
+    const code = Buffer.alloc(16);
+    code.write("MOON");
+    const [referralCode] = PublicKey.findProgramAddressSync(
+      [Buffer.from("referral_code"), code],
+      program.programId
+    );
+    await program.methods
+      .createReferralCode([...code])
+      .accounts({ referralCode, owner: provider.wallet.publicKey, systemProgram: SystemProgram.programId })
+      .rpc();
+    const account = await program.account.referralCode.fetch(referralCode);
+    assert.isTrue(account.owner.equals(provider.wallet.publicKey));
+    assert.equal(account.unclaimedRewards.toNumber(), 0);
+
+    const [referralAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("referral_authority")],
+      program.programId
+    );
+    const referralVault = await mint.createAccount(referralAuthority);
+    await program.methods
+      .configureReferrals(2000)
+      .accounts({ protocolConfig, referralVault, referralAuthority, admin: provider.wallet.publicKey })
+      .rpc();
+
+    try {
+      await program.methods
+        .claimReferralRewards()
+        .accounts({
+          protocolConfig,
+          referralCode,
+          referralVault,
+          referralAuthority,
+          ownerTokenAccount: userTokenAccount.publicKey,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+      assert.fail("nothing has been earned yet");
+    } catch (err) {
+      assert.include(err.toString(), "NoReferralRewards");
+    }
+  });
+
+  it("Caps open interest at the LP vault's utilization", async () => {
+    try {
+      await program.methods
+        .setLpUtilizationCap(10001)
+        .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+        .rpc();
+      assert.fail("utilization cannot exceed 100%");
+    } catch (err) {
+      assert.include(err.toString(), "ParameterOutOfBounds");
+    }
+
+    await program.methods
+      .setLpUtilizationCap(8000)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.lpMaxUtilizationBps, 8000);
+
+    await program.methods
+      .setLpUtilizationCap(0)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.lpMaxUtilizationBps, 0);
+  });
+
+  it("Sets and disables the vAMM depth", async () => {
+    await program.methods
+      .setVammDepth(new anchor.BN(1_000_000_000))
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    let market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.vamm.baseDepth.toNumber(), 1_000_000_000);
+
+    await program.methods
+      .setVammDepth(new anchor.BN(0))
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.vamm.baseDepth.toNumber(), 0);
+  });
+
+  it("Rejects a market order filling past its slippage bound", async () => {
+    const positionKey = await nextPosition();
+    try {
+      // Expecting 1000 for a short when the oracle is at 100
+      await program.methods
+        .placeOrder({ short: {} }, new anchor.BN(1000), new anchor.BN(1000), new anchor.BN(1000), 50, 2, 0, NO_TAG, null)
+        .accounts({
+          protocolConfig,
+          market: marketKeypair.publicKey,
+          user: provider.wallet.publicKey,
+          marginAccount,
+          position: positionKey,
+          userTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      assert.fail("fill is far below the expected price");
+    } catch (err) {
+      assert.include(err.toString(), "SlippageExceeded");
+    }
+  });
+
+  it("Rejects a market order landing after its expiry", async () => {
+    const positionKey = await nextPosition();
+    const expired = new anchor.BN(Math.floor(Date.now() / 1000) - 60);
+    try {
+      await program.methods
+        .placeOrder({ long: {} }, new anchor.BN(1000), new anchor.BN(1000), new anchor.BN(100), MAX_SLIPPAGE_BPS, 2, 0, NO_TAG, expired)
+        .accounts({
+          protocolConfig,
+          market: marketKeypair.publicKey,
+          user: provider.wallet.publicKey,
+          marginAccount,
+          position: positionKey,
+          userTokenAccount: userTokenAccount.publicKey,
+          marketVault: marketVault.publicKey,
+          priceFeed: mockPriceFeed.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      assert.fail("order expired a minute ago");
+    } catch (err) {
+      assert.include(err.toString(), "OrderExpired");
+    }
+  });
+
+  it("Publishes prices to a custom oracle", async () => {
+    const customOracle = Keypair.generate();
+    await program.methods
+      .initializeCustomOracle(-6)
+      .accounts({
+        customOracle: customOracle.publicKey,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([customOracle])
+      .rpc();
+
+    await program.methods
+      .updateCustomOracle(new anchor.BN(1_500_000), new anchor.BN(1_000))
+      .accounts({ customOracle: customOracle.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    const oracle = await program.account.customOracle.fetch(customOracle.publicKey);
+    assert.equal(oracle.price.toNumber(), 1_500_000);
+    assert.equal(oracle.expo, -6);
+    assert.isAbove(oracle.publishTime.toNumber(), 0);
+
+    try {
+      await program.methods
+        .updateCustomOracle(new anchor.BN(1_500_000), new anchor.BN(1_000))
+        .accounts({ customOracle: customOracle.publicKey, authority: Keypair.generate().publicKey })
+        .rpc();
+      assert.fail("only the authority publishes");
+    } catch (err) {
+      assert.notInclude(err.toString(), "only the authority publishes");
+    }
+
+    // A zero confidence, or one over 10% of the price
+    for (const conf of [0, 150_001]) {
+      try {
+        await program.methods
+          .updateCustomOracle(new anchor.BN(1_500_000), new anchor.BN(conf))
+          .accounts({ customOracle: customOracle.publicKey, authority: provider.wallet.publicKey })
+          .rpc();
+        assert.fail("the confidence is out of bounds");
+      } catch (err) {
+        assert.include(err.toString(), "InvalidConfidence");
+      }
+    }
+    await program.methods
+      .updateCustomOracle(new anchor.BN(1_500_000), new anchor.BN(150_000))
+      .accounts({ customOracle: customOracle.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    assert.equal((await program.account.customOracle.fetch(customOracle.publicKey)).conf.toNumber(), 150_000);
+  });
+
+  it("Bounds the market's oracle confidence settings", async () => {
+    for (const [maxConfBps, confMultiplierBps] of [[0, 10000], [10001, 10000], [200, 50001]]) {
+      try {
+        await program.methods
+          .setOracleConfidence(maxConfBps, confMultiplierBps)
+          .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+          .rpc();
+        assert.fail("confidence settings out of bounds");
+      } catch (err) {
+        assert.include(err.toString(), "ParameterOutOfBounds");
+      }
+    }
+
+    await program.methods
+      .setOracleConfidence(300, 20000)
+      .accounts({ market: marketKeypair.publicKey, authority: provider.wallet.publicKey })
+      .rpc();
+    const market = await program.account.market.fetch(marketKeypair.publicKey);
+    assert.equal(market.maxOracleConfBps, 300);
+    assert.equal(market.oracleConfMultiplierBps, 20000);
+  });
+
+  it("Swaps into the quote token and credits the deposit as collateral", async () => {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const jupiterProgram = new PublicKey("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+    const [poolAuthority] = PublicKey.findProgramAddressSync([Buffer.from("pool")], jupiterProgram);
+    const [collateralAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral_authority")],
+      program.programId
+    );
+
+    const quoteMint = await createMint(provider.connection, payer, provider.wallet.publicKey, null, 6);
+    const sourceMint = await createMint(provider.connection, payer, provider.wallet.publicKey, null, 6);
+    const collateralVault = await createAccount(
+      provider.connection, payer, quoteMint, collateralAuthority, Keypair.generate()
+    );
+    const poolSource = await createAccount(provider.connection, payer, sourceMint, poolAuthority, Keypair.generate());
+    const poolQuote = await createAccount(provider.connection, payer, quoteMint, poolAuthority, Keypair.generate());
+    const userSource = await createAccount(provider.connection, payer, sourceMint, provider.wallet.publicKey);
+    const userQuote = await createAccount(provider.connection, payer, quoteMint, provider.wallet.publicKey);
+    await mintTo(provider.connection, payer, quoteMint, poolQuote, payer, 1_000_000);
+    await mintTo(provider.connection, payer, sourceMint, userSource, payer, 500_000);
+
+    await program.methods
+      .setCollateralVault()
+      .accounts({ protocolConfig, collateralVault, collateralAuthority, admin: provider.wallet.publicKey })
+      .rpc();
+
+    // The mock route sells `amountIn` of the source token for `amountOut` of the quote token
+    const route = (amountIn: number, amountOut: number) =>
+      Buffer.concat([
+        new anchor.BN(amountIn).toArrayLike(Buffer, "le", 8),
+        new anchor.BN(amountOut).toArrayLike(Buffer, "le", 8),
+      ]);
+    const swap = (amountIn: number, amountOut: number, minAmountOut: number) =>
+      program.methods
+        .swapAndDeposit(route(amountIn, amountOut), new anchor.BN(amountIn), new anchor.BN(minAmountOut), 0)
+        .accounts({
+          protocolConfig,
+          user: provider.wallet.publicKey,
+          marginAccount,
+          sourceTokenAccount: userSource,
+          userQuoteAccount: userQuote,
+          collateralVault,
+          jupiterProgram,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts([
+          { pubkey: provider.wallet.publicKey, isSigner: true, isWritable: false },
+          { pubkey: userSource, isSigner: false, isWritable: true },
+          { pubkey: poolSource, isSigner: false, isWritable: true },
+          { pubkey: poolQuote, isSigner: false, isWritable: true },
+          { pubkey: userQuote, isSigner: false, isWritable: true },
+          { pubkey: poolAuthority, isSigner: false, isWritable: false },
+          { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        ])
+        .rpc();
+
+    const before = await program.account.marginAccount.fetch(marginAccount);
+    await swap(100_000, 95_000, 90_000);
+    const after = await program.account.marginAccount.fetch(marginAccount);
+    assert.equal(after.collateral.sub(before.collateral).toNumber(), 95_000);
+    assert.equal(Number((await getAccount(provider.connection, collateralVault)).amount), 95_000);
+    assert.equal(Number((await getAccount(provider.connection, userSource)).amount), 400_000);
+    assert.equal(Number((await getAccount(provider.connection, userQuote)).amount), 0);
+
+    try {
+      await swap(100_000, 95_000, 96_000);
+      assert.fail("a route paying less than min_amount_out must be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "SlippageExceeded");
+    }
+    const unchanged = await program.account.marginAccount.fetch(marginAccount);
+    assert.equal(unchanged.collateral.toString(), after.collateral.toString());
+  });
+
+  it("Covers a gapped liquidation's shortfall from the insurance fund, then the backstop", async () => {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const gap = await oracleMarket("GAP/USD", 100);
+    const { market, vault, vaultAuthority, oracle, quoteMint, publish } = gap;
+    const trader = await fundedTrader(quoteMint, 100_000);
+    const { account: traderAccount, tokens: traderTokens } = trader;
+    const [insuranceFund] = PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance_fund"), market.toBuffer()],
+      program.programId
+    );
+    const [insuranceBackstop] = PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance_backstop")],
+      program.programId
+    );
+    const insuranceVault = await createAccount(provider.connection, payer, quoteMint, insuranceFund, Keypair.generate());
+    const backstopVault = await createAccount(
+      provider.connection, payer, quoteMint, insuranceBackstop, Keypair.generate()
+    );
+    const funder = await createAccount(provider.connection, payer, quoteMint, provider.wallet.publicKey, Keypair.generate());
+    await mintTo(provider.connection, payer, quoteMint, funder, payer, 100_000);
+
+    await program.methods
+      .initializeInsuranceFund()
+      .accounts({
+        market,
+        insuranceFund,
+        insuranceVault,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    await program.methods
+      .depositInsurance(new anchor.BN(6_000))
+      .accounts({
+        insuranceFund,
+        insuranceVault,
+        depositor: provider.wallet.publicKey,
+        depositorTokenAccount: funder,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        market,
+        insuranceCoverage: null,
+      })
+      .rpc();
+    await program.methods
+      .initializeInsuranceBackstop()
+      .accounts({
+        protocolConfig,
+        insuranceBackstop,
+        backstopVault,
+        admin: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+    await program.methods
+      .depositBackstop(new anchor.BN(10_000))
+      .accounts({
+        insuranceBackstop,
+        backstopVault,
+        depositor: provider.wallet.publicKey,
+        depositorTokenAccount: funder,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    // 1,000 long at 100 with 10x leverage posts 10,000 of margin: bankrupt at 90
+    const positionKey = await openPosition(gap, trader, { long: {} }, 1_000, 100, 10);
+    const position = await program.account.position.fetch(positionKey);
+    assert.equal(position.margin.toNumber(), 10_000);
+    const vaultBefore = Number((await getAccount(provider.connection, vault)).amount);
+
+    // The oracle gaps straight to 80: a 20,000 loss on 10,000 of margin
+    await publish(80);
+    const preview = await program.methods
+      .viewLiquidationOutcome()
+      .accounts({ market, position: positionKey, priceFeed: oracle })
+      .view();
+    assert.isTrue(preview.liquidatable);
+    assert.equal(preview.oraclePrice.toNumber(), 80);
+    assert.equal(preview.exitPrice.toNumber(), 90);
+    assert.equal(preview.shortfall.toNumber(), 10_000);
+    assert.equal(preview.ownerAmount.toNumber(), 0);
+    assert.equal(preview.liquidatorFee.toNumber() + preview.insuranceFee.toNumber(), 0);
+    await program.methods
+      .liquidatePosition()
+      .accounts({
+        protocolConfig,
+        market,
+        marginAccount: traderAccount,
+        position: positionKey,
+        userTokenAccount: traderTokens,
+        marketVault: vault,
+        vaultAuthority,
+        liquidatorTokenAccount: funder,
+        priceFeed: oracle,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        insuranceFund,
+        insuranceVault,
+        insuranceBackstop,
+        backstopVault,
+        liquidator: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    assert.isNull(await program.account.position.fetchNullable(positionKey));
+    const fund = await program.account.insuranceFund.fetch(insuranceFund);
+    assert.equal(fund.totalBadDebt.toNumber(), preview.shortfall.toNumber());
+    assert.equal(fund.coveredByFund.toNumber(), 6_000);
+    assert.equal(fund.coveredByBackstop.toNumber(), 4_000);
+    assert.equal(fund.uncoveredBadDebt.toNumber(), 0);
+    assert.equal(Number((await getAccount(provider.connection, insuranceVault)).amount), 0);
+    assert.equal(Number((await getAccount(provider.connection, backstopVault)).amount), 6_000);
+    assert.equal(Number((await getAccount(provider.connection, vault)).amount), vaultBefore + 10_000);
+    // The trader's margin is gone and the market closed them out at the bankruptcy price
+    assert.equal(Number((await getAccount(provider.connection, traderTokens)).amount), 100_000 - vaultBefore);
+    const marketAfter = await program.account.market.fetch(market);
+    assert.equal(marketAfter.lastSettledPrice.toNumber(), preview.exitPrice.toNumber());
+    assert.equal(marketAfter.lastOraclePrice.toNumber(), 80);
+    assert.equal(marketAfter.longOpenInterest.toNumber(), 0);
+  });
+
+  it("Converts a winning position into the market's spot token", async () => {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const jupiterProgram = new PublicKey("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+    const [poolAuthority] = PublicKey.findProgramAddressSync([Buffer.from("pool")], jupiterProgram);
+    const spot = await oracleMarket("SPOT/USD", 100);
+    const trader = await fundedTrader(spot.quoteMint, 100_000);
+    const spotMint = await createMint(provider.connection, payer, provider.wallet.publicKey, null, 6);
+    const traderSpot = await createAccount(provider.connection, payer, spotMint, trader.trader.publicKey);
+    const poolQuote = await createAccount(provider.connection, payer, spot.quoteMint, poolAuthority, Keypair.generate());
+    const poolSpot = await createAccount(provider.connection, payer, spotMint, poolAuthority, Keypair.generate());
+    await mintTo(provider.connection, payer, spotMint, poolSpot, payer, 1_000_000);
+    await program.methods.setBaseMint(spotMint).accounts(spot.admin).rpc();
+
+    // 1,000 long at 100 with 10x leverage, then the price rises 10%: 10,000
+    // of margin plus 10,000 of profit. The vault is topped up to pay the win
+    const positionKey = await openPosition(spot, trader, { long: {} }, 1_000, 100, 10);
+    await mintTo(provider.connection, payer, spot.quoteMint, spot.vault, payer, 10_000);
+    await spot.publish(110);
+    const quoteBefore = Number((await getAccount(provider.connection, trader.tokens)).amount);
+
+    // The mock route spends the whole 20,000 payout on 2,000 of the spot token
+    const route = Buffer.concat([
+      new anchor.BN(20_000).toArrayLike(Buffer, "le", 8),
+      new anchor.BN(2_000).toArrayLike(Buffer, "le", 8),
+    ]);
+    await program.methods
+      .convertToSpot(route, new anchor.BN(2_000), 0)
+      .accounts({
+        market: spot.market,
+        protocolConfig,
+        owner: trader.trader.publicKey,
+        marginAccount: trader.account,
+        position: positionKey,
+        ownerQuoteAccount: trader.tokens,
+        spotTokenAccount: traderSpot,
+        marketVault: spot.vault,
+        vaultAuthority: spot.vaultAuthority,
+        priceFeed: spot.oracle,
+        jupiterProgram,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts([
+        { pubkey: trader.trader.publicKey, isSigner: true, isWritable: false },
+        { pubkey: trader.tokens, isSigner: false, isWritable: true },
+        { pubkey: poolQuote, isSigner: false, isWritable: true },
+        { pubkey: poolSpot, isSigner: false, isWritable: true },
+        { pubkey: traderSpot, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ])
+      .signers([trader.trader])
+      .rpc();
+
+    assert.equal(Number((await getAccount(provider.connection, traderSpot)).amount), 2_000);
+    assert.equal(Number((await getAccount(provider.connection, trader.tokens)).amount), quoteBefore);
+    assert.equal(Number((await getAccount(provider.connection, poolQuote)).amount), 20_000);
+    assert.isNull(await program.account.position.fetchNullable(positionKey));
+    const account = await program.account.marginAccount.fetch(trader.account);
+    assert.equal(account.positionCount, 0);
+    const market = await program.account.market.fetch(spot.market);
+    assert.equal(market.longOpenInterest.toNumber(), 0);
+    assert.equal(market.lastSettledPrice.toNumber(), 110);
+  });
+
+  it("Liquidates a position that is healthy at the index but not at the confidence band's edge", async () => {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const band = await oracleMarket("BAND/USD", 100);
+    const trader = await fundedTrader(band.quoteMint, 100_000);
+    const keeperTokens = await createAccount(
+      provider.connection, payer, band.quoteMint, provider.wallet.publicKey, Keypair.generate()
+    );
+
+    // 100 long at 100 with 1x leverage posts 10,000 of margin and is liquidated at 95
+    const positionKey = await openPosition(band, trader, { long: {} }, 100, 100, 1);
+    const position = await program.account.position.fetch(positionKey);
+    assert.equal(position.margin.toNumber(), 10_000);
+    assert.equal(position.liquidationPrice.toNumber(), 95);
+
+    const liquidate = () =>
+      program.methods
+        .liquidatePosition()
+        .accounts({
+          protocolConfig,
+          market: band.market,
+          marginAccount: trader.account,
+          position: positionKey,
+          userTokenAccount: trader.tokens,
+          marketVault: band.vault,
+          vaultAuthority: band.vaultAuthority,
+          liquidatorTokenAccount: keeperTokens,
+          priceFeed: band.oracle,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          liquidator: provider.wallet.publicKey,
+        })
+        .rpc();
+
+    // 96 with a confidence of 1: healthy while the band is off
+    await band.publish(96, 1);
+    try {
+      await liquidate();
+      assert.fail("the position is healthy at the index price");
+    } catch (err) {
+      assert.include(err.toString(), "CannotLiquidate");
+    }
+
+    // With k = 1 the long is checked at 96 - 1 = 95
+    await program.methods.setOracleConfidence(200, 10000).accounts(band.admin).rpc();
+    await liquidate();
+    assert.isNull(await program.account.position.fetchNullable(positionKey));
+    const market = await program.account.market.fetch(band.market);
+    assert.equal(market.lastSettledPrice.toNumber(), 95);
+    assert.equal(market.longOpenInterest.toNumber(), 0);
+
+    // A new long posts the move to the band edge on top of its initial margin
+    const reopened = await openPosition(band, trader, { long: {} }, 100, 96, 1);
+    assert.equal((await program.account.position.fetch(reopened)).margin.toNumber(), 9_600 + 100);
+  });
+
+  it("Keeps limit orders and their matches within a band of the index", async () => {
+    const m = await oracleMarket("CROSS/USD", 100);
+    const [orderBook] = PublicKey.findProgramAddressSync(
+      [Buffer.from("order_book"), m.market.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .initializeOrderBook()
+      .accounts({ market: m.market, orderBook, authority: provider.wallet.publicKey, systemProgram: SystemProgram.programId })
+      .rpc();
+    const t = await fundedTrader(m.quoteMint, 1_000_000);
+    const place = async (side: object, price: number) => {
+      const account = await program.account.marginAccount.fetch(t.account);
+      return program.methods
+        .placeLimitOrder(side, new anchor.BN(price), new anchor.BN(10), 5, 0, NO_TAG)
+        .accounts({
+          protocolConfig,
+          market: m.market,
+          orderBook,
+          user: t.trader.publicKey,
+          marginAccount: t.account,
+          position: positionAddress(account.nextPositionId, t.account, m.market),
+          userTokenAccount: t.tokens,
+          marketVault: m.vault,
+          vaultAuthority: m.vaultAuthority,
+          priceFeed: m.oracle,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([t.trader])
+        .rpc();
+    };
+
+    // A wallet crossing its own orders at 10x the index would open both sides
+    // there and close the short at the index for the difference
+    for (const side of [{ long: {} }, { short: {} }]) {
+      try {
+        await place(side, 1000);
+        assert.fail("a limit price far from the index should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "LimitPriceOutsideBand");
+      }
+    }
+
+    // Within the 10% band the bid escrows its initial margin plus the 5 it is
+    // above the index on each of its 10 units
+    await place({ long: {} }, 105);
+    await place({ short: {} }, 105);
+    let book = await program.account.orderBook.fetch(orderBook);
+    assert.equal(book.bids[0].lockedMargin.toNumber(), (10 * 105) / 5 + 10 * 5);
+
+    // Once the index moves away, the resting orders cannot match
+    const match = () =>
+      program.methods
+        .matchOrders(1)
+        .accounts({ protocolConfig, market: m.market, orderBook, priceFeed: m.oracle })
+        .remainingAccounts(
+          [...book.bids, ...book.asks].map((order) => ({ pubkey: order.position, isWritable: true, isSigner: false }))
+        )
+        .rpc();
+    await m.publish(50);
+    try {
+      await match();
+      assert.fail("a match far from the index should be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "LimitPriceOutsideBand");
+    }
+
+    await m.publish(100);
+    await match();
+    book = await program.account.orderBook.fetch(orderBook);
+    assert.equal(book.bids.length + book.asks.length, 0);
+  });
+
+  it("Closes on the vAMM curve, so a round trip against skewed open interest does not profit", async () => {
+    const price = 1_000_000;
+    const depth = 100_000n;
+    const amm = await oracleMarket("VAMM/USD", price);
+    await program.methods.setVammDepth(new anchor.BN(depth.toString())).accounts(amm.admin).rpc();
+    // Average price moving net long open interest from `from` to `to`, as `vamm_price` computes it
+    const vammPrice = (from: bigint, to: bigint, roundUp: boolean) => {
+      const mulDiv = (a: bigint, b: bigint, d: bigint) => (a * b + (roundUp ? d - 1n : 0n)) / d;
+      return Number(mulDiv(mulDiv(BigInt(price), depth, depth - from), depth, depth - to));
+    };
+
+    // A whale leaves the market 300 net long, so the mark sits above the index
+    const whale = await fundedTrader(amm.quoteMint, 1_000_000_000);
+    await openPosition(amm, whale, { long: {} }, 300, price, 10);
+
+    // A short sells into the premium, taking net long from 300 to 200...
+    const trader = await fundedTrader(amm.quoteMint, 1_000_000_000);
+    const positionKey = await openPosition(amm, trader, { short: {} }, 100, price, 10);
+    const position = await program.account.position.fetch(positionKey);
+    const entryPrice = vammPrice(300n, 200n, false);
+    assert.equal(position.entryPrice.toNumber(), entryPrice);
+    assert.isAbove(entryPrice, price);
+
+    // ...and buys back up the same stretch of the curve, not at the index
+    await program.methods
+      .reducePosition(new anchor.BN(100), 0)
+      .accounts({
+        market: amm.market,
+        protocolConfig,
+        owner: trader.trader.publicKey,
+        marginAccount: trader.account,
+        position: positionKey,
+        ownerTokenAccount: trader.tokens,
+        marketVault: amm.vault,
+        vaultAuthority: amm.vaultAuthority,
+        priceFeed: amm.oracle,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([trader.trader])
+      .rpc();
+    const market = await program.account.market.fetch(amm.market);
+    assert.equal(market.lastSettledPrice.toNumber(), vammPrice(200n, 300n, true));
+    assert.isAtLeast(market.lastSettledPrice.toNumber(), entryPrice);
+    assert.equal(market.lastOraclePrice.toNumber(), price);
+    const balance = Number((await getAccount(provider.connection, trader.tokens)).amount);
+    assert.isBelow(balance, 1_000_000_000);
+  });
+
+  it("Refuses an expired stop-loss and lets a keeper remove it from the registry", async () => {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const expiring = await oracleMarket("EXPIRE/USD", 100);
+    const trader = await fundedTrader(expiring.quoteMint, 100_000);
+    const positionKey = await openPosition(expiring, trader, { long: {} }, 100, 100, 1);
+    const keeperTokens = await createAccount(
+      provider.connection, payer, expiring.quoteMint, provider.wallet.publicKey, Keypair.generate()
+    );
+    const [triggerOrder] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trigger_order"), positionKey.toBuffer(), Buffer.from("stop_loss")],
+      program.programId
+    );
+    const [triggerRegistry] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trigger_registry"), expiring.market.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .initializeTriggerRegistry()
+      .accounts({
+        market: expiring.market,
+        triggerRegistry,
+        authority: provider.wallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    // A stop-loss the price has already crossed, expiring in two seconds
+    const now = await provider.connection.getBlockTime(await provider.connection.getSlot());
+    await program.methods
+      .placeStopLoss(new anchor.BN(200), 0, new anchor.BN(now + 2))
+      .accounts({
+        market: expiring.market,
+        owner: trader.trader.publicKey,
+        marginAccount: trader.account,
+        position: positionKey,
+        triggerOrder,
+        triggerRegistry,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([trader.trader])
+      .rpc();
+    const removeExpired = () =>
+      program.methods
+        .removeExpiredTriggerOrder()
+        .accounts({ keeper: provider.wallet.publicKey, triggerOrder, triggerRegistry })
+        .rpc();
+    try {
+      await removeExpired();
+      assert.fail("a trigger order that has not expired cannot be removed");
+    } catch (err) {
+      assert.include(err.toString(), "TriggerOrderNotExpired");
+    }
+
+    await new Promise((resolve) => setTimeout(resolve, 4000));
+    await expiring.publish(100);
+    try {
+      await program.methods
+        .executeTriggerOrder()
+        .accounts({
+          market: expiring.market,
+          protocolConfig,
+          triggerOrder,
+          triggerRegistry,
+          marginAccount: trader.account,
+          position: positionKey,
+          ownerTokenAccount: trader.tokens,
+          keeper: provider.wallet.publicKey,
+          keeperTokenAccount: keeperTokens,
+          marketVault: expiring.vault,
+          vaultAuthority: expiring.vaultAuthority,
+          priceFeed: expiring.oracle,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      assert.fail("an expired stop-loss should not close the position");
+    } catch (err) {
+      assert.include(err.toString(), "OrderExpired");
+    }
+    assert.isNotNull(await program.account.position.fetchNullable(positionKey));
+
+    // Any keeper frees the slot and takes the rent
+    const rent = (await provider.connection.getAccountInfo(triggerOrder)).lamports;
+    const keeperBefore = await provider.connection.getBalance(provider.wallet.publicKey);
+    await removeExpired();
+    assert.isNull(await program.account.triggerOrder.fetchNullable(triggerOrder));
+    const registry = await program.account.triggerRegistry.fetch(triggerRegistry);
+    assert.equal(registry.pending.length, 0);
+    // Less the transaction fee
+    assert.isAbove(await provider.connection.getBalance(provider.wallet.publicKey), keeperBefore + rent - 10_000);
+  });
+
+  it("Previews a liquidation's payouts as liquidate_position pays them", async () => {
+    const payer = (provider.wallet as anchor.Wallet).payer;
+    const m = await oracleMarket("PREVIEW/USD", 100);
+    await program.methods.setLiquidationFee(500, 4000).accounts(m.admin).rpc();
+    const trader = await fundedTrader(m.quoteMint, 100_000);
+    const keeperTokens = await createAccount(
+      provider.connection, payer, m.quoteMint, provider.wallet.publicKey, Keypair.generate()
+    );
+
+    // 100 long at 100 with 1x leverage posts 10,000 of margin and is liquidated at 95
+    const positionKey = await openPosition(m, trader, { long: {} }, 100, 100, 1);
+    await m.publish(95);
+    const preview = await program.methods
+      .viewLiquidationOutcome()
+      .accounts({ market: m.market, position: positionKey, priceFeed: m.oracle })
+      .view();
+    // 9,500 is left; the 5% fee on 10,000 of margin splits 40/60 between
+    // liquidator and insurance
+    assert.isTrue(preview.liquidatable);
+    assert.equal(preview.exitPrice.toNumber(), 95);
+    assert.equal(preview.liquidatorFee.toNumber(), 200);
+    assert.equal(preview.insuranceFee.toNumber(), 300);
+    assert.equal(preview.ownerAmount.toNumber(), 9_000);
+    assert.equal(preview.shortfall.toNumber(), 0);
+
+    const traderBefore = Number((await getAccount(provider.connection, trader.tokens)).amount);
+    const insuranceBefore = (await program.account.market.fetch(m.market)).pendingInsurance.toNumber();
+    await program.methods
+      .liquidatePosition()
+      .accounts({
+        protocolConfig,
+        market: m.market,
+        marginAccount: trader.account,
+        position: positionKey,
+        userTokenAccount: trader.tokens,
+        marketVault: m.vault,
+        vaultAuthority: m.vaultAuthority,
+        liquidatorTokenAccount: keeperTokens,
+        priceFeed: m.oracle,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        liquidator: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.market.fetch(m.market);
+    assert.equal(market.lastSettledPrice.toNumber(), preview.exitPrice.toNumber());
+    assert.equal(Number((await getAccount(provider.connection, keeperTokens)).amount), preview.liquidatorFee.toNumber());
+    assert.equal(market.pendingInsurance.toNumber() - insuranceBefore, preview.insuranceFee.toNumber());
+    assert.equal(
+      Number((await getAccount(provider.connection, trader.tokens)).amount) - traderBefore,
+      preview.ownerAmount.toNumber()
+    );
+  });
+
+  it("Previews a market order with the margin, discounted fee and liquidation price it is placed with", async () => {
+    const m = await oracleMarket("HEALTH/USD", 100);
+    const trader = await fundedTrader(m.quoteMint, 10_000_000);
+    const [traderStats] = PublicKey.findProgramAddressSync(
+      [Buffer.from("trader_stats"), trader.trader.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .setFeeTiers([
+        { minVolume: new anchor.BN(1_000_000), discountBps: 1000 },
+        { minVolume: new anchor.BN(10_000_000), discountBps: 2500 },
+      ])
+      .accounts({ protocolConfig, admin: provider.wallet.publicKey })
+      .rpc();
+    await program.methods
+      .initializeTraderStats()
+      .accounts({ traderStats, authority: trader.trader.publicKey, systemProgram: SystemProgram.programId })
+      .signers([trader.trader])
+      .rpc();
+
+    const place = async (size: number) => {
+      const account = await program.account.marginAccount.fetch(trader.account);
+      const positionKey = positionAddress(account.nextPositionId, trader.account, m.market);
+      await program.methods
+        .placeOrder({ long: {} }, new anchor.BN(size), new anchor.BN(size), new anchor.BN(100), MAX_SLIPPAGE_BPS, 5, 0,
+          NO_TAG, null)
+        .accounts({
+          protocolConfig,
+          market: m.market,
+          user: trader.trader.publicKey,
+          marginAccount: trader.account,
+          position: positionKey,
+          userTokenAccount: trader.tokens,
+          marketVault: m.vault,
+          priceFeed: m.oracle,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          traderStats,
+        })
+        .signers([trader.trader])
+        .rpc();
+      return positionKey;
+    };
+
+    // 1,000,000 of notional reaches the first tier's 10% discount
+    const first = await place(10_000);
+    const preview = (stats: PublicKey | null) =>
+      program.methods
+        .viewPostTradeHealth({ long: {} }, new anchor.BN(1_000), 5)
+        .accounts({
+          market: m.market,
+          protocolConfig,
+          marginAccount: trader.account,
+          position: null,
+          marketVault: m.vault,
+          priceFeed: m.oracle,
+          traderStats: stats,
+        })
+        .remainingAccounts([
+          { pubkey: first, isSigner: false, isWritable: false },
+          { pubkey: m.market, isSigner: false, isWritable: false },
+          { pubkey: m.oracle, isSigner: false, isWritable: false },
+        ])
+        .view();
+    const undiscounted = await preview(null);
+    const health = await preview(traderStats);
+    assert.isBelow(health.fee.toNumber(), undiscounted.fee.toNumber());
+
+    const tokensBefore = Number((await getAccount(provider.connection, trader.tokens)).amount);
+    const positionKey = await place(1_000);
+    const position = await program.account.position.fetch(positionKey);
+    const paid = tokensBefore - Number((await getAccount(provider.connection, trader.tokens)).amount);
+    assert.equal(health.fillSize.toNumber(), position.baseSize.toNumber());
+    assert.equal(health.price.toNumber(), position.entryPrice.toNumber());
+    assert.equal(health.requiredMargin.toNumber(), position.margin.toNumber());
+    assert.equal(health.fee.toNumber(), paid - position.margin.toNumber());
+    assert.equal(health.liquidationPrice.toNumber(), position.liquidationPrice.toNumber());
   });
 });
@@ -0,0 +1,67 @@
+//! Stand-in for Jupiter in local tests, loaded at `JUPITER_PROGRAM_ID` by
+//! the test validator. A "route" swaps at a fixed quote against a pool
+//! owned by the `["pool"]` PDA: it takes `amount_in` of the source token
+//! from the user and pays `amount_out` of the quote token back.
+//!
+//! Instruction data: `amount_in` and `amount_out`, both u64 LE.
+//! Accounts: user (signer), user source, pool source, pool quote, user
+//! quote, pool authority, token program.
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
+
+pub const POOL_SEED: &[u8] = b"pool";
+
+pub fn process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 16 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let amount_in = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let amount_out = u64::from_le_bytes(data[8..].try_into().unwrap());
+
+    let accounts = &mut accounts.iter();
+    let user = next_account_info(accounts)?;
+    let user_source = next_account_info(accounts)?;
+    let pool_source = next_account_info(accounts)?;
+    let pool_quote = next_account_info(accounts)?;
+    let user_quote = next_account_info(accounts)?;
+    let pool_authority = next_account_info(accounts)?;
+    let token_program = next_account_info(accounts)?;
+
+    let (expected_authority, bump) = Pubkey::find_program_address(&[POOL_SEED], program_id);
+    if *pool_authority.key != expected_authority {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    invoke(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            user_source.key,
+            pool_source.key,
+            user.key,
+            &[],
+            amount_in,
+        )?,
+        &[user_source.clone(), pool_source.clone(), user.clone(), token_program.clone()],
+    )?;
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            token_program.key,
+            pool_quote.key,
+            user_quote.key,
+            pool_authority.key,
+            &[],
+            amount_out,
+        )?,
+        &[pool_quote.clone(), user_quote.clone(), pool_authority.clone(), token_program.clone()],
+        &[&[POOL_SEED, &[bump]]],
+    )
+}